@@ -13,6 +13,8 @@ fi
 # Build origin argument: prefer ORIGIN_URL (full playlist URL) over ORIGIN_HOST (base only)
 if [ -n "$ORIGIN_URL" ]; then
   ORIGIN_ARG="${ORIGIN_URL}"
+  # ad_proxy reads ORIGIN_HOST from the environment as well, which would take precedence
+  unset ORIGIN_HOST
 else
   ORIGIN_ARG="--origin-host ${ORIGIN_HOST}"
 fi
@@ -0,0 +1,145 @@
+use crate::in_flight::{InFlight, Turn};
+use crate::metrics::Metrics;
+use actix_web::web::{self, Bytes};
+use dashmap::DashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+struct CachedPod {
+    vast: Bytes,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Caches the ad server reply per ad slot until the slot has ended, so the
+/// ad server is called once per break instead of once per viewer. Viewers
+/// asking for the same slot at the same time share a single ad server call.
+#[derive(Clone, Default)]
+pub struct AdPodCache {
+    enabled: bool,
+    pods: Arc<DashMap<String, CachedPod>>,
+    in_flight: InFlight<Option<Bytes>>,
+    metrics: web::Data<Metrics>,
+}
+
+impl AdPodCache {
+    pub fn new(enabled: bool, metrics: web::Data<Metrics>) -> Self {
+        Self {
+            enabled,
+            pods: Arc::new(DashMap::new()),
+            in_flight: InFlight::default(),
+            metrics,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "enabled": self.enabled,
+            "cached_slots": self.pods.len(),
+        }
+    }
+
+    /// Drop all cached pods, e.g. when the slots are rescheduled
+    pub fn clear(&self) {
+        self.pods.clear();
+    }
+
+    /// Return the cached VAST of the slot or call the ad server with `fetch`.
+    /// A fetch without a VAST, e.g. a failed ad server reply, isn't cached.
+    /// The callers waiting for the same slot get the VAST of the leading call,
+    /// none if it failed
+    pub async fn get_or_fetch<F, Fut, E>(
+        &self,
+        slot: &str,
+        expires_at: chrono::DateTime<chrono::Utc>,
+        fetch: F,
+    ) -> Result<Option<Bytes>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<Bytes>, E>>,
+    {
+        let leader = loop {
+            if let Some(vast) = self.cached(slot) {
+                self.metrics.inc("ad_pod_cache_requests_total", &[("result", "hit")]);
+                return Ok(Some(vast));
+            }
+            match self.in_flight.join(slot) {
+                Turn::Lead(leader) => break leader,
+                Turn::Wait(waiter) => {
+                    // A dropped leading call has no VAST to share, the next caller leads
+                    if let Some(vast) = waiter.result().await {
+                        self.metrics.inc("ad_pod_cache_requests_total", &[("result", "coalesced")]);
+                        return Ok(vast);
+                    }
+                }
+            }
+        };
+        // The previous call may have cached it in the meantime
+        if let Some(vast) = self.cached(slot) {
+            self.metrics.inc("ad_pod_cache_requests_total", &[("result", "coalesced")]);
+            leader.finish(Some(vast.clone()));
+            return Ok(Some(vast));
+        }
+
+        self.metrics.inc("ad_pod_cache_requests_total", &[("result", "miss")]);
+        let result = fetch().await;
+        if let Ok(Some(vast)) = &result {
+            if expires_at > chrono::Utc::now() {
+                // Drop the pods of ended slots
+                let now = chrono::Utc::now();
+                self.pods.retain(|_, pod| pod.expires_at > now);
+                self.pods.insert(
+                    slot.to_string(),
+                    CachedPod {
+                        vast: vast.clone(),
+                        expires_at,
+                    },
+                );
+            }
+        }
+        leader.finish(result.as_ref().ok().cloned().flatten());
+
+        result
+    }
+
+    fn cached(&self, slot: &str) -> Option<Bytes> {
+        self.pods
+            .get(slot)
+            .filter(|pod| pod.expires_at > chrono::Utc::now())
+            .map(|pod| pod.vast.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn concurrent_callers_share_one_ad_server_call() {
+        let vast = Bytes::from_static(b"<VAST/>");
+        let cases = [Ok(Some(vast.clone())), Ok(None), Err("ad server down")];
+        for reply in cases {
+            let cache = AdPodCache::new(true, web::Data::new(Metrics::default()));
+            let calls = AtomicUsize::new(0);
+            let expires_at = chrono::Utc::now() + chrono::Duration::seconds(30);
+            let fetch = || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+                reply.clone()
+            };
+
+            let results =
+                futures_util::future::join_all((0..5).map(|_| cache.get_or_fetch("slot-1", expires_at, fetch))).await;
+            assert_eq!(calls.load(Ordering::SeqCst), 1, "{reply:?}");
+            // The waiters get no VAST when the leading call failed
+            let shared = reply.clone().ok().flatten();
+            assert_eq!(results[0], reply);
+            assert!(results[1..].iter().all(|result| *result == Ok(shared.clone())), "{reply:?}");
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::metrics::Metrics;
+use crate::utils::{get_duration_from_linear, get_media_urls_from_linear, get_universal_ad_ids_from_creative};
+use actix_web::http::header;
+use actix_web::web;
+use awc::Client;
+use std::fmt;
+use std::time::Duration;
+use url::Url;
+
+// Larger replies of the selection service are left unread
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// The break an ad pod is selected for
+pub struct SelectionContext<'a> {
+    pub channel: &'a str,
+    pub slot: &'a str,
+    pub session: &'a str,
+    pub duration: f64,
+}
+
+/// A business rules service picking the ads of each pod: the Ads of the VAST
+/// reply, its pod and its buffet, are posted to it as candidates and it
+/// answers with the candidates to serve in their order, `{"ads": [2, 0]}`
+/// serving the third candidate then the first. The pod is served as the ad
+/// server sent it when the service fails or doesn't answer within `timeout`
+#[derive(Clone, Default)]
+pub struct AdSelection {
+    url: Option<Url>,
+    timeout: Duration,
+    metrics: web::Data<Metrics>,
+}
+
+// The candidates posted to the selection service, one per Ad of the VAST
+fn summarize(vast: &vast4_rs::Vast) -> json::JsonValue {
+    let candidates = vast.ads.iter().enumerate().map(|(index, ad)| {
+        let in_line = ad.in_line.as_ref();
+        let creatives = in_line
+            .iter()
+            .flat_map(|in_line| &in_line.creatives.creatives)
+            .filter_map(|creative| {
+                let linear = creative.linear.as_ref()?;
+                let universal_ad_ids = get_universal_ad_ids_from_creative(creative)
+                    .iter()
+                    .map(|id| format!("{}:{}", id.scheme, id.value))
+                    .collect::<Vec<_>>();
+                Some(json::object! {
+                    "id": creative.id.as_deref(),
+                    "ad_id": creative.ad_id.as_deref(),
+                    "universal_ad_ids": universal_ad_ids,
+                    "duration": get_duration_from_linear(linear),
+                    "media_urls": get_media_urls_from_linear(linear),
+                })
+            })
+            .collect::<Vec<_>>();
+        let categories = in_line
+            .iter()
+            .flat_map(|in_line| &in_line.categories)
+            .map(|category| category.code.trim().to_string())
+            .collect::<Vec<_>>();
+        json::object! {
+            "index": index,
+            "id": ad.id.as_deref(),
+            "sequence": ad.sequence,
+            "ad_system": in_line.map(|in_line| in_line.ad_system.name.trim().to_string()),
+            "ad_title": in_line.map(|in_line| in_line.ad_title.trim().to_string()),
+            "advertiser": in_line.and_then(|in_line| in_line.advertiser.as_deref()).map(str::trim),
+            "categories": categories,
+            "price": in_line.and_then(|in_line| in_line.pricing.as_ref()).map(|pricing| pricing.price),
+            "currency": in_line.and_then(|in_line| in_line.pricing.as_ref()).map(|pricing| pricing.currency.trim().to_string()),
+            "creatives": creatives,
+        }
+    });
+    json::JsonValue::Array(candidates.collect())
+}
+
+// The indexes of the candidates picked by a reply of the selection service,
+// each once and in the order of the reply
+fn parse_selection(reply: &json::JsonValue, candidates: usize) -> Result<Vec<usize>, String> {
+    if !reply["ads"].is_array() {
+        return Err("the reply has no ads array".to_string());
+    }
+    let mut selected = Vec::new();
+    for index in reply["ads"].members() {
+        let index = index
+            .as_usize()
+            .filter(|index| *index < candidates)
+            .ok_or_else(|| format!("{index} is not the index of one of the {candidates} candidates"))?;
+        if !selected.contains(&index) {
+            selected.push(index);
+        }
+    }
+    Ok(selected)
+}
+
+impl AdSelection {
+    pub fn new(url: Option<Url>, timeout: Duration, metrics: web::Data<Metrics>) -> Self {
+        Self { url, timeout, metrics }
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "url": self.url.as_ref().map(Url::as_str),
+            "timeout_ms": self.timeout.as_millis() as u64,
+        }
+    }
+
+    /// Keep the Ads of the VAST the selection service picks, in its order
+    pub async fn select(&self, client: &Client, vast: &mut vast4_rs::Vast<'_>, context: &SelectionContext<'_>) {
+        let Some(url) = &self.url else {
+            return;
+        };
+        if vast.ads.is_empty() {
+            return;
+        }
+        let request = json::object! {
+            "channel": context.channel,
+            "slot": context.slot,
+            "session": context.session,
+            "duration": context.duration,
+            "candidates": summarize(vast),
+        };
+        let (result, selected) = match self.request(client, url, request.dump()).await {
+            Ok(reply) => match parse_selection(&reply, vast.ads.len()) {
+                Ok(selected) => ("selected", Some(selected)),
+                Err(err) => {
+                    log::warn!("Invalid ad selection for slot {} from {url}: {err}", context.slot);
+                    ("invalid", None)
+                }
+            },
+            Err(err) => {
+                log::warn!("Ad selection for slot {} failed, serving the pod as is: {err}", context.slot);
+                ("failed", None)
+            }
+        };
+        self.metrics.inc("ad_selection_requests_total", &[("channel", context.channel), ("result", result)]);
+        if let Some(selected) = selected {
+            log::info!(
+                "Ad selection kept candidates {selected:?} of {} for session {} in slot {}",
+                vast.ads.len(),
+                context.session,
+                context.slot
+            );
+            vast.ads = selected.iter().map(|index| vast.ads[*index].clone()).collect();
+        }
+    }
+
+    async fn request(&self, client: &Client, url: &Url, body: String) -> Result<json::JsonValue, String> {
+        let mut response = client
+            .post(url.as_str())
+            .timeout(self.timeout)
+            .insert_header((header::ACCEPT, "application/json"))
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .send_body(body)
+            .await
+            .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("the service answered with status {}", response.status()));
+        }
+        let body = response
+            .body()
+            .limit(MAX_RESPONSE_SIZE)
+            .await
+            .map_err(|err| err.to_string())?;
+        json::parse(&String::from_utf8_lossy(&body)).map_err(|err| format!("invalid reply: {err}"))
+    }
+}
+
+impl fmt::Debug for AdSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AdSelection(url: {:?})", self.url.as_ref().map(Url::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_candidates_of_the_reply() {
+        let vast: vast4_rs::Vast = vast4_rs::from_str(
+            r#"<VAST version="4.1">
+                <Ad id="first" sequence="1"><InLine><AdSystem>ads</AdSystem><AdTitle>First</AdTitle>
+                    <Impression><![CDATA[https://example.com/impression]]></Impression>
+                    <Advertiser>Acme</Advertiser><Category authority="iab">IAB2</Category>
+                    <Creatives><Creative id="c1" adId="a1"><UniversalAdId idRegistry="ad-id.org">ACME0001</UniversalAdId>
+                        <Linear><Duration>00:00:15</Duration><MediaFiles>
+                            <MediaFile delivery="progressive" type="video/mp4" width="1280" height="720"><![CDATA[https://example.com/first.mp4]]></MediaFile>
+                        </MediaFiles></Linear>
+                    </Creative></Creatives>
+                </InLine></Ad>
+                <Ad id="second"><InLine><AdSystem>ads</AdSystem><AdTitle>Second</AdTitle>
+                    <Impression><![CDATA[https://example.com/impression]]></Impression>
+                    <Creatives></Creatives>
+                </InLine></Ad>
+            </VAST>"#,
+        )
+        .unwrap();
+        let candidates = summarize(&vast);
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0]["advertiser"], "Acme");
+        assert_eq!(candidates[0]["categories"][0], "IAB2");
+        assert_eq!(candidates[0]["creatives"][0]["universal_ad_ids"][0], "ad-id.org:ACME0001");
+        assert_eq!(candidates[0]["creatives"][0]["duration"], 15.0);
+        assert_eq!(candidates[1]["sequence"], json::JsonValue::Null);
+
+        assert_eq!(parse_selection(&json::object! {"ads": [1, 0, 1]}, 2), Ok(vec![1, 0]));
+        assert_eq!(parse_selection(&json::object! {"ads": []}, 2), Ok(vec![]));
+        assert!(parse_selection(&json::object! {"ads": [2]}, 2).is_err());
+        assert!(parse_selection(&json::object! {}, 2).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::utils::playback_session_id;
+use actix_web::HttpRequest;
+use clap::ValueEnum;
+use dashmap::DashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// What the sessions over the limits of --max-sessions get
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum SessionOverflow {
+    /// Playlists without ad breaks
+    #[default]
+    Clean,
+    /// 429 Too Many Requests
+    Reject,
+}
+
+impl SessionOverflow {
+    pub fn to_str(&self) -> &str {
+        match self {
+            SessionOverflow::Clean => "clean",
+            SessionOverflow::Reject => "reject",
+        }
+    }
+}
+
+/// Caps the playback sessions getting ads, in total and per client address.
+/// A session is known by its ID (the DASH session or the `X-Playback-Session-Id`
+/// header), or by its address without one, and is active until it hasn't
+/// requested a playlist for `idle_timeout`. The sessions over a limit are
+/// served by the `overflow` policy, and admitted once there is room again.
+#[derive(Clone, Debug, Default)]
+pub struct SessionAdmission {
+    max_sessions: Option<usize>,
+    max_sessions_per_ip: Option<usize>,
+    idle_timeout: Duration,
+    overflow: SessionOverflow,
+    // The client address of each active session, and when it was last seen
+    sessions: Arc<DashMap<String, (Option<IpAddr>, Instant)>>,
+}
+
+impl SessionAdmission {
+    pub fn new(
+        max_sessions: Option<usize>,
+        max_sessions_per_ip: Option<usize>,
+        idle_timeout: Duration,
+        overflow: SessionOverflow,
+    ) -> Self {
+        Self {
+            max_sessions,
+            max_sessions_per_ip,
+            idle_timeout,
+            overflow,
+            sessions: Arc::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_sessions.is_some() || self.max_sessions_per_ip.is_some()
+    }
+
+    pub fn overflow(&self) -> SessionOverflow {
+        self.overflow
+    }
+
+    /// The limit the session of a playlist request is over, if any
+    pub fn check(&self, req: &HttpRequest) -> Option<&'static str> {
+        if !self.is_enabled() {
+            return None;
+        }
+        // An address of X-Forwarded-For has no port
+        let ip = req.connection_info().realip_remote_addr().and_then(|address| {
+            address
+                .parse::<SocketAddr>()
+                .map(|address| address.ip())
+                .or_else(|_| address.parse::<IpAddr>())
+                .ok()
+        });
+        let session = playback_session_id(req)
+            .or_else(|| ip.map(|ip| ip.to_string()))
+            .unwrap_or_default();
+        self.admit(&session, ip, Instant::now())
+    }
+
+    fn admit(&self, session: &str, ip: Option<IpAddr>, now: Instant) -> Option<&'static str> {
+        if let Some(mut entry) = self.sessions.get_mut(session) {
+            entry.1 = now;
+            return None;
+        }
+        self.sessions.retain(|_, (_, seen_at)| now.duration_since(*seen_at) < self.idle_timeout);
+        if self.max_sessions.is_some_and(|max| self.sessions.len() >= max) {
+            log::debug!("Session {session} is over the limit of {:?} sessions", self.max_sessions);
+            return Some("sessions");
+        }
+        if let (Some(max), Some(ip)) = (self.max_sessions_per_ip, ip) {
+            let count = self.sessions.iter().filter(|entry| entry.0 == Some(ip)).count();
+            if count >= max {
+                log::debug!("Session {session} is over the limit of {max} sessions of {ip}");
+                return Some("ip");
+            }
+        }
+        self.sessions.insert(session.to_string(), (ip, now));
+        None
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "max_sessions": self.max_sessions,
+            "max_sessions_per_ip": self.max_sessions_per_ip,
+            "idle_timeout": self.idle_timeout.as_secs(),
+            "overflow": self.overflow.to_str(),
+            "active_sessions": self.sessions.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admits_the_sessions_within_the_limits() {
+        let admission = SessionAdmission::new(Some(2), Some(1), Duration::from_secs(30), SessionOverflow::Clean);
+        let now = Instant::now();
+        let first = "10.0.0.1".parse().ok();
+        let second = "10.0.0.2".parse().ok();
+        assert_eq!(admission.admit("a", first, now), None);
+        assert_eq!(admission.admit("a", first, now), None);
+        assert_eq!(admission.admit("b", first, now), Some("ip"));
+        assert_eq!(admission.admit("c", second, now), None);
+        assert_eq!(admission.admit("d", "10.0.0.3".parse().ok(), now), Some("sessions"));
+
+        // Idle sessions make room for new ones
+        let later = now + Duration::from_secs(31);
+        assert_eq!(admission.admit("d", "10.0.0.3".parse().ok(), later), None);
+        assert_eq!(admission.admit("b", first, later), None);
+    }
+}
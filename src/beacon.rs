@@ -0,0 +1,478 @@
+use crate::metrics::Metrics;
+use crate::utils::tracking_event_label;
+use actix_web::web;
+use awc::Client;
+use dashmap::DashMap;
+use json::object;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// How often expired deduplication entries are purged
+const DEDUP_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+// How often a flush checks for the beacons still being delivered
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const TIMESTAMP_MACRO: &str = "[TIMESTAMP]";
+const CACHEBUSTING_MACRO: &str = "[CACHEBUSTING]";
+const AD_PLAYHEAD_MACRO: &str = "[ADPLAYHEAD]";
+const CONTENT_PLAYHEAD_MACRO: &str = "[CONTENTPLAYHEAD]";
+const ERROR_CODE_MACRO: &str = "[ERRORCODE]";
+
+/// VAST error code 900 "Undefined Error"
+pub const UNDEFINED_ERROR_CODE: u16 = 900;
+
+// A fired tracking event: session, ad and lowercase event name
+type EventKey = (String, Uuid, String);
+
+/// Values available for VAST macro expansion when a tracker is fired.
+#[derive(Clone, Debug, Default)]
+pub struct MacroContext {
+    /// Playback position within the creative in seconds
+    pub ad_playhead: Option<f64>,
+    /// Playback position within the content stream in seconds
+    pub content_playhead: Option<f64>,
+    /// VAST error code for Error trackers
+    pub error_code: Option<u16>,
+}
+
+// Format seconds as the VAST time code HH:MM:SS.mmm
+pub fn to_vast_time_code(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+fn encode(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+/// Replace the supported VAST macros in a tracker URL.
+/// Macros without a known value are left untouched.
+pub fn expand_macros(url: &str, context: &MacroContext) -> String {
+    if !url.contains('[') {
+        return url.to_string();
+    }
+
+    let mut expanded = url.replace(
+        TIMESTAMP_MACRO,
+        &encode(&chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false)),
+    );
+    expanded = expanded.replace(
+        CACHEBUSTING_MACRO,
+        &format!("{:08}", rand::rng().random_range(0..100_000_000)),
+    );
+    if let Some(ad_playhead) = context.ad_playhead {
+        expanded = expanded.replace(AD_PLAYHEAD_MACRO, &encode(&to_vast_time_code(ad_playhead)));
+    }
+    if let Some(content_playhead) = context.content_playhead {
+        expanded = expanded.replace(
+            CONTENT_PLAYHEAD_MACRO,
+            &encode(&to_vast_time_code(content_playhead)),
+        );
+    }
+    if let Some(error_code) = context.error_code {
+        expanded = expanded.replace(ERROR_CODE_MACRO, &error_code.to_string());
+    }
+
+    expanded
+}
+
+/// Delivers tracker URLs in the background. Failed deliveries are retried
+/// with exponential backoff until `max_attempts` is reached; at most
+/// `queue_size` beacons can be waiting for a retry at any time.
+/// Tracking events can be deduplicated per (session, ad, event) for `dedup_ttl`.
+#[derive(Clone)]
+pub struct BeaconDispatcher {
+    max_attempts: u32,
+    base_delay: Duration,
+    queue_size: usize,
+    dedup_ttl: Duration,
+    pending: Arc<AtomicUsize>,
+    // Beacons being delivered, including the ones waiting for a retry
+    in_flight: Arc<AtomicUsize>,
+    fired_events: Arc<DashMap<EventKey, Instant>>,
+    last_purge: Arc<Mutex<Instant>>,
+    metrics: web::Data<Metrics>,
+}
+
+impl BeaconDispatcher {
+    pub fn new(
+        max_attempts: u32,
+        base_delay: Duration,
+        queue_size: usize,
+        dedup_ttl: Duration,
+        metrics: web::Data<Metrics>,
+    ) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+            queue_size,
+            dedup_ttl,
+            pending: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            fired_events: Arc::new(DashMap::new()),
+            last_purge: Arc::new(Mutex::new(Instant::now())),
+            metrics,
+        }
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        object! {
+            "max_attempts": self.max_attempts,
+            "base_delay_ms": self.base_delay.as_millis() as u64,
+            "queue_size": self.queue_size,
+            "pending_retries": self.pending.load(Ordering::Relaxed),
+            "in_flight": self.in_flight.load(Ordering::Relaxed),
+            "dedup_ttl_sec": self.dedup_ttl.as_secs(),
+            "deduplicated_events": self.fired_events.len(),
+        }
+    }
+
+    /// Fire the tracker URLs of an event unless the same event was already
+    /// fired for this session and ad within the deduplication TTL.
+    /// Returns false for duplicates. An event none of whose URLs could be
+    /// delivered is forgotten, so that it can be reported again.
+    pub fn fire_once(
+        &self,
+        client: &Client,
+        session: &str,
+        ad_id: Uuid,
+        event: &str,
+        urls: Vec<String>,
+    ) -> bool {
+        if self.dedup_ttl.is_zero() {
+            self.fire(client, urls);
+            return true;
+        }
+        self.purge_expired_events();
+
+        // Recorded before the delivery, so that concurrent reports are duplicates
+        let key = (session.to_string(), ad_id, event.to_ascii_lowercase());
+        let now = Instant::now();
+        let mut duplicate = false;
+        self.fired_events
+            .entry(key.clone())
+            .and_modify(|fired_at| {
+                duplicate = now.duration_since(*fired_at) < self.dedup_ttl;
+                if !duplicate {
+                    *fired_at = now;
+                }
+            })
+            .or_insert(now);
+
+        if duplicate {
+            log::debug!("Skipping duplicate {event} event for ad {ad_id} in session {session}");
+            self.metrics.inc("beacon_duplicates_total", &[("event", tracking_event_label(event))]);
+            return false;
+        }
+
+        self.dispatch(client, urls, Some((key, now)));
+        true
+    }
+
+    fn purge_expired_events(&self) {
+        let mut last_purge = self.last_purge.lock();
+        if last_purge.elapsed() < DEDUP_PURGE_INTERVAL {
+            return;
+        }
+        *last_purge = Instant::now();
+        drop(last_purge);
+
+        self.fired_events
+            .retain(|_, fired_at| fired_at.elapsed() < self.dedup_ttl);
+    }
+
+    // Fire the tracker URLs without blocking the response
+    pub fn fire(&self, client: &Client, urls: Vec<String>) {
+        self.dispatch(client, urls, None);
+    }
+
+    // Deliver the URLs in the background, the fired event is forgotten when
+    // each of its URLs was given up on
+    fn dispatch(&self, client: &Client, urls: Vec<String>, event: Option<(EventKey, Instant)>) {
+        let event = Arc::new(event);
+        let remaining = Arc::new(AtomicUsize::new(urls.len()));
+        let delivered = Arc::new(AtomicBool::new(false));
+        for url in urls {
+            let dispatcher = self.clone();
+            let client = client.clone();
+            let (event, remaining, delivered) = (event.clone(), remaining.clone(), delivered.clone());
+            self.in_flight.fetch_add(1, Ordering::AcqRel);
+            actix_web::rt::spawn(async move {
+                if dispatcher.deliver(client, url).await {
+                    delivered.store(true, Ordering::Release);
+                }
+                let given_up = remaining.fetch_sub(1, Ordering::AcqRel) == 1 && !delivered.load(Ordering::Acquire);
+                if let Some((key, fired_at)) = event.as_ref().as_ref().filter(|_| given_up) {
+                    // Unless the event has been fired again since
+                    dispatcher.fired_events.remove_if(key, |_, at| at == fired_at);
+                }
+                dispatcher.in_flight.fetch_sub(1, Ordering::AcqRel);
+            });
+        }
+    }
+
+    /// Wait up to `timeout` for the beacons being delivered, e.g. before a
+    /// shutdown. Returns the number of beacons still in flight.
+    pub async fn flush(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let in_flight = self.in_flight.load(Ordering::Acquire);
+            if in_flight == 0 || Instant::now() >= deadline {
+                return in_flight;
+            }
+            actix_web::rt::time::sleep(FLUSH_POLL_INTERVAL).await;
+        }
+    }
+
+    // Returns false when the beacon was given up on, a rejected beacon
+    // wouldn't be accepted by reporting it again
+    async fn deliver(&self, client: Client, url: String) -> bool {
+        let mut queued = false;
+        let mut attempt = 1;
+        let delivered = loop {
+            match send_beacon(&client, &url).await {
+                BeaconOutcome::Delivered => {
+                    self.metrics.inc("beacons_total", &[("result", "delivered")]);
+                    break true;
+                }
+                BeaconOutcome::Rejected => {
+                    self.metrics.inc("beacons_total", &[("result", "rejected")]);
+                    break true;
+                }
+                BeaconOutcome::Failed => {}
+            }
+
+            if attempt >= self.max_attempts {
+                log::error!("Giving up on beacon {url} after {attempt} attempts");
+                self.metrics.inc("beacons_total", &[("result", "failed")]);
+                break false;
+            }
+
+            if !queued {
+                // Bound the number of beacons waiting for a retry
+                let accepted = self
+                    .pending
+                    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+                        (pending < self.queue_size).then_some(pending + 1)
+                    })
+                    .is_ok();
+                if !accepted {
+                    log::error!("Beacon retry queue is full, dropping {url}");
+                    self.metrics.inc("beacons_total", &[("result", "dropped")]);
+                    return false;
+                }
+                queued = true;
+            }
+
+            let delay = self.backoff(attempt);
+            log::debug!("Retrying beacon {url} in {delay:?} (attempt {attempt})");
+            self.metrics.inc("beacon_retries_total", &[]);
+            actix_web::rt::time::sleep(delay).await;
+            attempt += 1;
+        };
+
+        if queued {
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+        }
+        delivered
+    }
+
+    // Exponential backoff with up to 50% jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(1 << (attempt - 1).min(16));
+        let jitter_ms = rand::rng().random_range(0..=delay.as_millis() as u64 / 2);
+        delay + Duration::from_millis(jitter_ms)
+    }
+}
+
+enum BeaconOutcome {
+    Delivered,
+    // Client errors won't go away by retrying
+    Rejected,
+    Failed,
+}
+
+async fn send_beacon(client: &Client, url: &str) -> BeaconOutcome {
+    match client.get(url).send().await {
+        Ok(res) if res.status().is_success() || res.status().is_redirection() => {
+            log::debug!("Fired beacon {url} ({})", res.status());
+            BeaconOutcome::Delivered
+        }
+        Ok(res) if res.status().is_client_error() => {
+            log::warn!("Beacon {url} was rejected with status {}", res.status());
+            BeaconOutcome::Rejected
+        }
+        Ok(res) => {
+            log::warn!("Beacon {url} answered with status {}", res.status());
+            BeaconOutcome::Failed
+        }
+        Err(err) => {
+            log::warn!("Failed to fire beacon {url}: {err}");
+            BeaconOutcome::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpResponse, HttpServer};
+
+    // Answers 503 to the first `failures` beacons and 200 to the next ones,
+    // counting them
+    fn start_probe(failures: usize) -> (String, web::Data<AtomicUsize>, actix_web::dev::ServerHandle) {
+        let requests = web::Data::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let server = HttpServer::new(move || {
+            App::new().app_data(requests.clone()).default_service(web::get().to(
+                move |requests: web::Data<AtomicUsize>| async move {
+                    match requests.fetch_add(1, Ordering::SeqCst) < failures {
+                        true => HttpResponse::ServiceUnavailable().finish(),
+                        false => HttpResponse::Ok().finish(),
+                    }
+                },
+            ))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        (format!("http://{addr}/impression"), counter, handle)
+    }
+
+    fn new_dispatcher(
+        max_attempts: u32,
+        queue_size: usize,
+        dedup_ttl: Duration,
+    ) -> (BeaconDispatcher, web::Data<Metrics>) {
+        let metrics = web::Data::new(Metrics::default());
+        let dispatcher =
+            BeaconDispatcher::new(max_attempts, Duration::from_millis(10), queue_size, dedup_ttl, metrics.clone());
+        (dispatcher, metrics)
+    }
+
+    #[test]
+    fn backs_off_exponentially_with_jitter() {
+        let (dispatcher, _) = new_dispatcher(5, 10, Duration::ZERO);
+        for (attempt, delay) in [(1, 10), (2, 20), (3, 40), (4, 80)] {
+            let backoff = dispatcher.backoff(attempt).as_millis() as u64;
+            assert!((delay..=delay + delay / 2).contains(&backoff), "attempt {attempt}: {backoff}ms");
+        }
+        // The doubling stops at 2^16 times the base delay
+        let capped = dispatcher.backoff(40).as_millis() as u64;
+        assert!((655_360..=983_040).contains(&capped), "{capped}ms");
+    }
+
+    #[actix_web::test]
+    async fn retries_a_failed_beacon_until_max_attempts() {
+        let (url, requests, handle) = start_probe(1);
+        let (dispatcher, metrics) = new_dispatcher(3, 10, Duration::ZERO);
+        dispatcher.fire(&Client::default(), vec![url]);
+        assert_eq!(dispatcher.flush(Duration::from_secs(5)).await, 0);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(metrics.render().contains(r#"beacons_total{result="delivered"} 1"#));
+        handle.stop(false).await;
+
+        let (url, requests, handle) = start_probe(usize::MAX);
+        let (dispatcher, metrics) = new_dispatcher(3, 10, Duration::ZERO);
+        dispatcher.fire(&Client::default(), vec![url]);
+        assert_eq!(dispatcher.flush(Duration::from_secs(5)).await, 0);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        let rendered = metrics.render();
+        assert!(rendered.contains(r#"beacons_total{result="failed"} 1"#));
+        assert!(rendered.contains("beacon_retries_total 2"));
+        assert_eq!(dispatcher.pending.load(Ordering::SeqCst), 0);
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn drops_the_retries_beyond_the_queue_size() {
+        let (url, requests, handle) = start_probe(usize::MAX);
+        let (dispatcher, metrics) = new_dispatcher(3, 1, Duration::ZERO);
+        dispatcher.fire(&Client::default(), vec![url.clone(), url]);
+        assert_eq!(dispatcher.flush(Duration::from_secs(5)).await, 0);
+        // One beacon is retried, the other is dropped after its first attempt
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        let rendered = metrics.render();
+        assert!(rendered.contains(r#"beacons_total{result="dropped"} 1"#));
+        assert!(rendered.contains(r#"beacons_total{result="failed"} 1"#));
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn fires_an_event_once_within_the_ttl() {
+        let (url, requests, handle) = start_probe(0);
+        let (dispatcher, metrics) = new_dispatcher(1, 10, Duration::from_secs(60));
+        let (client, ad_id) = (Client::default(), Uuid::new_v4());
+        assert!(dispatcher.fire_once(&client, "session", ad_id, "Start", vec![url.clone()]));
+        assert!(!dispatcher.fire_once(&client, "session", ad_id, "start", vec![url.clone()]));
+        // Another session or ad is another event
+        assert!(dispatcher.fire_once(&client, "other", ad_id, "start", vec![url.clone()]));
+        assert!(dispatcher.fire_once(&client, "session", Uuid::new_v4(), "start", vec![url]));
+        assert_eq!(dispatcher.flush(Duration::from_secs(5)).await, 0);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert!(metrics.render().contains(r#"beacon_duplicates_total{event="start"} 1"#));
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn fires_an_event_again_when_none_of_its_urls_was_delivered() {
+        let (url, requests, handle) = start_probe(1);
+        let (dispatcher, _) = new_dispatcher(1, 10, Duration::from_secs(60));
+        let (client, ad_id) = (Client::default(), Uuid::new_v4());
+        assert!(dispatcher.fire_once(&client, "session", ad_id, "complete", vec![url.clone()]));
+        assert_eq!(dispatcher.flush(Duration::from_secs(5)).await, 0);
+        assert!(dispatcher.fire_once(&client, "session", ad_id, "complete", vec![url.clone()]));
+        assert_eq!(dispatcher.flush(Duration::from_secs(5)).await, 0);
+        // Delivered this time, the event is a duplicate from now on
+        assert!(!dispatcher.fire_once(&client, "session", ad_id, "complete", vec![url]));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        handle.stop(false).await;
+    }
+
+    #[test]
+    fn formats_vast_time_codes() {
+        assert_eq!(to_vast_time_code(0.0), "00:00:00.000");
+        assert_eq!(to_vast_time_code(3723.4567), "01:02:03.457");
+        assert_eq!(to_vast_time_code(-5.0), "00:00:00.000");
+    }
+
+    #[test]
+    fn expands_the_known_macros() {
+        let url = "http://tracker.example.com/t?ad=[ADPLAYHEAD]&content=[CONTENTPLAYHEAD]&code=[ERRORCODE]&x=[OTHER]";
+        let context = MacroContext {
+            ad_playhead: Some(5.0),
+            content_playhead: None,
+            error_code: Some(UNDEFINED_ERROR_CODE),
+        };
+        assert_eq!(
+            expand_macros(url, &context),
+            "http://tracker.example.com/t?ad=00%3A00%3A05.000&content=[CONTENTPLAYHEAD]&code=900&x=[OTHER]"
+        );
+
+        let expanded = expand_macros("http://tracker.example.com/t?cb=[CACHEBUSTING]&ts=[TIMESTAMP]", &context);
+        let query = expanded.split_once("?cb=").unwrap().1;
+        let (cachebusting, timestamp) = query.split_once("&ts=").unwrap();
+        assert!(cachebusting.len() == 8 && cachebusting.chars().all(|c| c.is_ascii_digit()));
+        assert!(timestamp.starts_with(&chrono::Utc::now().format("%Y-").to_string()));
+        assert!(timestamp.ends_with("%2B00%3A00"), "{timestamp}");
+
+        let url = "http://tracker.example.com/t?ad=1";
+        assert_eq!(expand_macros(url, &context), url);
+    }
+}
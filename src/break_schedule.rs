@@ -0,0 +1,90 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// A break planned ahead by an external schedule (SCTE-224, EPG), scheduled
+/// as a dynamic ad slot. The first of its `templates` naming a pod template
+/// gives it the template's pod.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedBreak {
+    // Unique within its schedule, the same break keeps it across refreshes
+    pub id: String,
+    pub start_time: chrono::DateTime<chrono::Utc>,
+    pub duration: f64,
+    pub templates: Vec<String>,
+    pub cancelled: bool,
+}
+
+/// An external schedule of the breaks of a stream: where it is fetched from
+/// and how often, and the ad slot each of its breaks was scheduled as, so that
+/// a schedule ingested again only moves the breaks it changed
+#[derive(Clone, Default)]
+pub struct BreakSchedule {
+    // Names the schedule in the logs, the metrics and /status, e.g. esni
+    source: &'static str,
+    url: Option<Url>,
+    interval: Duration,
+    slots: Arc<DashMap<String, u64>>,
+    last_ingest: Arc<parking_lot::Mutex<Option<json::JsonValue>>>,
+}
+
+impl BreakSchedule {
+    pub fn new(source: &'static str, url: Option<Url>, interval: Duration) -> Self {
+        Self {
+            source,
+            url,
+            interval,
+            ..Default::default()
+        }
+    }
+
+    pub fn source(&self) -> &'static str {
+        self.source
+    }
+
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref()
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// The index of the ad slot the break was scheduled as
+    pub fn slot(&self, id: &str) -> Option<u64> {
+        self.slots.get(id).map(|index| *index)
+    }
+
+    pub fn set_slot(&self, id: &str, index: u64) {
+        self.slots.insert(id.to_string(), index);
+    }
+
+    pub fn remove_slot(&self, id: &str) -> Option<u64> {
+        self.slots.remove(id).map(|(_, index)| index)
+    }
+
+    /// The breaks scheduled, with their slot indexes
+    pub fn slots(&self) -> Vec<(String, u64)> {
+        self.slots.iter().map(|entry| (entry.key().clone(), *entry.value())).collect()
+    }
+
+    /// Forget the breaks whose slots are gone, e.g. expired
+    pub fn retain_slots(&self, mut keep: impl FnMut(u64) -> bool) {
+        self.slots.retain(|_, index| keep(*index));
+    }
+
+    /// Keep the outcome of the last ingestion for /status
+    pub fn record(&self, summary: json::JsonValue) {
+        *self.last_ingest.lock() = Some(summary);
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "url": self.url.as_ref().map(Url::as_str),
+            "interval": self.interval.as_secs(),
+            "breaks": self.slots.len(),
+            "last_ingest": self.last_ingest.lock().clone(),
+        }
+    }
+}
@@ -0,0 +1,30 @@
+/// The version of the proxy and how it was built, set by build.rs
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("GIT_HASH");
+const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+const BUILD_FEATURES: &str = env!("BUILD_FEATURES");
+const BUILD_PROFILE: &str = env!("BUILD_PROFILE");
+
+/// When the proxy was built, None if build.rs couldn't tell
+pub fn build_time() -> Option<chrono::DateTime<chrono::Utc>> {
+    BUILD_TIMESTAMP
+        .parse()
+        .ok()
+        .filter(|timestamp| *timestamp > 0)
+        .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp, 0))
+}
+
+/// The cargo features the proxy was built with
+pub fn features() -> Vec<&'static str> {
+    BUILD_FEATURES.split(',').filter(|feature| !feature.is_empty()).collect()
+}
+
+pub fn to_json() -> json::JsonValue {
+    json::object! {
+        "version": VERSION,
+        "git_hash": GIT_HASH,
+        "build_time": build_time().map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+        "profile": BUILD_PROFILE,
+        "features": features(),
+    }
+}
@@ -0,0 +1,137 @@
+use crate::InsertionMode;
+use clap::ValueEnum;
+use url::Url;
+
+// Paths served at the root, which can't be used as channel names
+const RESERVED_NAMES: [&str; 7] = ["admin", "click", "command", "metrics", "status", "timeline", "tracking"];
+
+/// A channel served under `/<name>/` from its own master playlist
+/// (`--channel name=url[,key=value...]`). The optional keys override the
+/// insertion mode and the ad break defaults for this channel only, `esni`
+/// and `epg` give the SCTE-224 schedule and the EPG its breaks are planned from.
+/// A comma in a URL is kept as part of it unless it is followed by
+/// `key=`, e.g. `?ids=1,2` is kept but `?a=1,b=2` needs `%2C`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelSpec {
+    pub name: String,
+    pub master_playlist_url: Url,
+    pub insertion_mode: Option<InsertionMode>,
+    pub ad_server_url: Option<Url>,
+    pub ad_duration: Option<u64>,
+    pub repeating_cycle: Option<u64>,
+    pub ad_number: Option<u64>,
+    // The SCTE-224 schedule polled for the breaks of the channel
+    pub esni_url: Option<Url>,
+    // The EPG the breaks of the channel are planned from
+    pub epg_url: Option<Url>,
+}
+
+impl ChannelSpec {
+    /// Parse the `--channel` values, the names have to be unique
+    pub fn parse_all(values: &[String]) -> Result<Vec<Self>, String> {
+        let mut specs: Vec<Self> = Vec::new();
+        for value in values {
+            let spec = Self::parse(value)?;
+            if specs.iter().any(|other| other.name == spec.name) {
+                return Err(format!("Channel {} is given more than once", spec.name));
+            }
+            specs.push(spec);
+        }
+        Ok(specs)
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = split_settings(value).into_iter();
+        let (name, url) = parts
+            .next()
+            .and_then(|part| part.split_once('='))
+            .ok_or_else(|| format!("Invalid channel '{value}', expected name=url[,key=value...]"))?;
+        let name = name.trim();
+        let is_valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_valid_name || RESERVED_NAMES.contains(&name) {
+            return Err(format!("Invalid channel name '{name}'"));
+        }
+
+        let mut spec = Self {
+            name: name.to_string(),
+            master_playlist_url: Url::parse(url.trim())
+                .map_err(|err| format!("Invalid master playlist URL of channel {name}: {err}"))?,
+            insertion_mode: None,
+            ad_server_url: None,
+            ad_duration: None,
+            repeating_cycle: None,
+            ad_number: None,
+            esni_url: None,
+            epg_url: None,
+        };
+
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid setting '{part}' of channel {name}"))?;
+            let (key, value) = (key.trim(), value.trim());
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|err| format!("Invalid {key} of channel {name}: {err}"))
+            };
+            match key {
+                "mode" => {
+                    spec.insertion_mode = Some(
+                        InsertionMode::from_str(value, true)
+                            .map_err(|err| format!("Invalid mode of channel {name}: {err}"))?,
+                    )
+                }
+                "ad-server" => {
+                    spec.ad_server_url = Some(
+                        Url::parse(value)
+                            .map_err(|err| format!("Invalid ad server URL of channel {name}: {err}"))?,
+                    )
+                }
+                "ad-duration" => spec.ad_duration = Some(number()?),
+                "repeating-cycle" => spec.repeating_cycle = Some(number()?),
+                "ad-number" => spec.ad_number = Some(number()?),
+                "esni" => {
+                    spec.esni_url = Some(
+                        Url::parse(value)
+                            .map_err(|err| format!("Invalid SCTE-224 schedule URL of channel {name}: {err}"))?,
+                    )
+                }
+                "epg" => {
+                    spec.epg_url =
+                        Some(Url::parse(value).map_err(|err| format!("Invalid EPG URL of channel {name}: {err}"))?)
+                }
+                _ => return Err(format!("Unknown setting '{key}' of channel {name}")),
+            }
+        }
+
+        Ok(spec)
+    }
+
+    /// The path prefix of the channel, e.g. `/news`
+    pub fn path_prefix(&self) -> String {
+        format!("/{}", self.name)
+    }
+}
+
+// Split at the commas starting a `key=value` setting, the other commas are
+// part of the preceding value (a URL)
+fn split_settings(value: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (index, _) in value.match_indices(',') {
+        let is_setting = value[index + 1..].split_once('=').is_some_and(|(key, _)| {
+            let key = key.trim();
+            !key.is_empty() && key.chars().all(|c| c.is_ascii_lowercase() || c == '-')
+        });
+        if is_setting {
+            parts.push(&value[start..index]);
+            start = index + 1;
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
@@ -0,0 +1,157 @@
+use crate::utils::is_mp4_media_file;
+use hls_m3u8::MasterPlaylist;
+use hls_m3u8::tags::VariantStream;
+
+// Creatives with a frame rate this much above the content's are incompatible
+const FRAME_RATE_TOLERANCE: f64 = 1.0;
+
+/// The video format of a creative's media file or of a content variant.
+/// Unknown values are `None` and aren't checked.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VideoFormat {
+    /// Codec family, e.g. `avc` for `avc1.64001f`
+    pub codec: Option<&'static str>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub frame_rate: Option<f64>,
+}
+
+impl VideoFormat {
+    /// The format of a VAST MediaFile from its attributes
+    pub fn from_media_file(media_file: &vast4_rs::MediaFile) -> Self {
+        let dimension = |value: i32| usize::try_from(value).ok().filter(|value| *value > 0);
+        Self {
+            codec: media_file.codec.as_deref().and_then(video_codec_family),
+            width: dimension(media_file.width),
+            height: dimension(media_file.height),
+            frame_rate: None,
+        }
+    }
+
+    /// Fill the values missing here from `other`, e.g. the probed format
+    pub fn or(self, other: Option<&VideoFormat>) -> Self {
+        let Some(other) = other else {
+            return self;
+        };
+        Self {
+            codec: self.codec.or(other.codec),
+            width: self.width.or(other.width),
+            height: self.height.or(other.height),
+            frame_rate: self.frame_rate.or(other.frame_rate),
+        }
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "codec": self.codec,
+            "width": self.width,
+            "height": self.height,
+            "frame_rate": self.frame_rate,
+        }
+    }
+}
+
+/// The codec family of an RFC 6381 codec list, ignoring the audio codecs
+pub fn video_codec_family(codecs: &str) -> Option<&'static str> {
+    codecs.split(',').find_map(|codec| {
+        let codec = codec.trim().to_ascii_lowercase();
+        let fourcc = codec.split('.').next().unwrap_or_default();
+        match fourcc {
+            "avc1" | "avc3" | "h264" | "h.264" => Some("avc"),
+            "hvc1" | "hev1" | "h265" | "h.265" | "hevc" => Some("hevc"),
+            "vp09" | "vp9" => Some("vp9"),
+            "av01" | "av1" => Some("av1"),
+            _ => None,
+        }
+    })
+}
+
+/// The top variant of the content, the highest bandwidth one. Creatives
+/// played in its breaks shouldn't need a decoder the content doesn't use
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContentProfile {
+    pub format: VideoFormat,
+    /// Codec families used by any variant of the content
+    pub codecs: Vec<&'static str>,
+}
+
+impl ContentProfile {
+    pub fn from_master_playlist(playlist: &MasterPlaylist) -> Option<Self> {
+        let variants = playlist
+            .variant_streams
+            .iter()
+            .filter_map(|variant| match variant {
+                VariantStream::ExtXStreamInf { frame_rate, stream_data, .. } => Some((frame_rate, stream_data)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let codec = |stream_data: &hls_m3u8::types::StreamData| {
+            stream_data.codecs().and_then(|codecs| video_codec_family(&codecs.to_string()))
+        };
+        let (frame_rate, top) = variants.iter().max_by_key(|(_, stream_data)| stream_data.bandwidth())?;
+        let mut codecs = variants.iter().filter_map(|(_, stream_data)| codec(stream_data)).collect::<Vec<_>>();
+        codecs.sort_unstable();
+        codecs.dedup();
+
+        Some(Self {
+            format: VideoFormat {
+                codec: codec(top),
+                width: top.resolution().map(|resolution| resolution.width()),
+                height: top.resolution().map(|resolution| resolution.height()),
+                frame_rate: frame_rate.map(|frame_rate| frame_rate.as_f32() as f64),
+            },
+            codecs,
+        })
+    }
+
+    /// Why a media file of this format would stall the player, if it would
+    pub fn incompatibility(&self, format: &VideoFormat) -> Option<String> {
+        if let Some(codec) = format.codec {
+            if !self.codecs.is_empty() && !self.codecs.contains(&codec) {
+                return Some(format!("codec {codec} not used by the content ({})", self.codecs.join(", ")));
+            }
+        }
+        if let (Some(height), Some(top)) = (format.height, self.format.height) {
+            if height > top {
+                return Some(format!("height {height} above the top variant's {top}"));
+            }
+        }
+        if let (Some(frame_rate), Some(top)) = (format.frame_rate, self.format.frame_rate) {
+            if frame_rate > top + FRAME_RATE_TOLERANCE {
+                return Some(format!("frame rate {frame_rate} above the top variant's {top}"));
+            }
+        }
+        None
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "top_variant": self.format.to_json(),
+            "codecs": self.codecs.clone(),
+        }
+    }
+}
+
+/// The first MP4 MediaFile of the linear the content's player can switch to,
+/// `probed` giving what is known of a media file beyond its attributes.
+/// Otherwise the reasons the MediaFiles were turned down
+pub fn select_media_file(
+    linear: &vast4_rs::Linear,
+    profile: &ContentProfile,
+    probed: impl Fn(&str) -> Option<VideoFormat>,
+) -> Result<String, String> {
+    let media_files = linear
+        .media_files
+        .iter()
+        .flat_map(|media_files| media_files.media_files.iter())
+        .filter(|media_file| is_mp4_media_file(media_file));
+    let mut reasons = Vec::new();
+    for media_file in media_files {
+        let format = VideoFormat::from_media_file(media_file).or(probed(&media_file.uri).as_ref());
+        match profile.incompatibility(&format) {
+            None => return Ok(media_file.uri.to_string()),
+            Some(reason) => reasons.push(format!("{}: {reason}", media_file.uri)),
+        }
+    }
+    Err(reasons.join(", "))
+}
@@ -0,0 +1,125 @@
+use clap::builder::Resettable;
+use clap::{ArgAction, ArgMatches, Command};
+use std::collections::BTreeMap;
+use std::path::Path;
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+/// Settings of a TOML config file keyed by the argument they set. Keys are
+/// the long option names (`default-ad-duration` or `default_ad_duration`),
+/// tables prefix their keys, e.g. `[upstream] timeout-ms = 3000` sets
+/// `--upstream-timeout-ms`.
+pub struct ConfigFile {
+    values: BTreeMap<String, Vec<String>>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read config file {}: {err}", path.display()))?;
+        let document = content
+            .parse::<DocumentMut>()
+            .map_err(|err| format!("Invalid config file {}: {err}", path.display()))?;
+
+        let mut values = BTreeMap::new();
+        flatten_table(document.as_table(), "", &mut values)?;
+
+        Ok(Self { values })
+    }
+
+    /// Use the file values as defaults of the arguments, so command line
+    /// options and environment variables still take precedence over them
+    pub fn apply(&self, command: Command) -> Result<Command, String> {
+        for (key, values) in &self.values {
+            let id = key.replace('-', "_");
+            let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == id.as_str()) else {
+                return Err(format!("Unknown setting '{key}' in config file"));
+            };
+            if !matches!(arg.get_action(), ArgAction::Append) && values.len() > 1 {
+                return Err(format!("Setting '{key}' takes a single value"));
+            }
+        }
+
+        // The subcommands taking the server arguments get the same defaults
+        let subcommands = command
+            .get_subcommands()
+            .filter(|subcommand| subcommand.get_arguments().any(|arg| arg.get_id() == "config"))
+            .map(|subcommand| subcommand.get_name().to_string())
+            .collect::<Vec<_>>();
+        let command = self.set_defaults(command);
+        Ok(subcommands.iter().fold(command, |command, name| {
+            command.mut_subcommand(name, |subcommand| self.set_defaults(subcommand))
+        }))
+    }
+
+    fn set_defaults(&self, command: Command) -> Command {
+        // Arguments are changed in place to keep the order of the positional ones
+        command.mut_args(|arg| {
+            match self.values.get(&arg.get_id().as_str().replace('_', "-")) {
+                Some(values) => arg
+                    .default_values(values.clone())
+                    .required(false)
+                    .required_unless_present(Resettable::Reset),
+                None => arg,
+            }
+        })
+    }
+}
+
+/// The value of every argument, to tell what a reload changed
+pub fn setting_values(command: &Command, matches: &ArgMatches) -> BTreeMap<String, String> {
+    command
+        .get_arguments()
+        .filter_map(|arg| {
+            let values = matches.get_raw(arg.get_id().as_str())?;
+            let values = values
+                .map(|value| value.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(",");
+            Some((arg.get_id().as_str().replace('_', "-"), values))
+        })
+        .collect()
+}
+
+fn flatten_table(
+    table: &Table,
+    prefix: &str,
+    values: &mut BTreeMap<String, Vec<String>>,
+) -> Result<(), String> {
+    for (key, item) in table.iter() {
+        let key = if prefix.is_empty() {
+            key.replace('_', "-")
+        } else {
+            format!("{prefix}-{}", key.replace('_', "-"))
+        };
+        match item {
+            Item::Table(table) => flatten_table(table, &key, values)?,
+            Item::Value(Value::Array(array)) => {
+                let array = array
+                    .iter()
+                    .map(|value| scalar(value).ok_or_else(|| format!("Setting '{key}' must be a list of values")))
+                    .collect::<Result<Vec<_>, _>>()?;
+                values.insert(key, array);
+            }
+            Item::Value(value) => {
+                let value = scalar(value).ok_or_else(|| format!("Unsupported value for setting '{key}'"))?;
+                values.insert(key, vec![value]);
+            }
+            Item::ArrayOfTables(_) | Item::None => {
+                return Err(format!("Unsupported value for setting '{key}'"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.value().clone()),
+        Value::Integer(value) => Some(value.value().to_string()),
+        Value::Float(value) => Some(value.value().to_string()),
+        Value::Boolean(value) => Some(value.value().to_string()),
+        Value::Datetime(value) => Some(value.value().to_string()),
+        Value::Array(_) | Value::InlineTable(_) => None,
+    }
+}
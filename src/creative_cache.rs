@@ -0,0 +1,163 @@
+use crate::metrics::Metrics;
+use crate::utils::{UniversalAdId, universal_ad_id_key};
+use actix_web::web;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// The parts of a creative that don't change between its appearances
+#[derive(Clone, Debug)]
+pub struct CachedCreative {
+    pub duration: f64,
+    pub media_url: String,
+    // The media playlist the MP4 is wrapped in, once it was requested
+    playlist: Option<String>,
+}
+
+impl CachedCreative {
+    pub fn new(duration: f64, media_url: String) -> Self {
+        Self {
+            duration,
+            media_url,
+            playlist: None,
+        }
+    }
+}
+
+struct CacheEntry {
+    creative: CachedCreative,
+    stored_at: Instant,
+}
+
+/// Caches the processed creatives by UniversalAdId, so a spot the ad server
+/// returns in many breaks has its media file selected and its playlist
+/// packaged once. Creatives without a UniversalAdId aren't cached.
+#[derive(Clone, Default)]
+pub struct CreativeCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Arc<DashMap<String, CacheEntry>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+    // The playlist lookups are counted apart, they follow a creative lookup
+    playlist_hits: Arc<AtomicU64>,
+    playlist_misses: Arc<AtomicU64>,
+    evictions: Arc<AtomicU64>,
+    metrics: web::Data<Metrics>,
+}
+
+impl CreativeCache {
+    pub fn new(ttl: Duration, max_entries: usize, metrics: web::Data<Metrics>) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            metrics,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.max_entries > 0
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "enabled": self.is_enabled(),
+            "ttl": self.ttl.as_secs(),
+            "max_entries": self.max_entries,
+            "entries": self.entries.len(),
+            "hits": self.hits.load(Ordering::Relaxed),
+            "misses": self.misses.load(Ordering::Relaxed),
+            "playlist_hits": self.playlist_hits.load(Ordering::Relaxed),
+            "playlist_misses": self.playlist_misses.load(Ordering::Relaxed),
+            "evictions": self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The cached creative of these ids, or the one made by `process`
+    pub fn get_or_insert_with(
+        &self,
+        universal_ad_ids: &[UniversalAdId],
+        process: impl FnOnce() -> CachedCreative,
+    ) -> CachedCreative {
+        let Some(key) = self.key(universal_ad_ids) else {
+            return process();
+        };
+        if let Some(creative) = self.cached(&key) {
+            self.record("creative", "hit", &self.hits);
+            return creative;
+        }
+
+        self.record("creative", "miss", &self.misses);
+        let creative = process();
+        self.store(key, creative.clone());
+        creative
+    }
+
+    /// The cached playlist of the creative, or the one made by `package`.
+    /// Only a playlist of the same media file is reused, another one may have
+    /// been picked for the content of this stream
+    pub fn playlist_or_insert_with(
+        &self,
+        universal_ad_ids: &[UniversalAdId],
+        media_url: &str,
+        package: impl FnOnce() -> String,
+    ) -> String {
+        let Some(key) = self.key(universal_ad_ids) else {
+            return package();
+        };
+        let cached = self.cached(&key).filter(|creative| creative.media_url == media_url);
+        if let Some(playlist) = cached.and_then(|creative| creative.playlist) {
+            self.record("playlist", "hit", &self.playlist_hits);
+            return playlist;
+        }
+
+        self.record("playlist", "miss", &self.playlist_misses);
+        let playlist = package();
+        if let Some(mut entry) = self.entries.get_mut(&key).filter(|entry| entry.creative.media_url == media_url) {
+            entry.creative.playlist = Some(playlist.clone());
+        }
+        playlist
+    }
+
+    fn key(&self, universal_ad_ids: &[UniversalAdId]) -> Option<String> {
+        universal_ad_id_key(universal_ad_ids).filter(|_| self.is_enabled())
+    }
+
+    fn cached(&self, key: &str) -> Option<CachedCreative> {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.stored_at.elapsed() < self.ttl)
+            .map(|entry| entry.creative.clone())
+    }
+
+    fn store(&self, key: String, creative: CachedCreative) {
+        if self.entries.len() >= self.max_entries && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, entry| entry.stored_at.elapsed() < self.ttl);
+            // Still full of fresh creatives, make room by dropping the oldest one
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|entry| entry.stored_at)
+                .map(|entry| entry.key().clone());
+            if let Some(oldest) = oldest.filter(|_| self.entries.len() >= self.max_entries) {
+                self.entries.remove(&oldest);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.entries.insert(
+            key,
+            CacheEntry {
+                creative,
+                stored_at: Instant::now(),
+            },
+        );
+    }
+
+    fn record(&self, lookup: &str, result: &str, counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.metrics.inc("creative_cache_requests_total", &[("lookup", lookup), ("result", result)]);
+    }
+}
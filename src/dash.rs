@@ -0,0 +1,331 @@
+use crate::probe::Fragments;
+use crate::scte35;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use dash_mpd::{Event, EventStream, MPD, Period, SegmentBase, SegmentTemplate};
+use std::time::Duration;
+
+pub const DASH_CONTENT_TYPE: &str = "application/dash+xml";
+/// Scheme of the events carrying the asset list URL of an ad break
+pub const ASSET_LIST_EVENT_SCHEME: &str = "urn:eyevinn:sgai:asset-list";
+// The breaks of a live presentation are signaled this long before they start
+const LIVE_LOOKAHEAD: Duration = Duration::from_secs(120);
+// The window of a live presentation without a timeShiftBufferDepth
+const DEFAULT_TIME_SHIFT_BUFFER: Duration = Duration::from_secs(300);
+// Timescale of the inserted events and ad segments, milliseconds
+const TIMESCALE: u64 = 1000;
+
+/// How the ad breaks are signaled in the DASH manifests
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum DashSignaling {
+    /// Split the content periods around remote ad periods resolved by the proxy
+    Periods,
+    /// Add an event with the asset list URL of each break to the content periods
+    Events,
+}
+
+impl DashSignaling {
+    pub fn to_str(&self) -> &str {
+        match self {
+            DashSignaling::Periods => "periods",
+            DashSignaling::Events => "events",
+        }
+    }
+}
+
+/// An ad break of a DASH presentation
+#[derive(Clone, Debug, PartialEq)]
+pub struct DashAdBreak {
+    /// Index and name of the ad slot, e.g. `3` and `ad_slot3`
+    pub index: u64,
+    pub name: String,
+    /// Start in seconds from the availabilityStartTime of a live presentation,
+    /// from the start of a VOD one
+    pub start: f64,
+    /// Duration in seconds
+    pub duration: f64,
+}
+
+pub fn is_dynamic(mpd: &MPD) -> bool {
+    mpd.mpdtype.as_deref() == Some("dynamic")
+}
+
+/// The earliest time a live presentation can still be played from, None for
+/// a VOD one
+pub fn time_shift_start(mpd: &MPD, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let depth = mpd.timeShiftBufferDepth.unwrap_or(DEFAULT_TIME_SHIFT_BUFFER);
+    is_dynamic(mpd).then(|| now - chrono::Duration::from_std(depth).unwrap_or_default())
+}
+
+/// The ad breaks of the slots, given by index, name, start date time and duration. A
+/// VOD presentation starts at the `epoch`. Only the breaks of a live
+/// presentation in its time shift buffer or starting soon are kept
+pub fn ad_breaks(
+    mpd: &MPD,
+    slots: impl Iterator<Item = (u64, String, DateTime<Utc>, Duration)>,
+    epoch: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Vec<DashAdBreak> {
+    let dynamic = is_dynamic(mpd);
+    let presentation_start = match mpd.availabilityStartTime.filter(|_| dynamic) {
+        Some(availability_start) => availability_start.with_timezone(&Utc),
+        None => epoch,
+    };
+    let seconds = |date_time: DateTime<Utc>| (date_time - presentation_start).num_milliseconds() as f64 / 1000.0;
+    let window = if dynamic {
+        let depth = mpd.timeShiftBufferDepth.unwrap_or(DEFAULT_TIME_SHIFT_BUFFER);
+        (seconds(now) - depth.as_secs_f64(), seconds(now) + LIVE_LOOKAHEAD.as_secs_f64())
+    } else {
+        let duration = mpd.mediaPresentationDuration.map_or(f64::INFINITY, |duration| duration.as_secs_f64());
+        (0.0, duration)
+    };
+
+    let mut breaks = slots
+        .map(|(index, name, start, duration)| DashAdBreak {
+            index,
+            name,
+            start: seconds(start),
+            duration: duration.as_secs_f64(),
+        })
+        .filter(|ad_break| ad_break.start >= window.0 && ad_break.start < window.1)
+        .collect::<Vec<_>>();
+    breaks.sort_by(|a, b| a.start.total_cmp(&b.start));
+    breaks
+}
+
+// The start and end of each period in seconds, None for a period without an end
+fn period_bounds(mpd: &MPD) -> Vec<(f64, Option<f64>)> {
+    let mut bounds: Vec<(f64, Option<f64>)> = Vec::new();
+    for (index, period) in mpd.periods.iter().enumerate() {
+        let start = period
+            .start
+            .map(|start| start.as_secs_f64())
+            .or_else(|| bounds.last().and_then(|(_, end)| *end))
+            .unwrap_or_default();
+        if let Some((_, end @ None)) = bounds.last_mut() {
+            *end = Some(start);
+        }
+        let end = match period.duration {
+            Some(duration) => Some(start + duration.as_secs_f64()),
+            None if index + 1 == mpd.periods.len() && !is_dynamic(mpd) => {
+                mpd.mediaPresentationDuration.map(|duration| duration.as_secs_f64())
+            }
+            None => None,
+        };
+        bounds.push((start, end));
+    }
+    bounds
+}
+
+/// Signal each break with an event carrying its asset list URL, in the period
+/// it starts in
+pub fn insert_ad_events(mpd: &mut MPD, breaks: &[DashAdBreak], asset_list_url: impl Fn(&DashAdBreak) -> String) {
+    insert_events(mpd, breaks, ASSET_LIST_EVENT_SCHEME, |ad_break| {
+        (ad_break.name.clone(), asset_list_url(ad_break))
+    });
+}
+
+/// Signal each break with an SCTE-35 cue-out event, for the packagers and
+/// measurement SDKs relying on timed metadata. Inserted before the ad periods,
+/// the event stays with the content period ending at the break
+pub fn insert_splice_events(mpd: &mut MPD, breaks: &[DashAdBreak]) {
+    insert_events(mpd, breaks, scte35::SCTE35_EVENT_SCHEME, |ad_break| {
+        let section = scte35::splice_out(ad_break.index as u32, Duration::from_secs_f64(ad_break.duration));
+        (ad_break.index.to_string(), scte35::to_base64(&section))
+    });
+}
+
+// Add an event stream of the scheme to each period a break starts in, the
+// event id and content given by `event`
+fn insert_events(mpd: &mut MPD, breaks: &[DashAdBreak], scheme: &str, event: impl Fn(&DashAdBreak) -> (String, String)) {
+    let bounds = period_bounds(mpd);
+    for (period, (start, end)) in mpd.periods.iter_mut().zip(bounds) {
+        let events = breaks
+            .iter()
+            .filter(|ad_break| ad_break.start >= start && end.is_none_or(|end| ad_break.start < end))
+            .map(|ad_break| {
+                let (id, content) = event(ad_break);
+                Event {
+                    id: Some(id),
+                    presentationTime: Some(((ad_break.start - start) * TIMESCALE as f64).round() as u64),
+                    duration: Some((ad_break.duration * TIMESCALE as f64).round() as u64),
+                    content: Some(content),
+                    ..Default::default()
+                }
+            })
+            .collect::<Vec<_>>();
+        if events.is_empty() {
+            continue;
+        }
+        period.event_streams.push(EventStream {
+            schemeIdUri: scheme.to_string(),
+            timescale: Some(TIMESCALE),
+            event: events,
+            ..Default::default()
+        });
+    }
+}
+
+/// Split the periods at each break, the content of the break replaced by a
+/// remote period at `ad_period_url` the player resolves when it loads the
+/// manifest. Breaks overlapping an earlier one or a period boundary are skipped
+pub fn insert_ad_periods(mpd: &mut MPD, breaks: &[DashAdBreak], ad_period_url: impl Fn(&DashAdBreak) -> String) {
+    for ad_break in breaks {
+        let bounds = period_bounds(mpd);
+        let found = bounds.iter().enumerate().find(|(index, (start, end))| {
+            ad_break.start > *start
+                && end.is_none_or(|end| ad_break.start + ad_break.duration <= end)
+                && mpd.periods[*index].href.is_none()
+        });
+        let Some((index, &(start, end))) = found else {
+            log::debug!("No content period to split for {}", ad_break.name);
+            continue;
+        };
+
+        let content = &mut mpd.periods[index];
+        let mut resumed = content.clone();
+        content.start = Some(Duration::from_secs_f64(start));
+        content.duration = Some(Duration::from_secs_f64(ad_break.start - start));
+
+        let ad_period = Period {
+            id: Some(ad_break.name.clone()),
+            start: Some(Duration::from_secs_f64(ad_break.start)),
+            duration: Some(Duration::from_secs_f64(ad_break.duration)),
+            href: Some(ad_period_url(ad_break)),
+            actuate: Some("onLoad".to_string()),
+            ..Default::default()
+        };
+
+        // The content resumes after the break, where it would be without it
+        let resume_at = ad_break.start + ad_break.duration;
+        resumed.id = resumed.id.map(|id| format!("{id}-{}", ad_break.name));
+        resumed.start = Some(Duration::from_secs_f64(resume_at));
+        resumed.duration = end.map(|end| Duration::from_secs_f64(end - resume_at));
+        resumed.event_streams.clear();
+        offset_period(&mut resumed, resume_at - start);
+
+        let mut inserted = vec![ad_period];
+        if end.is_none_or(|end| resume_at < end) {
+            inserted.push(resumed);
+        }
+        mpd.periods.splice(index + 1..index + 1, inserted);
+    }
+}
+
+// Start the segments of a period `offset` seconds into its media
+fn offset_period(period: &mut Period, offset: f64) {
+    let templates = period
+        .SegmentTemplate
+        .iter_mut()
+        .chain(period.adaptations.iter_mut().flat_map(|adaptation| {
+            adaptation.SegmentTemplate.iter_mut().chain(
+                adaptation
+                    .representations
+                    .iter_mut()
+                    .flat_map(|representation| representation.SegmentTemplate.iter_mut()),
+            )
+        }));
+    for template in templates {
+        offset_segment_template(template, offset);
+    }
+    let bases = period.SegmentBase.iter_mut().chain(period.adaptations.iter_mut().flat_map(|adaptation| {
+        adaptation.SegmentBase.iter_mut().chain(
+            adaptation
+                .representations
+                .iter_mut()
+                .flat_map(|representation| representation.SegmentBase.iter_mut()),
+        )
+    }));
+    for base in bases {
+        offset_segment_base(base, offset);
+    }
+}
+
+fn offset_segment_template(template: &mut SegmentTemplate, offset: f64) {
+    let offset = (offset * template.timescale.unwrap_or(1) as f64).round() as u64;
+    template.presentationTimeOffset = Some(template.presentationTimeOffset.unwrap_or_default() + offset);
+    // Numbered segments of a constant duration are numbered from the period start
+    if template.SegmentTimeline.is_none() {
+        if let Some(duration) = template.duration.filter(|duration| *duration > 0.0) {
+            let skipped = (offset as f64 / duration).round() as u64;
+            template.startNumber = Some(template.startNumber.unwrap_or(1) + skipped);
+        }
+    }
+}
+
+fn offset_segment_base(base: &mut SegmentBase, offset: f64) {
+    let offset = (offset * base.timescale.unwrap_or(1) as f64).round() as u64;
+    base.presentationTimeOffset = Some(base.presentationTimeOffset.unwrap_or_default() + offset);
+}
+
+/// A creative of an ad period, a fragmented MP4 played from its byte ranges
+pub struct DashCreative {
+    pub id: String,
+    pub url: String,
+    /// RFC 6381 codecs, if known
+    pub codecs: Option<String>,
+    pub fragments: Fragments,
+}
+
+impl DashCreative {
+    fn duration(&self) -> f64 {
+        self.fragments.fragments.iter().map(|fragment| fragment.duration).sum()
+    }
+}
+
+/// The periods resolving a remote ad period, one per creative
+pub fn ad_periods_xml(creatives: &[DashCreative]) -> String {
+    creatives
+        .iter()
+        .map(|creative| {
+            let codecs = creative
+                .codecs
+                .as_deref()
+                .map(|codecs| format!(" codecs=\"{}\"", escape(codecs)))
+                .unwrap_or_default();
+            let timeline = creative
+                .fragments
+                .fragments
+                .iter()
+                .map(|fragment| format!("<S d=\"{}\"/>", (fragment.duration * TIMESCALE as f64).round() as u64))
+                .collect::<String>();
+            let segments = creative
+                .fragments
+                .fragments
+                .iter()
+                .map(|fragment| {
+                    format!("<SegmentURL mediaRange=\"{}-{}\"/>", fragment.offset, fragment.offset + fragment.length - 1)
+                })
+                .collect::<String>();
+            format!(
+                concat!(
+                    "<Period id=\"{id}\" duration=\"PT{duration:.3}S\">",
+                    "<AdaptationSet mimeType=\"video/mp4\" segmentAlignment=\"true\">",
+                    "<Representation id=\"{id}\" bandwidth=\"0\"{codecs}>",
+                    "<BaseURL>{url}</BaseURL>",
+                    "<SegmentList timescale=\"{timescale}\">",
+                    "<Initialization range=\"0-{init_end}\"/>",
+                    "<SegmentTimeline>{timeline}</SegmentTimeline>",
+                    "{segments}",
+                    "</SegmentList></Representation></AdaptationSet></Period>"
+                ),
+                id = escape(&creative.id),
+                duration = creative.duration(),
+                codecs = codecs,
+                url = escape(&creative.url),
+                timescale = TIMESCALE,
+                init_end = creative.fragments.init_length.saturating_sub(1),
+                timeline = timeline,
+                segments = segments,
+            )
+        })
+        .collect()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,99 @@
+use hls_m3u8::MediaPlaylist;
+
+const DATE_RANGE_TAG: &str = "#EXT-X-DATERANGE:";
+const SEGMENT_DURATION_TAG: &str = "#EXTINF";
+
+/// The `EXT-X-DATERANGE` tags of an origin media playlist, program metadata or
+/// SCTE-35 cues, kept as they are. The playlist parser holds a single date
+/// range per segment and drops the attributes it doesn't know, so the tags
+/// are read from the playlist text, the parsed date ranges are cleared to make
+/// room for the interstitials, and the tags are written back with the playlist
+#[derive(Clone, Debug, Default)]
+pub struct OriginDateRanges {
+    // The tags preceding each segment, by its index in the parsed playlist
+    tags: Vec<(usize, String)>,
+}
+
+impl OriginDateRanges {
+    /// Take the date ranges out of a playlist parsed from `text`
+    pub fn take(text: &str, playlist: &mut MediaPlaylist<'_>) -> Self {
+        let indexes = playlist.segments.iter().map(|(index, _)| index).collect::<Vec<_>>();
+        let mut tags = Vec::new();
+        let mut position = 0;
+        for line in text.lines().map(str::trim) {
+            if line.starts_with(DATE_RANGE_TAG) {
+                // A tag after the last segment fails the parsing, every tag has a segment
+                if let Some(index) = indexes.get(position) {
+                    tags.push((*index, line.to_string()));
+                }
+            } else if !line.is_empty() && !line.starts_with('#') {
+                position += 1;
+            }
+        }
+        for (_, segment) in playlist.segments.iter_mut() {
+            segment.date_range = None;
+        }
+        Self { tags }
+    }
+
+    /// Write the tags back into the serialized playlist, ahead of the
+    /// `EXTINF` of their segment. The segments removed since are left out
+    pub fn restore(&self, playlist: &MediaPlaylist<'_>, output: String) -> String {
+        if self.tags.is_empty() {
+            return output;
+        }
+        let indexes = playlist.segments.iter().map(|(index, _)| index).collect::<Vec<_>>();
+        let length = output.len() + self.tags.iter().map(|(_, tag)| tag.len() + 1).sum::<usize>();
+        let mut result = String::with_capacity(length);
+        let mut position = 0;
+        for line in output.lines() {
+            if line.starts_with(SEGMENT_DURATION_TAG) {
+                if let Some(index) = indexes.get(position) {
+                    for (_, tag) in self.tags.iter().filter(|(other, _)| other == index) {
+                        result.push_str(tag);
+                        result.push('\n');
+                    }
+                }
+                position += 1;
+            }
+            result.push_str(line);
+            result.push('\n');
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hls_m3u8::tags::ExtXDateRange;
+
+    #[test]
+    fn keeps_all_the_date_ranges_of_a_segment() {
+        let text = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-PROGRAM-DATE-TIME:2024-03-01T12:00:00.000Z\n\
+            #EXTINF:6.000,\nseg0.ts\n\
+            #EXT-X-DATERANGE:ID=\"program\",START-DATE=\"2024-03-01T12:00:06.000Z\",X-COM-EXAMPLE-TITLE=\"News\"\n\
+            #EXT-X-DATERANGE:ID=\"splice\",START-DATE=\"2024-03-01T12:00:06.000Z\",PLANNED-DURATION=30.000\n\
+            #EXTINF:6.000,\nseg1.ts\n";
+        let mut playlist = MediaPlaylist::try_from(text).unwrap();
+        let date_ranges = OriginDateRanges::take(text, &mut playlist);
+        assert!(playlist.segments.iter().all(|(_, segment)| segment.date_range.is_none()));
+
+        let interstitial = ExtXDateRange::builder()
+            .id("ad_slot1")
+            .start_date("2024-03-01T12:00:06.000Z")
+            .build()
+            .unwrap();
+        playlist.segments[1].date_range = Some(interstitial);
+        let output = date_ranges.restore(&playlist, playlist.to_string());
+        let lines = output.lines().collect::<Vec<_>>();
+        let date_ranges = lines
+            .iter()
+            .filter(|line| line.starts_with(DATE_RANGE_TAG))
+            .collect::<Vec<_>>();
+        assert_eq!(date_ranges.len(), 3, "{output}");
+        assert!(output.contains("PLANNED-DURATION=30.000"));
+        let position = |text: &str| lines.iter().position(|line| line.contains(text)).unwrap();
+        assert!(position("seg0.ts") < position("ID=\"program\"") && position("ID=\"splice\"") < position("seg1.ts"));
+    }
+}
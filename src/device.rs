@@ -0,0 +1,184 @@
+use actix_web::HttpRequest;
+use actix_web::http::header;
+
+/// The kind of player requesting the playlists, told by its User-Agent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceClass {
+    /// AVPlayer (AppleCoreMedia) and Safari, playing the interstitials natively
+    Apple,
+    /// Other web browsers, playing with hls.js or a similar MSE player
+    Browser,
+    /// ExoPlayer and Media3 on Android
+    Exoplayer,
+    /// Smart TVs and streaming devices: Tizen, webOS, HbbTV, Roku, Fire TV...
+    SmartTv,
+    Other,
+}
+
+impl DeviceClass {
+    const ALL: [DeviceClass; 5] = [
+        DeviceClass::Apple,
+        DeviceClass::Browser,
+        DeviceClass::Exoplayer,
+        DeviceClass::SmartTv,
+        DeviceClass::Other,
+    ];
+
+    pub fn to_str(&self) -> &str {
+        match self {
+            DeviceClass::Apple => "apple",
+            DeviceClass::Browser => "browser",
+            DeviceClass::Exoplayer => "exoplayer",
+            DeviceClass::SmartTv => "smarttv",
+            DeviceClass::Other => "other",
+        }
+    }
+
+    /// The class of a User-Agent. TV browsers claim to be Safari or Chrome,
+    /// so the TV platforms are looked for first
+    pub fn from_user_agent(user_agent: &str) -> Self {
+        let user_agent = user_agent.to_ascii_lowercase();
+        let has = |token: &str| user_agent.contains(token);
+        // Fire TV models are AFTxx, e.g. "Android 9; AFTMM Build/PS7233"
+        let smart_tv = ["smart-tv", "smarttv", "tizen", "web0s", "webos", "hbbtv", "bravia", "roku", "crkey", "; aft"];
+        if smart_tv.iter().any(|token| has(token)) {
+            return DeviceClass::SmartTv;
+        }
+        if has("exoplayer") || has("media3") {
+            return DeviceClass::Exoplayer;
+        }
+        // AppleCoreMedia is AVPlayer on iOS, macOS and tvOS
+        if has("applecoremedia") || has("avplayer") {
+            return DeviceClass::Apple;
+        }
+        let other_browsers = ["chrome", "chromium", "crios", "edg", "firefox", "fxios", "android"];
+        if has("safari") && !other_browsers.iter().any(|token| has(token)) {
+            return DeviceClass::Apple;
+        }
+        if has("mozilla") {
+            return DeviceClass::Browser;
+        }
+        DeviceClass::Other
+    }
+}
+
+/// How the ad breaks are inserted for a class of devices
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InsertionProfile {
+    /// HLS interstitials, or the DASH signaling of --dash-signaling
+    #[default]
+    Interstitials,
+    /// SCTE-35 cue-outs only, for devices served by a downstream SSAI
+    /// stitcher or a client-side ad SDK
+    Ssai,
+    /// No ad breaks at all
+    None,
+}
+
+impl InsertionProfile {
+    pub fn to_str(&self) -> &str {
+        match self {
+            InsertionProfile::Interstitials => "interstitials",
+            InsertionProfile::Ssai => "ssai",
+            InsertionProfile::None => "none",
+        }
+    }
+}
+
+/// The insertion profiles of the device classes (`--device-profile class=profile`),
+/// the classes not given get the interstitials
+#[derive(Clone, Debug, Default)]
+pub struct DeviceProfiles {
+    profiles: Vec<(DeviceClass, InsertionProfile)>,
+}
+
+impl DeviceProfiles {
+    pub fn parse_all(values: &[String]) -> Result<Self, String> {
+        let mut profiles: Vec<(DeviceClass, InsertionProfile)> = Vec::new();
+        for value in values {
+            let (class, profile) = value
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid device profile '{value}', expected class=profile"))?;
+            let class = DeviceClass::ALL
+                .into_iter()
+                .find(|known| known.to_str() == class.trim().to_ascii_lowercase())
+                .ok_or_else(|| {
+                    let classes = DeviceClass::ALL.map(|class| class.to_str().to_string()).join(", ");
+                    format!("Unknown device class '{}', expected one of {classes}", class.trim())
+                })?;
+            let profile = [InsertionProfile::Interstitials, InsertionProfile::Ssai, InsertionProfile::None]
+                .into_iter()
+                .find(|known| known.to_str() == profile.trim().to_ascii_lowercase())
+                .ok_or_else(|| {
+                    format!("Unknown insertion profile '{}', expected interstitials, ssai or none", profile.trim())
+                })?;
+            if profiles.iter().any(|(other, _)| *other == class) {
+                return Err(format!("Device class {} is given more than once", class.to_str()));
+            }
+            profiles.push((class, profile));
+        }
+        Ok(Self { profiles })
+    }
+
+    /// The class of the requesting device and its insertion profile
+    pub fn profile(&self, req: &HttpRequest) -> (DeviceClass, InsertionProfile) {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let class = DeviceClass::from_user_agent(user_agent);
+        let profile = self
+            .profiles
+            .iter()
+            .find(|(other, _)| *other == class)
+            .map_or_else(InsertionProfile::default, |(_, profile)| *profile);
+        (class, profile)
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut profiles = json::JsonValue::new_object();
+        for (class, profile) in &self.profiles {
+            profiles[class.to_str()] = profile.to_str().into();
+        }
+        profiles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_common_players() {
+        let classes = [
+            ("AppleCoreMedia/1.0.0.21A329 (iPhone; U; CPU OS 17_0 like Mac OS X; en_us)", DeviceClass::Apple),
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.0.1 Safari/605.1.15",
+                DeviceClass::Apple,
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/129.0.0.0 Safari/537.36",
+                DeviceClass::Browser,
+            ),
+            ("ExoPlayerLib/2.19.1", DeviceClass::Exoplayer),
+            (
+                "Mozilla/5.0 (SMART-TV; LINUX; Tizen 6.0) AppleWebKit/537.36 (KHTML, like Gecko) 76.0.3809.146/6.0 TV Safari/537.36",
+                DeviceClass::SmartTv,
+            ),
+            ("Dalvik/2.1.0 (Linux; U; Android 9; AFTMM Build/PS7233)", DeviceClass::SmartTv),
+            ("curl/8.5.0", DeviceClass::Other),
+        ];
+        for (user_agent, class) in classes {
+            assert_eq!(DeviceClass::from_user_agent(user_agent), class, "{user_agent}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_classes_and_profiles() {
+        assert!(DeviceProfiles::parse_all(&["smarttv=ssai".to_string(), "other=none".to_string()]).is_ok());
+        assert!(DeviceProfiles::parse_all(&["phone=none".to_string()]).is_err());
+        assert!(DeviceProfiles::parse_all(&["apple=stitch".to_string()]).is_err());
+        assert!(DeviceProfiles::parse_all(&["apple=none".to_string(), "apple=ssai".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use crate::metrics::Metrics;
+use actix_tls::connect::Resolve;
+use actix_web::web;
+use dashmap::DashMap;
+use futures_util::future::LocalBoxFuture;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+struct CachedLookup {
+    // The error message of a failed lookup
+    addresses: Result<Vec<IpAddr>, String>,
+    expires_at: Instant,
+}
+
+/// Resolves upstream host names with static overrides (`--resolve host=ip`)
+/// and caches the results shared by all workers, so resolver latency and
+/// flakiness don't add up on every request.
+#[derive(Clone)]
+pub struct DnsResolver {
+    overrides: Arc<HashMap<String, Vec<IpAddr>>>,
+    cache: Arc<DashMap<String, CachedLookup>>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    metrics: web::Data<Metrics>,
+}
+
+impl DnsResolver {
+    pub fn new(
+        overrides: HashMap<String, Vec<IpAddr>>,
+        positive_ttl: Duration,
+        negative_ttl: Duration,
+        metrics: web::Data<Metrics>,
+    ) -> Self {
+        Self {
+            overrides: Arc::new(overrides),
+            cache: Arc::new(DashMap::new()),
+            positive_ttl,
+            negative_ttl,
+            metrics,
+        }
+    }
+
+    /// Parse `host=ip` overrides, several entries for a host add up
+    pub fn parse_overrides(entries: &[String]) -> Result<HashMap<String, Vec<IpAddr>>, String> {
+        let mut overrides: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for entry in entries {
+            let (host, ip) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid --resolve entry {entry}, expected host=ip"))?;
+            let ip = ip
+                .trim()
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .map_err(|err| format!("Invalid IP address in --resolve entry {entry}: {err}"))?;
+            overrides
+                .entry(host.trim().to_ascii_lowercase())
+                .or_default()
+                .push(ip);
+        }
+
+        Ok(overrides)
+    }
+
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        let host = host.to_ascii_lowercase();
+        if let Some(addresses) = self.overrides.get(&host) {
+            self.metrics.inc("dns_lookups_total", &[("result", "override")]);
+            return Ok(addresses.clone());
+        }
+
+        if let Some(cached) = self.cache.get(&host) {
+            if cached.expires_at > Instant::now() {
+                self.metrics.inc("dns_lookups_total", &[("result", "hit")]);
+                return cached.addresses.clone();
+            }
+        }
+
+        let lookup_host = host.clone();
+        let addresses = actix_web::rt::task::spawn_blocking(move || {
+            (lookup_host.as_str(), 0)
+                .to_socket_addrs()
+                .map(|addrs| addrs.map(|addr| addr.ip()).collect::<Vec<_>>())
+        })
+        .await
+        .map_err(|err| io::Error::other(err.to_string()))
+        .and_then(|result| result)
+        .map_err(|err| err.to_string());
+
+        let ttl = match &addresses {
+            Ok(_) => {
+                self.metrics.inc("dns_lookups_total", &[("result", "miss")]);
+                self.positive_ttl
+            }
+            Err(err) => {
+                log::warn!("Failed to resolve {host}: {err}");
+                self.metrics.inc("dns_lookups_total", &[("result", "error")]);
+                self.negative_ttl
+            }
+        };
+        if !ttl.is_zero() {
+            self.cache.insert(
+                host,
+                CachedLookup {
+                    addresses: addresses.clone(),
+                    expires_at: Instant::now() + ttl,
+                },
+            );
+        }
+
+        addresses
+    }
+}
+
+impl Resolve for DnsResolver {
+    fn lookup<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> LocalBoxFuture<'a, Result<Vec<SocketAddr>, Box<dyn StdError>>> {
+        Box::pin(async move {
+            let addresses = self.resolve(host).await?;
+            Ok(addresses
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect())
+        })
+    }
+}
@@ -0,0 +1,173 @@
+use hls_m3u8::MediaPlaylist;
+use std::borrow::Cow;
+
+const KEY_TAG: &str = "#EXT-X-KEY:";
+const SESSION_KEY_TAG: &str = "#EXT-X-SESSION-KEY:";
+const VERSION_TAG: &str = "#EXT-X-VERSION:";
+// The tags written ahead of the segments of a media playlist
+const MEDIA_HEADER_TAGS: [&str; 9] = [
+    "#EXTM3U",
+    VERSION_TAG,
+    "#EXT-X-TARGETDURATION:",
+    "#EXT-X-MEDIA-SEQUENCE:",
+    "#EXT-X-DISCONTINUITY-SEQUENCE:",
+    "#EXT-X-PLAYLIST-TYPE:",
+    "#EXT-X-I-FRAMES-ONLY",
+    "#EXT-X-INDEPENDENT-SEGMENTS",
+    "#EXT-X-START:",
+];
+
+/// The key tags of a protected origin playlist, its `EXT-X-SESSION-KEY`s and
+/// `EXT-X-KEY`s, kept as they are. The playlist parser knows neither the
+/// `SAMPLE-AES-CTR` method of multi-DRM streams, failing on it, nor attributes
+/// like `KEYID`, and writes the keys of FairPlay (`skd://`) back without their
+/// `KEYFORMATVERSIONS`. So the tags are taken out of the playlist text before
+/// it is parsed and written back as they were with the modified playlist
+#[derive(Clone, Debug, Default)]
+pub struct OriginKeys {
+    session_keys: Vec<String>,
+    // The tags preceding each segment, by its position in the origin playlist
+    keys: Vec<(usize, String)>,
+    version: Option<u64>,
+}
+
+impl OriginKeys {
+    /// The playlist text without its key tags, and the tags
+    pub fn strip(text: &str) -> (Cow<'_, str>, Self) {
+        let is_key = |line: &str| line.starts_with(KEY_TAG) || line.starts_with(SESSION_KEY_TAG);
+        if !text.lines().any(|line| is_key(line.trim())) {
+            return (Cow::Borrowed(text), Self::default());
+        }
+        let mut keys = Self::default();
+        let mut stripped = String::with_capacity(text.len());
+        let mut position = 0;
+        for line in text.lines() {
+            let tag = line.trim();
+            if tag.starts_with(SESSION_KEY_TAG) {
+                keys.session_keys.push(tag.to_string());
+                continue;
+            }
+            if tag.starts_with(KEY_TAG) {
+                keys.keys.push((position, tag.to_string()));
+                continue;
+            }
+            if let Some(version) = tag.strip_prefix(VERSION_TAG) {
+                keys.version = version.trim().parse().ok();
+            } else if !tag.is_empty() && !tag.starts_with('#') {
+                position += 1;
+            }
+            stripped.push_str(line);
+            stripped.push('\n');
+        }
+        (Cow::Owned(stripped), keys)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.session_keys.is_empty() && self.keys.is_empty()
+    }
+
+    /// Write the session keys back into a serialized master playlist, after
+    /// its version
+    pub fn restore_master(&self, output: String) -> String {
+        if self.session_keys.is_empty() {
+            return output;
+        }
+        let mut result = String::with_capacity(output.len() + self.session_keys.iter().map(|key| key.len() + 1).sum::<usize>());
+        let mut lines = output.lines().peekable();
+        while let Some(line) = lines.next_if(|line| line.starts_with("#EXTM3U") || line.starts_with(VERSION_TAG)) {
+            result.push_str(&self.version_line(line));
+            result.push('\n');
+        }
+        for key in &self.session_keys {
+            result.push_str(key);
+            result.push('\n');
+        }
+        for line in lines {
+            result.push_str(line);
+            result.push('\n');
+        }
+        result
+    }
+
+    /// Write the keys back into a serialized media playlist, ahead of the tags
+    /// of the segment they preceded, the segment parsed at the same position.
+    /// The keys of the segments removed since apply to the next segment
+    pub fn restore_media(&self, playlist: &MediaPlaylist<'_>, output: String) -> String {
+        if self.keys.is_empty() {
+            return output;
+        }
+        let indexes = playlist.segments.iter().map(|(index, _)| index).collect::<Vec<_>>();
+        let length = output.len() + self.keys.iter().map(|(_, key)| key.len() + 1).sum::<usize>();
+        let mut result = String::with_capacity(length);
+        let mut position = 0;
+        let mut in_segment = false;
+        // The keys before the segments up to this index were written
+        let mut written = 0;
+        for line in output.lines() {
+            let is_header = position == 0 && !in_segment && MEDIA_HEADER_TAGS.iter().any(|tag| line.starts_with(tag));
+            if !is_header && !in_segment {
+                if let Some(index) = indexes.get(position) {
+                    for (_, key) in self.keys.iter().filter(|(other, _)| (written..=*index).contains(other)) {
+                        result.push_str(key);
+                        result.push('\n');
+                    }
+                    written = index + 1;
+                }
+                in_segment = true;
+            }
+            result.push_str(&self.version_line(line));
+            result.push('\n');
+            if !line.is_empty() && !line.starts_with('#') {
+                position += 1;
+                in_segment = false;
+            }
+        }
+        result
+    }
+
+    // The keys may need a later version than the playlist without them
+    fn version_line<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let version = line.strip_prefix(VERSION_TAG).and_then(|version| version.trim().parse::<u64>().ok());
+        match (version, self.version) {
+            (Some(version), Some(origin)) if origin > version => Cow::Owned(format!("{VERSION_TAG}{origin}")),
+            _ => Cow::Borrowed(line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAIRPLAY: &str = r#"#EXT-X-KEY:METHOD=SAMPLE-AES,URI="skd://key-1",KEYFORMAT="com.apple.streamingkeydelivery",KEYFORMATVERSIONS="1""#;
+    const WIDEVINE: &str = r#"#EXT-X-KEY:METHOD=SAMPLE-AES-CTR,URI="data:text/plain;base64,AAAAW3Bzc2g=",KEYID=0x1234,KEYFORMAT="urn:uuid:edef8ba9-79d6-4ace-a3c8-27dcd51d21ed",KEYFORMATVERSIONS="1""#;
+
+    #[test]
+    fn keeps_the_keys_of_a_media_playlist() {
+        let text = format!(
+            "#EXTM3U\n#EXT-X-VERSION:5\n#EXT-X-TARGETDURATION:6\n{FAIRPLAY}\n{WIDEVINE}\n#EXT-X-MAP:URI=\"init.mp4\"\n\
+             #EXTINF:6.000,\ns0.m4s\n#EXT-X-KEY:METHOD=NONE\n#EXTINF:6.000,\ns1.m4s\n#EXT-X-ENDLIST\n"
+        );
+        let (stripped, keys) = OriginKeys::strip(&text);
+        let playlist = MediaPlaylist::try_from(stripped.as_ref()).unwrap();
+        let output = keys.restore_media(&playlist, playlist.to_string());
+        let lines = output.lines().collect::<Vec<_>>();
+        let position = |text: &str| lines.iter().position(|line| line.starts_with(text)).unwrap();
+        assert_eq!(lines[position("#EXT-X-TARGETDURATION") + 1], FAIRPLAY, "{output}");
+        assert_eq!(lines[position("#EXT-X-TARGETDURATION") + 2], WIDEVINE);
+        assert!(position("#EXT-X-KEY:METHOD=NONE") > position("s0.m4s"));
+        assert!(position("#EXT-X-KEY:METHOD=NONE") < position("s1.m4s"));
+        assert!(position("#EXT-X-VERSION:") < position("#EXT-X-KEY"));
+        assert_eq!(lines[1], "#EXT-X-VERSION:6");
+    }
+
+    #[test]
+    fn keeps_the_session_keys_of_a_master_playlist() {
+        let session_key = FAIRPLAY.replace(KEY_TAG, SESSION_KEY_TAG);
+        let text = format!("#EXTM3U\n#EXT-X-VERSION:5\n{session_key}\n#EXT-X-STREAM-INF:BANDWIDTH=1000000\nv0.m3u8\n");
+        let (stripped, keys) = OriginKeys::strip(&text);
+        assert!(!stripped.contains(SESSION_KEY_TAG));
+        let output = keys.restore_master(stripped.into_owned());
+        assert_eq!(output.lines().nth(2), Some(session_key.as_str()));
+    }
+}
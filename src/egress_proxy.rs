@@ -0,0 +1,192 @@
+use actix_tls::connect::{ConnectError, ConnectInfo, Connection, ConnectorService};
+use actix_web::dev::Service;
+use actix_web::http::Uri;
+use actix_web::rt::net::TcpStream;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures_util::future::LocalBoxFuture;
+use percent_encoding::percent_decode_str;
+use std::io;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+// Maximum size of the response head of a CONNECT request
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+#[derive(Clone, Debug)]
+struct ProxyServer {
+    uri: Uri,
+    // Basic credentials from the user info of the proxy URL
+    authorization: Option<String>,
+}
+
+impl ProxyServer {
+    fn parse(value: &str) -> Result<Self, String> {
+        let value = if value.contains("://") {
+            value.to_string()
+        } else {
+            format!("http://{value}")
+        };
+        let url = Url::parse(&value).map_err(|err| format!("Invalid proxy URL {value}: {err}"))?;
+        if url.scheme() != "http" {
+            return Err(format!("Unsupported proxy scheme {}, only http is supported", url.scheme()));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("Missing host in proxy URL {value}"))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let uri = format!("http://{host}:{port}")
+            .parse::<Uri>()
+            .map_err(|err| format!("Invalid proxy URL {value}: {err}"))?;
+
+        let authorization = (!url.username().is_empty()).then(|| {
+            let username = percent_decode_str(url.username()).decode_utf8_lossy();
+            let password = percent_decode_str(url.password().unwrap_or_default()).decode_utf8_lossy();
+            format!("Basic {}", BASE64.encode(format!("{username}:{password}")))
+        });
+
+        Ok(Self { uri, authorization })
+    }
+}
+
+/// Outbound HTTP proxies of the origin and ad server connections, as given by
+/// `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY`. Both HTTP and HTTPS upstreams
+/// are reached through CONNECT tunnels, so the TLS handshake stays end to end.
+#[derive(Debug)]
+pub struct EgressProxy {
+    http: Option<ProxyServer>,
+    https: Option<ProxyServer>,
+    // Hosts and domains connected to directly, "*" for all
+    no_proxy: Vec<String>,
+}
+
+impl EgressProxy {
+    pub fn new(
+        http_proxy: Option<&str>,
+        https_proxy: Option<&str>,
+        no_proxy: Option<&str>,
+    ) -> Result<Self, String> {
+        let parse = |value: Option<&str>| {
+            value
+                .filter(|value| !value.trim().is_empty())
+                .map(|value| ProxyServer::parse(value.trim()))
+                .transpose()
+        };
+
+        Ok(Self {
+            http: parse(http_proxy)?,
+            https: parse(https_proxy)?,
+            no_proxy: no_proxy
+                .unwrap_or_default()
+                .split(',')
+                .map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|entry| !entry.is_empty())
+                .collect(),
+        })
+    }
+
+    /// Describe the proxies without their credentials
+    pub fn describe(&self) -> Option<String> {
+        let servers = [("http", &self.http), ("https", &self.https)]
+            .into_iter()
+            .filter_map(|(scheme, server)| {
+                server
+                    .as_ref()
+                    .map(|server| format!("{scheme} through {}", server.uri.authority().unwrap()))
+            })
+            .collect::<Vec<_>>();
+
+        (!servers.is_empty()).then(|| servers.join(", "))
+    }
+
+    fn server_for(&self, uri: &Uri) -> Option<&ProxyServer> {
+        let server = match uri.scheme_str() {
+            Some("https") => self.https.as_ref(),
+            _ => self.http.as_ref(),
+        }?;
+
+        let host = uri.host()?.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let bypass = self.no_proxy.iter().any(|entry| {
+            entry == "*"
+                || host == *entry
+                || host.strip_suffix(entry.as_str()).is_some_and(|prefix| prefix.ends_with('.'))
+        });
+
+        (!bypass).then_some(server)
+    }
+}
+
+/// TCP connector of the upstream clients, tunneling connections through the
+/// egress proxy unless the host is excluded by `NO_PROXY`
+#[derive(Clone)]
+pub struct ProxyConnector {
+    tcp: ConnectorService,
+    proxy: Arc<EgressProxy>,
+}
+
+impl ProxyConnector {
+    pub fn new(tcp: ConnectorService, proxy: Arc<EgressProxy>) -> Self {
+        Self { tcp, proxy }
+    }
+}
+
+impl Service<ConnectInfo<Uri>> for ProxyConnector {
+    type Response = Connection<Uri, TcpStream>;
+    type Error = ConnectError;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::<ConnectInfo<Uri>>::poll_ready(&self.tcp, cx)
+    }
+
+    fn call(&self, req: ConnectInfo<Uri>) -> Self::Future {
+        let Some(server) = self.proxy.server_for(req.request()).cloned() else {
+            return Box::pin(self.tcp.call(req));
+        };
+
+        let tcp = self.tcp.clone();
+        Box::pin(async move {
+            let target = format!("{}:{}", req.hostname(), req.port());
+            let (mut io, _) = tcp.call(ConnectInfo::new(server.uri.clone())).await?.into_parts();
+            open_tunnel(&mut io, &target, server.authorization.as_deref())
+                .await
+                .map_err(|err| {
+                    log::error!("Failed to tunnel to {target} through the proxy {}: {err}", server.uri);
+                    ConnectError::Io(err)
+                })?;
+
+            Ok(Connection::new(req.request().clone(), io))
+        })
+    }
+}
+
+async fn open_tunnel(io: &mut TcpStream, target: &str, authorization: Option<&str>) -> io::Result<()> {
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some(authorization) = authorization {
+        request.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+    }
+    request.push_str("\r\n");
+    io.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte to not consume anything past the response head
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Proxy response head too large"));
+        }
+        if io.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Proxy closed the connection"));
+        }
+        head.push(byte[0]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("Proxy refused the tunnel: {status_line}"))),
+    }
+}
@@ -0,0 +1,89 @@
+use crate::utils::{SESSION_PARAM, get_header_value, get_query_param, playback_session_id};
+use actix_web::HttpRequest;
+use awc::Client;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Which playback sessions are entitled to clean playlists without ad breaks:
+/// those requesting the playlists with a truthy `param` query parameter or
+/// `header`, set by the edge after authorizing the viewer, or those whose
+/// session the `callback` answers `{"ad_free": true}` for
+#[derive(Clone, Debug, Default)]
+pub struct AdFreeEntitlement {
+    param: Option<String>,
+    header: Option<String>,
+    callback: Option<Url>,
+    ttl: Duration,
+    // The answers of the callback by session, and when they were given
+    sessions: Arc<DashMap<String, (bool, Instant)>>,
+}
+
+impl AdFreeEntitlement {
+    pub fn new(param: Option<String>, header: Option<String>, callback: Option<Url>, ttl: Duration) -> Self {
+        Self {
+            param,
+            header: header.map(|header| header.to_ascii_lowercase()),
+            callback,
+            ttl,
+            sessions: Arc::default(),
+        }
+    }
+
+    /// The signal marking the request's session ad-free, if any
+    pub async fn check(&self, req: &HttpRequest, client: &Client) -> Option<&'static str> {
+        let is_truthy = |value: String| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes");
+        if self.param.as_ref().and_then(|param| get_query_param(req, param)).is_some_and(is_truthy) {
+            return Some("param");
+        }
+        if self.header.as_ref().and_then(|header| get_header_value(req, header)).is_some_and(is_truthy) {
+            return Some("header");
+        }
+
+        let callback = self.callback.as_ref()?;
+        let session = playback_session_id(req)?;
+        if let Some(entry) = self.sessions.get(&session).filter(|entry| entry.1.elapsed() < self.ttl) {
+            return entry.0.then_some("entitlement");
+        }
+        let ad_free = query_entitlement(client, callback, &session).await?;
+        self.sessions.retain(|_, (_, checked_at)| checked_at.elapsed() < self.ttl);
+        self.sessions.insert(session, (ad_free, Instant::now()));
+        ad_free.then_some("entitlement")
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "param": self.param.as_deref(),
+            "header": self.header.as_deref(),
+            "callback": self.callback.as_ref().map(Url::as_str),
+            "cache_ttl": self.ttl.as_secs(),
+            "sessions": self.sessions.len(),
+        }
+    }
+}
+
+// Ask the entitlement service about a session, None if it can't tell. The
+// session gets the ads then, and is asked about again on its next playlist
+async fn query_entitlement(client: &Client, callback: &Url, session: &str) -> Option<bool> {
+    let mut url = callback.clone();
+    url.query_pairs_mut().append_pair(SESSION_PARAM, session);
+    let mut res = client
+        .get(url.as_str())
+        .insert_header((awc::http::header::ACCEPT, mime::APPLICATION_JSON.as_ref()))
+        .send()
+        .await
+        .inspect_err(|err| log::warn!("Entitlement request for session {session} failed: {err}"))
+        .ok()?;
+    if !res.status().is_success() {
+        log::warn!("Entitlement service answered {} for session {session}", res.status());
+        return None;
+    }
+    let body = res.body().limit(64 * 1024).await.ok()?;
+    let answer = json::parse(&String::from_utf8_lossy(&body))
+        .inspect_err(|err| log::warn!("Invalid entitlement of session {session}: {err}"))
+        .ok()?;
+    let ad_free = answer["ad_free"].as_bool().unwrap_or(false);
+    log::debug!("Session {session} is {}", if ad_free { "ad-free" } else { "not ad-free" });
+    Some(ad_free)
+}
@@ -0,0 +1,213 @@
+use crate::break_schedule::PlannedBreak;
+use quick_xml::events::{BytesStart, Event};
+
+/// A planned midroll of a program, `offset` seconds into it. Without a
+/// `duration` it lasts as long as its pod template
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+pub struct EpgBreak {
+    pub offset: f64,
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+/// A program of the EPG and its planned breaks
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct EpgProgram {
+    // The start time names a program without an ID
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub breaks: Vec<EpgBreak>,
+}
+
+#[derive(serde::Deserialize)]
+struct EpgFile {
+    programs: Vec<EpgProgram>,
+}
+
+impl EpgProgram {
+    fn id(&self) -> String {
+        self.id.clone().unwrap_or_else(|| self.start.to_rfc3339())
+    }
+
+    /// The planned breaks of the program, with a break of `boundary_break`
+    /// seconds ending with the program when it isn't zero
+    fn planned_breaks(&self, boundary_break: f64) -> Vec<PlannedBreak> {
+        let id = self.id();
+        let length = (self.end - self.start).num_milliseconds() as f64 / 1000.0;
+        let mut breaks = Vec::new();
+        for (index, midroll) in self.breaks.iter().enumerate() {
+            if !(0.0..length).contains(&midroll.offset) {
+                log::debug!("Skipping break {index} of program {id}, it is {}s into a {length}s program", midroll.offset);
+                continue;
+            }
+            breaks.push(PlannedBreak {
+                id: format!("{id}/break{index}"),
+                start_time: self.start + chrono::Duration::milliseconds((midroll.offset * 1000.0).round() as i64),
+                duration: midroll.duration.unwrap_or_default(),
+                templates: midroll.template.iter().cloned().collect(),
+                cancelled: false,
+            });
+        }
+        if boundary_break > 0.0 && boundary_break < length {
+            breaks.push(PlannedBreak {
+                id: format!("{id}/boundary"),
+                start_time: self.end - chrono::Duration::milliseconds((boundary_break * 1000.0).round() as i64),
+                duration: boundary_break,
+                templates: Vec::new(),
+                cancelled: false,
+            });
+        }
+        breaks
+    }
+}
+
+// The value of an attribute by its local name
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name.as_bytes())
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.trim().to_string())
+}
+
+/// A time of XMLTV, `20261015120000 +0000`, in UTC without an offset
+fn parse_xmltv_time(value: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::parse_from_str(value, "%Y%m%d%H%M%S %z")
+        .map(|time| time.with_timezone(&chrono::Utc))
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%S").map(|time| time.and_utc()))
+        .ok()
+}
+
+/// The programs of an XMLTV document, their breaks being `<break offset=...
+/// duration=... template=.../>` elements. The channel of a program names it
+/// with its start time
+fn parse_xmltv(xml: &str) -> Result<Vec<EpgProgram>, String> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+    let mut programs = Vec::new();
+    let mut program: Option<EpgProgram> = None;
+    let mut found_tv = false;
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|err| format!("Invalid XMLTV document at {}: {err}", reader.error_position()))?;
+        let is_empty = matches!(event, Event::Empty(_));
+        match event {
+            Event::Start(element) | Event::Empty(element) => match element.local_name().as_ref() {
+                b"tv" => found_tv = true,
+                b"programme" => {
+                    let start = attribute(&element, "start").as_deref().and_then(parse_xmltv_time);
+                    let end = attribute(&element, "stop").as_deref().and_then(parse_xmltv_time);
+                    let (Some(start), Some(end)) = (start, end) else {
+                        log::debug!("Skipping an XMLTV programme without start or stop");
+                        continue;
+                    };
+                    let channel = attribute(&element, "channel").unwrap_or_default();
+                    let parsed = EpgProgram {
+                        id: Some(format!("{channel}@{}", start.to_rfc3339())),
+                        title: None,
+                        start,
+                        end,
+                        breaks: Vec::new(),
+                    };
+                    if is_empty {
+                        programs.push(parsed);
+                    } else {
+                        program = Some(parsed);
+                    }
+                }
+                b"break" => {
+                    let (Some(program), Some(offset)) = (
+                        program.as_mut(),
+                        attribute(&element, "offset").and_then(|offset| offset.parse().ok()),
+                    ) else {
+                        continue;
+                    };
+                    program.breaks.push(EpgBreak {
+                        offset,
+                        duration: attribute(&element, "duration").and_then(|duration| duration.parse().ok()),
+                        template: attribute(&element, "template"),
+                    });
+                }
+                _ => {}
+            },
+            Event::End(element) if element.local_name().as_ref() == b"programme" => {
+                programs.extend(program.take());
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if !found_tv {
+        return Err("Not an XMLTV document, it has no tv element".to_string());
+    }
+    Ok(programs)
+}
+
+/// The planned breaks of an EPG, either XMLTV or JSON
+/// (`{"programs": [{"start": ..., "end": ..., "breaks": [{"offset": 600, "duration": 90}]}]}`),
+/// with a break of `boundary_break` seconds at the end of each program when it isn't zero
+pub fn parse_epg(content: &str, boundary_break: f64) -> Result<Vec<PlannedBreak>, String> {
+    let programs = if content.trim_start().starts_with('<') {
+        parse_xmltv(content)?
+    } else {
+        serde_json::from_str::<EpgFile>(content)
+            .map_err(|err| format!("Invalid EPG: {err}"))?
+            .programs
+    };
+    Ok(programs
+        .iter()
+        .flat_map(|program| program.planned_breaks(boundary_break))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(breaks: &[PlannedBreak]) -> Vec<(String, String, f64)> {
+        breaks
+            .iter()
+            .map(|planned| (planned.id.clone(), planned.start_time.to_rfc3339(), planned.duration))
+            .collect()
+    }
+
+    #[test]
+    fn plans_the_breaks_of_the_programs() {
+        let json = r#"{"programs": [
+            {"id": "news", "start": "2026-10-15T12:00:00Z", "end": "2026-10-15T12:30:00Z",
+             "breaks": [{"offset": 600, "duration": 90}, {"offset": 1200, "template": "midroll"}, {"offset": 3600, "duration": 30}]},
+            {"start": "2026-10-15T12:30:00Z", "end": "2026-10-15T13:00:00Z"}
+        ]}"#;
+        let breaks = parse_epg(json, 60.0).unwrap();
+        assert_eq!(
+            summary(&breaks),
+            [
+                ("news/break0".to_string(), "2026-10-15T12:10:00+00:00".to_string(), 90.0),
+                ("news/break1".to_string(), "2026-10-15T12:20:00+00:00".to_string(), 0.0),
+                ("news/boundary".to_string(), "2026-10-15T12:29:00+00:00".to_string(), 60.0),
+                ("2026-10-15T12:30:00+00:00/boundary".to_string(), "2026-10-15T12:59:00+00:00".to_string(), 60.0),
+            ]
+        );
+        assert_eq!(breaks[1].templates, ["midroll"]);
+
+        let xmltv = r#"<?xml version="1.0"?><tv>
+            <programme start="20261015120000 +0200" stop="20261015123000 +0200" channel="news.example">
+                <title>News</title><break offset="300" duration="30.5"/>
+            </programme>
+        </tv>"#;
+        assert_eq!(
+            summary(&parse_epg(xmltv, 0.0).unwrap()),
+            [("news.example@2026-10-15T10:00:00+00:00/break0".to_string(), "2026-10-15T10:05:00+00:00".to_string(), 30.5)]
+        );
+        assert!(parse_epg("<VAST/>", 0.0).is_err());
+    }
+}
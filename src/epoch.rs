@@ -0,0 +1,32 @@
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+/// The reference date time of a stream. Static ad slots of live streams are
+/// scheduled from it and VOD playlists without program date times are
+/// anchored to it, so each stream keeps its own timeline.
+#[derive(Clone)]
+pub struct StreamEpoch(Arc<RwLock<DateTime<Utc>>>);
+
+impl StreamEpoch {
+    pub fn new(epoch: DateTime<Utc>) -> Self {
+        Self(Arc::new(RwLock::new(epoch)))
+    }
+
+    pub fn get(&self) -> DateTime<Utc> {
+        *self.0.read()
+    }
+
+    /// Re-anchor the stream to now and return the new epoch
+    pub fn reset(&self) -> DateTime<Utc> {
+        let now = Utc::now();
+        *self.0.write() = now;
+        now
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "epoch": self.get().to_rfc3339(),
+        }
+    }
+}
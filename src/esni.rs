@@ -0,0 +1,226 @@
+use crate::break_schedule::PlannedBreak;
+use quick_xml::events::{BytesStart, Event};
+
+// A MediaPoint being read
+#[derive(Default)]
+struct MediaPoint {
+    id: String,
+    match_time: Option<chrono::DateTime<chrono::Utc>>,
+    expired: bool,
+    // Inside its Apply or its Remove
+    applying: bool,
+    removing: bool,
+    duration: Option<f64>,
+    applied: Vec<String>,
+    removed: Vec<String>,
+}
+
+// The value of an attribute by its local name, without its namespace prefix
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name.as_bytes())
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.trim().to_string())
+}
+
+// The ID of a policy reference, `id="sports"` or `xlink:href="...#sports"`
+fn policy_id(element: &BytesStart) -> Option<String> {
+    attribute(element, "id").or_else(|| {
+        attribute(element, "href").map(|href| href.rsplit(['#', '/']).next().unwrap_or_default().to_string())
+    })
+}
+
+/// The seconds of an ISO 8601 duration, e.g. `PT2M30.5S`
+fn parse_duration(value: &str) -> Option<f64> {
+    let rest = value.trim().strip_prefix('P')?;
+    let mut seconds = 0.0;
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in rest.chars() {
+        match c {
+            'T' if number.is_empty() => in_time = true,
+            '0'..='9' | '.' => number.push(c),
+            unit => {
+                let value: f64 = number.parse().ok()?;
+                number.clear();
+                seconds += value
+                    * match (unit, in_time) {
+                        ('D', false) => 86400.0,
+                        ('H', true) => 3600.0,
+                        ('M', true) => 60.0,
+                        ('S', true) => 1.0,
+                        _ => return None,
+                    };
+            }
+        }
+    }
+    (number.is_empty() && seconds.is_finite() && seconds > 0.0).then_some(seconds)
+}
+
+fn parse_time(value: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::parse_from_rfc3339(value.trim())
+        .ok()
+        .map(|time| time.with_timezone(&chrono::Utc))
+}
+
+/// The breaks of an SCTE-224 (ESNI) document: a MediaPoint applying policies
+/// is a break from its `matchTime`, for the `duration` of its Apply or until a
+/// later MediaPoint removes them, and a MediaPoint past its `expires` cancels
+/// its break. The IDs of the policies name the pod templates of the breaks.
+/// MediaPoints without a `matchTime` and Applies without a duration nor a later
+/// Remove of their policies are left out
+pub fn parse_schedule(xml: &str, now: chrono::DateTime<chrono::Utc>) -> Result<Vec<PlannedBreak>, String> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+    let mut breaks = Vec::new();
+    // The breaks applied without a duration, until their policies are removed
+    let mut open: Vec<PlannedBreak> = Vec::new();
+    let mut media_point: Option<MediaPoint> = None;
+    let mut found_media = false;
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|err| format!("Invalid SCTE-224 document at {}: {err}", reader.error_position()))?;
+        let is_start = matches!(event, Event::Start(_));
+        match event {
+            Event::Start(element) | Event::Empty(element) => match element.local_name().as_ref() {
+                b"Media" => found_media = true,
+                b"MediaPoint" => {
+                    media_point = Some(MediaPoint {
+                        id: attribute(&element, "id").unwrap_or_default(),
+                        match_time: attribute(&element, "matchTime").as_deref().and_then(parse_time),
+                        expired: attribute(&element, "expires")
+                            .as_deref()
+                            .and_then(parse_time)
+                            .is_some_and(|expires| expires <= now),
+                        ..Default::default()
+                    });
+                }
+                b"Apply" => {
+                    if let Some(point) = media_point.as_mut() {
+                        point.applying = is_start;
+                        point.duration = attribute(&element, "duration").as_deref().and_then(parse_duration);
+                    }
+                }
+                b"Remove" => {
+                    if let Some(point) = media_point.as_mut() {
+                        point.removing = is_start;
+                    }
+                }
+                b"Policy" => {
+                    let (Some(point), Some(policy)) = (media_point.as_mut(), policy_id(&element)) else {
+                        continue;
+                    };
+                    if point.applying {
+                        point.applied.push(policy);
+                    } else if point.removing {
+                        point.removed.push(policy);
+                    }
+                }
+                _ => {}
+            },
+            Event::End(element) => match element.local_name().as_ref() {
+                b"Apply" => {
+                    if let Some(point) = media_point.as_mut() {
+                        point.applying = false;
+                    }
+                }
+                b"Remove" => {
+                    if let Some(point) = media_point.as_mut() {
+                        point.removing = false;
+                    }
+                }
+                b"MediaPoint" => {
+                    let Some(point) = media_point.take() else {
+                        continue;
+                    };
+                    let Some(match_time) = point.match_time else {
+                        log::debug!("Skipping MediaPoint {} without a matchTime", point.id);
+                        continue;
+                    };
+                    // The open breaks of the removed policies end here
+                    for policy in &point.removed {
+                        while let Some(index) = open.iter().position(|open| open.templates.contains(policy)) {
+                            let mut ended = open.remove(index);
+                            ended.duration = (match_time - ended.start_time).num_milliseconds() as f64 / 1000.0;
+                            if ended.duration > 0.0 {
+                                breaks.push(ended);
+                            }
+                        }
+                    }
+                    if point.applied.is_empty() && point.duration.is_none() {
+                        continue;
+                    }
+                    let esni_break = PlannedBreak {
+                        id: point.id,
+                        start_time: match_time,
+                        duration: point.duration.unwrap_or_default(),
+                        templates: point.applied,
+                        cancelled: point.expired,
+                    };
+                    if point.duration.is_some() {
+                        breaks.push(esni_break);
+                    } else {
+                        open.push(esni_break);
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if !found_media {
+        return Err("Not an SCTE-224 document, it has no Media".to_string());
+    }
+    for esni_break in open {
+        log::debug!("Skipping MediaPoint {}, its policies are never removed", esni_break.id);
+    }
+    Ok(breaks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_the_media_points_into_breaks() {
+        let xml = r##"<Media id="channel" xmlns="http://www.scte.org/schemas/224" xmlns:xlink="http://www.w3.org/1999/xlink">
+            <MediaPoint id="break-1" matchTime="2026-10-15T12:00:00Z">
+                <Apply duration="PT1M30S"><Policy xlink:href="https://traffic.example.com/policies#midroll"/></Apply>
+            </MediaPoint>
+            <MediaPoint id="break-2" matchTime="2026-10-15T12:30:00Z">
+                <Apply><Policy id="sports"/></Apply>
+            </MediaPoint>
+            <MediaPoint id="break-2-end" matchTime="2026-10-15T12:31:00.500Z">
+                <Remove><Policy id="sports"/></Remove>
+            </MediaPoint>
+            <MediaPoint id="break-3" matchTime="2026-10-15T13:00:00Z" expires="2026-10-15T11:00:00Z">
+                <Apply duration="PT20S"><Policy id="midroll"/></Apply>
+            </MediaPoint>
+        </Media>"##;
+        let now = parse_time("2026-10-15T11:30:00Z").unwrap();
+        let breaks = parse_schedule(xml, now).unwrap();
+        let breaks = breaks
+            .iter()
+            .map(|esni_break| {
+                (esni_break.id.as_str(), esni_break.start_time.to_rfc3339(), esni_break.duration, esni_break.templates.clone(), esni_break.cancelled)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            breaks,
+            [
+                ("break-1", "2026-10-15T12:00:00+00:00".to_string(), 90.0, vec!["midroll".to_string()], false),
+                ("break-2", "2026-10-15T12:30:00+00:00".to_string(), 60.5, vec!["sports".to_string()], false),
+                ("break-3", "2026-10-15T13:00:00+00:00".to_string(), 20.0, vec!["midroll".to_string()], true),
+            ]
+        );
+
+        assert_eq!(parse_duration("PT2M30.5S"), Some(150.5));
+        assert_eq!(parse_duration("P1DT1H"), Some(90000.0));
+        assert_eq!(parse_duration("PT"), None);
+        assert!(parse_schedule("<VAST/>", now).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use url::Url;
+
+/// A variant of an A/B experiment
+/// (`--experiment-variant experiment/variant=weight[,key=value...]`). Sessions
+/// are assigned to the variants of an experiment in proportion to their weights.
+/// `ad_server` requests the pods from another ad server, `ads=none` plays no
+/// ads (a holdout group), and the other settings are added to the ad server
+/// requests, e.g. `pods/short=50,dur=15`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExperimentVariant {
+    pub experiment: String,
+    pub name: String,
+    pub weight: u64,
+    pub ad_server_url: Option<Url>,
+    pub no_ads: bool,
+    pub ad_server_params: Vec<(String, String)>,
+}
+
+impl ExperimentVariant {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = value.split(',');
+        let (id, weight) = parts
+            .next()
+            .and_then(|part| part.split_once('='))
+            .ok_or_else(|| format!("Invalid experiment variant '{value}', expected experiment/variant=weight[,key=value...]"))?;
+        let (experiment, name) = id
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| format!("Invalid experiment variant '{}', expected experiment/variant", id.trim()))?;
+        let is_valid_name = |name: &str| {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+        if !is_valid_name(experiment) || !is_valid_name(name) {
+            return Err(format!("Invalid experiment variant name '{experiment}/{name}'"));
+        }
+        let weight = weight
+            .trim()
+            .parse::<u64>()
+            .map_err(|err| format!("Invalid weight of experiment variant {experiment}/{name}: {err}"))?;
+
+        let mut variant = Self {
+            experiment: experiment.to_string(),
+            name: name.to_string(),
+            weight,
+            ad_server_url: None,
+            no_ads: false,
+            ad_server_params: Vec::new(),
+        };
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid setting '{part}' of experiment variant {experiment}/{name}"))?;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "ad_server" => {
+                    variant.ad_server_url = Some(Url::parse(value).map_err(|err| {
+                        format!("Invalid ad server of experiment variant {experiment}/{name}: {err}")
+                    })?)
+                }
+                "ads" if value == "none" => variant.no_ads = true,
+                "ads" | "" => return Err(format!("Invalid setting '{part}' of experiment variant {experiment}/{name}")),
+                _ => variant.ad_server_params.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        Ok(variant)
+    }
+
+    /// The variant as named in the logs and metrics, e.g. `pods/short`
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.experiment, self.name)
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        let mut params = json::JsonValue::new_object();
+        for (key, value) in &self.ad_server_params {
+            params[key.as_str()] = value.as_str().into();
+        }
+        json::object! {
+            "name": self.name.as_str(),
+            "weight": self.weight,
+            "ad_server_url": self.ad_server_url.as_ref().map(Url::as_str),
+            "no_ads": self.no_ads,
+            "ad_server_params": params,
+        }
+    }
+}
+
+/// The A/B experiments a session takes part in, one variant of each. The
+/// assignment hashes the session ID, so a session stays in its variants
+/// across requests, restarts and instances
+#[derive(Debug, Clone, Default)]
+pub struct Experiments {
+    // The variants of each experiment, in the order given
+    experiments: Vec<(String, Vec<ExperimentVariant>)>,
+}
+
+impl Experiments {
+    pub fn parse_all(values: &[String]) -> Result<Self, String> {
+        let mut experiments: Vec<(String, Vec<ExperimentVariant>)> = Vec::new();
+        for value in values {
+            let variant = ExperimentVariant::parse(value)?;
+            match experiments.iter_mut().find(|(name, _)| *name == variant.experiment) {
+                Some((_, variants)) if variants.iter().any(|other| other.name == variant.name) => {
+                    return Err(format!("Experiment variant {} is given more than once", variant.id()));
+                }
+                Some((_, variants)) => variants.push(variant),
+                None => experiments.push((variant.experiment.clone(), vec![variant])),
+            }
+        }
+        if let Some((name, _)) = experiments
+            .iter()
+            .find(|(_, variants)| variants.iter().all(|variant| variant.weight == 0))
+        {
+            return Err(format!("The variants of experiment {name} all have a weight of 0"));
+        }
+        Ok(Self { experiments })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.experiments.is_empty()
+    }
+
+    /// The variant of each experiment the session is assigned to
+    pub fn assign(&self, session: &str) -> Vec<&ExperimentVariant> {
+        self.experiments
+            .iter()
+            .filter_map(|(name, variants)| {
+                let total = variants.iter().map(|variant| variant.weight).sum::<u64>();
+                let mut bucket = fnv1a(format!("{name}\n{session}").as_bytes()) % total;
+                variants.iter().find(|variant| {
+                    if bucket < variant.weight {
+                        return true;
+                    }
+                    bucket -= variant.weight;
+                    false
+                })
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut experiments = json::JsonValue::new_object();
+        for (name, variants) in &self.experiments {
+            experiments[name.as_str()] = variants.iter().map(ExperimentVariant::to_json).collect::<Vec<_>>().into();
+        }
+        experiments
+    }
+}
+
+// FNV-1a, a stable hash unlike the std ones, so sessions keep their variants
+// when the proxy is upgraded
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn experiments(values: &[&str]) -> Result<Experiments, String> {
+        Experiments::parse_all(&values.iter().map(|value| value.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn assigns_sessions_by_weight_and_stably() {
+        let experiments = experiments(&["pods/control=3", "pods/short=1,dur=15", "pods/off=0"]).unwrap();
+        let sessions = (0..4000).map(|index| format!("session-{index}")).collect::<Vec<_>>();
+        let short = sessions
+            .iter()
+            .filter(|session| experiments.assign(session)[0].name == "short")
+            .count();
+        assert!((800..1200).contains(&short), "{short} sessions in the short variant");
+        assert!(sessions.iter().all(|session| experiments.assign(session)[0].name != "off"));
+        assert_eq!(experiments.assign("session-1"), experiments.assign("session-1"));
+    }
+
+    #[test]
+    fn rejects_invalid_variants() {
+        assert!(experiments(&["pods=50"]).is_err());
+        assert!(experiments(&["pods/a=50", "pods/a=50"]).is_err());
+        assert!(experiments(&["pods/a=0"]).is_err());
+        assert!(experiments(&["pods/a=1,ads=some"]).is_err());
+        assert!(experiments(&["pods/a=1,ad_server=not a url"]).is_err());
+    }
+}
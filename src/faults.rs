@@ -0,0 +1,97 @@
+use actix_web::web::Bytes;
+use rand::Rng;
+use std::time::Duration;
+
+/// Artificial failures of the origin and ad server requests, to test how
+/// players and the proxy cope with a misbehaving upstream (testing only)
+#[derive(Clone, Debug, Default)]
+pub struct FaultInjector {
+    latency: Duration,
+    latency_rate: f64,
+    error_rate: f64,
+    truncate_rate: f64,
+    malformed_vast_rate: f64,
+}
+
+impl FaultInjector {
+    /// Rates are the share of the requests a fault is injected into, from 0.0 to 1.0
+    pub fn new(
+        latency: Duration,
+        latency_rate: f64,
+        error_rate: f64,
+        truncate_rate: f64,
+        malformed_vast_rate: f64,
+    ) -> Result<Self, String> {
+        let rates = [
+            ("latency", latency_rate),
+            ("error", error_rate),
+            ("truncate", truncate_rate),
+            ("malformed VAST", malformed_vast_rate),
+        ];
+        for (name, rate) in rates {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("Invalid {name} fault rate {rate}, expected a value from 0.0 to 1.0"));
+            }
+        }
+
+        Ok(Self {
+            latency,
+            latency_rate,
+            error_rate,
+            truncate_rate,
+            malformed_vast_rate,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        (!self.latency.is_zero() && self.latency_rate > 0.0)
+            || self.error_rate > 0.0
+            || self.truncate_rate > 0.0
+            || self.malformed_vast_rate > 0.0
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "latency_ms": self.latency.as_millis() as u64,
+            "latency_rate": self.latency_rate,
+            "error_rate": self.error_rate,
+            "truncate_rate": self.truncate_rate,
+            "malformed_vast_rate": self.malformed_vast_rate,
+        }
+    }
+
+    /// Wait before an upstream request is sent
+    pub async fn delay(&self) {
+        if !self.latency.is_zero() && roll(self.latency_rate) {
+            log::debug!("Injecting {}ms of upstream latency", self.latency.as_millis());
+            actix_web::rt::time::sleep(self.latency).await;
+        }
+    }
+
+    /// Whether the upstream request should fail as if it answered with a 503
+    pub fn fail(&self) -> bool {
+        roll(self.error_rate)
+    }
+
+    /// Cut an origin playlist off halfway, mid-line most of the time
+    pub fn truncate_playlist(&self, body: Bytes) -> Bytes {
+        if roll(self.truncate_rate) {
+            log::debug!("Injecting a truncated origin playlist");
+            return body.slice(..body.len() / 2);
+        }
+        body
+    }
+
+    /// Cut a VAST response off halfway, leaving unclosed elements
+    pub fn malform_vast(&self, body: Bytes) -> Bytes {
+        if roll(self.malformed_vast_rate) {
+            log::debug!("Injecting a malformed VAST response");
+            return body.slice(..body.len() / 2);
+        }
+        body
+    }
+}
+
+fn roll(rate: f64) -> bool {
+    rate > 0.0 && rand::rng().random_bool(rate)
+}
@@ -0,0 +1,1580 @@
+use crate::admission::SessionOverflow;
+use crate::beacon::{BeaconDispatcher, MacroContext, UNDEFINED_ERROR_CODE, expand_macros};
+use crate::break_schedule::{BreakSchedule, PlannedBreak};
+use crate::compatibility::ContentProfile;
+use crate::dash::{DASH_CONTENT_TYPE, DashCreative, DashSignaling};
+use crate::date_ranges::OriginDateRanges;
+use crate::device::InsertionProfile;
+use crate::drm::OriginKeys;
+use crate::metrics::{Metrics, PROMETHEUS_CONTENT_TYPE, StageTimer};
+use crate::origin_cache::{CacheHeaders, OriginPlaylist};
+use crate::playlist_detection::{is_playlist_content, is_playlist_content_type};
+use crate::rules::RulePolicy;
+use crate::shutdown::ShutdownState;
+use crate::timeline::{Timeline, TimelineSegment, TimelineSlot};
+use crate::transcoder::Transcoder;
+use crate::utils::{
+    ProgramDateTimeCursor, SESSION_HEADER, content_length, copy_headers, find_program_datetime_tag, get_header_value,
+    get_query_param, is_hls_playlist, is_master_playlist, playback_session_id, tracking_event_label,
+};
+use crate::{
+    APPLICATION_XML, Ad, AdServerClient, AdSlot, AssetUrlFormat, AvailableAdSlots, AvailableAds, CLICK_SESSION,
+    DASH_AD_PERIOD_PATH, DASH_SESSION, ERROR_EVENT, HLS_INTERSTITIAL_ID, HLS_PLAYLIST_CONTENT_TYPE, HLS_PRIMARY_ID,
+    IMPRESSION_EVENT, InsertionMode, InterstitialTarget, PlayerTrackingEvent, RequestType, SEGMENT_INDEX, ServerConfig,
+    StreamState, UserDefinedQueryParams, X_AD_ID, ad_event_urls, build_info, dash, esni, fetch_ad_pod,
+    get_request_type, insert_interstitials, progress, replace_absolute_url_with_relative_url, save_static_ad_slots,
+    serve_interstitial, slot_ad_server_url, update_last_seen_pdt,
+};
+use actix_web::body::SizedStream;
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpRequest, HttpResponse, HttpResponseBuilder, error, web};
+use awc::Client;
+use awc::http::{StatusCode, header};
+use futures_util::StreamExt;
+use hls_m3u8::tags::ExtXMap;
+use hls_m3u8::{MasterPlaylist, MediaPlaylist, MediaSegment};
+use json::object;
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+use url::Url;
+use uuid::Uuid;
+
+// Map player-reported events onto the stored VAST tracking URLs and fire them
+pub async fn handle_tracking(
+    event: web::Json<PlayerTrackingEvent>,
+    available_ads: web::Data<AvailableAds>,
+    client: web::Data<Client>,
+    beacons: web::Data<BeaconDispatcher>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let event = event.into_inner();
+    log::info!(
+        "Received tracking event {} for ad {} from session {} at {:?}",
+        event.event, event.ad_id, event.session, event.position
+    );
+
+    let ad = Uuid::parse_str(&event.ad_id)
+        .ok()
+        .and_then(|id| available_ads.linears.get(&id).map(|ad| ad.clone()));
+    let Some(ad) = ad else {
+        let response = object! {
+            status: "error",
+            message: format!("Unknown ad {}", event.ad_id),
+        };
+        return Ok(HttpResponse::NotFound()
+            .content_type(mime::APPLICATION_JSON)
+            .body(response.pretty(2)));
+    };
+    // Only the session the ad was served to reports its events
+    if ad.session != event.session {
+        let response = object! {
+            status: "error",
+            message: format!("Ad {} wasn't served to session {}", event.ad_id, event.session),
+        };
+        return Ok(HttpResponse::Forbidden()
+            .content_type(mime::APPLICATION_JSON)
+            .body(response.pretty(2)));
+    }
+
+    let is_error = event.event.eq_ignore_ascii_case(ERROR_EVENT);
+    let context = MacroContext {
+        ad_playhead: event.position,
+        content_playhead: event.content_position.or(ad.content_playhead),
+        error_code: is_error.then(|| event.error_code.unwrap_or(UNDEFINED_ERROR_CODE)),
+    };
+    let urls = ad_event_urls(&ad, &event.event)
+        .into_iter()
+        .map(|url| expand_macros(url, &context))
+        .collect::<Vec<_>>();
+    let mut fired = urls.len();
+    metrics.inc("player_tracking_events_total", &[("event", tracking_event_label(&event.event))]);
+    let duplicate = !beacons.fire_once(&client, &event.session, ad.ad_id, &event.event, urls);
+    if duplicate {
+        fired = 0;
+    }
+
+    let response = object! {
+        status: "success",
+        event: event.event,
+        fired: fired,
+        duplicate: duplicate,
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(mime::APPLICATION_JSON)
+        .body(response.pretty(2)))
+}
+
+// Fire the ClickTracking URLs and redirect the viewer to the ClickThrough URL
+pub async fn handle_click(
+    req: HttpRequest,
+    ad_id: web::Path<String>,
+    available_ads: web::Data<AvailableAds>,
+    client: web::Data<Client>,
+    beacons: web::Data<BeaconDispatcher>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let ad_id = ad_id.into_inner();
+    let session = get_query_param(&req, CLICK_SESSION).unwrap_or_else(|| "default_user".to_string());
+    log::info!("Received click for ad {ad_id} from session {session}");
+
+    let ad = Uuid::parse_str(&ad_id)
+        .ok()
+        .and_then(|id| available_ads.linears.get(&id).map(|ad| ad.clone()))
+        .ok_or_else(|| error::ErrorNotFound(format!("Unknown ad {ad_id}")))?;
+    // An ad without VideoClicks has no click-through URL either
+    let no_click_through = || error::ErrorNotFound(format!("Ad {ad_id} has no click-through URL"));
+    let clicks = ad.clicks.ok_or_else(no_click_through)?;
+    let click_through = clicks.click_through.ok_or_else(no_click_through)?;
+
+    let context = MacroContext {
+        content_playhead: ad.content_playhead,
+        ..Default::default()
+    };
+    let urls = clicks
+        .click_trackings
+        .iter()
+        .map(|url| expand_macros(url, &context))
+        .collect::<Vec<_>>();
+    metrics.inc("ad_clicks_total", &[]);
+    beacons.fire(&client, urls);
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, expand_macros(&click_through, &context)))
+        .finish())
+}
+
+// Stream a media of the ad from its source, for ads served with --proxy-creative-media.
+// `path` is the file name of the media, or a path relative to it, e.g. the variants
+// and segments of a creative HLS stream
+pub async fn handle_creative_media(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    available_ads: web::Data<AvailableAds>,
+    client: web::Data<Client>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let (ad_id, path) = path.into_inner();
+    let ad = Uuid::parse_str(&ad_id)
+        .ok()
+        .and_then(|id| available_ads.linears.get(&id).map(|ad| ad.clone()))
+        .ok_or_else(|| error::ErrorNotFound(format!("Unknown ad {ad_id}")))?;
+    // The MP4 of the ad, or the transcoded stream of it once transcoded
+    let transcoded = available_ads
+        .transcoder
+        .as_ref()
+        .and_then(|transcoder| transcoder.stream_url(&ad.universal_ad_ids));
+    let media_urls = std::iter::once(ad.url)
+        .chain(transcoded)
+        .filter_map(|url| Url::parse(&url).ok())
+        .collect::<Vec<_>>();
+    let file_name = |url: &Url| url.path_segments().and_then(|mut segments| segments.next_back()).map(str::to_string);
+    let (media_url, source) = match media_urls.iter().find(|url| file_name(url).as_deref() == Some(path.as_str())) {
+        Some(media_url) => (media_url.clone(), media_url.clone()),
+        None => {
+            let media_url = media_urls.last().cloned().ok_or_else(|| error::ErrorNotFound(format!("Ad {ad_id} has no media")))?;
+            let mut source = media_url.join(&path).map_err(error::ErrorNotFound)?;
+            source.set_query(req.uri().query());
+            (media_url, source)
+        }
+    };
+    // Only the host of the creative is proxied
+    if source.origin() != media_url.origin() {
+        return Err(error::ErrorForbidden(format!("{path} is not a media of ad {ad_id}")));
+    }
+    log::debug!("Proxying {source} of ad {ad_id}");
+
+    let mut forward_req = client.request(req.method().clone(), source.as_str()).no_decompress();
+    for name in [header::RANGE, header::IF_RANGE, header::ACCEPT_ENCODING] {
+        if let Some(value) = req.headers().get(&name) {
+            forward_req = forward_req.insert_header((name, value.clone()));
+        }
+    }
+    let res = forward_req
+        .send()
+        .await
+        .inspect_err(|_| metrics.inc("creative_proxy_requests_total", &[("status", "error")]))
+        .map_err(error::ErrorBadGateway)?;
+    let status = res.status();
+    metrics.inc("creative_proxy_requests_total", &[("status", status.as_str())]);
+
+    let mut client_resp = HttpResponse::build(status);
+    // The CORS headers are the proxy's own
+    for (name, value) in res.headers().iter() {
+        if name != header::CONNECTION && !name.as_str().starts_with("access-control-") {
+            client_resp.insert_header((name.clone(), value.clone()));
+        }
+    }
+    if !res.headers().contains_key(header::CONTENT_ENCODING) {
+        client_resp.insert_header(header::ContentEncoding::Identity);
+    }
+    let length = content_length(&res);
+    let body = res.map(move |chunk| {
+        if let Ok(bytes) = &chunk {
+            metrics.add("creative_proxy_bytes_total", &[], bytes.len() as u64);
+        }
+        chunk
+    });
+
+    Ok(passthrough_response(client_resp, length, body))
+}
+
+pub(crate) async fn handle_raw_asset_request(
+    req_url: Url,
+    ad_slot_id: &str,
+    linear_id: &str,
+    user_id: &str,
+    available_ads: web::Data<AvailableAds>,
+    config: &ServerConfig,
+) -> Result<HttpResponse, Error> {
+    log::info!(
+        "Received follow-up interstitial request for slot {ad_slot_id} with id {linear_id} from user {user_id}"
+    );
+
+    // return http 404 error if the ad is not found
+    let linear = available_ads
+        .linears
+        .get(&Uuid::parse_str(linear_id).unwrap_or_default())
+        .ok_or_else(|| error::ErrorNotFound("Ad not found".to_string()))?;
+
+    let media_url = config.creative_media_url(&req_url, linear.ad_id, &linear.url);
+    let package = || {
+        let segment = MediaSegment::builder()
+            .duration(Duration::from_secs_f64(linear.duration))
+            .uri(media_url.clone())
+            .keys(clear_keys(config))
+            .build()
+            .unwrap();
+
+        // Wrap the MP4 in a media playlist
+        MediaPlaylist::builder()
+            .media_sequence(0)
+            .target_duration(Duration::from_secs(linear.duration.ceil() as u64))
+            .segments(vec![segment])
+            .has_end_list(true)
+            .build()
+            .inspect(|m3u8| {
+                log::debug!("creative playlist \n{m3u8}");
+            })
+            .unwrap()
+            .to_string()
+    };
+    // The segments of inferred quartiles go through the proxy with the ad's own id
+    let m3u8 = if config.infer_quartiles {
+        progress_playlist(&req_url, &linear, &media_url, &available_ads, config)
+    } else if config.proxy_creatives {
+        // The proxied media is the ad's own, the playlist isn't shared
+        package()
+    } else {
+        available_ads.creatives.playlist_or_insert_with(&linear.universal_ad_ids, &linear.url, package)
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(HLS_PLAYLIST_CONTENT_TYPE)
+        .body(m3u8))
+}
+
+// The segments of the creative, each fragment of a fragmented MP4 or else
+// the whole MP4, as (byte range, duration in seconds)
+fn creative_segments(ad: &Ad, available_ads: &AvailableAds) -> Vec<(Option<std::ops::Range<usize>>, f64)> {
+    match available_ads.durations.fragments(&ad.url) {
+        Some(fragments) => fragments
+            .fragments
+            .iter()
+            .map(|fragment| {
+                let start = fragment.offset as usize;
+                (Some(start..start + fragment.length as usize), fragment.duration)
+            })
+            .collect(),
+        None => vec![(None, ad.duration)],
+    }
+}
+
+// The playlist of a creative whose segments are routed through the proxy,
+// which infers the playback progress from their requests
+fn progress_playlist(req_url: &Url, ad: &Ad, media_url: &str, available_ads: &AvailableAds, config: &ServerConfig) -> String {
+    let init_length = available_ads.durations.fragments(&ad.url).map(|fragments| fragments.init_length);
+    let segments = creative_segments(ad, available_ads)
+        .into_iter()
+        .enumerate()
+        .map(|(index, (range, duration))| {
+            let mut url = req_url.clone();
+            url.query_pairs_mut().append_pair(SEGMENT_INDEX, &index.to_string());
+            let mut segment = MediaSegment::builder();
+            segment.duration(Duration::from_secs_f64(duration)).uri(url.to_string());
+            if let Some(range) = range {
+                segment.byte_range(range);
+            }
+            if index == 0 {
+                segment.keys(clear_keys(config));
+            }
+            // The initialization section applies to the following segments too
+            if let Some(init_length) = init_length.filter(|_| index == 0) {
+                segment.map(ExtXMap::with_range(media_url.to_string(), 0..init_length as usize));
+            }
+            segment.build().unwrap()
+        })
+        .collect::<Vec<_>>();
+    let target_duration = segments
+        .iter()
+        .map(|segment| segment.duration.duration())
+        .max()
+        .unwrap_or_default();
+
+    MediaPlaylist::builder()
+        .media_sequence(0)
+        .target_duration(Duration::from_secs(target_duration.as_secs_f64().ceil() as u64))
+        .segments(segments)
+        .has_end_list(true)
+        .build()
+        .inspect(|m3u8| {
+            log::debug!("creative playlist \n{m3u8}");
+        })
+        .unwrap()
+        .to_string()
+}
+
+// The keys of the first segment of a creative playlist, METHOD=NONE telling the
+// players of protected content that the ad is clear
+fn clear_keys(config: &ServerConfig) -> Vec<hls_m3u8::tags::ExtXKey<'static>> {
+    match config.clear_ads {
+        true => vec![hls_m3u8::tags::ExtXKey::empty()],
+        false => Vec::new(),
+    }
+}
+
+// Fire the progress trackers reached by this segment request and redirect to the creative
+pub(crate) async fn handle_raw_segment_request(
+    linear_id: &str,
+    segment_index: &str,
+    user_id: &str,
+    config: &ServerConfig,
+    available_ads: web::Data<AvailableAds>,
+    client: web::Data<Client>,
+    beacons: web::Data<BeaconDispatcher>,
+) -> Result<HttpResponse, Error> {
+    let ad_id = Uuid::parse_str(linear_id).unwrap_or_default();
+    let ad = available_ads
+        .linears
+        .get(&ad_id)
+        .map(|ad| ad.clone())
+        .ok_or_else(|| error::ErrorNotFound(format!("Unknown ad {linear_id}")))?;
+    let segment_index: usize = segment_index
+        .parse()
+        .map_err(|_| error::ErrorBadRequest("Invalid segment index".to_string()))?;
+
+    // The segments as packaged by progress_playlist
+    let segments = creative_segments(&ad, &available_ads);
+    let segment_count = segments.len();
+    let segment_index = segment_index.min(segment_count - 1);
+    let context = MacroContext {
+        // The segments before this one have been played
+        ad_playhead: Some(segments[..segment_index].iter().map(|(_, duration)| duration).sum()),
+        content_playhead: ad.content_playhead,
+        ..Default::default()
+    };
+    let mut events = progress::reached_events(segment_index, segment_count);
+    // The impression is counted when the creative starts playing
+    if events.contains(&"start") {
+        events.push(IMPRESSION_EVENT);
+    }
+    for event in events {
+        let urls = ad_event_urls(&ad, event)
+            .into_iter()
+            .map(|url| expand_macros(url, &context))
+            .collect::<Vec<_>>();
+        if beacons.fire_once(&client, user_id, ad_id, event, urls) {
+            log::info!("Segment {segment_index} of ad {ad_id} requested by user {user_id}, fired {event}");
+        }
+    }
+
+    // Relative to the segment request, which is next to the asset list
+    let location = config.creative_media_path(ad_id, &ad.url).unwrap_or(ad.url);
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, location))
+        .finish())
+}
+
+pub async fn handle_media_stream(
+    req: HttpRequest,
+    stream: web::Data<StreamState>,
+    client: web::Data<Client>,
+    user_defined_query_params: web::Data<UserDefinedQueryParams>,
+    metrics: web::Data<Metrics>,
+    shutdown: web::Data<ShutdownState>,
+) -> Result<HttpResponse, Error> {
+    log::trace!("Received request \n{:?}", req);
+    let request_type = get_request_type(&req, &stream.config);
+    let is_playlist = !matches!(request_type, RequestType::Segment | RequestType::Other);
+    let admission = &stream.config.admission;
+    if is_playlist && admission.overflow() == SessionOverflow::Reject {
+        if let Some(limit) = admission.check(&req) {
+            metrics.inc("sessions_refused_total", &[("limit", limit), ("overflow", "reject")]);
+            return Ok(too_many_sessions_response());
+        }
+    }
+
+    match request_type {
+        RequestType::MasterPlayList => {
+            handle_master_playlist(req, &stream, client, user_defined_query_params, metrics, shutdown).await
+        }
+        RequestType::MediaPlayList => {
+            handle_media_playlist(req, &stream, client, user_defined_query_params, metrics, shutdown).await
+        }
+        RequestType::Playlist => {
+            handle_playlist(req, &stream, client, user_defined_query_params, metrics, shutdown).await
+        }
+        RequestType::DashManifest => handle_dash_manifest(req, &stream, client, metrics).await,
+        RequestType::Segment => handle_segment(req, &stream.config, client, metrics).await,
+        RequestType::Other => {
+            handle_unknown(req, &stream, client, user_defined_query_params, metrics, shutdown).await
+        }
+    }
+}
+
+async fn handle_master_playlist(
+    req: HttpRequest,
+    stream: &StreamState,
+    client: web::Data<Client>,
+    user_defined_query_params: web::Data<UserDefinedQueryParams>,
+    metrics: web::Data<Metrics>,
+    shutdown: web::Data<ShutdownState>,
+) -> Result<HttpResponse, Error> {
+    let StreamState { config, origin_cache, .. } = stream;
+    let mut timer = StageTimer::start("master");
+    let new_url = config.origin_url(&req);
+
+    let OriginPlaylist { body: payload, cache_headers } = origin_cache
+        .fetch_with_headers(&client, new_url.as_str(), &config.origin_headers.forwarded(&req))
+        .await
+        .inspect_err(|err| {
+            log::error!("Error fetching master playlist: {:?}", err);
+        })?;
+    timer.mark("origin");
+    let m3u8 = std::str::from_utf8(&payload).map_err(error::ErrorBadRequest)?;
+    let (m3u8, keys) = OriginKeys::strip(m3u8);
+
+    // An origin serving a media playlist at the master playlist path gets its breaks
+    if !is_master_playlist(&m3u8) {
+        if let Ok(media) = MediaPlaylist::try_from(m3u8.as_ref()) {
+            log::warn!("The master playlist {} of the origin is a media playlist", req.uri().path());
+            timer.set_playlist("media");
+            timer.mark("parse");
+            return handle_media_playlist_content(&req, &m3u8, media, &keys, &cache_headers, stream, &client, timer, metrics)
+                .await;
+        }
+    }
+    if shutdown.is_draining() {
+        return Ok(draining_response());
+    }
+    let playlist = MasterPlaylist::try_from(m3u8.as_ref()).inspect_err(|err| {
+        log::error!(
+            "Error {:?} when parsing master playlist. Returning the original playlist.",
+            err.to_string()
+        );
+    });
+    timer.mark("parse");
+
+    match playlist {
+        Ok(playlist) => {
+            handle_master_playlist_content(
+                req,
+                playlist,
+                &keys,
+                &cache_headers,
+                user_defined_query_params,
+                stream,
+                timer,
+                metrics,
+            )
+            .await
+        }
+        // Just pass the original payload in case of parsing error
+        Err(_) => Ok(playlist_response(&req, payload, &cache_headers, &timer, config, &metrics)),
+    }
+}
+
+async fn handle_media_playlist(
+    req: HttpRequest,
+    stream: &StreamState,
+    client: web::Data<Client>,
+    user_defined_query_params: web::Data<UserDefinedQueryParams>,
+    metrics: web::Data<Metrics>,
+    shutdown: web::Data<ShutdownState>,
+) -> Result<HttpResponse, Error> {
+    let StreamState { config, origin_cache, .. } = stream;
+    let mut timer = StageTimer::start("media");
+    let new_url = config.origin_url(&req);
+
+    let OriginPlaylist { body: payload, cache_headers } = origin_cache
+        .fetch_with_headers(&client, new_url.as_str(), &config.origin_headers.forwarded(&req))
+        .await?;
+    timer.mark("origin");
+    let m3u8 = std::str::from_utf8(&payload).map_err(error::ErrorInternalServerError)?;
+    let (m3u8, keys) = OriginKeys::strip(m3u8);
+
+    // Another master playlist of the origin, e.g. of another ladder, is served as one
+    if is_master_playlist(&m3u8) {
+        if let Ok(master) = MasterPlaylist::try_from(m3u8.as_ref()) {
+            if shutdown.is_draining() {
+                return Ok(draining_response());
+            }
+            timer.set_playlist("master");
+            timer.mark("parse");
+            return handle_master_playlist_content(
+                req,
+                master,
+                &keys,
+                &cache_headers,
+                user_defined_query_params,
+                stream,
+                timer,
+                metrics,
+            )
+            .await;
+        }
+    }
+    let playlist = MediaPlaylist::try_from(m3u8.as_ref()).inspect_err(|err| {
+        log::error!(
+            "Error {:?} when parsing media playlist. Returning the original playlist.",
+            err.to_string()
+        );
+    });
+    timer.mark("parse");
+
+    if playlist.is_err() {
+        // Just pass the original payload in case of parsing error
+        return Ok(playlist_response(&req, payload.clone(), &cache_headers, &timer, config, &metrics));
+    }
+
+    let playlist = playlist.unwrap();
+    handle_media_playlist_content(&req, &m3u8, playlist, &keys, &cache_headers, stream, &client, timer, metrics).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_master_playlist_content(
+    req: HttpRequest,
+    mut playlist: MasterPlaylist<'_>,
+    keys: &OriginKeys,
+    cache_headers: &CacheHeaders,
+    user_defined_query_params: web::Data<UserDefinedQueryParams>,
+    stream: &StreamState,
+    mut timer: StageTimer,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let config = &stream.config;
+    // Save the user-defined query parameters for later use
+    user_defined_query_params.save(&req);
+
+    config.variant_ladder.apply(&mut playlist);
+    stream.update_content_profile(&playlist);
+    replace_absolute_url_with_relative_url(&mut playlist, &config.path_prefix);
+    config.hooks.on_master_playlist(&mut playlist, &config.playlist_context(&req));
+    timer.mark("rewrite");
+    let playlist_str = keys.restore_master(playlist.to_string());
+
+    // Prepend the request's directory path to any still-relative variant URIs.
+    // Needed when the origin returns relative URIs (e.g. "v0/media.m3u8") and the
+    // master playlist is served under a sub-path (e.g. /loop/master.m3u8).
+    let req_path = req.uri().path();
+    let base_dir = req_path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+    let output = if !base_dir.is_empty() {
+        let mut result = String::with_capacity(playlist_str.len() + 64);
+        let mut prev_was_stream_inf = false;
+        for line in playlist_str.lines() {
+            if prev_was_stream_inf && !line.starts_with('#') && !line.starts_with("http") && !line.starts_with('/') {
+                result.push_str(base_dir);
+                result.push('/');
+            }
+            result.push_str(line);
+            result.push('\n');
+            prev_was_stream_inf = line.starts_with("#EXT-X-STREAM-INF");
+        }
+        result
+    } else {
+        playlist_str
+    };
+    timer.mark("serialize");
+
+    log::debug!("master playlist \n{output}");
+
+    Ok(playlist_response(&req, output, cache_headers, &timer, config, &metrics))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_media_playlist_content(
+    req: &HttpRequest,
+    text: &str,
+    mut playlist: MediaPlaylist<'_>,
+    keys: &OriginKeys,
+    cache_headers: &CacheHeaders,
+    stream: &StreamState,
+    client: &Client,
+    mut timer: StageTimer,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let StreamState { config, available_slots, epoch, .. } = stream;
+    update_last_seen_pdt(&playlist, stream);
+    let date_ranges = OriginDateRanges::take(text, &mut playlist);
+    let profile = insertion_profile(req, config, client, &metrics).await;
+    // The path-style asset lists name the playback session of the player, if it tells it
+    let session = get_header_value(req, SESSION_HEADER)
+        .filter(|_| config.asset_url_format == AssetUrlFormat::Path);
+    let is_vod = playlist.playlist_type == Some(hls_m3u8::types::PlaylistType::Vod);
+    let stats = insert_interstitials(&mut playlist, config, available_slots, epoch, profile, session.as_deref());
+    stats.record(&metrics, &config.channel);
+    if let Some(session) = playback_session_id(req) {
+        let timeline = playlist_timeline(req, &playlist, config, available_slots, profile);
+        stream.timelines.record(&session, timeline);
+    }
+    if is_vod && profile == InsertionProfile::Interstitials {
+        if let Some(session) = get_header_value(req, SESSION_HEADER) {
+            let cues = playlist
+                .segments
+                .iter()
+                .filter_map(|(_, segment)| segment.date_range.as_ref())
+                .map(|date_range| date_range.id().to_string())
+                .collect::<HashSet<_>>();
+            prefetch_vod_pods(req, stream, &session, &cues);
+        }
+    }
+    config.hooks.on_media_playlist(&mut playlist, &config.playlist_context(req));
+    timer.mark("insert");
+    let output = keys.restore_media(&playlist, date_ranges.restore(&playlist, playlist.to_string()));
+    timer.mark("serialize");
+    log::debug!("media playlist \n{output}");
+
+    Ok(playlist_response(req, output, cache_headers, &timer, config, &metrics))
+}
+
+// The segments of the playlist as served to the session and the ad slots of the stream
+fn playlist_timeline(
+    req: &HttpRequest,
+    playlist: &MediaPlaylist,
+    config: &ServerConfig,
+    available_slots: &AvailableAdSlots,
+    profile: InsertionProfile,
+) -> Timeline {
+    let mut segments = Vec::new();
+    if let Some(first_program_date_time) = find_program_datetime_tag(playlist, config.origin_time_zone) {
+        let mut program_date_times = ProgramDateTimeCursor::new(first_program_date_time, config.origin_time_zone);
+        for (position, (_, segment)) in playlist.segments.iter().enumerate() {
+            let (program_date_time, duration) = program_date_times.advance(segment);
+            segments.push(TimelineSegment {
+                sequence: playlist.media_sequence as u64 + position as u64,
+                uri: segment.uri().to_string(),
+                program_date_time,
+                duration: duration.as_secs_f64(),
+                cue: segment.date_range.as_ref().map(|date_range| date_range.id().to_string()),
+            });
+        }
+    }
+    let mut slots = available_slots
+        .slots
+        .iter()
+        .map(|slot| TimelineSlot {
+            name: slot.name(),
+            start_time: slot.start_time,
+            duration: slot.duration.as_secs_f64(),
+            state: available_slots.lifecycle.state(slot.index).map(|state| state.to_str().to_string()),
+        })
+        .collect::<Vec<_>>();
+    slots.sort_by_key(|slot| slot.start_time);
+    Timeline::new(req.path(), profile.to_str(), segments, slots)
+}
+
+// Request the pods of the breaks cued in the VOD playlist of a session at its
+// start, as its asset lists will request them unless a rule applies to the session
+fn prefetch_vod_pods(req: &HttpRequest, stream: &StreamState, session: &str, cues: &HashSet<String>) {
+    let StreamState { config, available_slots, pod_prefetch, .. } = stream;
+    let (Some(ad_server_client), Some(user_defined_query_params)) = (
+        req.app_data::<web::Data<AdServerClient>>(),
+        req.app_data::<web::Data<UserDefinedQueryParams>>(),
+    ) else {
+        return;
+    };
+    let variants = config.experiments.assign(session);
+    if variants.iter().any(|variant| variant.no_ads) || !pod_prefetch.start(session) {
+        return;
+    }
+    let ad_breaks = config.ad_breaks();
+    let ad_server_url = variants
+        .iter()
+        .find_map(|variant| variant.ad_server_url.as_ref())
+        .unwrap_or(&ad_breaks.ad_server_url);
+    let languages = config.localization.languages(req);
+    let slots = available_slots
+        .slots
+        .iter()
+        .filter(|slot| cues.contains(&slot.name()))
+        .map(|slot| slot.clone())
+        .collect::<Vec<_>>();
+    log::info!("Prefetching the pods of {} breaks for VOD session {session}", slots.len());
+    for slot in slots {
+        let mut ad_url = slot_ad_server_url(ad_server_url, &slot, session, user_defined_query_params);
+        for (key, value) in variants.iter().flat_map(|variant| &variant.ad_server_params) {
+            ad_url.query_pairs_mut().append_pair(key, value);
+        }
+        let accept_language = config.localization.localize_request(&mut ad_url, &languages);
+        let client = ad_server_client.clone();
+        let (max_size, faults) = (config.max_vast_size, config.faults.clone());
+        let key = ad_url.to_string();
+        pod_prefetch.prefetch(session, &key, async move {
+            fetch_ad_pod(&client.0, &ad_url, accept_language.as_deref(), max_size, &faults)
+                .await
+                .ok()
+                .flatten()
+        });
+    }
+}
+
+async fn handle_playlist(
+    req: HttpRequest,
+    stream: &StreamState,
+    client: web::Data<Client>,
+    user_defined_query_params: web::Data<UserDefinedQueryParams>,
+    metrics: web::Data<Metrics>,
+    shutdown: web::Data<ShutdownState>,
+) -> Result<HttpResponse, Error> {
+    let StreamState { config, origin_cache, .. } = stream;
+    let mut timer = StageTimer::start("unknown");
+    let new_url = config.origin_url(&req);
+
+    let OriginPlaylist { body: payload, cache_headers } = origin_cache
+        .fetch_with_headers(&client, new_url.as_str(), &config.origin_headers.forwarded(&req))
+        .await?;
+    timer.mark("origin");
+    let m3u8 = std::str::from_utf8(&payload).map_err(error::ErrorBadRequest)?;
+    let (m3u8, keys) = OriginKeys::strip(m3u8);
+
+    // A master playlist has variant streams
+    let master = match is_master_playlist(&m3u8) {
+        true => MasterPlaylist::try_from(m3u8.as_ref()).ok(),
+        false => None,
+    };
+    if let Some(master) = master {
+        if shutdown.is_draining() {
+            return Ok(draining_response());
+        }
+        timer.set_playlist("master");
+        timer.mark("parse");
+        return handle_master_playlist_content(
+            req,
+            master,
+            &keys,
+            &cache_headers,
+            user_defined_query_params,
+            stream,
+            timer,
+            metrics,
+        )
+        .await;
+    }
+
+    // Otherwise handle as media playlist
+    if let Ok(media) = MediaPlaylist::try_from(m3u8.as_ref()) {
+        timer.set_playlist("media");
+        timer.mark("parse");
+        return handle_media_playlist_content(&req, &m3u8, media, &keys, &cache_headers, stream, &client, timer, metrics)
+            .await;
+    }
+    timer.mark("parse");
+
+    // If neither parsing works, return the original content
+    log::warn!("Could not parse playlist as master or media playlist, returning original");
+    Ok(playlist_response(&req, payload, &cache_headers, &timer, config, &metrics))
+}
+
+// New sessions start with the master playlist, they are sent to another instance while draining
+fn draining_response() -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, "1"))
+        .insert_header((header::CONNECTION, "close"))
+        .body("The server is shutting down")
+}
+
+// The sessions over the limits are sent to another instance, or retry later
+fn too_many_sessions_response() -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, "5"))
+        .body("Too many sessions")
+}
+
+// How the ad breaks are inserted into a playlist for the requesting session
+async fn insertion_profile(
+    req: &HttpRequest,
+    config: &ServerConfig,
+    client: &Client,
+    metrics: &Metrics,
+) -> InsertionProfile {
+    if let Some(signal) = config.ad_free.check(req, client).await {
+        // Still counted with the other playlists by the stage timings
+        metrics.inc("ad_free_playlists_total", &[("signal", signal)]);
+        log::debug!("Serving a clean playlist to an ad-free session ({signal})");
+        return InsertionProfile::None;
+    }
+    if config.admission.overflow() == SessionOverflow::Clean {
+        if let Some(limit) = config.admission.check(req) {
+            metrics.inc("sessions_refused_total", &[("limit", limit), ("overflow", "clean")]);
+            log::debug!("Serving a clean playlist to a session over the {limit} limit");
+            return InsertionProfile::None;
+        }
+    }
+    if let Some(rule) = config.rules.evaluate(req, &config.channel).filter(|rule| rule.policy == RulePolicy::NoAds) {
+        metrics.inc("rule_matches_total", &[("rule", &rule.name), ("policy", rule.policy.to_str())]);
+        log::debug!("Serving a clean playlist by rule {}", rule.name);
+        return InsertionProfile::None;
+    }
+    let (device, profile) = config.device_profiles.profile(req);
+    log::debug!("Inserting the ad breaks for a {} device as {}", device.to_str(), profile.to_str());
+    profile
+}
+
+// Wrap a playlist body into a response and record its stage timings
+fn playlist_response(
+    req: &HttpRequest,
+    body: impl Into<Bytes>,
+    cache_headers: &CacheHeaders,
+    timer: &StageTimer,
+    config: &ServerConfig,
+    metrics: &Metrics,
+) -> HttpResponse {
+    manifest_response(req, body, HLS_PLAYLIST_CONTENT_TYPE, cache_headers, timer, config, metrics)
+}
+
+// The manifest is tagged with a hash of its content, players polling with a
+// matching If-None-Match get a 304 Not Modified until it changes
+fn manifest_response(
+    req: &HttpRequest,
+    body: impl Into<Bytes>,
+    content_type: &str,
+    cache_headers: &CacheHeaders,
+    timer: &StageTimer,
+    config: &ServerConfig,
+    metrics: &Metrics,
+) -> HttpResponse {
+    let body = body.into();
+    let etag = manifest_etag(&body);
+    let server_timing = timer.finish(metrics);
+    let not_modified = if_none_match(req, &etag);
+    let mut response = if not_modified {
+        metrics.inc("manifest_not_modified_total", &[]);
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response.insert_header((header::ETAG, etag));
+    for header in config.manifest_cache_headers(req, cache_headers) {
+        response.insert_header(header);
+    }
+    if config.server_timing {
+        response.insert_header(("Server-Timing", server_timing));
+    }
+    if not_modified {
+        return response.finish();
+    }
+    response.content_type(content_type);
+    response.body(body)
+}
+
+// The --playlist-cache-control and --segment-cache-control values must be valid header values
+pub(crate) fn parse_cache_control_override(value: Option<&str>) -> Result<Option<header::HeaderValue>, String> {
+    value
+        .map(|value| {
+            header::HeaderValue::from_str(value.trim()).map_err(|_| format!("Invalid Cache-Control value '{value}'"))
+        })
+        .transpose()
+}
+
+fn manifest_etag(body: &[u8]) -> String {
+    use std::hash::{DefaultHasher, Hasher};
+    // DefaultHasher::new() has fixed keys, instances of a build agree on the tags
+    let mut hasher = DefaultHasher::new();
+    hasher.write(body);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+// Whether one of the tags of If-None-Match matches, compared weakly as RFC 9110 asks
+fn if_none_match(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// Proxy a DASH manifest with the ad breaks of the stream signaled in it
+async fn handle_dash_manifest(
+    req: HttpRequest,
+    stream: &StreamState,
+    client: web::Data<Client>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let StreamState { config, available_slots, origin_cache, epoch, .. } = stream;
+    let mut timer = StageTimer::start("dash");
+    let new_url = config.origin_url(&req);
+
+    let OriginPlaylist { body: payload, cache_headers } = origin_cache
+        .fetch_with_headers(&client, new_url.as_str(), &config.origin_headers.forwarded(&req))
+        .await?;
+    timer.mark("origin");
+    let xml = std::str::from_utf8(&payload).map_err(error::ErrorInternalServerError)?;
+    let mpd = dash_mpd::parse(xml).inspect_err(|err| {
+        log::error!("Error {err} when parsing DASH manifest. Returning the original manifest.");
+    });
+    timer.mark("parse");
+    let Ok(mut mpd) = mpd else {
+        let response = manifest_response(&req, payload.clone(), DASH_CONTENT_TYPE, &cache_headers, &timer, config, &metrics);
+        return Ok(response);
+    };
+
+    let ad_breaks = config.ad_breaks();
+    if config.insertion_mode == InsertionMode::Static {
+        let vod_duration = (!dash::is_dynamic(&mpd)).then_some(mpd.mediaPresentationDuration).flatten();
+        save_static_ad_slots(available_slots, &ad_breaks, epoch.get(), vod_duration);
+    }
+    if let Some(time_shift_start) = dash::time_shift_start(&mpd, chrono::Utc::now()) {
+        available_slots.expire(time_shift_start - chrono::Duration::from_std(config.slot_expiry_grace).unwrap_or_default());
+    }
+    let slots = available_slots
+        .slots
+        .iter()
+        .map(|slot| (slot.index, slot.name(), slot.start_time, slot.duration))
+        .collect::<Vec<_>>();
+    let breaks = dash::ad_breaks(&mpd, slots.into_iter(), epoch.get(), chrono::Utc::now());
+    let session = get_query_param(&req, DASH_SESSION).unwrap_or_else(|| "default_user".to_string());
+    let slot_url = |path: &str, ad_break: &dash::DashAdBreak| {
+        let mut url = config.interstitials_address.join(path).expect("Invalid interstitials address");
+        url.query_pairs_mut()
+            .append_pair(HLS_INTERSTITIAL_ID, &ad_break.name)
+            .append_pair(HLS_PRIMARY_ID, &session);
+        url.to_string()
+    };
+    let profile = insertion_profile(&req, config, &client, &metrics).await;
+    if profile != InsertionProfile::None {
+        for ad_break in &breaks {
+            available_slots.lifecycle.announce(ad_break.index);
+        }
+    }
+    let markers = match profile {
+        InsertionProfile::Interstitials => config.scte35_markers,
+        InsertionProfile::Ssai => true,
+        InsertionProfile::None => false,
+    };
+    if markers {
+        dash::insert_splice_events(&mut mpd, &breaks);
+    }
+    if profile == InsertionProfile::Interstitials {
+        match config.dash_signaling {
+            DashSignaling::Periods => dash::insert_ad_periods(&mut mpd, &breaks, |ad_break| {
+                slot_url(DASH_AD_PERIOD_PATH.trim_start_matches('/'), ad_break)
+            }),
+            DashSignaling::Events => dash::insert_ad_events(&mut mpd, &breaks, |ad_break| {
+                config.asset_list_url(&ad_break.name, Some(&session)).to_string()
+            }),
+        }
+    }
+    // The manifest refreshes go through the proxy too
+    mpd.locations.clear();
+    timer.mark("insert");
+    let output = mpd.to_string();
+    timer.mark("serialize");
+    log::debug!("DASH manifest \n{output}");
+
+    Ok(manifest_response(&req, output, DASH_CONTENT_TYPE, &cache_headers, &timer, config, &metrics))
+}
+
+/// Resolve the remote ad period of a DASH break: the ad pod is decided like the
+/// asset list of the interstitial, and its fragmented MP4 creatives are played
+/// from their byte ranges, one period each
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_dash_ad_period(
+    req: HttpRequest,
+    stream: web::Data<StreamState>,
+    available_ads: web::Data<AvailableAds>,
+    client: web::Data<Client>,
+    ad_server_client: web::Data<AdServerClient>,
+    user_defined_query_params: web::Data<UserDefinedQueryParams>,
+    beacons: web::Data<BeaconDispatcher>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let req_url = req.full_url();
+    let target = InterstitialTarget::new(&req);
+    let asset_list = serve_interstitial(
+        req,
+        target,
+        stream.clone(),
+        available_ads.clone(),
+        client.clone(),
+        ad_server_client,
+        user_defined_query_params,
+        beacons,
+        metrics,
+    )
+    .await?;
+    let body = actix_web::body::to_bytes(asset_list.into_body())
+        .await
+        .map_err(|_| error::ErrorInternalServerError("Failed to read the asset list"))?;
+    let asset_list = json::parse(&String::from_utf8_lossy(&body)).map_err(error::ErrorInternalServerError)?;
+
+    let mut creatives = Vec::new();
+    for asset in asset_list["ASSETS"].members() {
+        let ad = asset[X_AD_ID]
+            .as_str()
+            .and_then(|id| Uuid::parse_str(id).ok())
+            .and_then(|id| available_ads.linears.get(&id).map(|ad| ad.clone()));
+        let Some(ad) = ad else {
+            continue;
+        };
+        // HLS creatives can't be played in a DASH period
+        if is_hls_playlist(&ad.url) {
+            log::warn!("Skipping the HLS creative {} in the DASH ad period", ad.url);
+            continue;
+        }
+        available_ads.durations.index(&client, &ad.url).await;
+        match available_ads.durations.fragments(&ad.url) {
+            Some(fragments) => creatives.push(DashCreative {
+                id: ad.ad_id.to_string(),
+                url: stream.config.creative_media_url(&req_url, ad.ad_id, &ad.url),
+                codecs: ad.codec.clone(),
+                fragments,
+            }),
+            None => log::warn!("Skipping the creative {} in the DASH ad period, it isn't a fragmented MP4", ad.url),
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(APPLICATION_XML)
+        .body(dash::ad_periods_xml(&creatives)))
+}
+
+async fn handle_segment(
+    req: HttpRequest,
+    config: &ServerConfig,
+    client: web::Data<Client>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let started_at = std::time::Instant::now();
+    let res = send_passthrough_request(&req, config, &client, &metrics).await?;
+    metrics.observe("segment_upstream_duration_seconds", &[], started_at.elapsed());
+    let length = content_length(&res);
+    let client_resp = passthrough_response_head(&res, config);
+    Ok(passthrough_segment(client_resp, length, res, metrics))
+}
+
+// A request neither of a playlist nor of a segment by its path, e.g. of a
+// packager taking the format as a query parameter. The origin's answer is
+// passed through unless it is a playlist, by its Content-Type or its first
+// bytes, which is then served as one along with the next requests of its shape
+async fn handle_unknown(
+    req: HttpRequest,
+    stream: &StreamState,
+    client: web::Data<Client>,
+    user_defined_query_params: web::Data<UserDefinedQueryParams>,
+    metrics: web::Data<Metrics>,
+    shutdown: web::Data<ShutdownState>,
+) -> Result<HttpResponse, Error> {
+    let config = &stream.config;
+    let mut res = send_passthrough_request(&req, config, &client, &metrics).await?;
+    let content_type = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    // Only the bodies of the generic types are looked into, as the origin encoded them
+    let sniffable = req.method() == actix_web::http::Method::GET
+        && !res.headers().contains_key(header::CONTENT_ENCODING)
+        && (content_type.is_empty()
+            || content_type.starts_with("text/plain")
+            || content_type.starts_with("application/octet-stream"));
+    let mut first_chunk = None;
+    let is_playlist = res.status().is_success()
+        && (is_playlist_content_type(&content_type) || {
+            if sniffable {
+                first_chunk = res.next().await.transpose().map_err(error::ErrorBadGateway)?;
+            }
+            first_chunk.as_ref().is_some_and(|chunk| is_playlist_content(chunk))
+        });
+    if is_playlist {
+        config.playlist_detection.learn(req.uri().path(), req.uri().query());
+        return handle_playlist(req, stream, client, user_defined_query_params, metrics, shutdown).await;
+    }
+
+    let length = content_length(&res);
+    let client_resp = passthrough_response_head(&res, config);
+    let body = futures_util::stream::iter(first_chunk.map(Ok)).chain(res);
+    Ok(passthrough_segment(client_resp, length, body, metrics))
+}
+
+// Forward a request to the origin for its response to be passed through as is
+async fn send_passthrough_request(
+    req: &HttpRequest,
+    config: &ServerConfig,
+    client: &Client,
+    metrics: &Metrics,
+) -> Result<awc::ClientResponse<impl futures_util::Stream<Item = Result<Bytes, awc::error::PayloadError>> + use<>>, Error> {
+    let new_url = config.origin_url(req);
+    // Pass the segment through as encoded by the origin for this viewer
+    let accept_encoding = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .cloned()
+        .unwrap_or_else(|| header::HeaderValue::from_static("identity"));
+    // A HEAD is forwarded as such, for the length of the segment
+    let mut forward_req = client.request(req.method().clone(), new_url.as_str());
+    for header in config.origin_headers.forwarded(req) {
+        forward_req = forward_req.insert_header(header);
+    }
+    let mut forward_req = forward_req
+        .insert_header((header::ACCEPT_ENCODING, accept_encoding))
+        .no_decompress();
+    // Forward byte-range requests (byte-range HLS, fMP4 ad packaging) and the
+    // validators of conditional requests, the segments are passed through as is
+    for name in [header::RANGE, header::IF_RANGE, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE] {
+        if let Some(value) = req.headers().get(&name) {
+            forward_req = forward_req.insert_header((name, value.clone()));
+        }
+    }
+    let res = forward_req
+        .send()
+        .await
+        .inspect_err(|_| metrics.inc("segment_requests_total", &[("status", "error")]))
+        .map_err(error::ErrorInternalServerError)?;
+    metrics.inc("segment_requests_total", &[("status", res.status().as_str())]);
+    Ok(res)
+}
+
+// The status and the headers of a passed through origin response
+fn passthrough_response_head<S>(res: &awc::ClientResponse<S>, config: &ServerConfig) -> HttpResponseBuilder {
+    let mut client_resp = HttpResponse::build(res.status());
+    copy_headers(res, &mut client_resp);
+    if !res.headers().contains_key(header::CONTENT_ENCODING) {
+        // Segments are passed through as is, whatever their content type
+        client_resp.insert_header(header::ContentEncoding::Identity);
+    }
+    if let Some(cache_control) = &config.segment_cache_control {
+        // Takes precedence over the origin's Expires
+        client_resp.insert_header((header::CACHE_CONTROL, cache_control.clone()));
+    }
+    client_resp
+}
+
+// Count the bytes as they are streamed to the viewer, without buffering
+fn passthrough_segment<S>(client_resp: HttpResponseBuilder, length: Option<u64>, body: S, metrics: web::Data<Metrics>) -> HttpResponse
+where
+    S: futures_util::Stream<Item = Result<Bytes, awc::error::PayloadError>> + 'static,
+{
+    let body = body.map(move |chunk| {
+        if let Ok(bytes) = &chunk {
+            metrics.add("segment_bytes_total", &[], bytes.len() as u64);
+        }
+        chunk
+    });
+    passthrough_response(client_resp, length, body)
+}
+
+pub async fn handle_status(
+    stream: web::Data<StreamState>,
+    available_ads: web::Data<AvailableAds>,
+    user_defined_query_params: web::Data<UserDefinedQueryParams>,
+    beacons: web::Data<BeaconDispatcher>,
+    shutdown: web::Data<ShutdownState>,
+) -> Result<HttpResponse, Error> {
+    let StreamState { config, available_slots, ad_pod_cache, session_asset_lists, pod_prefetch, esni, epg, origin_cache, epoch, .. } =
+        stream.get_ref();
+    // Return the status of the server
+    let response = object! {
+        "version": build_info::to_json(),
+        "config": config.to_json(),
+        "draining": shutdown.is_draining(),
+        "stream": epoch.to_json(),
+        "beacons": beacons.to_json(),
+        "origin_cache": origin_cache.to_json(),
+        "asset_list_cache": ad_pod_cache.to_json(),
+        "asset_list_retries": session_asset_lists.to_json(),
+        "vod_prefetch": pod_prefetch.to_json(),
+        "esni": esni.to_json(),
+        "epg": epg.to_json(),
+        "creative_cache": available_ads.creatives.to_json(),
+        "duration_probing": available_ads.durations.to_json(),
+        "content_profile": stream.content_profile.read().as_ref().map_or(object! {}, ContentProfile::to_json),
+        "transcoder": available_ads.transcoder.as_ref().map_or(object! {}, Transcoder::to_json),
+        "ad_server_url": config.ad_breaks().ad_server_url.as_str(),
+        "user_defined_query_params": user_defined_query_params.to_json(),
+        "available_ads": available_ads.to_json(),
+        "available_slots": available_slots.to_json(),
+    }
+    .pretty(2);
+
+    Ok(HttpResponse::Ok()
+        .content_type(mime::APPLICATION_JSON)
+        .body(response))
+}
+
+// The error response of a request to an admin endpoint without the admin token
+fn check_admin_token(req: &HttpRequest, config: &ServerConfig) -> Option<HttpResponse> {
+    let (status, message) = match &config.admin_token {
+        None => (StatusCode::FORBIDDEN, "The admin endpoints are disabled, set --admin-token to enable them"),
+        Some(token) => {
+            let bearer = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "));
+            // Compare in constant time to not leak the token
+            match bearer {
+                Some(bearer) if bearer.len() == token.len() && openssl::memcmp::eq(bearer.as_bytes(), token.as_bytes()) => {
+                    return None;
+                }
+                _ => (StatusCode::UNAUTHORIZED, "Missing or wrong admin token"),
+            }
+        }
+    };
+    let response = object! {
+        status: "error",
+        message: message,
+    };
+    Some(
+        HttpResponse::build(status)
+            .content_type(mime::APPLICATION_JSON)
+            .body(response.pretty(2)),
+    )
+}
+
+// Re-anchor the static schedule of a live stream to now
+pub async fn handle_reset_epoch(req: HttpRequest, stream: web::Data<StreamState>) -> Result<HttpResponse, Error> {
+    let StreamState { config, available_slots, ad_pod_cache, session_asset_lists, epoch, .. } = stream.get_ref();
+    if let Some(response) = check_admin_token(&req, config) {
+        return Ok(response);
+    }
+    let epoch = epoch.reset();
+    let cleared_slots = if config.insertion_mode == InsertionMode::Static {
+        // The static slots are regenerated from the new epoch on the next refresh,
+        // under new names so the sessions playing don't see their breaks moved
+        let count = available_slots.slots.len();
+        available_slots.clear();
+        ad_pod_cache.clear();
+        session_asset_lists.clear();
+        count
+    } else {
+        0
+    };
+    log::info!("Stream epoch reset to {epoch}, {cleared_slots} static slots cleared");
+
+    let response = object! {
+        status: "success",
+        epoch: epoch.to_rfc3339(),
+        cleared_slots: cleared_slots,
+        next_index: available_slots.lifecycle.next_index(),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(mime::APPLICATION_JSON)
+        .body(response.pretty(2)))
+}
+
+// Drop the ads of the channel whose slot is gone, e.g. kept again by a player
+// refetching its asset list after the break
+pub async fn handle_gc(req: HttpRequest, stream: web::Data<StreamState>) -> Result<HttpResponse, Error> {
+    let StreamState { config, available_slots, .. } = stream.get_ref();
+    if let Some(response) = check_admin_token(&req, config) {
+        return Ok(response);
+    }
+    let (orphaned, unslotted) = available_slots.collect_ads(&config.channel);
+    log::info!("Dropped {orphaned} ads of expired slots and {unslotted} ads served outside of a slot");
+
+    let response = object! {
+        status: "success",
+        orphaned_ads: orphaned,
+        unslotted_ads: unslotted,
+        remaining_ads: available_slots.ads.len(),
+        slots: available_slots.slots.len(),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(mime::APPLICATION_JSON)
+        .body(response.pretty(2)))
+}
+
+// Whether the master playlist of the origin a stream would be swapped to can be
+// played, the requests of an origin host aren't known in advance
+async fn check_new_origin(client: &Client, config: &ServerConfig, url: &Url) -> Result<(), String> {
+    if config.master_playlist_path.is_none() {
+        return Ok(());
+    }
+    if !is_hls_playlist(url.as_str()) {
+        return Err(format!("{url} is not an HLS playlist"));
+    }
+    let mut res = client
+        .get(url.as_str())
+        .send()
+        .await
+        .map_err(|err| format!("Failed to fetch {url}: {err}"))?;
+    if !res.status().is_success() {
+        return Err(format!("Failed to fetch {url}: {}", res.status()));
+    }
+    let payload = res.body().await.map_err(|err| format!("Failed to read {url}: {err}"))?;
+    let text = std::str::from_utf8(&payload).map_err(|err| format!("{url} is not UTF-8: {err}"))?;
+    let (text, _) = OriginKeys::strip(text);
+    MasterPlaylist::try_from(text.as_ref()).map_err(|err| format!("Invalid master playlist {url}: {err}"))?;
+    Ok(())
+}
+
+// Forward the stream to another origin, e.g. a backup packager, keeping its
+// sessions and slots. The players keep requesting the same paths
+pub async fn handle_origin_swap(
+    req: HttpRequest,
+    stream: web::Data<StreamState>,
+    client: web::Data<Client>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let config = &stream.config;
+    if let Some(response) = check_admin_token(&req, config) {
+        return Ok(response);
+    }
+    let previous = config.origin.forward_url();
+    let url = get_query_param(&req, "url")
+        .ok_or_else(|| "Missing url of the new origin".to_string())
+        .and_then(|url| Url::parse(&url).map_err(|err| format!("Invalid URL '{url}': {err}")));
+    let result = match url {
+        Ok(url) => match check_new_origin(&client, config, &url).await {
+            Ok(()) => config.origin.swap(&url),
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(()) => {
+            log::info!("Swapped the origin of {previous} for {}", config.origin.forward_url());
+            metrics.inc("origin_swaps_total", &[("channel", &config.channel)]);
+            let response = object! {
+                status: "success",
+                previous: previous.as_str(),
+                origin: config.origin.to_json(),
+            };
+            Ok(HttpResponse::Ok()
+                .content_type(mime::APPLICATION_JSON)
+                .body(response.pretty(2)))
+        }
+        Err(err) => {
+            let response = object! {
+                status: "error",
+                message: err
+            };
+            Ok(HttpResponse::BadRequest()
+                .content_type(mime::APPLICATION_JSON)
+                .body(response.pretty(2)))
+        }
+    }
+}
+
+// Schedule the planned breaks of an external schedule as dynamic ad slots. A
+// break ingested again is moved or cancelled when it changed, unless it has
+// started. With `replace`, the breaks are the whole plan and the scheduled
+// breaks missing from it are cancelled as well
+pub(crate) fn ingest_planned_breaks(
+    stream: &StreamState,
+    schedule: &BreakSchedule,
+    breaks: Vec<PlannedBreak>,
+    replace: bool,
+    metrics: &Metrics,
+) -> json::JsonValue {
+    let StreamState { available_slots, .. } = stream;
+    let now = chrono::Utc::now();
+    // The slots of the breaks that have expired since
+    schedule.retain_slots(|index| available_slots.slots.iter().any(|slot| slot.index == index));
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    if replace {
+        let planned = breaks.iter().map(|planned| planned.id.as_str()).collect::<HashSet<_>>();
+        for (id, index) in schedule.slots().into_iter().filter(|(id, _)| !planned.contains(id.as_str())) {
+            let started = available_slots.slots.iter().any(|slot| slot.index == index && slot.start_time <= now);
+            if started {
+                continue;
+            }
+            if let Some(slot) = available_slots.cancel(index) {
+                log::info!("Cancelled {} of {} break {id}, it is not planned anymore", slot.name(), schedule.source());
+            }
+            schedule.remove_slot(&id);
+            metrics.inc("planned_breaks_total", &[("source", schedule.source()), ("result", "cancelled")]);
+            *counts.entry("cancelled").or_default() += 1;
+        }
+    }
+    for planned in breaks {
+        let result = schedule_planned_break(stream, schedule, planned, now);
+        metrics.inc("planned_breaks_total", &[("source", schedule.source()), ("result", result)]);
+        *counts.entry(result).or_default() += 1;
+    }
+    let mut summary = object! { "at": now.to_rfc3339() };
+    for (result, count) in counts {
+        summary[result] = count.into();
+    }
+    schedule.record(summary.clone());
+    summary
+}
+
+// What became of the break: scheduled, moved, unchanged, cancelled, started,
+// expired, past, invalid or conflict
+fn schedule_planned_break(
+    stream: &StreamState,
+    schedule: &BreakSchedule,
+    planned: PlannedBreak,
+    now: chrono::DateTime<chrono::Utc>,
+) -> &'static str {
+    let StreamState { config, available_slots, .. } = stream;
+    let source = schedule.source();
+    let template = config
+        .pod_templates
+        .iter()
+        .find(|template| planned.templates.contains(&template.name));
+    // A break planned without a duration lasts as long as its template
+    let duration = Some(planned.duration)
+        .filter(|duration| *duration > 0.0)
+        .or(template.map(|template| template.duration))
+        .filter(|duration| duration.is_finite() && *duration > 0.0);
+    let Some(duration) = duration else {
+        log::warn!("Skipping {source} break {}, it has no duration", planned.id);
+        return "invalid";
+    };
+    let duration = Duration::from_secs_f64(duration);
+    let previous = schedule
+        .slot(&planned.id)
+        .and_then(|index| available_slots.slots.iter().find(|slot| slot.index == index).map(|slot| slot.clone()));
+    if let Some(previous) = &previous {
+        if !planned.cancelled && previous.start_time == planned.start_time && previous.duration == duration {
+            return "unchanged";
+        }
+        // The players have seen the DATERANGE of a break playing already
+        if previous.start_time <= now {
+            log::warn!("Keeping {} of {source} break {}, it has started", previous.name(), planned.id);
+            return "started";
+        }
+        available_slots.cancel(previous.index);
+        schedule.remove_slot(&planned.id);
+        if planned.cancelled {
+            log::info!("Cancelled {} of {source} break {}", previous.name(), planned.id);
+            return "cancelled";
+        }
+    }
+    if planned.cancelled {
+        return "expired";
+    }
+
+    let ad_slot = AdSlot {
+        id: Uuid::new_v4(),
+        index: available_slots.lifecycle.next_index(),
+        start_time: planned.start_time,
+        duration,
+        pod_num: template.and_then(|template| template.pod_num).unwrap_or(config.default_pod_num),
+        template: template.map(|template| template.name.clone()),
+        ad_server_params: template.map(|template| template.ad_server_params.clone()).unwrap_or_default(),
+        asset_uri: None,
+        replacement: None,
+        offset: Duration::ZERO,
+    };
+    if ad_slot.end_time() <= now {
+        return "past";
+    }
+    let overlapping = available_slots.slots.iter().find(|slot| slot.overlaps(&ad_slot)).map(|slot| slot.clone());
+    if let Some(scheduled) = overlapping {
+        log::warn!("Skipping {source} break {}, it overlaps {}", planned.id, scheduled.name());
+        return "conflict";
+    }
+    log::info!(
+        "Scheduled {} from {} for {}s for {source} break {}",
+        ad_slot.name(),
+        ad_slot.start_time,
+        duration.as_secs_f64(),
+        planned.id
+    );
+    schedule.set_slot(&planned.id, ad_slot.index);
+    available_slots.schedule(ad_slot);
+    if previous.is_some() { "moved" } else { "scheduled" }
+}
+
+pub async fn handle_esni(
+    req: HttpRequest,
+    body: Bytes,
+    stream: web::Data<StreamState>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let config = &stream.config;
+    if let Some(response) = check_admin_token(&req, config) {
+        return Ok(response);
+    }
+    if config.insertion_mode == InsertionMode::Static {
+        return Ok(HttpResponse::BadRequest().body("Ad insertion is not supported in static mode."));
+    }
+    let breaks = std::str::from_utf8(&body)
+        .map_err(|err| format!("The SCTE-224 document is not UTF-8: {err}"))
+        .and_then(|xml| esni::parse_schedule(xml, chrono::Utc::now()));
+    match breaks {
+        Ok(breaks) => {
+            let response = object! {
+                status: "success",
+                breaks: ingest_planned_breaks(&stream, &stream.esni, breaks, false, &metrics),
+            };
+            Ok(HttpResponse::Ok()
+                .content_type(mime::APPLICATION_JSON)
+                .body(response.pretty(2)))
+        }
+        Err(err) => {
+            let response = object! {
+                status: "error",
+                message: err
+            };
+            Ok(HttpResponse::BadRequest()
+                .content_type(mime::APPLICATION_JSON)
+                .body(response.pretty(2)))
+        }
+    }
+}
+
+pub async fn handle_timeline(req: HttpRequest, stream: web::Data<StreamState>) -> Result<HttpResponse, Error> {
+    let Some(session) = get_query_param(&req, "session") else {
+        return Ok(HttpResponse::BadRequest().body("Missing the session query parameter"));
+    };
+    let Some(timeline) = stream.timelines.get(&session) else {
+        return Ok(HttpResponse::NotFound().body(format!("No media playlist was served to session {session} lately")));
+    };
+    let mut response = timeline.to_json();
+    response["session"] = session.into();
+    Ok(HttpResponse::Ok()
+        .content_type(mime::APPLICATION_JSON)
+        .body(response.pretty(2)))
+}
+
+// The build of the proxy serving the stream and its insertion mode, for the
+// fleets to check what is deployed
+pub async fn handle_version(stream: web::Data<StreamState>) -> Result<HttpResponse, Error> {
+    let config = &stream.config;
+    let mut response = build_info::to_json();
+    response["channel"] = config.channel.as_str().into();
+    response["insertion_mode"] = config.insertion_mode.to_str().into();
+    Ok(HttpResponse::Ok()
+        .content_type(mime::APPLICATION_JSON)
+        .body(response.pretty(2)))
+}
+
+pub async fn handle_debug_vast(stream: web::Data<StreamState>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok()
+        .content_type(mime::APPLICATION_JSON)
+        .body(stream.config.vast_validator.to_json().pretty(2)))
+}
+
+pub async fn handle_metrics(metrics: web::Data<Metrics>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok()
+        .content_type(PROMETHEUS_CONTENT_TYPE)
+        .body(metrics.render()))
+}
+
+// Stream the body of an upstream response with its Content-Length, or chunked
+// when the upstream didn't tell it
+fn passthrough_response<S, E>(mut client_resp: HttpResponseBuilder, length: Option<u64>, body: S) -> HttpResponse
+where
+    S: futures_util::Stream<Item = Result<Bytes, E>> + 'static,
+    E: Into<Box<dyn std::error::Error>> + 'static,
+{
+    match length {
+        Some(length) => client_resp.body(SizedStream::new(length, body)),
+        None => client_resp.streaming(body),
+    }
+}
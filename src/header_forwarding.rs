@@ -0,0 +1,83 @@
+use actix_web::HttpRequest;
+use actix_web::http::header::{HeaderName, HeaderValue};
+
+// Headers about the connection to the proxy, never forwarded
+const HOP_BY_HOP: [&str; 10] = [
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Which headers of the player's requests are forwarded to the origin with
+/// the playlist and segment requests (`--forward-origin-header`), except those
+/// of the denylist (`--block-origin-header`). A pattern is a header name or a
+/// prefix ending with `*`, e.g. `x-device-*`, in any letter case
+#[derive(Clone, Debug, Default)]
+pub struct HeaderForwarding {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl HeaderForwarding {
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Self, String> {
+        let normalize = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| pattern.trim().to_ascii_lowercase())
+                .filter(|pattern| !pattern.is_empty())
+                .map(|pattern| {
+                    let name = pattern.trim_end_matches('*');
+                    match name.is_empty() || HeaderName::from_bytes(name.as_bytes()).is_ok() {
+                        true => Ok(pattern),
+                        false => Err(format!("Invalid header name pattern '{pattern}'")),
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            allow: normalize(allow)?,
+            deny: normalize(deny)?,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.allow.is_empty()
+    }
+
+    fn is_forwarded(&self, name: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        };
+        !HOP_BY_HOP.contains(&name) && self.allow.iter().any(matches) && !self.deny.iter().any(matches)
+    }
+
+    /// The headers of the request to forward to the origin, sorted by name
+    pub fn forwarded(&self, req: &HttpRequest) -> Vec<(HeaderName, HeaderValue)> {
+        if !self.is_enabled() {
+            return Vec::new();
+        }
+        let mut headers = req
+            .headers()
+            .iter()
+            .filter(|(name, _)| self.is_forwarded(name.as_str()))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        headers.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        headers
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "forwarded": self.allow.clone(),
+            "blocked": self.deny.clone(),
+        }
+    }
+}
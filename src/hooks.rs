@@ -0,0 +1,50 @@
+use actix_web::HttpRequest;
+use hls_m3u8::{MasterPlaylist, MediaPlaylist};
+use std::fmt;
+use std::sync::Arc;
+
+/// The playlist request a hook is called for
+pub struct PlaylistContext<'a> {
+    pub request: &'a HttpRequest,
+    /// Name of the channel, empty for the stream served at the root
+    pub channel: &'a str,
+}
+
+/// Changes the playlists before they are serialized, e.g. to add custom
+/// tags, filter variants or insert markers of its own. Hooks are called in
+/// the order they were added, only for playlists the proxy could parse.
+pub trait PlaylistHook: Send + Sync {
+    /// Called after the variant URIs were rewritten to go through the proxy
+    fn on_master_playlist(&self, _playlist: &mut MasterPlaylist<'_>, _context: &PlaylistContext<'_>) {}
+
+    /// Called after the interstitials were inserted. The date ranges of the
+    /// origin are left out, they are written back as they are afterwards
+    fn on_media_playlist(&self, _playlist: &mut MediaPlaylist<'_>, _context: &PlaylistContext<'_>) {}
+}
+
+#[derive(Clone, Default)]
+pub struct PlaylistHooks(Arc<Vec<Arc<dyn PlaylistHook>>>);
+
+impl PlaylistHooks {
+    pub fn push(&mut self, hook: Arc<dyn PlaylistHook>) {
+        Arc::make_mut(&mut self.0).push(hook);
+    }
+
+    pub fn on_master_playlist(&self, playlist: &mut MasterPlaylist<'_>, context: &PlaylistContext<'_>) {
+        for hook in self.0.iter() {
+            hook.on_master_playlist(playlist, context);
+        }
+    }
+
+    pub fn on_media_playlist(&self, playlist: &mut MediaPlaylist<'_>, context: &PlaylistContext<'_>) {
+        for hook in self.0.iter() {
+            hook.on_media_playlist(playlist, context);
+        }
+    }
+}
+
+impl fmt::Debug for PlaylistHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PlaylistHooks({})", self.0.len())
+    }
+}
@@ -0,0 +1,81 @@
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// The upstream requests in flight per key. The first caller of a key leads
+/// the request, the callers arriving before it ends wait for its result
+/// instead of repeating it.
+pub struct InFlight<T> {
+    requests: Arc<DashMap<String, watch::Receiver<Option<T>>>>,
+}
+
+impl<T> Default for InFlight<T> {
+    fn default() -> Self {
+        Self {
+            requests: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+impl<T> Clone for InFlight<T> {
+    fn clone(&self) -> Self {
+        Self {
+            requests: self.requests.clone(),
+        }
+    }
+}
+
+pub enum Turn<'a, T> {
+    /// Make the request and hand its result to the waiting callers
+    Lead(Leader<'a, T>),
+    /// Another caller makes the request
+    Wait(Waiter<T>),
+}
+
+impl<T: Clone> InFlight<T> {
+    pub fn join(&self, key: &str) -> Turn<'_, T> {
+        match self.requests.entry(key.to_string()) {
+            Entry::Occupied(entry) => Turn::Wait(Waiter(entry.get().clone())),
+            Entry::Vacant(entry) => {
+                let (sender, receiver) = watch::channel(None);
+                entry.insert(receiver);
+                Turn::Lead(Leader {
+                    requests: &self.requests,
+                    key: key.to_string(),
+                    sender,
+                })
+            }
+        }
+    }
+}
+
+/// The leading request of a key. Its entry is removed when it's dropped,
+/// finished or cancelled, so that the next caller leads a new request.
+pub struct Leader<'a, T> {
+    requests: &'a DashMap<String, watch::Receiver<Option<T>>>,
+    key: String,
+    sender: watch::Sender<Option<T>>,
+}
+
+impl<T> Leader<'_, T> {
+    pub fn finish(self, result: T) {
+        self.sender.send_replace(Some(result));
+    }
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        self.requests.remove(&self.key);
+    }
+}
+
+pub struct Waiter<T>(watch::Receiver<Option<T>>);
+
+impl<T: Clone> Waiter<T> {
+    /// The result of the leading request, None if it was cancelled before it had one
+    pub async fn result(mut self) -> Option<T> {
+        let result = self.0.wait_for(Option::is_some).await.ok()?;
+        result.clone()
+    }
+}
@@ -0,0 +1,139 @@
+use clap::ValueEnum;
+use hls_m3u8::MasterPlaylist;
+use hls_m3u8::tags::VariantStream;
+
+/// The order of the variants in the master playlists
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq)]
+pub enum VariantOrder {
+    /// As listed by the origin
+    #[default]
+    Origin,
+    /// Lowest bandwidth first
+    Ascending,
+    /// Highest bandwidth first
+    Descending,
+}
+
+impl VariantOrder {
+    pub fn to_str(&self) -> &str {
+        match self {
+            VariantOrder::Origin => "origin",
+            VariantOrder::Ascending => "ascending",
+            VariantOrder::Descending => "descending",
+        }
+    }
+}
+
+/// Changes to the variant ladder of the master playlists: variants outside
+/// of `min_bitrate..=max_bitrate` (BANDWIDTH, in bits per second) are dropped,
+/// the others are ordered, and the one closest to `start_bitrate` from below
+/// comes first as players start with the first variant listed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VariantLadder {
+    pub min_bitrate: Option<u64>,
+    pub max_bitrate: Option<u64>,
+    pub order: VariantOrder,
+    pub start_bitrate: Option<u64>,
+}
+
+impl VariantLadder {
+    pub fn new(
+        min_bitrate: Option<u64>,
+        max_bitrate: Option<u64>,
+        order: VariantOrder,
+        start_bitrate: Option<u64>,
+    ) -> Result<Self, String> {
+        if let (Some(min_bitrate), Some(max_bitrate)) = (min_bitrate, max_bitrate) {
+            if min_bitrate > max_bitrate {
+                return Err(format!(
+                    "The minimum variant bitrate {min_bitrate} is above the maximum {max_bitrate}"
+                ));
+            }
+        }
+        Ok(Self {
+            min_bitrate,
+            max_bitrate,
+            order,
+            start_bitrate,
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    fn admits(&self, bandwidth: u64) -> bool {
+        self.min_bitrate.is_none_or(|min_bitrate| bandwidth >= min_bitrate)
+            && self.max_bitrate.is_none_or(|max_bitrate| bandwidth <= max_bitrate)
+    }
+
+    /// Filter and reorder the variants of a master playlist. I-frame variants
+    /// are filtered only, and the variant closest to the limits is kept when
+    /// none of the playlist is within them
+    pub fn apply(&self, playlist: &mut MasterPlaylist) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let variants = std::mem::take(&mut playlist.variant_streams);
+        let (streams, i_frames): (Vec<_>, Vec<_>) =
+            variants.into_iter().partition(|variant| matches!(variant, VariantStream::ExtXStreamInf { .. }));
+        let bandwidth = |variant: &VariantStream| variant.bandwidth();
+
+        let mut kept = streams
+            .iter()
+            .filter(|variant| self.admits(bandwidth(variant)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if kept.is_empty() {
+            // The lowest variant above the limits, or else the highest one below
+            let closest = match self.min_bitrate {
+                Some(min_bitrate) => streams
+                    .iter()
+                    .filter(|variant| bandwidth(variant) >= min_bitrate)
+                    .min_by_key(|variant| bandwidth(variant))
+                    .or_else(|| streams.iter().max_by_key(|variant| bandwidth(variant))),
+                None => streams.iter().min_by_key(|variant| bandwidth(variant)),
+            };
+            if let Some(closest) = closest {
+                log::warn!(
+                    "No variant within the bitrate limits, keeping the one of {} bps",
+                    bandwidth(closest)
+                );
+                kept.push(closest.clone());
+            }
+        }
+
+        match self.order {
+            VariantOrder::Origin => {}
+            VariantOrder::Ascending => kept.sort_by_key(|variant| bandwidth(variant)),
+            VariantOrder::Descending => kept.sort_by_key(|variant| std::cmp::Reverse(bandwidth(variant))),
+        }
+
+        if let Some(start_bitrate) = self.start_bitrate {
+            let start = kept
+                .iter()
+                .enumerate()
+                .filter(|(_, variant)| bandwidth(variant) <= start_bitrate)
+                .max_by_key(|(_, variant)| bandwidth(variant))
+                .or_else(|| kept.iter().enumerate().min_by_key(|(_, variant)| bandwidth(variant)))
+                .map(|(index, _)| index);
+            if let Some(start) = start {
+                let variant = kept.remove(start);
+                kept.insert(0, variant);
+            }
+        }
+
+        kept.extend(i_frames.into_iter().filter(|variant| self.admits(bandwidth(variant))));
+        playlist.variant_streams = kept;
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "min_bitrate": self.min_bitrate,
+            "max_bitrate": self.max_bitrate,
+            "order": self.order.to_str(),
+            "start_bitrate": self.start_bitrate,
+        }
+    }
+}
@@ -0,0 +1,3536 @@
+//! Server Guided Ad Insertion proxy. [`AdProxyServer`] runs the proxy inside
+//! another service, with the same options as the `ad_proxy` binary. The
+//! playlist and VAST manipulation is available on its own: [`insert_interstitials`],
+//! [`wrap_into_assets`] and the [`utils`] module, along with the request
+//! handlers and the [`ServerConfig`] they share.
+
+pub mod ad_pod_cache;
+pub mod ad_selection;
+pub mod admission;
+pub mod beacon;
+pub mod break_schedule;
+pub mod build_info;
+pub mod channel;
+mod cli;
+pub mod compatibility;
+mod config_file;
+pub mod creative_cache;
+pub mod dash;
+pub mod date_ranges;
+pub mod device;
+mod dns;
+pub mod drm;
+mod egress_proxy;
+pub mod entitlement;
+pub mod epg;
+pub mod epoch;
+pub mod esni;
+pub mod experiments;
+pub mod faults;
+mod handlers;
+pub mod header_forwarding;
+pub mod hooks;
+mod in_flight;
+pub mod ladder;
+mod listener;
+pub mod localization;
+pub mod metrics;
+pub mod mock_origin;
+pub mod origin_cache;
+pub mod origin_route;
+pub mod playlist_detection;
+pub mod playlist_stats;
+pub mod pod_selection;
+pub mod pod_template;
+pub mod prefetch;
+pub mod probe;
+mod progress;
+pub mod request_log;
+pub mod rules;
+pub mod scte35;
+pub mod separation;
+mod server;
+pub mod session_asset_lists;
+pub mod shutdown;
+pub mod slot_lifecycle;
+pub mod test_pods;
+pub mod timeline;
+mod tools;
+pub mod transcoder;
+mod upstream_cookies;
+pub mod utils;
+pub mod vast_validation;
+use ad_pod_cache::AdPodCache;
+use ad_selection::{AdSelection, SelectionContext};
+use admission::SessionAdmission;
+use beacon::BeaconDispatcher;
+use break_schedule::BreakSchedule;
+use cli::CliArguments;
+pub use cli::run_cli;
+use compatibility::{ContentProfile, select_media_file};
+use creative_cache::{CachedCreative, CreativeCache};
+use dash::DashSignaling;
+use device::{DeviceProfiles, InsertionProfile};
+use dns::DnsResolver;
+use drm::OriginKeys;
+use egress_proxy::{EgressProxy, ProxyConnector};
+use entitlement::AdFreeEntitlement;
+use epoch::StreamEpoch;
+use experiments::Experiments;
+use faults::FaultInjector;
+pub use handlers::{
+    handle_click, handle_creative_media, handle_dash_ad_period, handle_debug_vast, handle_esni, handle_gc,
+    handle_media_stream, handle_metrics, handle_origin_swap, handle_reset_epoch, handle_status, handle_timeline,
+    handle_tracking, handle_version,
+};
+use handlers::{handle_raw_asset_request, handle_raw_segment_request};
+use header_forwarding::HeaderForwarding;
+use hooks::{PlaylistContext, PlaylistHooks};
+use ladder::VariantLadder;
+use localization::Localization;
+use metrics::Metrics;
+use origin_cache::{CacheHeaders, OriginCache};
+use origin_route::OriginRoute;
+use playlist_detection::PlaylistDetection;
+use playlist_stats::PlaylistStats;
+use pod_selection::{Candidate, select_pod};
+use pod_template::PodTemplate;
+use prefetch::PodPrefetch;
+use probe::DurationProber;
+use request_log::RequestLog;
+use rules::{InsertionRules, RulePolicy};
+use separation::CompetitiveSeparation;
+pub use server::AdProxyServer;
+use session_asset_lists::SessionAssetLists;
+use slot_lifecycle::{SlotLifecycle, SlotState};
+use test_pods::{TestPod, TestPods};
+use timeline::SessionTimelines;
+use transcoder::Transcoder;
+use upstream_cookies::{CookieJar, UpstreamCookies};
+use vast_validation::VastValidator;
+use rustls::ClientConfig;
+use utils::{
+    BumperFilter, InteractiveCreative, Tracking, UniversalAdId, VideoClicks,
+    find_program_datetime_tag, get_all_raw_creatives_from_vast, get_error_urls_for_creative,
+    get_impression_urls_for_creative, get_all_transcoded_creatives_from_vast,
+    get_ad_parameters_from_linear, get_duration_from_linear, get_media_urls_from_linear, get_tracking_events_from_linear, get_header_value, get_interactive_creative_from_linear, get_universal_ad_ids_from_creative, get_query_param, get_video_clicks_from_linear, is_media_segment, is_hls_playlist, is_transcoded_media_segment, is_sequenced_creative, make_program_date_time_tag, remove_conditional_ads, ProgramDateTimeCursor, SESSION_HEADER,
+};
+
+use actix_web::{error, web, web::Bytes, Error, HttpRequest, HttpResponse};
+use awc::{http::header, Client, Connector};
+use clap::ValueEnum;
+use dashmap::DashMap;
+use hls_m3u8::tags::{ExtXDateRange, VariantStream};
+use hls_m3u8::types::Value;
+use hls_m3u8::{MasterPlaylist, MediaPlaylist};
+use json::object;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+use url::Url;
+use uuid::Uuid;
+
+const STATUS_PREFIX: &str = "/status";
+const VERSION_PATH: &str = "/version";
+const COMMAND_PREFIX: &str = "/command";
+const METRICS_PREFIX: &str = "/metrics";
+const TRACKING_PREFIX: &str = "/tracking";
+const CLICK_PREFIX: &str = "/click";
+const CREATIVE_PREFIX: &str = "/creative";
+const RESET_EPOCH_PATH: &str = "/admin/reset-epoch";
+const ESNI_PATH: &str = "/admin/esni";
+const ORIGIN_PATH: &str = "/admin/origin";
+const GC_PATH: &str = "/admin/gc";
+const DEBUG_VAST_PATH: &str = "/debug/vast";
+const TIMELINE_PATH: &str = "/timeline";
+// Pod and break durations closer than this are the same, in seconds
+const POD_DURATION_TOLERANCE: f64 = 0.001;
+const DASH_AD_PERIOD_PATH: &str = "/dash/ad-period";
+// The name of the interstitial routes unless --interstitials-route is given: the
+// query-style interstitials.m3u8, and the path-style interstitials/<slot>/<session>/asset-list.json
+// and interstitials/<slot>/<ad id>/playlist.m3u8
+const DEFAULT_INTERSTITIALS_ROUTE: &str = "interstitials";
+const ASSET_LIST_FILE: &str = "asset-list.json";
+// The content types an asset list is served as
+const ASSET_LIST_JSON: &str = "application/json";
+const ASSET_LIST_TEXT: &str = "text/plain; charset=utf-8";
+// The ads served outside of a known slot are dropped by /admin/gc past this age
+const UNSLOTTED_AD_TTL: chrono::Duration = chrono::Duration::hours(1);
+const CREATIVE_PLAYLIST_FILE: &str = "playlist.m3u8";
+
+const SESSION_ID_TEMPLATE: &str = "[template.sessionId]";
+const DURATION_TEMPLATE: &str = "[template.duration]";
+const POD_NUM_TEMPLATE: &str = "[template.pod]";
+const OFFSET_TEMPLATE: &str = "[template.offset]";
+// Longer session IDs aren't kept, to bound the memory of the saved query parameters
+const MAX_SESSION_ID_LENGTH: usize = 256;
+// Number of creatives of a break when nothing else sets it
+const DEFAULT_POD_NUM: u64 = 2;
+
+const HLS_PLAYLIST_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+const HLS_INTERSTITIAL_ID: &str = "_HLS_interstitial_id";
+const HLS_PRIMARY_ID: &str = "_HLS_primary_id";
+const AD_ID: &str = "_ad_id";
+const SEGMENT_INDEX: &str = "_segment";
+const X_AD_ID: &str = "X-AD-ID";
+const X_AD_CLICK_URL: &str = "X-AD-CLICK-URL";
+const CLICK_SESSION: &str = "session";
+// Set to 1 or true by the players running the interactive layer of the ads
+const INTERACTIVE_PARAM: &str = "interactive";
+// The session of a DASH player, a query parameter of its manifest URL
+const DASH_SESSION: &str = "session";
+const IMPRESSION_EVENT: &str = "impression";
+const ERROR_EVENT: &str = "error";
+
+const APPLICATION_XML: &str = "application/xml";
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.0.1 Safari/605.1.15";
+const DEFAULT_MAX_VAST_SIZE: usize = 2 * 1024 * 1024;
+// The largest SCTE-224 schedule or EPG polled
+const MAX_SCHEDULE_SIZE: usize = 4 * 1024 * 1024;
+const DEFAULT_CREATIVE_SIGNALING_VERSION: u64 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum RequestType {
+    MasterPlayList,
+    MediaPlayList,
+    Playlist, // Unknown playlist type (origin host mode, or without a .m3u8 extension)
+    DashManifest,
+    Segment,
+    Other,
+}
+
+/// Connection settings for the origin and the ad server
+#[derive(Clone)]
+struct UpstreamOptions {
+    tls: Arc<ClientConfig>,
+    resolver: DnsResolver,
+    proxy: Arc<EgressProxy>,
+    user_agent: String,
+    // Added to every request
+    headers: Vec<(header::HeaderName, header::HeaderValue)>,
+    max_connections: usize,
+    keep_alive: Duration,
+    connection_lifetime: Duration,
+    connect_timeout: Duration,
+    timeout: Duration,
+    // HTTP/2 flow-control windows of the responses and of the connections
+    stream_window: u32,
+    connection_window: u32,
+    // Hops of a redirected request, 0 not to follow redirects
+    max_redirects: u8,
+    // Shared by the clients of all workers, None not to keep cookies
+    cookies: Option<Arc<UpstreamCookies>>,
+}
+
+impl UpstreamOptions {
+    fn new(
+        mut tls: ClientConfig,
+        resolver: DnsResolver,
+        proxy: Arc<EgressProxy>,
+        args: &CliArguments,
+    ) -> Self {
+        // Negotiate HTTP/2 with TLS origins supporting it
+        tls.alpn_protocols = if args.upstream_http2 {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+
+        Self {
+            tls: Arc::new(tls),
+            resolver,
+            proxy,
+            user_agent: args.origin_user_agent.clone(),
+            headers: Vec::new(),
+            max_connections: args.upstream_max_connections,
+            keep_alive: Duration::from_secs(args.upstream_keep_alive),
+            connection_lifetime: Duration::from_secs(args.upstream_connection_lifetime),
+            connect_timeout: Duration::from_millis(args.upstream_connect_timeout_ms),
+            timeout: Duration::from_millis(args.upstream_timeout_ms),
+            stream_window: args.upstream_h2_stream_window,
+            connection_window: args.upstream_h2_connection_window,
+            max_redirects: args.upstream_max_redirects,
+            cookies: args.upstream_cookies.then(|| Arc::new(UpstreamCookies::default())),
+        }
+    }
+}
+
+/// Parse `name=value` headers
+fn parse_headers(entries: &[String]) -> Result<Vec<(header::HeaderName, header::HeaderValue)>, String> {
+    entries
+        .iter()
+        .map(|entry| {
+            let (name, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid header '{entry}', expected name=value"))?;
+            let name = header::HeaderName::try_from(name.trim())
+                .map_err(|err| format!("Invalid header name in '{entry}': {err}"))?;
+            let value = header::HeaderValue::try_from(value.trim())
+                .map_err(|err| format!("Invalid header value in '{entry}': {err}"))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// Check the name of the interstitial routes: a single path segment, not
+/// one of the other routes of the stream
+fn parse_interstitials_route(name: &str) -> Result<String, String> {
+    let name = name.trim().trim_matches('/');
+    let reserved = [
+        STATUS_PREFIX, VERSION_PATH, COMMAND_PREFIX, METRICS_PREFIX, TRACKING_PREFIX, CLICK_PREFIX, CREATIVE_PREFIX,
+        TIMELINE_PATH, "/admin", "/debug", "/dash",
+    ];
+    if name.is_empty() || name.starts_with('.') {
+        return Err(format!("Invalid interstitials route '{name}'"));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+        return Err(format!("Invalid interstitials route '{name}', only letters, digits, '-', '_' and '.' are allowed"));
+    }
+    if reserved.iter().any(|route| route.trim_start_matches('/') == name) {
+        return Err(format!("The interstitials route '{name}' is taken by another route"));
+    }
+    Ok(name.to_string())
+}
+
+/// Client of the ad server, separate from the origin one as it may
+/// authenticate with a client certificate
+pub struct AdServerClient(Client);
+
+#[derive(Clone, Debug)]
+pub struct TestAsset {
+    url: Url,
+    duration: u64,
+}
+
+impl TestAsset {
+    pub fn new(url: Url, duration: u64) -> Self {
+        Self { url, duration }
+    }
+    
+    fn to_json(&self) -> json::JsonValue {
+        object! {
+            "url": self.url.as_str(),
+            "duration": self.duration,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Ad {
+    ad_id: Uuid,
+    universal_ad_ids: Vec<UniversalAdId>,
+    duration: f64,
+    url: String,
+    requested_at: chrono::DateTime<chrono::Utc>,
+    tracking: Vec<Tracking>,
+    impressions: Vec<String>,
+    errors: Vec<String>,
+    clicks: Option<VideoClicks>,
+    interactive: Option<InteractiveCreative>,
+    // The <AdParameters> of the linear, passed through to the player
+    ad_parameters: Option<String>,
+    // RFC 6381 codecs of the media file, from the VAST
+    codec: Option<String>,
+    // Position of the ad break in the content stream in seconds
+    content_playhead: Option<f64>,
+    // The playback session the ad was served to
+    session: String,
+    // The channel and the slot the ad was served in, the ad is dropped along
+    // with its slot
+    channel: String,
+    slot: Option<Uuid>,
+}
+
+#[derive(Clone, Default)]
+pub struct AvailableAds {
+    linears: Arc<DashMap<Uuid, Ad>>,
+    creatives: CreativeCache,
+    durations: DurationProber,
+    transcoder: Option<Transcoder>,
+}
+
+impl AvailableAds {
+    pub fn with_creative_cache(mut self, creatives: CreativeCache) -> Self {
+        self.creatives = creatives;
+        self
+    }
+
+    pub fn with_duration_prober(mut self, durations: DurationProber) -> Self {
+        self.durations = durations;
+        self
+    }
+
+    pub fn with_transcoder(mut self, transcoder: Option<Transcoder>) -> Self {
+        self.transcoder = transcoder;
+        self
+    }
+
+    // Save an ad for the follow-up requests. An ad served already under its ID,
+    // to a player refetching the asset list, is served again as it was
+    fn keep(&self, ad: Ad) -> Ad {
+        self.linears.entry(ad.ad_id).or_insert(ad).clone()
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        let linears = self
+            .linears
+            .iter()
+            .map(|entry| {
+                let (id, ad) = entry.pair();
+                object! {
+                    "id": id.to_string(),
+                    "duration": ad.duration,
+                    "url": ad.url.clone(),
+                    "requested_at": ad.requested_at.to_rfc3339(),
+                    "slot": ad.slot.map(|slot| slot.to_string()),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        object! {
+            "count": linears.len(),
+            "linears": linears,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AdSlot {
+    id: Uuid,
+    index: u64,
+    start_time: chrono::DateTime<chrono::Utc>,
+    // Fractional seconds, a Duration keeps the slot hashable
+    duration: Duration,
+    pod_num: u64,
+    // The pod template of /command the break was scheduled with
+    template: Option<String>,
+    // Added to the ad server requests of the break
+    ad_server_params: Vec<(String, String)>,
+    // The HLS stream of a pod of a single creative, played from the DATERANGE's
+    // X-ASSET-URI instead of an asset list (delivery=inline of /command)
+    asset_uri: Option<String>,
+    // The HLS stream of the alternate content replacing the primary content
+    // for the whole break, instead of an ad pod (replace= of /command)
+    replacement: Option<String>,
+    // How far into its break a page of a paged break starts, zero otherwise
+    offset: Duration,
+}
+
+impl AdSlot {
+    fn name(&self) -> String {
+        ad_slot_name(self.index)
+    }
+
+    fn end_time(&self) -> chrono::DateTime<chrono::Utc> {
+        self.start_time + chrono::Duration::from_std(self.duration).unwrap_or_default()
+    }
+
+    // Whether the break shares some of its window with `other`
+    fn overlaps(&self, other: &AdSlot) -> bool {
+        self.start_time < other.end_time() && other.start_time < self.end_time()
+    }
+
+    // The pages of a break longer than `page_duration`, cued one after the
+    // other with their own asset lists. Inlined and replaced breaks are whole
+    fn pages(&self, page_duration: Option<Duration>) -> Vec<AdSlot> {
+        let page_duration = page_duration.filter(|page| {
+            !page.is_zero() && self.duration > *page && self.asset_uri.is_none() && self.replacement.is_none()
+        });
+        let Some(page_duration) = page_duration else {
+            return vec![self.clone()];
+        };
+        let mut pages = Vec::new();
+        let mut offset = Duration::ZERO;
+        while offset < self.duration {
+            let duration = page_duration.min(self.duration - offset);
+            pages.push(AdSlot {
+                start_time: self.start_time + chrono::Duration::from_std(offset).unwrap_or_default(),
+                duration,
+                offset,
+                ..self.clone()
+            });
+            offset += duration;
+        }
+        pages
+    }
+
+    // The page of the break an asset list is requested for
+    fn page(&self, page: usize, page_duration: Option<Duration>) -> Option<AdSlot> {
+        self.pages(page_duration).into_iter().nth(page)
+    }
+}
+
+fn ad_slot_name(index: u64) -> String {
+    format!("ad_slot{index}")
+}
+
+// The DATERANGE of a page of a break, ad_slot<index>.<page> past the first one
+fn ad_page_name(index: u64, page: usize) -> String {
+    match page {
+        0 => ad_slot_name(index),
+        page => format!("{}.{page}", ad_slot_name(index)),
+    }
+}
+
+// The slot and the page an interstitial ID names
+fn split_page_name(interstitial_id: &str) -> (&str, usize) {
+    interstitial_id
+        .rsplit_once('.')
+        .and_then(|(name, page)| Some((name, page.parse().ok()?)))
+        .unwrap_or((interstitial_id, 0))
+}
+
+/// The ad slots still matched with the playlists, and the lifecycle of all of them
+#[derive(Clone)]
+pub struct AvailableAdSlots {
+    // By index, a slot is replaced in place when its break is widened
+    slots: Arc<DashMap<u64, AdSlot>>,
+    lifecycle: SlotLifecycle,
+    // The ads served, shared with AvailableAds, dropped along with their slot
+    ads: Arc<DashMap<Uuid, Ad>>,
+}
+
+impl AvailableAdSlots {
+    /// No slots yet, the ads served in them are kept by `available_ads`
+    pub fn new(available_ads: &AvailableAds) -> Self {
+        Self {
+            slots: Arc::default(),
+            lifecycle: SlotLifecycle::default(),
+            ads: available_ads.linears.clone(),
+        }
+    }
+
+    fn schedule(&self, slot: AdSlot) {
+        self.lifecycle.schedule(slot.index);
+        self.slots.insert(slot.index, slot);
+    }
+
+    // Swap a slot for its new version in place, unless it has changed meanwhile
+    fn replace(&self, slot: &AdSlot, replacement: AdSlot) -> bool {
+        match self.slots.get_mut(&slot.index) {
+            Some(mut current) if *current == *slot => {
+                *current = replacement;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Drop the slots ending before `before`, their breaks have left the playlists
+    fn expire(&self, before: chrono::DateTime<chrono::Utc>) {
+        let expired = self
+            .slots
+            .iter()
+            .filter(|slot| slot.end_time() < before)
+            .map(|slot| slot.clone())
+            .collect::<Vec<_>>();
+        for slot in &expired {
+            log::info!("Expiring {}, its break ended at {}", slot.name(), slot.end_time());
+            self.slots.remove(&slot.index);
+            self.lifecycle.expire(slot.index);
+        }
+        self.forget_ads(&expired.iter().map(|slot| slot.id).collect::<Vec<_>>());
+    }
+
+    fn clear(&self) {
+        let ids = self.slots.iter().map(|slot| slot.id).collect::<Vec<_>>();
+        self.slots.clear();
+        self.lifecycle.clear();
+        self.forget_ads(&ids);
+    }
+
+    // Drop a slot before its break, it isn't matched nor served anymore
+    fn cancel(&self, index: u64) -> Option<AdSlot> {
+        let (_, slot) = self.slots.remove(&index)?;
+        self.lifecycle.expire(index);
+        self.forget_ads(&[slot.id]);
+        Some(slot)
+    }
+
+    // Drop the ads served in these slots, their breaks are over
+    fn forget_ads(&self, slots: &[Uuid]) {
+        if slots.is_empty() {
+            return;
+        }
+        let mut forgotten = 0;
+        self.ads.retain(|_, ad| {
+            let expired = ad.slot.is_some_and(|slot| slots.contains(&slot));
+            forgotten += usize::from(expired);
+            !expired
+        });
+        if forgotten > 0 {
+            log::debug!("Dropped {forgotten} ads of {} expired slots", slots.len());
+        }
+    }
+
+    // Drop the ads of the channel served in a slot it doesn't have anymore, and
+    // those served outside of a slot for longer than UNSLOTTED_AD_TTL. The
+    // number of ads dropped of each kind
+    fn collect_ads(&self, channel: &str) -> (usize, usize) {
+        let live = self.slots.iter().map(|slot| slot.id).collect::<HashSet<_>>();
+        let unslotted_before = chrono::Utc::now() - UNSLOTTED_AD_TTL;
+        let (mut orphaned, mut unslotted) = (0, 0);
+        self.ads.retain(|_, ad| {
+            if ad.channel != channel {
+                return true;
+            }
+            match ad.slot {
+                Some(slot) if !live.contains(&slot) => orphaned += 1,
+                None if ad.requested_at < unslotted_before => unslotted += 1,
+                _ => return true,
+            }
+            false
+        });
+        (orphaned, unslotted)
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        let slots = self
+            .slots
+            .iter()
+            .map(|slot| {
+                object! {
+                    "id": slot.id.to_string(),
+                    "index": slot.index,
+                    "start_time": slot.start_time.to_rfc3339(),
+                    "duration": slot.duration.as_secs_f64(),
+                    "pod_num": slot.pod_num,
+                    "template": slot.template.as_deref(),
+                    "asset_uri": slot.asset_uri.as_deref(),
+                    "replacement": slot.replacement.as_deref(),
+                    "state": self.lifecycle.state(slot.index).map(|state| state.to_str().to_string()),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        object! {
+            "count": slots.len(),
+            "slots": slots,
+            "lifecycle": self.lifecycle.to_json(),
+        }
+    }
+}
+
+/// The query parameters of the master playlist of each playback session, by
+/// session ID. A session ID is any opaque string, a UUID being matched in any
+/// letter case
+#[derive(Clone, Default)]
+pub struct UserDefinedQueryParams(Arc<DashMap<String, String>>);
+
+impl UserDefinedQueryParams {
+    // The map key of a session ID
+    fn key(session_id: &str) -> String {
+        match Uuid::parse_str(session_id) {
+            Ok(uuid) => uuid.to_string(),
+            Err(_) => session_id.to_string(),
+        }
+    }
+
+    /// Save the query parameters of the master playlist request of the session
+    /// given by its `X-PLAYBACK-SESSION-ID` header
+    fn save(&self, req: &HttpRequest) {
+        let (Some(query_params), Some(session_id)) = (req.uri().query(), get_header_value(req, SESSION_HEADER))
+        else {
+            return;
+        };
+        let session_id = session_id.trim();
+        if session_id.is_empty() || session_id.len() > MAX_SESSION_ID_LENGTH {
+            log::warn!("Ignoring the query parameters of a session ID of {} bytes", session_id.len());
+            return;
+        }
+        log::info!("Saved user-defined query parameters: {query_params} for session {session_id}");
+        self.0.insert(Self::key(session_id), query_params.to_string());
+    }
+
+    /// The saved query parameters of the session
+    fn get(&self, session_id: &str) -> Option<String> {
+        self.0.get(&Self::key(session_id)).map(|query| query.clone())
+    }
+
+    fn contains(&self, session_id: &str) -> bool {
+        self.0.contains_key(&Self::key(session_id))
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        let params = self
+            .0
+            .iter()
+            .map(|entry| {
+                let (id, query) = entry.pair();
+                object! {
+                    "id": id.as_str(),
+                    "query": query.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        object! {
+            "params": params,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum InsertionMode {
+    Static,
+    Dynamic,
+}
+
+impl InsertionMode {
+    pub fn to_str(&self) -> &str {
+        match self {
+            InsertionMode::Static => "static",
+            InsertionMode::Dynamic => "dynamic",
+        }
+    }
+}
+
+/// What is done to an ad pod shorter or longer than its break
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum PodFillPolicy {
+    AsIs,
+    Trim,
+    Pad,
+    Extend,
+    Fit,
+}
+
+impl PodFillPolicy {
+    pub fn to_str(&self) -> &str {
+        match self {
+            PodFillPolicy::AsIs => "as-is",
+            PodFillPolicy::Trim => "trim",
+            PodFillPolicy::Pad => "pad",
+            PodFillPolicy::Extend => "extend",
+            PodFillPolicy::Fit => "fit",
+        }
+    }
+}
+
+/// Where the present of a live stream is taken from to schedule the breaks of /command
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum ClockSource {
+    Origin,
+    System,
+}
+
+impl ClockSource {
+    pub fn to_str(&self) -> &str {
+        match self {
+            ClockSource::Origin => "origin",
+            ClockSource::System => "system",
+        }
+    }
+}
+
+/// What is done with a break of /command overlapping a scheduled one
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum SlotOverlapPolicy {
+    Reject,
+    Merge,
+}
+
+impl SlotOverlapPolicy {
+    pub fn to_str(&self) -> &str {
+        match self {
+            SlotOverlapPolicy::Reject => "reject",
+            SlotOverlapPolicy::Merge => "merge",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum AssetUrlFormat {
+    Query,
+    Path,
+}
+
+impl AssetUrlFormat {
+    pub fn to_str(&self) -> &str {
+        match self {
+            AssetUrlFormat::Query => "query",
+            AssetUrlFormat::Path => "path",
+        }
+    }
+}
+
+/// The state of one stream the handlers share: its config, its ad slots,
+/// the live edge and epoch they are scheduled from, and the caches
+#[derive(Clone)]
+pub struct StreamState {
+    config: ServerConfig,
+    available_slots: AvailableAdSlots,
+    ad_pod_cache: AdPodCache,
+    session_asset_lists: SessionAssetLists,
+    pod_prefetch: PodPrefetch,
+    esni: BreakSchedule,
+    epg: BreakSchedule,
+    timelines: SessionTimelines,
+    origin_cache: OriginCache,
+    last_seen_pdt: Arc<AtomicI64>,
+    // Whether the live edge was last found too far from the system clock
+    clock_skewed: Arc<AtomicBool>,
+    epoch: StreamEpoch,
+    // The top variant of the last master playlist, the creatives are checked against
+    content_profile: Arc<parking_lot::RwLock<Option<ContentProfile>>>,
+}
+
+impl StreamState {
+    /// A stream without ad slots yet, its epoch is now, the ads served in its
+    /// slots kept by `available_ads`
+    pub fn new(config: ServerConfig, origin_cache: OriginCache, available_ads: &AvailableAds) -> Self {
+        Self {
+            config,
+            available_slots: AvailableAdSlots::new(available_ads),
+            ad_pod_cache: AdPodCache::default(),
+            session_asset_lists: SessionAssetLists::default(),
+            pod_prefetch: PodPrefetch::default(),
+            esni: BreakSchedule::default(),
+            epg: BreakSchedule::default(),
+            timelines: SessionTimelines::default(),
+            origin_cache,
+            last_seen_pdt: Arc::new(AtomicI64::new(0)),
+            clock_skewed: Arc::default(),
+            epoch: StreamEpoch::new(chrono::Utc::now()),
+            content_profile: Arc::default(),
+        }
+    }
+
+    /// Reuse the ad server replies per slot with this cache
+    pub fn with_ad_pod_cache(mut self, ad_pod_cache: AdPodCache) -> Self {
+        self.ad_pod_cache = ad_pod_cache;
+        self
+    }
+
+    /// Serve the asset lists again to the sessions retrying with this cache
+    pub fn with_session_asset_lists(mut self, session_asset_lists: SessionAssetLists) -> Self {
+        self.session_asset_lists = session_asset_lists;
+        self
+    }
+
+    /// Request the pods of the VOD sessions at their start with this prefetcher
+    pub fn with_pod_prefetch(mut self, pod_prefetch: PodPrefetch) -> Self {
+        self.pod_prefetch = pod_prefetch;
+        self
+    }
+
+    /// Schedule the breaks of this SCTE-224 schedule (dynamic mode)
+    pub fn with_esni(mut self, esni: BreakSchedule) -> Self {
+        self.esni = esni;
+        self
+    }
+
+    /// Schedule the breaks planned by this EPG (dynamic mode)
+    pub fn with_epg(mut self, epg: BreakSchedule) -> Self {
+        self.epg = epg;
+        self
+    }
+
+    /// Schedule the static ad slots from this epoch instead of now
+    pub fn with_epoch(mut self, epoch: StreamEpoch) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    // Check the following creatives against the variants of this master playlist
+    fn update_content_profile(&self, playlist: &MasterPlaylist) {
+        if !self.config.check_compatibility {
+            return;
+        }
+        let profile = ContentProfile::from_master_playlist(playlist);
+        let mut current = self.content_profile.write();
+        if *current != profile {
+            log::info!("Content profile of the creatives: {:?}", profile);
+            *current = profile;
+        }
+    }
+}
+
+/// Ad break settings which can be changed by reloading the config file
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdBreakSettings {
+    pub ad_server_url: Url,
+    pub target_ad_duration: u64,
+    pub target_repeating_cycle: u64,
+    pub target_ad_number: u64,
+}
+
+impl AdBreakSettings {
+    // Ad pods requested per session can't be shared between viewers
+    fn session_targeting(&self) -> bool {
+        self.ad_server_url
+            .query_pairs()
+            .any(|(_, value)| value == SESSION_ID_TEMPLATE)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // Path of the channel, the base path for the stream served at the root
+    path_prefix: String,
+    // Name of the channel, empty for the stream served at the root
+    channel: String,
+    // Where the requests are forwarded, swapped by /admin/origin
+    origin: OriginRoute,
+    interstitials_address: Url,
+    // The master playlist the players request
+    master_playlist_path: Option<String>,
+    insertion_mode: InsertionMode,
+    ad_breaks: Arc<parking_lot::RwLock<AdBreakSettings>>,
+    test_asset: Option<TestAsset>,
+    test_pods: Option<TestPods>,
+    infer_quartiles: bool,
+    check_compatibility: bool,
+    bumpers: BumperFilter,
+    separation: CompetitiveSeparation,
+    ad_selection: AdSelection,
+    strip_interactive: bool,
+    localization: Localization,
+    proxy_creatives: bool,
+    clear_ads: bool,
+    // Version of the creative signaling, None when it is left out
+    creative_signaling: Option<u64>,
+    pod_fill_policy: PodFillPolicy,
+    pod_fill_tolerance: f64,
+    slate_url: Option<Url>,
+    slot_overlap: SlotOverlapPolicy,
+    asset_url_format: AssetUrlFormat,
+    interstitials_route: String,
+    asset_list_headers: Vec<(header::HeaderName, header::HeaderValue)>,
+    slot_expiry_grace: Duration,
+    vod_preroll: bool,
+    vod_postroll: bool,
+    pod_page_duration: Option<Duration>,
+    // The player's headers forwarded to the origin
+    origin_headers: HeaderForwarding,
+    // Replace the origin's Cache-Control of the playlists and the segments
+    playlist_cache_control: Option<header::HeaderValue>,
+    segment_cache_control: Option<header::HeaderValue>,
+    playlist_detection: PlaylistDetection,
+    // Of the origin's date times without an offset
+    origin_time_zone: chrono::FixedOffset,
+    clock_source: ClockSource,
+    max_clock_skew: Option<Duration>,
+    pod_templates: Vec<PodTemplate>,
+    default_pod_num: u64,
+    experiments: Experiments,
+    dash_signaling: DashSignaling,
+    variant_ladder: VariantLadder,
+    device_profiles: DeviceProfiles,
+    ad_free: AdFreeEntitlement,
+    admission: SessionAdmission,
+    rules: InsertionRules,
+    scte35_markers: bool,
+    server_timing: bool,
+    max_vast_size: usize,
+    vast_validator: VastValidator,
+    admin_token: Option<String>,
+    hooks: PlaylistHooks,
+    faults: FaultInjector,
+}
+
+impl ServerConfig {
+    /// A stream forwarded to `forward_url`, with any path of the origin host
+    /// proxied and static ad breaks inserted
+    pub fn new(forward_url: Url, interstitials_address: Url, ad_breaks: AdBreakSettings) -> Self {
+        Self {
+            path_prefix: String::new(),
+            channel: String::new(),
+            origin: OriginRoute::new(forward_url, None),
+            interstitials_address,
+            master_playlist_path: None,
+            insertion_mode: InsertionMode::Static,
+            ad_breaks: Arc::new(parking_lot::RwLock::new(ad_breaks)),
+            test_asset: None,
+            test_pods: None,
+            infer_quartiles: false,
+            check_compatibility: false,
+            bumpers: BumperFilter::default(),
+            separation: CompetitiveSeparation::default(),
+            ad_selection: AdSelection::default(),
+            strip_interactive: false,
+            localization: Localization::default(),
+            proxy_creatives: false,
+            clear_ads: false,
+            creative_signaling: Some(DEFAULT_CREATIVE_SIGNALING_VERSION),
+            pod_fill_policy: PodFillPolicy::AsIs,
+            pod_fill_tolerance: 0.5,
+            slate_url: None,
+            slot_overlap: SlotOverlapPolicy::Reject,
+            asset_url_format: AssetUrlFormat::Query,
+            interstitials_route: DEFAULT_INTERSTITIALS_ROUTE.to_string(),
+            asset_list_headers: Vec::new(),
+            slot_expiry_grace: Duration::from_secs(60),
+            vod_preroll: false,
+            vod_postroll: false,
+            pod_page_duration: None,
+            origin_headers: HeaderForwarding::default(),
+            playlist_cache_control: None,
+            segment_cache_control: None,
+            playlist_detection: PlaylistDetection::default(),
+            origin_time_zone: chrono::FixedOffset::east_opt(0).unwrap(),
+            clock_source: ClockSource::Origin,
+            max_clock_skew: None,
+            pod_templates: Vec::new(),
+            experiments: Experiments::default(),
+            default_pod_num: DEFAULT_POD_NUM,
+            dash_signaling: DashSignaling::Periods,
+            variant_ladder: VariantLadder::default(),
+            device_profiles: DeviceProfiles::default(),
+            ad_free: AdFreeEntitlement::default(),
+            admission: SessionAdmission::default(),
+            rules: InsertionRules::default(),
+            scte35_markers: false,
+            server_timing: false,
+            max_vast_size: DEFAULT_MAX_VAST_SIZE,
+            vast_validator: VastValidator::default(),
+            admin_token: None,
+            hooks: PlaylistHooks::default(),
+            faults: FaultInjector::default(),
+        }
+    }
+
+    /// These options for another stream forwarded to `forward_url`, with its own
+    /// ad breaks and the playlists it learns
+    pub fn for_stream(&self, forward_url: Url, interstitials_address: Url, ad_breaks: AdBreakSettings) -> Self {
+        Self {
+            origin: OriginRoute::new(forward_url, None),
+            interstitials_address,
+            ad_breaks: Arc::new(parking_lot::RwLock::new(ad_breaks)),
+            playlist_detection: self.playlist_detection.unlearned(),
+            ..self.clone()
+        }
+    }
+
+    /// Serve the stream under this path (the base path of the stream at the root)
+    pub fn with_path_prefix(mut self, path_prefix: &str) -> Self {
+        self.path_prefix = path_prefix.to_string();
+        self
+    }
+
+    /// Only proxy this master playlist and its media playlists
+    pub fn with_master_playlist_path(mut self, master_playlist_path: Option<String>) -> Self {
+        self.origin = OriginRoute::new(self.origin.forward_url(), master_playlist_path.clone());
+        self.master_playlist_path = master_playlist_path;
+        self
+    }
+
+    pub fn with_insertion_mode(mut self, insertion_mode: InsertionMode) -> Self {
+        self.insertion_mode = insertion_mode;
+        self
+    }
+
+    /// Serve this asset instead of the creatives of the ad server
+    pub fn with_test_asset(mut self, test_asset: Option<TestAsset>) -> Self {
+        self.test_asset = test_asset;
+        self
+    }
+
+    /// Serve these pods in turn instead of calling the ad server
+    pub fn with_test_pods(mut self, test_pods: Option<TestPods>) -> Self {
+        self.test_pods = test_pods;
+        self
+    }
+
+    /// Serve the raw MP4 segments through the proxy and fire the quartile trackers server-side
+    pub fn with_infer_quartiles(mut self, infer_quartiles: bool) -> Self {
+        self.infer_quartiles = infer_quartiles;
+        self
+    }
+
+    /// Only serve the creatives the content's player can switch to
+    pub fn with_compatibility_check(mut self, check_compatibility: bool) -> Self {
+        self.check_compatibility = check_compatibility;
+        self
+    }
+
+    /// Tell the bumpers of the ad pods apart with this filter, which may keep them
+    pub fn with_bumpers(mut self, bumpers: BumperFilter) -> Self {
+        self.bumpers = bumpers;
+        self
+    }
+
+    /// Keep the competing creatives of the ad pods apart
+    pub fn with_competitive_separation(mut self, separation: CompetitiveSeparation) -> Self {
+        self.separation = separation;
+        self
+    }
+
+    /// Have the ads of the pods picked by this selection service
+    pub fn with_ad_selection(mut self, ad_selection: AdSelection) -> Self {
+        self.ad_selection = ad_selection;
+        self
+    }
+
+    /// Leave the interactive layer of the creatives out of the asset lists
+    pub fn with_strip_interactive(mut self, strip_interactive: bool) -> Self {
+        self.strip_interactive = strip_interactive;
+        self
+    }
+
+    /// Localize the ads to the viewer's languages
+    pub fn with_localization(mut self, localization: Localization) -> Self {
+        self.localization = localization;
+        self
+    }
+
+    /// Serve the creative media through the proxy instead of from their own URLs
+    pub fn with_creative_proxy(mut self, proxy_creatives: bool) -> Self {
+        self.proxy_creatives = proxy_creatives;
+        self
+    }
+
+    /// Mark the creative playlists as clear, unlike the protected content
+    pub fn with_clear_ads(mut self, clear_ads: bool) -> Self {
+        self.clear_ads = clear_ads;
+        self
+    }
+
+    /// Signal the creatives with this version of the creative signaling, or not at all
+    pub fn with_creative_signaling(mut self, creative_signaling: Option<u64>) -> Self {
+        self.creative_signaling = creative_signaling;
+        self
+    }
+
+    /// Fit the ad pods to their break with this policy, padding them with the slate
+    pub fn with_pod_fill(mut self, pod_fill_policy: PodFillPolicy, slate_url: Option<Url>) -> Self {
+        self.pod_fill_policy = pod_fill_policy;
+        self.slate_url = slate_url;
+        self
+    }
+
+    /// Let the pods fitted to their break go over it by this many seconds
+    pub fn with_pod_fill_tolerance(mut self, pod_fill_tolerance: f64) -> Self {
+        self.pod_fill_tolerance = pod_fill_tolerance;
+        self
+    }
+
+    /// Signal the ad breaks of the DASH manifests this way
+    pub fn with_dash_signaling(mut self, dash_signaling: DashSignaling) -> Self {
+        self.dash_signaling = dash_signaling;
+        self
+    }
+
+    /// Insert the ad breaks for each class of devices according to its profile
+    pub fn with_device_profiles(mut self, device_profiles: DeviceProfiles) -> Self {
+        self.device_profiles = device_profiles;
+        self
+    }
+
+    /// Serve clean playlists to the sessions entitled to them
+    pub fn with_ad_free(mut self, ad_free: AdFreeEntitlement) -> Self {
+        self.ad_free = ad_free;
+        self
+    }
+
+    /// Cap the sessions served with ads, shared by the channels
+    pub fn with_admission(mut self, admission: SessionAdmission) -> Self {
+        self.admission = admission;
+        self
+    }
+
+    /// Apply these blackout and regional restriction rules to the ad breaks
+    pub fn with_rules(mut self, rules: InsertionRules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Filter and reorder the variants of the master playlists
+    pub fn with_variant_ladder(mut self, variant_ladder: VariantLadder) -> Self {
+        self.variant_ladder = variant_ladder;
+        self
+    }
+
+    /// Reject or merge breaks overlapping a scheduled one
+    pub fn with_slot_overlap(mut self, slot_overlap: SlotOverlapPolicy) -> Self {
+        self.slot_overlap = slot_overlap;
+        self
+    }
+
+    /// Write the X-ASSET-LIST URLs with the slot in the query or in the path
+    pub fn with_asset_url_format(mut self, asset_url_format: AssetUrlFormat) -> Self {
+        self.asset_url_format = asset_url_format;
+        self
+    }
+
+    /// Serve the interstitials under this name instead of "interstitials"
+    pub fn with_interstitials_route(mut self, interstitials_route: &str) -> Self {
+        self.interstitials_route = interstitials_route.to_string();
+        self
+    }
+
+    /// Add these headers to the asset list responses
+    pub fn with_asset_list_headers(mut self, asset_list_headers: Vec<(header::HeaderName, header::HeaderValue)>) -> Self {
+        self.asset_list_headers = asset_list_headers;
+        self
+    }
+
+    /// Keep the passed ad slots of live streams for this long
+    pub fn with_slot_expiry_grace(mut self, slot_expiry_grace: Duration) -> Self {
+        self.slot_expiry_grace = slot_expiry_grace;
+        self
+    }
+
+    /// Cue the breaks longer than `pod_page_duration` as pages of that length
+    pub fn with_pod_page_duration(mut self, pod_page_duration: Option<Duration>) -> Self {
+        self.pod_page_duration = pod_page_duration;
+        self
+    }
+
+    /// Play breaks before and after the content of the VOD assets
+    pub fn with_vod_rolls(mut self, preroll: bool, postroll: bool) -> Self {
+        self.vod_preroll = preroll;
+        self.vod_postroll = postroll;
+        self
+    }
+
+    /// Forward these headers of the player's requests to the origin
+    pub fn with_origin_headers(mut self, origin_headers: HeaderForwarding) -> Self {
+        self.origin_headers = origin_headers;
+        self
+    }
+
+    /// Send these Cache-Control values instead of the origin's
+    pub fn with_cache_control(
+        mut self,
+        playlists: Option<header::HeaderValue>,
+        segments: Option<header::HeaderValue>,
+    ) -> Self {
+        self.playlist_cache_control = playlists;
+        self.segment_cache_control = segments;
+        self
+    }
+
+    /// Tell the playlists without a .m3u8 extension with this detection
+    pub fn with_playlist_detection(mut self, playlist_detection: PlaylistDetection) -> Self {
+        self.playlist_detection = playlist_detection;
+        self
+    }
+
+    /// Read the origin's date times without an offset in `origin_time_zone`,
+    /// schedule the breaks of /command from `clock_source` and warn when the
+    /// origin's clock is off by more than `max_clock_skew`
+    pub fn with_clock(
+        mut self,
+        origin_time_zone: chrono::FixedOffset,
+        clock_source: ClockSource,
+        max_clock_skew: Option<Duration>,
+    ) -> Self {
+        self.origin_time_zone = origin_time_zone;
+        self.clock_source = clock_source;
+        self.max_clock_skew = max_clock_skew;
+        self
+    }
+
+    /// Assign the sessions to the variants of these A/B experiments
+    pub fn with_experiments(mut self, experiments: Experiments) -> Self {
+        self.experiments = experiments;
+        self
+    }
+
+    /// The named breaks of /command, and the number of creatives of the
+    /// breaks nothing else sets it for
+    pub fn with_pod_templates(mut self, pod_templates: Vec<PodTemplate>, default_pod_num: u64) -> Self {
+        self.pod_templates = pod_templates;
+        self.default_pod_num = default_pod_num;
+        self
+    }
+
+    /// Mark the ad breaks with SCTE-35 cue-outs too
+    pub fn with_scte35_markers(mut self, scte35_markers: bool) -> Self {
+        self.scte35_markers = scte35_markers;
+        self
+    }
+
+    /// Add a Server-Timing header to the playlist responses
+    pub fn with_server_timing(mut self, server_timing: bool) -> Self {
+        self.server_timing = server_timing;
+        self
+    }
+
+    /// Reject larger ad server responses
+    pub fn with_max_vast_size(mut self, max_vast_size: usize) -> Self {
+        self.max_vast_size = max_vast_size;
+        self
+    }
+
+    /// Parse the ad server responses with this validator, shared by the channels
+    pub fn with_vast_validator(mut self, vast_validator: VastValidator) -> Self {
+        self.vast_validator = vast_validator;
+        self
+    }
+
+    /// Serve the admin endpoints to the requests with this bearer token
+    pub fn with_admin_token(mut self, admin_token: Option<String>) -> Self {
+        self.admin_token = admin_token;
+        self
+    }
+
+    pub fn with_channel(mut self, name: &str) -> Self {
+        self.channel = name.to_string();
+        self
+    }
+
+    /// Change the playlists of this stream with these hooks before serializing them
+    pub fn with_hooks(mut self, hooks: PlaylistHooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Inject these failures into the ad server requests
+    pub fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    fn playlist_context<'a>(&'a self, req: &'a HttpRequest) -> PlaylistContext<'a> {
+        PlaylistContext {
+            request: req,
+            channel: &self.channel,
+        }
+    }
+
+    // The caching headers of a playlist: the origin's unless overridden, never
+    // let shared caches keep a playlist made for one viewer
+    fn manifest_cache_headers(
+        &self,
+        req: &HttpRequest,
+        origin: &CacheHeaders,
+    ) -> Vec<(header::HeaderName, header::HeaderValue)> {
+        let personalized =
+            !self.origin_headers.forwarded(req).is_empty() || get_query_param(req, DASH_SESSION).is_some();
+        if personalized {
+            return vec![(header::CACHE_CONTROL, header::HeaderValue::from_static("private, no-store"))];
+        }
+
+        let mut headers = Vec::new();
+        match &self.playlist_cache_control {
+            Some(cache_control) => headers.push((header::CACHE_CONTROL, cache_control.clone())),
+            None => {
+                if let Some(cache_control) = origin.cache_control() {
+                    headers.push((header::CACHE_CONTROL, cache_control.clone()));
+                }
+                if let Some(expires) = origin.expires() {
+                    headers.push((header::EXPIRES, expires.clone()));
+                }
+            }
+        }
+        if let Some(age) = origin.age() {
+            headers.push((header::AGE, header::HeaderValue::from(age)));
+        }
+        headers
+    }
+
+    // The current ad break settings, requests keep using a snapshot of them
+    pub fn ad_breaks(&self) -> AdBreakSettings {
+        self.ad_breaks.read().clone()
+    }
+
+    pub fn set_ad_breaks(&self, ad_breaks: AdBreakSettings) {
+        *self.ad_breaks.write() = ad_breaks;
+    }
+
+    // The origin URL of a request, without the channel path
+    fn origin_url(&self, req: &HttpRequest) -> Url {
+        let path = req.uri().path();
+        let path = path.strip_prefix(self.path_prefix.as_str()).unwrap_or(path);
+        self.origin.origin_url(path, req.uri().query())
+    }
+
+    /// The X-ASSET-LIST URL of a slot, the session is left to the player's
+    /// `_HLS_primary_id` when not known
+    pub fn asset_list_url(&self, slot: &str, session: Option<&str>) -> Url {
+        let mut url = self.interstitials_address.clone();
+        match self.asset_url_format {
+            AssetUrlFormat::Query => {
+                url = url.join(&self.interstitial_playlist()).expect("Invalid interstitials address");
+                url.query_pairs_mut().append_pair(HLS_INTERSTITIAL_ID, slot);
+                if let Some(session) = session {
+                    url.query_pairs_mut().append_pair(HLS_PRIMARY_ID, session);
+                }
+            }
+            AssetUrlFormat::Path => {
+                url.path_segments_mut()
+                    .expect("Invalid interstitials address")
+                    .pop_if_empty()
+                    .extend([self.interstitials_route.as_str(), slot, session.unwrap_or("default_user"), ASSET_LIST_FILE]);
+            }
+        }
+        url
+    }
+
+    // The playlist of the query-style interstitials
+    fn interstitial_playlist(&self) -> String {
+        format!("{}.m3u8", self.interstitials_route)
+    }
+
+    // The URL the player gets a media of the ad from, relative to the asset
+    // list so it stays under the base path and the channel. `media_url` is the
+    // creative's own media or the transcoded stream of it
+    fn creative_media_url(&self, req_url: &Url, ad_id: Uuid, media_url: &str) -> String {
+        match self
+            .creative_media_path(ad_id, media_url)
+            .map(|path| join_stream_path(req_url, &path, &self.interstitials_route))
+        {
+            Some(Ok(url)) => url.to_string(),
+            _ => media_url.to_string(),
+        }
+    }
+
+    // The path of the proxied media relative to the asset list, if proxied
+    fn creative_media_path(&self, ad_id: Uuid, media_url: &str) -> Option<String> {
+        if !self.proxy_creatives {
+            return None;
+        }
+        let file_name = Url::parse(media_url)
+            .ok()
+            .and_then(|url| url.path_segments()?.next_back().map(str::to_string))
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "media".to_string());
+        Some(format!("{}/{ad_id}/{file_name}", CREATIVE_PREFIX.trim_start_matches('/')))
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        let ad_breaks = self.ad_breaks();
+        object! {
+            "channel": self.channel.as_str(),
+            "forward_url": self.origin.forward_url().as_str(),
+            "origin": self.origin.to_json(),
+            "interstitials_address": self.interstitials_address.as_str(),
+            "master_playlist_path": self.master_playlist_path.clone().unwrap_or_default(),
+            "insertion_mode": self.insertion_mode.to_str(),
+            "target_ad_duration": ad_breaks.target_ad_duration,
+            "target_repeating_cycle": ad_breaks.target_repeating_cycle,
+            "target_ad_number": ad_breaks.target_ad_number,
+            "test_asset": self.test_asset.as_ref().map(|asset| asset.to_json()).unwrap_or_else(|| object! {}),
+            "test_pods": self.test_pods.as_ref().map(|pods| pods.to_json()).unwrap_or_else(|| object! {}),
+            "infer_quartiles": self.infer_quartiles,
+            "check_creative_compatibility": self.check_compatibility,
+            "bumpers": self.bumpers.to_json(),
+            "competitive_separation": self.separation.to_json(),
+            "ad_selection": self.ad_selection.to_json(),
+            "strip_interactive_creatives": self.strip_interactive,
+            "localization": self.localization.to_json(),
+            "proxy_creative_media": self.proxy_creatives,
+            "clear_ads": self.clear_ads,
+            "creative_signaling_version": self.creative_signaling,
+            "pod_fill_policy": self.pod_fill_policy.to_str(),
+            "pod_fill_tolerance": self.pod_fill_tolerance,
+            "slate_url": self.slate_url.as_ref().map(Url::as_str),
+            "slot_overlap": self.slot_overlap.to_str(),
+            "asset_url_format": self.asset_url_format.to_str(),
+            "interstitials_route": self.interstitials_route.as_str(),
+            "asset_list_headers": self
+                .asset_list_headers
+                .iter()
+                .map(|(name, value)| format!("{name}={}", value.to_str().unwrap_or_default()))
+                .collect::<Vec<_>>(),
+            "slot_expiry_grace": self.slot_expiry_grace.as_secs(),
+            "vod_preroll": self.vod_preroll,
+            "vod_postroll": self.vod_postroll,
+            "pod_page_duration": self.pod_page_duration.map(|duration| duration.as_secs()),
+            "origin_headers": self.origin_headers.to_json(),
+            "playlist_cache_control": self.playlist_cache_control.as_ref().and_then(|value| value.to_str().ok()),
+            "segment_cache_control": self.segment_cache_control.as_ref().and_then(|value| value.to_str().ok()),
+            "playlist_detection": self.playlist_detection.to_json(),
+            "origin_time_zone": self.origin_time_zone.to_string(),
+            "clock_source": self.clock_source.to_str(),
+            "max_clock_skew": self.max_clock_skew.map(|skew| skew.as_secs()),
+            "pod_templates": self.pod_templates.iter().map(PodTemplate::to_json).collect::<Vec<_>>(),
+            "experiments": self.experiments.to_json(),
+            "default_pod_num": self.default_pod_num,
+            "dash_signaling": self.dash_signaling.to_str(),
+            "variant_ladder": self.variant_ladder.to_json(),
+            "device_profiles": self.device_profiles.to_json(),
+            "ad_free": self.ad_free.to_json(),
+            "admission": self.admission.to_json(),
+            "rules": self.rules.to_json(),
+            "scte35_markers": self.scte35_markers,
+            "server_timing": self.server_timing,
+            "max_vast_size": self.max_vast_size,
+            "admin_endpoints": self.admin_token.is_some(),
+            "faults": self.faults.to_json(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct InsertionCommand {
+    // Fractional seconds, whole seconds as before are fine too
+    in_sec: f64,
+    duration: f64,
+    pod_num: u64,
+    template: Option<PodTemplate>,
+    // Inline the pod into the DATERANGE rather than serving an asset list
+    inline: bool,
+    // The alternate content replacing the primary content during the break
+    replacement: Option<Url>,
+}
+
+impl InsertionCommand {
+    /// The `dur` and `pod` of the query take precedence over those of its
+    /// `template`, `default_pod_num` being used without either
+    fn from_query(query: &str, templates: &[PodTemplate], default_pod_num: u64) -> Result<Self, String> {
+        let mut in_sec = None;
+        let mut duration = None;
+        let mut pod_num = None;
+        let mut template = None;
+        let mut inline = false;
+        let mut replacement = None;
+
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "template" => {
+                    template = Some(
+                        templates
+                            .iter()
+                            .find(|template| template.name == value)
+                            .cloned()
+                            .ok_or_else(|| format!("Unknown pod template '{value}'"))?,
+                    )
+                }
+                "in" => in_sec = value.parse().ok().filter(|in_sec: &f64| in_sec.is_finite() && *in_sec >= 0.0),
+                "dur" => duration = value.parse().ok().filter(|duration: &f64| duration.is_finite() && *duration > 0.0),
+                "pod" => pod_num = value.parse().ok(),
+                "replace" => {
+                    let url = Url::parse(&value).map_err(|err| format!("Invalid replacement URL '{value}': {err}"))?;
+                    if !is_hls_playlist(url.as_str()) {
+                        return Err(format!("The replacement {value} is not an HLS playlist"));
+                    }
+                    replacement = Some(url);
+                }
+                "delivery" => {
+                    inline = match value.as_ref() {
+                        "inline" => true,
+                        "list" => false,
+                        _ => return Err(format!("Unknown delivery '{value}', expected list or inline")),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if replacement.is_some() && template.is_some() {
+            return Err("A replacement takes no pod template".to_string());
+        }
+        let duration = duration.or(template.as_ref().map(|template: &PodTemplate| template.duration));
+        let pod_num = pod_num
+            .or(template.as_ref().and_then(|template| template.pod_num))
+            .unwrap_or(default_pod_num);
+        match (in_sec, duration) {
+            (Some(in_sec), Some(duration)) => Ok(Self {
+                in_sec,
+                duration,
+                pod_num,
+                template,
+                inline,
+                replacement,
+            }),
+            _ => Err("Missing required query parameters".to_string()),
+        }
+    }
+}
+
+/// A tracking event reported by a custom player via `POST /tracking`
+#[derive(Debug, serde::Deserialize)]
+pub struct PlayerTrackingEvent {
+    session: String,
+    ad_id: String,
+    event: String,
+    /// Playback position within the creative in seconds
+    position: Option<f64>,
+    /// Playback position within the content stream in seconds
+    content_position: Option<f64>,
+    /// VAST error code of an `error` event
+    error_code: Option<u16>,
+}
+
+// The type of a request by its path. The playlists are told apart by their
+// content once fetched, a master playlist of the origin at another path than
+// the configured one is served as a master playlist all the same
+fn get_request_type(req: &HttpRequest, config: &ServerConfig) -> RequestType {
+    let path = req.uri().path();
+
+    // In specific playlist mode, the master playlist is at its path under the channel
+    if let Some(ref master_path) = config.master_playlist_path {
+        if path.strip_prefix(config.path_prefix.as_str()).unwrap_or(path) == master_path {
+            return RequestType::MasterPlayList;
+        }
+    }
+
+    if is_media_segment(path) {
+        return RequestType::Segment;
+    } else if path.ends_with(".m3u8") {
+        // In origin host mode (master_playlist_path is None), return generic Playlist
+        if config.master_playlist_path.is_none() {
+            return RequestType::Playlist;
+        }
+        return RequestType::MediaPlayList;
+    } else if path.ends_with(".mpd") {
+        return RequestType::DashManifest;
+    } else if config.playlist_detection.is_playlist(path, req.uri().query()) {
+        return RequestType::Playlist;
+    }
+    RequestType::Other
+}
+
+async fn build_ad_server_url(
+    ad_server_url: &Url,
+    slot: Option<&AdSlot>,
+    user_id: &str,
+    user_defined_query_params: &web::Data<UserDefinedQueryParams>,
+) -> Result<Url, Error> {
+    let slot = slot.ok_or_else(|| error::ErrorNotFound("Ad slot missing".to_string()))?;
+
+    Ok(slot_ad_server_url(ad_server_url, slot, user_id, user_defined_query_params))
+}
+
+// The ad server request of the break for a session
+fn slot_ad_server_url(
+    ad_server_url: &Url,
+    slot: &AdSlot,
+    user_id: &str,
+    user_defined_query_params: &UserDefinedQueryParams,
+) -> Url {
+    // Create a map of query templates to replace in the ad_server_url
+    let duration_str = slot.duration.as_secs_f64().to_string();
+    let pod_num_str = slot.pod_num.to_string();
+    let offset_str = slot.offset.as_secs_f64().to_string();
+    let query_templates: HashMap<&str, &str> = [
+        (SESSION_ID_TEMPLATE, user_id),
+        (DURATION_TEMPLATE, &duration_str),
+        (POD_NUM_TEMPLATE, &pod_num_str),
+        (OFFSET_TEMPLATE, &offset_str),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+
+    if query_templates.is_empty() {
+        log::warn!("No query templates found for ad server URL. Missing [duration] ...");
+    }
+
+    // Extract and transform query parameters from the ad_server_url
+    let transformed_queries: String = ad_server_url
+        .query_pairs()
+        .map(|(key, value)| {
+            // Check if the value matches any template in query_templates
+            let new_value = if let Some(&matched_value) = query_templates.get(value.as_ref()) {
+                // Use the matched value if a template is found
+                matched_value.to_string()
+            } else {
+                // Otherwise, use the original value
+                value.into_owned()
+            };
+
+            format!("{}={}", key, new_value)
+        })
+        .chain(slot.ad_server_params.iter().map(|(key, value)| {
+            // The settings of the pod template of the break
+            url::form_urlencoded::Serializer::new(String::new())
+                .append_pair(key, value)
+                .finish()
+        }))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    // AVPlayer and Safari support setting the 'X-PLAYBACK-SESSION-ID' request
+    // header with a common, globally-unique value on every HTTP request
+    // associated with a particular playback session, which matches the
+    // _HLS_primary_id query parameter of interstitial requests.
+    let user_defined_queries = user_defined_query_params.get(user_id);
+
+    let full_queries = if let Some(user_defined_queries) = user_defined_queries {
+        format!("{}&{}", transformed_queries, user_defined_queries.as_str())
+    } else {
+        transformed_queries
+    };
+
+    // Clone the original URL and set the new query string
+    let mut updated_ad_server_url = ad_server_url.clone();
+    updated_ad_server_url.set_query(Some(&full_queries));
+
+    updated_ad_server_url
+}
+
+fn make_new_ad_from_creative(
+    vast: &vast4_rs::Vast,
+    creative: &vast4_rs::Creative,
+    available_ads: &AvailableAds,
+) -> Ad {
+    let universal_ad_ids = get_universal_ad_ids_from_creative(creative);
+    let linear = creative.linear.as_ref().unwrap();
+    let trackings = get_tracking_events_from_linear(linear);
+    // The tracking URLs differ per impression, the media file doesn't
+    let processed = available_ads.creatives.get_or_insert_with(&universal_ad_ids, || {
+        let url = get_media_urls_from_linear(linear).first().unwrap().clone();
+        CachedCreative::new(get_duration_from_linear(linear), url)
+    });
+    let ad_id = Uuid::new_v4();
+    let codec = linear
+        .media_files
+        .iter()
+        .flat_map(|media_files| media_files.media_files.iter())
+        .find(|media_file| media_file.uri == processed.media_url)
+        .and_then(|media_file| media_file.codec.as_deref().map(str::to_string));
+
+    Ad {
+        ad_id,
+        universal_ad_ids,
+        duration: available_ads
+            .durations
+            .duration(&processed.media_url)
+            .unwrap_or(processed.duration),
+        url: processed.media_url,
+        requested_at: chrono::Utc::now(),
+        tracking: trackings,
+        impressions: get_impression_urls_for_creative(vast, creative),
+        errors: get_error_urls_for_creative(vast, creative),
+        clicks: get_video_clicks_from_linear(linear),
+        interactive: get_interactive_creative_from_linear(linear),
+        ad_parameters: get_ad_parameters_from_linear(linear),
+        codec,
+        content_playhead: None,
+        session: String::new(),
+        channel: String::new(),
+        slot: None,
+    }
+}
+
+// The ID of an ad served to a session, the same on every asset list of the
+// break so that a player refetching it gets the URLs it may be playing
+// already. The players without a session can't be told apart, their ads get
+// new IDs
+fn stable_ad_id(interstitial_id: &str, user_id: &str, creative: &vast4_rs::Creative, position: &str) -> Option<Uuid> {
+    if user_id == "default_user" {
+        return None;
+    }
+    let creative_id = creative.ad_id.as_deref().or(creative.id.as_deref()).unwrap_or_default();
+    let key = format!("{interstitial_id}\n{user_id}\n{position}\n{creative_id}");
+    let digest = openssl::sha::sha256(key.as_bytes());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    Some(uuid::Builder::from_custom_bytes(bytes).into_uuid())
+}
+
+fn make_test_ad_from_creative(vast: &vast4_rs::Vast, creative: &vast4_rs::Creative, test_asset: &TestAsset) -> Ad {
+    // The test asset replaces the media file, it isn't cached
+    let mut ad = make_new_ad_from_creative(vast, creative, &AvailableAds::default());
+    ad.url = test_asset.url.as_str().to_string();
+    ad.duration = test_asset.duration as f64;
+
+    // Replace the http with https in urls
+    ad.tracking
+        .iter_mut()
+        .flat_map(|tracking| tracking.urls.iter_mut())
+        .chain(ad.impressions.iter_mut())
+        .chain(ad.errors.iter_mut())
+        .for_each(|url| {
+            if url.starts_with("http://") {
+                *url = url.replace("http://", "https://");
+            }
+        });
+
+    ad
+}
+
+pub fn to_tracking_json(tracking: &Tracking) -> json::JsonValue {
+    if tracking.offset.is_none() {
+        object! {
+            "type": tracking.event.clone(),
+            "urls": tracking.urls.clone(),
+        }
+    } else {
+        object! {
+            "type": tracking.event.clone(),
+            "offset": tracking.offset.as_ref().unwrap().as_str(),
+            "urls": tracking.urls.clone(),
+        }
+    }
+
+}
+
+// `signaling` is the version of the creative signaling, None to leave it out
+fn to_ad_asset_json(url: &str, ad: &Ad, start: f64, signaling: Option<u64>) -> json::JsonValue {
+    let mut asset = object! {
+        "URI": url,
+        "DURATION": ad.duration,
+    };
+    if let Some(version) = signaling {
+        let mut signaling = object! {
+            "version": version,
+            "type": "slot",
+            "payload": object! {
+                "type": "linear",
+                "start": start,
+                "duration": ad.duration,
+                "identifiers": ad.universal_ad_ids.iter().map(|id| {
+                    object! {
+                        "scheme": id.scheme.as_str(),
+                        "value": id.value.as_str(),
+                    }
+                }).collect::<Vec<_>>(),
+                "tracking": ad.tracking.iter().map(to_tracking_json).collect::<Vec<_>>(),
+                "impressions": ad.impressions.clone(),
+            },
+        };
+        if let Some(ad_parameters) = &ad.ad_parameters {
+            signaling["payload"]["adParameters"] = ad_parameters.as_str().into();
+        }
+        // Web players capable of it run the interactive layer on top of the ad
+        if let Some(interactive) = &ad.interactive {
+            signaling["payload"]["interactive"] = object! {
+                "uri": interactive.uri.as_str(),
+                "apiFramework": interactive.api_framework.clone(),
+                "type": interactive.mime_type.clone(),
+                "variableDuration": interactive.variable_duration,
+                "parameters": interactive.parameters.clone(),
+            };
+        }
+        asset["X-AD-CREATIVE-SIGNALING"] = signaling;
+    }
+
+    // Let players report tracking events for this ad back to the proxy
+    if !ad.ad_id.is_nil() {
+        asset[X_AD_ID] = ad.ad_id.to_string().into();
+    }
+
+    asset
+}
+
+// Route "visit advertiser" clicks through the proxy so click trackers are fired server-side
+fn attach_click_url(asset: &mut json::JsonValue, req_url: &Url, ad: &Ad, user_id: &str, config: &ServerConfig) {
+    let has_click_through = ad
+        .clicks
+        .as_ref()
+        .is_some_and(|clicks| clicks.click_through.is_some());
+    if !has_click_through {
+        return;
+    }
+
+    // Relative to the asset list, so the click stays under the base path and the channel
+    let click_path = format!("{}/{}", CLICK_PREFIX.trim_start_matches('/'), ad.ad_id);
+    if let Ok(mut click_url) = join_stream_path(req_url, &click_path, &config.interstitials_route) {
+        click_url
+            .query_pairs_mut()
+            .clear()
+            .append_pair(CLICK_SESSION, user_id);
+        asset[X_AD_CLICK_URL] = click_url.as_str().into();
+    }
+}
+
+// The routes of the stream are relative to the query-style interstitials. The
+// path-style ones are three levels below them
+fn join_stream_path(req_url: &Url, path: &str, route: &str) -> Result<Url, url::ParseError> {
+    match InterstitialTarget::is_path_style(req_url, route) {
+        true => req_url.join(&format!("../../../{path}")),
+        false => req_url.join(path),
+    }
+}
+
+// The playlist of a raw creative, in the format of the asset list it is in
+fn raw_creative_url(req_url: &Url, interstitial_id: &str, user_id: &str, id: Uuid, route: &str) -> Url {
+    let mut url = req_url.clone();
+    url.set_query(None);
+    if InterstitialTarget::is_path_style(req_url, route) {
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop().pop().extend([id.to_string().as_str(), CREATIVE_PLAYLIST_FILE]);
+        }
+        url.query_pairs_mut().append_pair(HLS_PRIMARY_ID, user_id);
+    } else {
+        url.query_pairs_mut()
+            .append_pair(HLS_INTERSTITIAL_ID, interstitial_id)
+            .append_pair(HLS_PRIMARY_ID, user_id)
+            .append_pair(AD_ID, &id.to_string());
+    }
+    url
+}
+
+fn to_asset_list_json_string(assets: Vec<json::JsonValue>, duration: f64, signaling: Option<u64>) -> String {
+    let mut asset_list = object! {
+        "ASSETS": assets,
+    };
+    if let Some(version) = signaling {
+        asset_list["X-AD-CREATIVE-SIGNALING"] = object! {
+            "version": version,
+            "type": "pod",
+            "payload": object! {
+                "duration": duration,
+            },
+        };
+    }
+    asset_list.pretty(2)
+}
+
+pub fn wrap_into_assets(
+    vast: vast4_rs::Vast,
+    req_url: Url,
+    interstitial_id: &str,
+    user_id: &str,
+    slot: Option<&AdSlot>,
+    stream: &StreamState,
+    available_ads: web::Data<AvailableAds>,
+) -> String {
+    let test_asset = &stream.config.test_asset;
+    let content_playhead = slot.map(|slot| slot_content_playhead(slot, stream));
+    let content_profile = stream.content_profile.read().clone().filter(|_| stream.config.check_compatibility);
+    // The ad as served to this session, the `position`th creative of its kind
+    // of the pod
+    let serve = |mut ad: Ad, creative: &vast4_rs::Creative, position: String| {
+        if let Some(id) = stable_ad_id(interstitial_id, user_id, creative, &position) {
+            ad.ad_id = id;
+        }
+        ad.content_playhead = content_playhead;
+        ad.session = user_id.to_string();
+        ad.channel = stream.config.channel.clone();
+        ad.slot = slot.map(|slot| slot.id);
+        if stream.config.strip_interactive {
+            ad.interactive = None;
+        }
+        ad
+    };
+    let mut start_offset = 0.0;
+    // Competitors of a creative served before it are left out
+    let mut separation = stream.config.separation.pod(user_id);
+    // Get all linears (regular MP4s) from the VAST
+    let raw_assets = get_all_raw_creatives_from_vast(&vast, &stream.config.bumpers)
+        .iter()
+        .enumerate()
+        .filter_map(|(position, creative)| {
+            // Admitted once it is kept, a creative without a compatible media
+            // file doesn't keep its competitors out
+            if !separation.admits(&vast, creative) {
+                return None;
+            }
+            let position = format!("raw{position}");
+            let asset = if test_asset.is_some() {
+                let ad = serve(make_test_ad_from_creative(&vast, creative, &test_asset.as_ref().unwrap()), creative, position);
+                let ad = available_ads.keep(ad);
+
+                start_offset += ad.duration;
+                let url = stream.config.creative_media_url(&req_url, ad.ad_id, &ad.url);
+                let mut asset = to_ad_asset_json(&url, &ad, start_offset, stream.config.creative_signaling);
+                attach_click_url(&mut asset, &req_url, &ad, user_id, &stream.config);
+                asset
+            } else {
+                let mut ad = serve(make_new_ad_from_creative(&vast, creative, &available_ads), creative, position);
+                let transcoder = available_ads.transcoder.as_ref();
+                let id = ad.ad_id;
+                // Once transcoded, the creative is played like the transcoded ones
+                let transcoded = transcoder.and_then(|transcoder| transcoder.stream_url(&ad.universal_ad_ids));
+                if let Some(stream_url) = transcoded {
+                    log::info!("Processing raw asset {id} as its transcoded stream {stream_url}, tracking: {:?}", ad.tracking);
+                    let ad = available_ads.keep(ad);
+
+                    start_offset += ad.duration;
+                    let url = stream.config.creative_media_url(&req_url, id, &stream_url);
+                    let mut asset = to_ad_asset_json(&url, &ad, start_offset, stream.config.creative_signaling);
+                    attach_click_url(&mut asset, &req_url, &ad, user_id, &stream.config);
+                    separation.record(&vast, creative);
+                    return Some((asset, is_sequenced_creative(&vast, creative)));
+                }
+                // Switching to a media file the player can't decode stalls it at the break
+                if let Some(profile) = &content_profile {
+                    let linear = creative.linear.as_ref().unwrap();
+                    match select_media_file(linear, profile, |url| available_ads.durations.format(url)) {
+                        Ok(url) => {
+                            if url != ad.url {
+                                log::info!("Picked the compatible media file {url} of raw asset {id} instead of {}", ad.url);
+                                ad.url = url;
+                            }
+                        }
+                        Err(reason) => {
+                            let until = if transcoder.is_some() { " until it is transcoded" } else { "" };
+                            log::warn!("Dropping raw asset {id} ({:?}){until}, no compatible media file: {reason}", ad.universal_ad_ids);
+                            return None;
+                        }
+                    }
+                }
+                log::info!("Processing raw asset {id}, tracking: {:?}", ad.tracking);
+
+                // Save the asset for follow-up requests (this applies to not-transcoded ads)
+                let ad = available_ads.keep(ad);
+
+                let url = raw_creative_url(&req_url, interstitial_id, user_id, id, &stream.config.interstitials_route);
+
+                start_offset += ad.duration;
+                let mut asset = to_ad_asset_json(&url.as_str(), &ad, start_offset, stream.config.creative_signaling);
+                attach_click_url(&mut asset, &req_url, &ad, user_id, &stream.config);
+                asset
+            };
+
+            separation.record(&vast, creative);
+            Some((asset, is_sequenced_creative(&vast, creative)))
+        })
+        .collect::<Vec<_>>();
+
+    let transcoded_assets = get_all_transcoded_creatives_from_vast(&vast, &stream.config.bumpers)
+        .iter()
+        .enumerate()
+        .filter(|(_, creative)| separation.admit(&vast, creative))
+        .map(|(position, creative)| {
+            let ad = serve(make_new_ad_from_creative(&vast, creative, &available_ads), creative, format!("transcoded{position}"));
+            let id = ad.ad_id;
+            log::info!("Processing transcoded asset {id}, tracking: {:?}", ad.tracking);
+
+            // Keep the tracking events for player-reported tracking
+            let ad = available_ads.keep(ad);
+            let url = stream.config.creative_media_url(&req_url, id, &ad.url);
+            let mut asset = to_ad_asset_json(&url, &ad, start_offset, stream.config.creative_signaling);
+            attach_click_url(&mut asset, &req_url, &ad, user_id, &stream.config);
+            start_offset += ad.duration;
+
+            (asset, is_sequenced_creative(&vast, creative))
+        })
+        .collect::<Vec<_>>();
+
+    separation.finish();
+    // The ads of a sequenced VAST pod are kept whatever the policy, the buffet ads may be left out
+    let (mut assets, sequenced): (Vec<_>, Vec<_>) = raw_assets
+        .into_iter()
+        .chain(transcoded_assets.into_iter())
+        .unzip();
+    if let Some(slot) = slot {
+        fit_pod(&mut assets, &sequenced, slot, stream);
+    }
+    let duration = pod_duration(&assets);
+
+    to_asset_list_json_string(assets, duration, stream.config.creative_signaling)
+}
+
+fn pod_duration(assets: &[json::JsonValue]) -> f64 {
+    assets.iter().map(|asset| asset["DURATION"].as_f64().unwrap_or_default()).sum()
+}
+
+// Fit the assets of a pod to the duration of its break as set by --pod-fill-policy
+fn fit_pod(assets: &mut Vec<json::JsonValue>, sequenced: &[bool], slot: &AdSlot, stream: &StreamState) {
+    let duration = pod_duration(assets);
+    let slot_duration = slot.duration.as_secs_f64();
+    if (duration - slot_duration).abs() < POD_DURATION_TOLERANCE {
+        return;
+    }
+    let policy = &stream.config.pod_fill_policy;
+    let break_name = slot.name();
+    let action = match policy {
+        PodFillPolicy::Trim if duration > slot_duration => {
+            let count = assets.len();
+            while pod_duration(assets) > slot_duration + POD_DURATION_TOLERANCE {
+                assets.pop();
+            }
+            format!("dropped the last {} creatives", count - assets.len())
+        }
+        PodFillPolicy::Pad if duration < slot_duration => match &stream.config.slate_url {
+            Some(slate_url) => {
+                let gap = slot_duration - duration;
+                assets.push(object! {
+                    "URI": slate_url.as_str(),
+                    "DURATION": gap,
+                });
+                format!("padded with {gap}s of slate")
+            }
+            None => "no slate to pad with".to_string(),
+        },
+        PodFillPolicy::Extend if duration > slot_duration => {
+            let extended = AdSlot {
+                duration: Duration::from_secs_f64(duration),
+                ..slot.clone()
+            };
+            let overlapping = stream
+                .available_slots
+                .slots
+                .iter()
+                .find(|other| other.index != slot.index && other.overlaps(&extended))
+                .map(|other| other.name());
+            match overlapping {
+                Some(other) => format!("served as is, the longer break would overlap {other}"),
+                // The next refreshes of the media playlists carry the longer DATERANGE
+                None if stream.available_slots.replace(slot, extended) => format!("extended the break to {duration}s"),
+                // The pages of a paged break can't be extended, the next page follows
+                None => "served as is".to_string(),
+            }
+        }
+        PodFillPolicy::Fit => {
+            let candidates = assets
+                .iter()
+                .zip(sequenced)
+                .map(|(asset, sequenced)| Candidate {
+                    duration: asset["DURATION"].as_f64().unwrap_or_default(),
+                    mandatory: *sequenced,
+                })
+                .collect::<Vec<_>>();
+            let selected = select_pod(&candidates, slot_duration, stream.config.pod_fill_tolerance);
+            let count = assets.len();
+            let mut index = 0;
+            assets.retain(|_| {
+                index += 1;
+                selected.contains(&(index - 1))
+            });
+            format!("kept {} of {count} creatives lasting {}s", assets.len(), pod_duration(assets))
+        }
+        _ => "served as is".to_string(),
+    };
+    log::info!(
+        "Pod of {break_name} lasts {duration}s for a {slot_duration}s break, {} policy: {action}",
+        policy.to_str()
+    );
+}
+
+// The asset list of a test pod, its ads kept for player-reported tracking
+fn wrap_test_pod(
+    pod: &TestPod,
+    req_url: &Url,
+    user_id: &str,
+    slot: Option<&AdSlot>,
+    config: &ServerConfig,
+    available_ads: &AvailableAds,
+) -> String {
+    let mut start_offset = 0.0;
+    let assets = pod
+        .assets
+        .iter()
+        .map(|test_asset| {
+            let ad = Ad {
+                ad_id: Uuid::new_v4(),
+                duration: test_asset.duration,
+                url: test_asset.url.clone(),
+                requested_at: chrono::Utc::now(),
+                tracking: test_asset.tracking(),
+                impressions: test_asset.impressions.clone(),
+                session: user_id.to_string(),
+                channel: config.channel.clone(),
+                slot: slot.map(|slot| slot.id),
+                ..Default::default()
+            };
+            available_ads.linears.insert(ad.ad_id, ad.clone());
+
+            let url = config.creative_media_url(req_url, ad.ad_id, &ad.url);
+            let asset = to_ad_asset_json(&url, &ad, start_offset, config.creative_signaling);
+            start_offset += ad.duration;
+            asset
+        })
+        .collect::<Vec<_>>();
+
+    to_asset_list_json_string(assets, start_offset, config.creative_signaling)
+}
+
+pub fn replace_absolute_url_with_relative_url(m3u8: &mut MasterPlaylist, path_prefix: &str) {
+    m3u8.variant_streams.iter_mut().for_each(|variant| {
+        // Skip iframe playlists
+
+        if let VariantStream::ExtXStreamInf { uri, .. } = variant {
+            if !uri.starts_with("http") {
+                // Relative URIs
+                return;
+            }
+
+            // Replace the absolute URI by their relative path
+            let absolute_media_playlist_url = Url::parse(&uri).expect("Invalid media playlist URI");
+            let mut relative_url = format!("{path_prefix}{}", absolute_media_playlist_url.path());
+            if let Some(query) = absolute_media_playlist_url.query() {
+                relative_url.push('?');
+                relative_url.push_str(query);
+            }
+
+            *uri = relative_url.into();
+        }
+    });
+}
+
+pub fn generate_static_ad_slots(ad_duration:u64, every:u64, number: u64, date_time: chrono::DateTime<chrono::Utc>, base: u64) -> Vec<AdSlot> {
+    (1..number)
+        .map(|i| {
+            let seconds = i * every;
+            let start_time = date_time + chrono::Duration::seconds(seconds as i64);
+            AdSlot {
+                id: Uuid::new_v4(),
+                index: base + i as u64,
+                start_time: start_time,
+                duration: Duration::from_secs(ad_duration),
+                pod_num: DEFAULT_POD_NUM,
+                template: None,
+                ad_server_params: Vec::new(),
+                asset_uri: None,
+                replacement: None,
+                offset: Duration::ZERO,
+            }
+        })
+        .collect()
+}
+
+// Save the fixed ad slots of static mode to the available slots. Those of a
+// live stream are saved once, those of a VOD asset up to its end, so an asset
+// longer than the ones before it adds the slots past their end
+fn save_static_ad_slots(
+    available_slots: &AvailableAdSlots,
+    ad_breaks: &AdBreakSettings,
+    start_date_time: chrono::DateTime<chrono::Utc>,
+    vod_duration: Option<Duration>,
+) {
+    let number = match vod_duration {
+        Some(duration) => vod_ad_slot_number(duration, ad_breaks),
+        None if available_slots.lifecycle.has_scheduled() => return,
+        None => ad_breaks.target_ad_number,
+    };
+    let scheduled = available_slots.slots.iter().map(|slot| slot.index).collect::<HashSet<_>>();
+    let fixed_ad_slots = generate_static_ad_slots(
+        ad_breaks.target_ad_duration,
+        ad_breaks.target_repeating_cycle,
+        number,
+        start_date_time,
+        available_slots.lifecycle.static_base(),
+    );
+    let mut saved = 0;
+    for slot in fixed_ad_slots.into_iter().filter(|slot| !scheduled.contains(&slot.index)) {
+        available_slots.schedule(slot);
+        saved += 1;
+    }
+    if saved > 0 {
+        log::debug!("Saved {saved} fixed ad slots for VOD or static mode.");
+    }
+}
+
+// The number of static slots of a VOD asset, as given to
+// generate_static_ad_slots: slot `i` starts before the end of the asset
+fn vod_ad_slot_number(duration: Duration, ad_breaks: &AdBreakSettings) -> u64 {
+    let every_ms = ad_breaks.target_repeating_cycle as u128 * 1000;
+    if every_ms == 0 {
+        return ad_breaks.target_ad_number;
+    }
+    (duration.as_millis().div_ceil(every_ms) as u64).clamp(1, ad_breaks.target_ad_number.max(1))
+}
+
+// Save the pre-roll or the post-roll slot of the VOD assets, once
+fn save_vod_roll_slot(
+    available_slots: &AvailableAdSlots,
+    ad_breaks: &AdBreakSettings,
+    index: u64,
+    start_time: chrono::DateTime<chrono::Utc>,
+) {
+    if available_slots.slots.iter().any(|slot| slot.index == index) {
+        return;
+    }
+    available_slots.schedule(AdSlot {
+        id: Uuid::new_v4(),
+        index,
+        start_time,
+        duration: Duration::from_secs(ad_breaks.target_ad_duration),
+        pod_num: DEFAULT_POD_NUM,
+        template: None,
+        ad_server_params: Vec::new(),
+        asset_uri: None,
+        replacement: None,
+        offset: Duration::ZERO,
+    });
+}
+
+pub fn insert_interstitials(
+    m3u8: &mut MediaPlaylist,
+    config: &ServerConfig,
+    available_slots: &AvailableAdSlots,
+    epoch: &StreamEpoch,
+    profile: InsertionProfile,
+    session: Option<&str>,
+) -> PlaylistStats {
+    let mut stats = PlaylistStats::new(m3u8);
+    if profile == InsertionProfile::None {
+        return stats.skip("clean_profile");
+    }
+    let ad_insert_mode = &config.insertion_mode;
+
+    let mut first_program_date_time = find_program_datetime_tag(&m3u8, config.origin_time_zone);
+    let segments = &mut m3u8.segments;
+
+    let is_vod = m3u8
+        .playlist_type
+        .is_some_and(|t| t == hls_m3u8::types::PlaylistType::Vod);
+    let is_static = *ad_insert_mode == InsertionMode::Static;
+    if is_vod && !is_static {
+        log::error!("Dynamic ad insertion is not supported for VOD streams.");
+        return stats.skip("dynamic_vod");
+    }
+
+    if first_program_date_time.is_none() {
+        if !is_vod {
+            log::warn!("No program_date_time found in the live stream media playlist. Skipping interstitials.");
+            return stats.skip("no_program_date_time");
+        }
+        log::warn!("No program_date_time found in the VOD stream media playlist. Using the stream epoch.");
+
+        // Use the stream epoch as the program_date_time for the first segment
+        let stream_epoch = epoch.get();
+        segments.find_first_mut().and_then(|first_segment| {
+            // Add to the playlist
+            first_segment.program_date_time = Some(make_program_date_time_tag(&stream_epoch));
+
+            // Update the optional
+            first_program_date_time = Some(stream_epoch);
+            stats.synthesized_program_date_times += 1;
+
+            log::info!(
+                "Insert program_date_time: {:?} to first segment",
+                first_program_date_time
+            );
+            Some(first_segment)
+        });
+    }
+
+    // By this point, we should have a valid program_date_time
+    let first_program_date_time = first_program_date_time.expect("Missing program_date_time Tag");
+
+    // Static slots repeat at a fixed interval from a reference date time, so they
+    // are looked up directly instead of being generated on every refresh
+    let ad_breaks = config.ad_breaks();
+    let vod_duration = is_vod.then(|| {
+        m3u8.segments
+            .iter()
+            .map(|(_, segment)| segment.duration.duration())
+            .sum::<Duration>()
+    });
+    let static_slots_start_date_time = is_static.then(|| {
+        let ad_slots_start_date_time = if is_vod {
+            // Use the first program_date_time for VoD streams
+            first_program_date_time
+        } else {
+            // Use the stream epoch for Live streams
+            epoch.get()
+        };
+
+        save_static_ad_slots(available_slots, &ad_breaks, ad_slots_start_date_time, vod_duration);
+        ad_slots_start_date_time
+    });
+
+    // The pre-roll takes the static index 0, left out of the cycle, and the
+    // post-roll the one past the last slot of the cycle
+    let static_base = available_slots.lifecycle.static_base();
+    let preroll = vod_duration.filter(|_| config.vod_preroll).map(|_| {
+        let index = static_base;
+        save_vod_roll_slot(available_slots, &ad_breaks, index, first_program_date_time);
+        (index, first_program_date_time)
+    });
+    let postroll = vod_duration.filter(|_| config.vod_postroll).map(|duration| {
+        let index = static_base + ad_breaks.target_ad_number;
+        let end = first_program_date_time + chrono::Duration::from_std(duration).unwrap_or_default();
+        save_vod_roll_slot(available_slots, &ad_breaks, index, end);
+        (index, end)
+    });
+    let last_segment = m3u8.segments.num_elements().saturating_sub(1);
+
+    // Only dynamic slots starting within this playlist can be matched
+    let window_start = first_program_date_time
+        - chrono::Duration::from_std(m3u8.target_duration).unwrap_or_default();
+    // VOD playlists keep all of their breaks
+    if !is_vod {
+        available_slots.expire(window_start - chrono::Duration::from_std(config.slot_expiry_grace).unwrap_or_default());
+    }
+    // The later pages of a paged break are cued after its start left the window
+    let page_duration = config
+        .pod_page_duration
+        .filter(|page_duration| profile == InsertionProfile::Interstitials && !page_duration.is_zero());
+    let dynamic_slots: Vec<AdSlot> = if is_static {
+        Vec::new()
+    } else {
+        available_slots
+            .slots
+            .iter()
+            .flat_map(|slot| slot.pages(page_duration))
+            .filter(|slot| slot.start_time > window_start)
+            .collect()
+    };
+    log::trace!("Available dynamic slots: {:?}", dynamic_slots);
+
+    // Find the date time tag for each segment
+    // Or calculate the expected date time based on the previous segments
+    let mut program_date_times = ProgramDateTimeCursor::new(first_program_date_time, config.origin_time_zone);
+    let mut window_end = first_program_date_time;
+    let mut cued = HashSet::new();
+    for (position, (index, segment)) in m3u8.segments.iter_mut().enumerate() {
+        let (program_date_time, duration) = program_date_times.advance(segment);
+        window_end = program_date_time + chrono::Duration::from_std(duration).unwrap_or_default();
+        log::trace!(
+            "Segment {index} starts at {program_date_time} and lasts for {:?}",
+            duration
+        );
+
+        // If a segment has a discontinuity tag but no program_date_time, insert one
+        if segment.has_discontinuity && segment.program_date_time.is_none() {
+            let program_date_time_tag = make_program_date_time_tag(&program_date_time);
+            segment.program_date_time = Some(program_date_time_tag);
+            stats.synthesized_program_date_times += 1;
+        }
+
+        // Match the segment with the first possible ad slot
+        let ad_slot = match static_slots_start_date_time {
+            Some(start_date_time) => find_static_ad_slot(start_date_time, program_date_time, duration, &ad_breaks)
+                .map(|(index, start_time, slot_duration)| {
+                    let index = available_slots.lifecycle.static_base() + index;
+                    // The break may have been extended to the length of its pod
+                    let extended = available_slots.slots.iter().find(|slot| slot.index == index).map(|slot| slot.duration);
+                    (index, start_time, extended.unwrap_or_default().max(slot_duration))
+                }),
+            None => dynamic_slots
+                .iter()
+                .find(|ad_slot| {
+                    // The ad slot is between two segments
+                    program_date_time >= ad_slot.start_time
+                        && program_date_time < ad_slot.start_time + duration
+                })
+                .map(|ad_slot| (ad_slot.index, ad_slot.start_time, ad_slot.duration)),
+        };
+
+        // The pre-roll is cued on the first segment, the post-roll on the last
+        // one unless a break of the cycle starts in it
+        let roll_duration = Duration::from_secs(ad_breaks.target_ad_duration);
+        let (ad_slot, cue) = match (preroll, postroll) {
+            (Some((index, start_time)), _) if position == 0 => (Some((index, start_time, roll_duration)), Some("PRE")),
+            (_, Some((index, end))) if position == last_segment && ad_slot.is_none() => {
+                (Some((index, end, roll_duration)), Some("POST"))
+            }
+            _ => (ad_slot, None),
+        };
+
+        if let Some((ad_slot_index, expected_date_time, slot_duration)) = ad_slot {
+            log::debug!("Insert interstitial at time: {expected_date_time}");
+            available_slots.lifecycle.announce(ad_slot_index);
+            stats.date_ranges += 1;
+            cued.insert((ad_slot_index, expected_date_time));
+            // The pages past the first of a paged break are cued under their own name
+            let page = dynamic_slots
+                .iter()
+                .find(|slot| slot.index == ad_slot_index && slot.start_time == expected_date_time)
+                .zip(page_duration)
+                .map_or(0, |(slot, page_duration)| (slot.offset.as_millis() / page_duration.as_millis()) as usize);
+            let ad_slot_name = ad_page_name(ad_slot_index, page);
+            let url = config.asset_list_url(&ad_slot_name, session).to_string();
+            let dynamic_slot = dynamic_slots.iter().find(|slot| slot.index == ad_slot_index);
+            let asset_uri = dynamic_slot.and_then(|slot| slot.asset_uri.clone());
+            let replacement = dynamic_slot.is_some_and(|slot| slot.replacement.is_some());
+
+            let mut date_range = ExtXDateRange::builder();
+            if profile == InsertionProfile::Ssai {
+                // Only the cue-out, the break is left to the stitcher downstream
+                let splice = scte35::splice_out(ad_slot_index as u32, slot_duration);
+                date_range
+                    .id(ad_slot_name)
+                    .start_date(expected_date_time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+                    .duration(slot_duration)
+                    .scte35_out(scte35::to_hex(&splice));
+                segment.date_range = Some(date_range.build().unwrap());
+                continue;
+            }
+            date_range
+                .id(ad_slot_name)
+                .class("com.apple.hls.interstitial")
+                .start_date(
+                    expected_date_time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                )
+                .duration(slot_duration)
+                .insert_client_attribute("X-SNAP", Value::String("IN,OUT".into()))
+                .insert_client_attribute("X-RESTRICT", Value::String("SKIP,JUMP".into()));
+            match asset_uri {
+                Some(asset_uri) => date_range.insert_client_attribute("X-ASSET-URI", Value::String(asset_uri.into())),
+                None => date_range.insert_client_attribute("X-ASSET-LIST", Value::String(url.into())),
+            };
+            if let Some(cue) = cue {
+                date_range.insert_client_attribute("CUE", Value::String(cue.into()));
+            }
+            if replacement {
+                // The alternate content stands for the primary content of the
+                // break, which is skipped over rather than resumed at its start
+                let seconds = slot_duration.as_secs_f32();
+                date_range.insert_client_attribute(
+                    "X-RESUME-OFFSET",
+                    Value::Float(hls_m3u8::types::Float::new(seconds)),
+                );
+                date_range.insert_client_attribute(
+                    "X-PLAYOUT-LIMIT",
+                    Value::Float(hls_m3u8::types::Float::new(seconds)),
+                );
+            } else if is_vod {
+                // Set the resume offset to 0 for VOD streams
+                date_range.insert_client_attribute(
+                    "X-RESUME-OFFSET",
+                    Value::Float(hls_m3u8::types::Float::new(0.0)),
+                );
+            }
+            if config.scte35_markers {
+                let splice = scte35::splice_out(ad_slot_index as u32, slot_duration);
+                date_range.scte35_out(scte35::to_hex(&splice));
+            }
+            segment.date_range = Some(date_range.build().unwrap());
+        }
+    }
+
+    // The slots starting within the playlist, the static ones by index as
+    // their breaks may have been extended
+    let in_window = |start_time: &chrono::DateTime<chrono::Utc>| {
+        *start_time >= first_program_date_time && *start_time < window_end
+    };
+    let slots_cued: Vec<bool> = if is_static {
+        available_slots
+            .slots
+            .iter()
+            .filter(|slot| in_window(&slot.start_time))
+            .map(|slot| cued.iter().any(|(index, _)| *index == slot.index))
+            .collect()
+    } else {
+        dynamic_slots
+            .iter()
+            .filter(|slot| in_window(&slot.start_time))
+            .map(|slot| cued.contains(&(slot.index, slot.start_time)))
+            .collect()
+    };
+    stats.matched_slots = slots_cued.iter().filter(|cued| **cued).count() as u64;
+    stats.unmatched_slots = slots_cued.len() as u64 - stats.matched_slots;
+    if stats.unmatched_slots > 0 {
+        log::debug!("{} slots within the media playlist weren't cued", stats.unmatched_slots);
+    }
+    stats
+}
+
+// Find the static ad slot starting during the segment: slot `i` starts
+// `i * target_repeating_cycle` seconds after the reference date time
+fn find_static_ad_slot(
+    start_date_time: chrono::DateTime<chrono::Utc>,
+    program_date_time: chrono::DateTime<chrono::Utc>,
+    segment_duration: Duration,
+    ad_breaks: &AdBreakSettings,
+) -> Option<(u64, chrono::DateTime<chrono::Utc>, Duration)> {
+    let offset_ms = (program_date_time - start_date_time).num_milliseconds();
+    let segment_ms = segment_duration.as_millis() as i64;
+    let every_ms = ad_breaks.target_repeating_cycle as i64 * 1000;
+
+    // The first slot starting after `offset - segment` and no later than `offset`
+    let index = if every_ms == 0 {
+        1
+    } else {
+        ((offset_ms - segment_ms).div_euclid(every_ms) + 1).max(1)
+    };
+    let slot_offset_ms = index * every_ms;
+    let starts_in_segment = slot_offset_ms <= offset_ms && slot_offset_ms > offset_ms - segment_ms;
+    if !starts_in_segment || index as u64 >= ad_breaks.target_ad_number {
+        return None;
+    }
+
+    let slot_start = start_date_time + chrono::Duration::milliseconds(slot_offset_ms);
+    Some((index as u64, slot_start, Duration::from_secs(ad_breaks.target_ad_duration)))
+}
+
+// Extract the live edge PDT from a media playlist and store it in the shared cache.
+fn update_last_seen_pdt(playlist: &MediaPlaylist, stream: &StreamState) {
+    let time_zone = stream.config.origin_time_zone;
+    if let Some(seed) = find_program_datetime_tag(playlist, time_zone) {
+        let mut program_date_times = ProgramDateTimeCursor::new(seed, time_zone);
+        let last = playlist
+            .segments
+            .iter()
+            .map(|(_, segment)| program_date_times.advance(segment))
+            .last();
+        if let Some((last_pdt, last_dur)) = last {
+            let live_edge = last_pdt + chrono::Duration::from_std(last_dur).unwrap_or_default();
+            stream.last_seen_pdt.store(live_edge.timestamp_millis(), Ordering::Relaxed);
+            if !playlist.has_end_list {
+                check_clock_skew(stream, live_edge);
+            }
+        }
+    }
+}
+
+// Warn once when the live edge drifts further than --max-clock-skew from the
+// system clock, and once when it is back within it
+fn check_clock_skew(stream: &StreamState, live_edge: chrono::DateTime<chrono::Utc>) {
+    let Some(max_skew) = stream.config.max_clock_skew else {
+        return;
+    };
+    let skew = live_edge - chrono::Utc::now();
+    let skewed = skew.abs().to_std().unwrap_or_default() > max_skew;
+    if stream.clock_skewed.swap(skewed, Ordering::Relaxed) == skewed {
+        return;
+    }
+    if skewed {
+        let direction = if skew > chrono::TimeDelta::zero() { "ahead of" } else { "behind" };
+        log::warn!(
+            "The live edge {live_edge} of the origin is {:.1}s {direction} the system clock, check the clock synchronization (NTP) of the origin and the proxy",
+            skew.num_milliseconds().abs() as f64 / 1000.0
+        );
+    } else {
+        log::info!("The live edge of the origin is back within {}s of the system clock", max_skew.as_secs());
+    }
+}
+
+// Returns the current live edge PDT for ad slot scheduling.
+// Always fetches a fresh media playlist from origin; falls back to cached PDT if that fails.
+async fn fetch_stream_now(stream: &StreamState, client: &Client) -> chrono::DateTime<chrono::Utc> {
+    let StreamState { config, last_seen_pdt, .. } = stream;
+    if config.clock_source == ClockSource::System {
+        return chrono::Utc::now();
+    }
+    // Always fetch a fresh media playlist from origin to get the current live edge PDT.
+    // The cached value is stale if the player hasn't polled recently, causing slots to be
+    // scheduled in the past relative to the live edge.
+    if let Some(media_url) = resolve_media_playlist_url(config, client).await {
+        log::debug!("Fetching live edge PDT from origin: {media_url}");
+        if let Ok(mut res) = client.get(media_url.as_str()).send().await {
+            if let Ok(payload) = res.body().await {
+                if let Ok(text) = std::str::from_utf8(&payload) {
+                    let (text, _) = OriginKeys::strip(text);
+                    if let Ok(playlist) = MediaPlaylist::try_from(text.as_ref()) {
+                        update_last_seen_pdt(&playlist, stream);
+                        let ts = last_seen_pdt.load(Ordering::Relaxed);
+                        if let Some(dt) = chrono::DateTime::from_timestamp_millis(ts) {
+                            log::info!("Live edge PDT from origin: {dt}");
+                            return dt;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Fall back to cached PDT if origin fetch failed
+    let ts = last_seen_pdt.load(Ordering::Relaxed);
+    if ts != 0 {
+        if let Some(dt) = chrono::DateTime::from_timestamp_millis(ts) {
+            log::warn!("Origin fetch failed; using cached stream PDT: {dt}");
+            return dt;
+        }
+    }
+
+    log::warn!("Could not determine stream PDT; falling back to wall clock");
+    chrono::Utc::now()
+}
+
+// Resolves a usable media playlist URL from the configured origin.
+// For master-playlist mode: fetches the master, picks the first variant stream.
+// For origin-host mode: returns None (no known playlist path).
+async fn resolve_media_playlist_url(config: &ServerConfig, client: &Client) -> Option<url::Url> {
+    let master_url = config.origin.master_playlist_url()?;
+
+    let mut res = client.get(master_url.as_str()).send().await.ok()?;
+    let payload = res.body().await.ok()?;
+    let (text, _) = OriginKeys::strip(std::str::from_utf8(&payload).ok()?);
+    let text = text.as_ref();
+
+    // Try to parse as a master playlist and pick the first variant
+    if let Ok(master) = MasterPlaylist::try_from(text) {
+        if let Some(variant) = master.variant_streams.iter().next() {
+            if let VariantStream::ExtXStreamInf { uri, .. } = variant {
+                return master_url.join(uri).ok();
+            }
+        }
+    }
+
+    // Already a media playlist (single-rendition stream) — use it directly
+    if MediaPlaylist::try_from(text).is_ok() {
+        return Some(master_url);
+    }
+
+    None
+}
+
+// Take http get requests and parse the query string into commands
+pub async fn handle_commands(
+    req: HttpRequest,
+    stream: web::Data<StreamState>,
+    client: web::Data<Client>,
+    ad_server_client: web::Data<AdServerClient>,
+) -> Result<HttpResponse, Error> {
+    let StreamState { config, available_slots, .. } = stream.get_ref();
+    if config.insertion_mode == InsertionMode::Static {
+        return Ok(HttpResponse::BadRequest().body("Ad insertion is not supported in static mode."));
+    }
+
+    let query = req.uri().query().unwrap_or_default();
+    match InsertionCommand::from_query(query, &config.pod_templates, config.default_pod_num) {
+        Ok(command) => {
+            let stream_now = fetch_stream_now(&stream, &client).await;
+            let start_time = stream_now + chrono::Duration::milliseconds((command.in_sec * 1000.0).round() as i64);
+            let index = available_slots.lifecycle.next_index();
+            let mut ad_slot = AdSlot {
+                id: Uuid::new_v4(),
+                index,
+                start_time: start_time,
+                duration: Duration::from_secs_f64(command.duration),
+                pod_num: command.pod_num,
+                template: command.template.as_ref().map(|template| template.name.clone()),
+                ad_server_params: command
+                    .template
+                    .as_ref()
+                    .map(|template| template.ad_server_params.clone())
+                    .unwrap_or_default(),
+                asset_uri: None,
+                replacement: command.replacement.as_ref().map(Url::to_string),
+                offset: Duration::ZERO,
+            };
+            log::debug!("Received ad slot: {:?}", ad_slot);
+
+            // Two DATERANGEs over the same segments would fight over them
+            let overlapping = available_slots.slots.iter().find(|slot| slot.overlaps(&ad_slot)).map(|slot| slot.clone());
+            if let Some(scheduled) = overlapping {
+                return Ok(handle_overlapping_slot(available_slots, &config.slot_overlap, ad_slot, scheduled));
+            }
+            // The pod is known before the break is first announced, the
+            // attributes of a DATERANGE can't change once the players saw it
+            if command.inline {
+                ad_slot.asset_uri = match &ad_slot.replacement {
+                    Some(replacement) => Some(replacement.clone()),
+                    None => inline_asset_uri(&ad_slot, &stream, &ad_server_client.0).await,
+                };
+            }
+            let delivery = if ad_slot.asset_uri.is_some() { "inline" } else { "list" };
+            available_slots.schedule(ad_slot);
+
+            let response = object! {
+                status: "success",
+                command: {
+                    "index": index,
+                    "in_sec": command.in_sec,
+                    "duration": command.duration,
+                    "pod_num": command.pod_num,
+                    "template": command.template.as_ref().map(|template| template.name.as_str()),
+                    "delivery": delivery,
+                    "replacement": command.replacement.as_ref().map(Url::as_str),
+                }
+            };
+            Ok(HttpResponse::Ok()
+                .content_type(mime::APPLICATION_JSON)
+                .body(response.pretty(2)))
+        }
+        Err(err) => {
+            let response = object! {
+                status: "error",
+                message: err
+            };
+            Ok(HttpResponse::BadRequest()
+                .content_type(mime::APPLICATION_JSON)
+                .body(response.pretty(2)))
+        }
+    }
+}
+
+// The HLS stream to inline into the DATERANGE of the break, when its pod is a
+// single HLS creative the same for all the viewers. The pod is requested now,
+// and kept in the ad pod cache for the asset lists of the break
+async fn inline_asset_uri(slot: &AdSlot, stream: &StreamState, client: &Client) -> Option<String> {
+    let StreamState { config, ad_pod_cache, .. } = stream;
+    let ad_breaks = config.ad_breaks();
+    let name = slot.name();
+    if let Some(test_asset) = &config.test_asset {
+        return Some(test_asset.url.to_string());
+    }
+    // The viewers get pods of their own otherwise
+    let per_session = config.test_pods.is_some()
+        || ad_breaks.session_targeting()
+        || !config.rules.is_empty()
+        || !config.experiments.is_empty()
+        || config.localization.is_enabled();
+    if per_session {
+        log::info!("The pod of {name} depends on the session, it is served as an asset list");
+        return None;
+    }
+
+    let ad_url = slot_ad_server_url(&ad_breaks.ad_server_url, slot, "default_user", &UserDefinedQueryParams::default());
+    let fetch = || fetch_ad_pod(client, &ad_url, None, config.max_vast_size, &config.faults);
+    let payload = match ad_pod_cache.is_enabled() {
+        true => ad_pod_cache.get_or_fetch(&name, slot.end_time(), fetch).await,
+        false => fetch().await,
+    };
+    let payload = payload
+        .inspect_err(|err| log::warn!("Failed to request the pod of {name}: {err}"))
+        .ok()
+        .flatten()?;
+    let xml = String::from_utf8_lossy(&payload);
+    let mut vast = config.vast_validator.parse(&ad_url, &xml);
+    // The pod is shared by all viewers, interactive or not
+    remove_conditional_ads(&mut vast);
+    let raw = get_all_raw_creatives_from_vast(&vast, &config.bumpers);
+    let transcoded = get_all_transcoded_creatives_from_vast(&vast, &config.bumpers);
+    let asset_uri = match (raw.as_slice(), transcoded.as_slice()) {
+        ([], [creative]) => creative
+            .linear
+            .as_ref()
+            .and_then(|linear| get_media_urls_from_linear(linear).into_iter().find(|url| is_transcoded_media_segment(url))),
+        _ => None,
+    };
+    match &asset_uri {
+        Some(asset_uri) => log::info!("Inlined the pod of {name}: {asset_uri}"),
+        None => log::info!(
+            "The pod of {name} has {} creatives, it is served as an asset list",
+            raw.len() + transcoded.len()
+        ),
+    }
+    asset_uri
+}
+
+// Reject a new break overlapping a scheduled one, or widen the scheduled one
+// to cover both. A break the players may have seen can't be widened, nor one
+// that would then overlap another break
+fn handle_overlapping_slot(
+    available_slots: &AvailableAdSlots,
+    policy: &SlotOverlapPolicy,
+    ad_slot: AdSlot,
+    scheduled: AdSlot,
+) -> HttpResponse {
+    let conflict = |message: String, overlapping: &AdSlot| {
+        log::warn!(
+            "Rejected a break from {} for {}s: {message}",
+            ad_slot.start_time,
+            ad_slot.duration.as_secs_f64()
+        );
+        let response = object! {
+            status: "error",
+            message: message,
+            overlapping: {
+                "index": overlapping.index,
+                "start_time": overlapping.start_time.to_rfc3339(),
+                "duration": overlapping.duration.as_secs_f64(),
+            },
+        };
+        HttpResponse::Conflict()
+            .content_type(mime::APPLICATION_JSON)
+            .body(response.pretty(2))
+    };
+    match policy {
+        SlotOverlapPolicy::Reject => {
+            conflict(format!("The break overlaps the scheduled break {}", scheduled.name()), &scheduled)
+        }
+        SlotOverlapPolicy::Merge => {
+            let announced = available_slots.lifecycle.state(scheduled.index) != Some(SlotState::Scheduled);
+            if announced || scheduled.start_time <= chrono::Utc::now() {
+                return conflict(
+                    format!("The break overlaps the break {}, which has been announced already", scheduled.name()),
+                    &scheduled,
+                );
+            }
+            let start_time = scheduled.start_time.min(ad_slot.start_time);
+            let end_time = scheduled.end_time().max(ad_slot.end_time());
+            let merged = AdSlot {
+                start_time,
+                duration: (end_time - start_time).to_std().unwrap_or_default(),
+                pod_num: scheduled.pod_num.max(ad_slot.pod_num),
+                ..scheduled.clone()
+            };
+            let overlapping = available_slots
+                .slots
+                .iter()
+                .find(|slot| slot.index != scheduled.index && slot.overlaps(&merged))
+                .map(|slot| slot.clone());
+            if let Some(overlapping) = overlapping {
+                return conflict(
+                    format!(
+                        "Merged with {}, the break would overlap the scheduled break {}",
+                        scheduled.name(),
+                        overlapping.name()
+                    ),
+                    &overlapping,
+                );
+            }
+            if !available_slots.replace(&scheduled, merged.clone()) {
+                return conflict(format!("The break {} changed while merging", scheduled.name()), &scheduled);
+            }
+            log::info!(
+                "Merged a break from {} for {}s into {}, now from {} for {}s",
+                ad_slot.start_time,
+                ad_slot.duration.as_secs_f64(),
+                merged.name(),
+                merged.start_time,
+                merged.duration.as_secs_f64()
+            );
+            let response = object! {
+                status: "merged",
+                command: {
+                    "index": merged.index,
+                    "start_time": merged.start_time.to_rfc3339(),
+                    "duration": merged.duration.as_secs_f64(),
+                    "pod_num": merged.pod_num,
+                },
+                merged_with: {
+                    "index": scheduled.index,
+                    "start_time": scheduled.start_time.to_rfc3339(),
+                    "duration": scheduled.duration.as_secs_f64(),
+                },
+            };
+            HttpResponse::Ok()
+                .content_type(mime::APPLICATION_JSON)
+                .body(response.pretty(2))
+        }
+    }
+}
+
+/// What an interstitial request is for, from the path of the path-style routes
+/// or else from the query
+struct InterstitialTarget {
+    slot: Option<String>,
+    session: String,
+    // The raw creative whose playlist is asked for, none for the asset lists
+    ad_id: Option<String>,
+}
+
+impl InterstitialTarget {
+    fn new(req: &HttpRequest) -> Self {
+        let info = req.match_info();
+        // The player adds its _HLS_primary_id to the asset lists of the
+        // playlists that didn't know the session
+        let session = info
+            .get("session")
+            .filter(|session| *session != "default_user")
+            .map(str::to_string)
+            .or_else(|| get_query_param(req, HLS_PRIMARY_ID))
+            .unwrap_or_else(|| "default_user".to_string());
+        Self {
+            slot: info.get("slot").map(str::to_string).or_else(|| get_query_param(req, HLS_INTERSTITIAL_ID)),
+            session,
+            ad_id: info.get("ad_id").map(str::to_string).or_else(|| get_query_param(req, AD_ID)),
+        }
+    }
+
+    // Whether the URL is one of <route>/<slot>/<id>/<file>
+    fn is_path_style(url: &Url, route: &str) -> bool {
+        let Some(segments) = url.path_segments() else {
+            return false;
+        };
+        let segments = segments.rev().take(4).collect::<Vec<_>>();
+        segments.len() == 4
+            && segments[3] == route
+            && [ASSET_LIST_FILE, CREATIVE_PLAYLIST_FILE].contains(&segments[0])
+    }
+}
+
+/// The asset lists of the interstitials, and the creatives played from them.
+/// A failing asset list is answered with its error as JSON
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_interstitials(
+    req: HttpRequest,
+    stream: web::Data<StreamState>,
+    available_ads: web::Data<AvailableAds>,
+    client: web::Data<Client>,
+    ad_server_client: web::Data<AdServerClient>,
+    user_defined_query_params: web::Data<UserDefinedQueryParams>,
+    beacons: web::Data<BeaconDispatcher>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let target = InterstitialTarget::new(&req);
+    let is_asset_list = target.ad_id.is_none();
+    let interstitial_id = target.slot.clone();
+    let result = serve_interstitial(
+        req,
+        target,
+        stream,
+        available_ads,
+        client,
+        ad_server_client,
+        user_defined_query_params,
+        beacons,
+        metrics.clone(),
+    )
+    .await;
+    match result {
+        Err(err) if is_asset_list => {
+            let response = err.error_response();
+            let status = response.status();
+            log::error!("Asset list of {} failed with {status}: {err}", interstitial_id.as_deref().unwrap_or("default_ad"));
+            metrics.inc("asset_list_errors_total", &[("status", status.as_str())]);
+            let body = object! {
+                "error": {
+                    "status": status.as_u16(),
+                    "reason": status.canonical_reason(),
+                    "message": err.to_string(),
+                    "interstitial_id": interstitial_id,
+                },
+            };
+            Ok(HttpResponse::build(status)
+                .insert_header((header::CACHE_CONTROL, "no-store"))
+                .content_type(mime::APPLICATION_JSON)
+                .body(body.dump()))
+        }
+        result => result,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve_interstitial(
+    req: HttpRequest,
+    target: InterstitialTarget,
+    stream: web::Data<StreamState>,
+    available_ads: web::Data<AvailableAds>,
+    client: web::Data<Client>,
+    ad_server_client: web::Data<AdServerClient>,
+    user_defined_query_params: web::Data<UserDefinedQueryParams>,
+    beacons: web::Data<BeaconDispatcher>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let StreamState { config, available_slots, session_asset_lists, .. } = stream.get_ref();
+    let req_url = req.full_url();
+
+    let interstitial_id = target.slot.unwrap_or_else(|| "default_ad".to_string());
+    let user_id = target.session;
+
+    // For non-transcoded ads
+    if let Some(linear_id) = target.ad_id {
+        if let Some(segment_index) = get_query_param(&req, SEGMENT_INDEX) {
+            return handle_raw_segment_request(
+                &linear_id,
+                &segment_index,
+                &user_id,
+                config,
+                available_ads,
+                client,
+                beacons,
+            )
+            .await;
+        }
+        return handle_raw_asset_request(req_url, &interstitial_id, &linear_id, &user_id, available_ads, config)
+            .await;
+    }
+    log::info!("Received interstitial request from user {user_id} for slot {interstitial_id}");
+    let started_at = std::time::Instant::now();
+    // The page of a paged break is served as a break of its own
+    let (slot_name, page) = split_page_name(&interstitial_id);
+    let slot = available_slots
+        .slots
+        .iter()
+        .find(|slot| slot.name() == slot_name)
+        .and_then(|slot| slot.page(page, config.pod_page_duration));
+    if let Some(slot) = &slot {
+        available_slots.lifecycle.fetch(slot.index);
+    }
+
+    // A player retrying its request within the slot gets the asset list it was served
+    let build = || {
+        build_asset_list(
+            &req,
+            req_url,
+            &interstitial_id,
+            &user_id,
+            slot.as_ref(),
+            &stream,
+            available_ads,
+            &client,
+            &ad_server_client,
+            &user_defined_query_params,
+            &metrics,
+        )
+    };
+    let response = match &slot {
+        Some(slot) if session_asset_lists.is_enabled() && user_id != "default_user" => {
+            session_asset_lists
+                .get_or_build(&interstitial_id, &user_id, slot.end_time(), build)
+                .await
+        }
+        _ => build().await,
+    };
+    // How close to its start the decisioning of a break gets, failed or not
+    if let Some(slot) = &slot {
+        let latency = started_at.elapsed();
+        available_slots.lifecycle.record_latency(slot.index, latency);
+        metrics.observe_summary(
+            "asset_list_latency_seconds",
+            &[("channel", &config.channel), ("slot", &slot.name())],
+            latency,
+        );
+    }
+
+    Ok(asset_list_response(&req, config, response?))
+}
+
+// The asset list of a break served to a session: the replacement, the test
+// asset or pods, or the ad server's pod as the rules and experiments decide
+#[allow(clippy::too_many_arguments)]
+async fn build_asset_list(
+    req: &HttpRequest,
+    req_url: Url,
+    interstitial_id: &str,
+    user_id: &str,
+    slot: Option<&AdSlot>,
+    stream: &StreamState,
+    available_ads: web::Data<AvailableAds>,
+    client: &Client,
+    ad_server_client: &AdServerClient,
+    user_defined_query_params: &web::Data<UserDefinedQueryParams>,
+    metrics: &Metrics,
+) -> Result<String, Error> {
+    let StreamState { config, ad_pod_cache, pod_prefetch, .. } = stream;
+    let ad_breaks = config.ad_breaks();
+
+    // Alternate content replaces the primary content for the whole break
+    let replacement = slot
+        .as_ref()
+        .and_then(|slot| slot.replacement.clone().map(|url| (url, slot.duration.as_secs_f64())));
+    if let Some((replacement, duration)) = replacement {
+        let ad = Ad { duration, ..Default::default() };
+        let asset = to_ad_asset_json(&replacement, &ad, 0.0, config.creative_signaling);
+        let response = to_asset_list_json_string(vec![asset], duration, config.creative_signaling);
+        log::info!("Serving the replacement of slot {interstitial_id} to user {user_id}: {replacement}");
+        metrics.inc("replacement_asset_lists_total", &[]);
+        return Ok(response);
+    }
+
+    // If a test asset is configured, skip VAST entirely and serve it directly.
+    if let Some(test_asset) = &config.test_asset {
+        let duration = test_asset.duration as f64;
+        let ad = Ad { duration, ..Default::default() };
+        let asset = to_ad_asset_json(test_asset.url.as_str(), &ad, duration, config.creative_signaling);
+        let response = to_asset_list_json_string(vec![asset], duration, config.creative_signaling);
+        log::info!("Serving test asset directly (no VAST): {response}");
+        return Ok(response);
+    }
+
+    // Test pods are served in turn instead of the ad server's
+    if let Some(test_pods) = &config.test_pods {
+        let response = wrap_test_pod(test_pods.next_pod(), &req_url, user_id, slot, config, &available_ads);
+        log::info!("Serving a test pod (no VAST): {response}");
+        return Ok(response);
+    }
+
+    // Blackouts and regional restrictions come first
+    let rule = config.rules.evaluate(req, &config.channel);
+    if let Some(rule) = rule {
+        log::info!("Rule {} ({}) applies to session {user_id}", rule.name, rule.policy.to_str());
+        metrics.inc("rule_matches_total", &[("rule", &rule.name), ("policy", rule.policy.to_str())]);
+    }
+    match rule.map(|rule| &rule.policy) {
+        Some(RulePolicy::NoAds) => {
+            let body = to_asset_list_json_string(Vec::new(), 0.0, config.creative_signaling);
+            return Ok(body);
+        }
+        Some(RulePolicy::Slate(slate_url)) => {
+            let duration = slot.map_or(0.0, |slot| slot.duration.as_secs_f64());
+            let ad = Ad { duration, ..Default::default() };
+            let asset = to_ad_asset_json(slate_url.as_str(), &ad, 0.0, config.creative_signaling);
+            let body = to_asset_list_json_string(vec![asset], duration, config.creative_signaling);
+            return Ok(body);
+        }
+        _ => {}
+    }
+
+    // The A/B experiment variants of the session decide on the ad request
+    let variants = config.experiments.assign(user_id);
+    for variant in &variants {
+        log::info!("Experiment {}: session {user_id} is in variant {}", variant.experiment, variant.name);
+        metrics.inc(
+            "experiment_asset_lists_total",
+            &[("experiment", &variant.experiment), ("variant", &variant.name)],
+        );
+    }
+    if let Some(variant) = variants.iter().find(|variant| variant.no_ads) {
+        log::info!("Serving no ads to session {user_id} of variant {}", variant.id());
+        let body = to_asset_list_json_string(Vec::new(), 0.0, config.creative_signaling);
+        return Ok(body);
+    }
+    let ad_server_url = match rule.map(|rule| &rule.policy) {
+        Some(RulePolicy::AdServer(ad_server_url)) => ad_server_url,
+        _ => variants
+            .iter()
+            .find_map(|variant| variant.ad_server_url.as_ref())
+            .unwrap_or(&ad_breaks.ad_server_url),
+    };
+    let mut ad_url = build_ad_server_url(ad_server_url, slot, user_id, user_defined_query_params).await?;
+    for (key, value) in variants.iter().flat_map(|variant| &variant.ad_server_params) {
+        ad_url.query_pairs_mut().append_pair(key, value);
+    }
+    let languages = config.localization.languages(req);
+    let accept_language = config.localization.localize_request(&mut ad_url, &languages);
+
+    // Viewers with their own query parameters get their own ad pod
+    let personalized = user_defined_query_params.contains(user_id);
+    let prefetched = pod_prefetch.take(user_id, ad_url.as_str()).await;
+    let payload = match slot {
+        _ if prefetched.is_some() => prefetched,
+        Some(slot) if ad_pod_cache.is_enabled() && !personalized && !ad_breaks.session_targeting() => {
+            let slot_end = slot.end_time();
+            // The variants of the experiments, the rules and the languages share their own pods
+            let pod_key = variants
+                .iter()
+                .map(|variant| variant.id())
+                .chain(rule.map(|rule| rule.name.clone()))
+                .chain(accept_language.clone())
+                .fold(interstitial_id.to_string(), |key, id| format!("{key} {id}"));
+            ad_pod_cache
+                .get_or_fetch(&pod_key, slot_end, || {
+                    fetch_ad_pod(&ad_server_client.0, &ad_url, accept_language.as_deref(), config.max_vast_size, &config.faults)
+                })
+                .await?
+        }
+        _ => fetch_ad_pod(&ad_server_client.0, &ad_url, accept_language.as_deref(), config.max_vast_size, &config.faults).await?,
+    }
+    // A failed ad server reply gets an empty asset list
+    .unwrap_or_default();
+    let xml = String::from_utf8_lossy(&payload);
+    log::debug!("VAST response from ad server \n{:?}", xml);
+    // An invalid VAST is reported and gets an empty asset list
+    let mut vast = config.vast_validator.parse(&ad_url, &xml);
+    if !is_interactive_player(req, user_id, user_defined_query_params, config) {
+        let removed = remove_conditional_ads(&mut vast);
+        if removed > 0 {
+            log::info!("Left {removed} conditional ads out of the pod of session {user_id}, its player isn't interactive");
+        }
+    }
+    config.localization.select(&mut vast, &languages);
+    let context = SelectionContext {
+        channel: &config.channel,
+        slot: interstitial_id,
+        session: user_id,
+        duration: slot.map_or(0.0, |slot| slot.duration.as_secs_f64()),
+    };
+    config.ad_selection.select(client, &mut vast, &context).await;
+    // The VAST durations are sometimes missing or wrong, probe the media files.
+    // Inferring the progress needs the fragments of the MP4s to segment them
+    if (available_ads.durations.is_enabled() || config.infer_quartiles) && config.test_asset.is_none() {
+        let media_files = get_all_raw_creatives_from_vast(&vast, &config.bumpers)
+            .into_iter()
+            .chain(get_all_transcoded_creatives_from_vast(&vast, &config.bumpers))
+            .filter_map(|creative| {
+                let linear = creative.linear.as_ref()?;
+                let media_url = get_media_urls_from_linear(linear).first()?.clone();
+                Some((media_url, get_duration_from_linear(linear)))
+            })
+            .collect::<Vec<_>>();
+        available_ads.durations.probe_all(client, media_files, config.infer_quartiles).await;
+    }
+    // Have the MP4-only creatives transcoded for the next asset lists
+    if let Some(transcoder) = available_ads.transcoder.as_ref().filter(|_| config.test_asset.is_none()) {
+        for creative in get_all_raw_creatives_from_vast(&vast, &config.bumpers) {
+            let linear = creative.linear.as_ref().unwrap();
+            if let Some(media_url) = get_media_urls_from_linear(linear).first() {
+                transcoder.submit(client, &get_universal_ad_ids_from_creative(creative), media_url);
+            }
+        }
+    }
+    // Wrap the VAST into JSON
+    let response = wrap_into_assets(
+        vast,
+        req_url,
+        interstitial_id,
+        user_id,
+        slot,
+        stream,
+        available_ads,
+    );
+    log::info!("asset json reply \n{response}");
+
+    Ok(response)
+}
+
+// An asset list in the content type the player accepts, JSON unless it only
+// accepts text, with the --asset-list-header headers
+fn asset_list_response(req: &HttpRequest, config: &ServerConfig, body: String) -> HttpResponse {
+    if let Some(request_log) = req.app_data::<web::Data<Option<RequestLog>>>().and_then(|log| log.as_ref().as_ref()) {
+        log_decision(req, config, request_log, &body);
+    }
+    let accept = req.headers().get(header::ACCEPT).and_then(|value| value.to_str().ok());
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(asset_list_content_type(accept))
+        .insert_header((header::VARY, "Accept"));
+    for header in &config.asset_list_headers {
+        response.insert_header(header.clone());
+    }
+    response.body(body)
+}
+
+// The decision entry of the request log: the assets served to a session for a break
+fn log_decision(req: &HttpRequest, config: &ServerConfig, request_log: &RequestLog, body: &str) {
+    let target = InterstitialTarget::new(req);
+    let asset_list = json::parse(body).unwrap_or_else(|_| object! {});
+    let assets = asset_list["ASSETS"]
+        .members()
+        .map(|asset| {
+            object! {
+                "uri": asset["URI"].clone(),
+                "duration": asset["DURATION"].clone(),
+            }
+        })
+        .collect::<Vec<_>>();
+    let duration = asset_list["ASSETS"]
+        .members()
+        .filter_map(|asset| asset["DURATION"].as_f64())
+        .sum::<f64>();
+    request_log.record(
+        "decision",
+        object! {
+            "channel": config.channel.as_str(),
+            "slot": target.slot,
+            "session": target.session,
+            "assets": assets,
+            "duration": duration,
+        },
+    );
+}
+
+// The preferred of the media types of an Accept header an asset list can be served as
+fn asset_list_content_type(accept: Option<&str>) -> &'static str {
+    let mut ranges = accept
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next().filter(|media_type| !media_type.is_empty())?.to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((media_type, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect::<Vec<_>>();
+    // The first of the ranges of the highest quality wins
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges
+        .iter()
+        .find_map(|(media_type, _)| match media_type.as_str() {
+            "application/json" | "application/*" | "*/*" => Some(ASSET_LIST_JSON),
+            "text/plain" | "text/*" => Some(ASSET_LIST_TEXT),
+            _ => None,
+        })
+        // Players with an Accept header of other types still get JSON
+        .unwrap_or(ASSET_LIST_JSON)
+}
+
+// Whether the player runs the interactive layer of the ads, as told by the
+// interactive query parameter of its asset list or master playlist request
+fn is_interactive_player(
+    req: &HttpRequest,
+    user_id: &str,
+    user_defined_query_params: &UserDefinedQueryParams,
+    config: &ServerConfig,
+) -> bool {
+    let is_truthy = |value: &str| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes");
+    let session_param = || {
+        let query = user_defined_query_params.get(user_id)?;
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == INTERACTIVE_PARAM)
+            .map(|(_, value)| value.into_owned())
+    };
+    !config.strip_interactive
+        && get_query_param(req, INTERACTIVE_PARAM)
+            .or_else(session_param)
+            .is_some_and(|value| is_truthy(&value))
+}
+
+async fn fetch_ad_pod(
+    client: &Client,
+    ad_url: &Url,
+    accept_language: Option<&str>,
+    max_size: usize,
+    faults: &FaultInjector,
+) -> Result<Option<Bytes>, Error> {
+    log::info!("Request ad pod with url {ad_url}");
+    faults.delay().await;
+    if faults.fail() {
+        log::error!("Injected ad server failure for {ad_url}");
+        return Ok(None);
+    }
+    let mut request = client
+        .get(ad_url.as_str())
+        // Specify the Accept header to request XML
+        .insert_header((header::ACCEPT, APPLICATION_XML));
+    if let Some(accept_language) = accept_language {
+        request = request.insert_header((header::ACCEPT_LANGUAGE, accept_language));
+    }
+    let mut res = request
+        .send()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // Failed replies have no VAST, which keeps them out of the ad pod cache
+    if !res.status().is_success() {
+        log::error!("Ad server answered with status {}", res.status());
+        return Ok(None);
+    }
+
+    let body = res.body().limit(max_size).await.map_err(|err| match err {
+        awc::error::PayloadError::Overflow => {
+            log::error!("VAST response from {ad_url} exceeds {max_size} bytes");
+            error::ErrorBadGateway(format!("VAST response exceeds the maximum size of {max_size} bytes"))
+        }
+        err => error::ErrorInternalServerError(err),
+    })?;
+
+    Ok(Some(faults.malform_vast(body)))
+}
+
+// Seconds between the start of the content stream and the ad slot
+fn slot_content_playhead(slot: &AdSlot, stream: &StreamState) -> f64 {
+    let StreamState { config, available_slots, epoch, .. } = stream;
+    if config.insertion_mode == InsertionMode::Static {
+        // Static slots are placed relative to the stream start (see generate_static_ad_slots)
+        let index = slot.index.saturating_sub(available_slots.lifecycle.static_base());
+        (index * config.ad_breaks().target_repeating_cycle) as f64
+    } else {
+        let offset = slot.start_time - epoch.get();
+        offset.num_milliseconds().max(0) as f64 / 1000.0
+    }
+}
+
+// The impression, error or tracking URLs for a tracking event of the ad
+fn ad_event_urls<'a>(ad: &'a Ad, event: &str) -> Vec<&'a String> {
+    if event.eq_ignore_ascii_case(IMPRESSION_EVENT) {
+        return ad.impressions.iter().collect();
+    }
+    if event.eq_ignore_ascii_case(ERROR_EVENT) {
+        return ad.errors.iter().collect();
+    }
+
+    ad.tracking
+        .iter()
+        .filter(|tracking| tracking.event.eq_ignore_ascii_case(event))
+        .flat_map(|tracking| tracking.urls.iter())
+        .collect()
+}
+
+fn make_https_client(upstream: &UpstreamOptions) -> Client {
+    let mut builder = Client::builder()
+        .add_default_header((header::USER_AGENT, upstream.user_agent.as_str()))
+        .timeout(upstream.timeout)
+        .max_redirects(upstream.max_redirects);
+    for header in &upstream.headers {
+        builder = builder.add_default_header(header.clone());
+    }
+
+    builder
+        // a "connector" wraps the stream into an encrypted connection
+        .connector(
+            Connector::new()
+                .connector(ProxyConnector::new(
+                    actix_tls::connect::Connector::new(actix_tls::connect::Resolver::custom(
+                        upstream.resolver.clone(),
+                    ))
+                    .service(),
+                    upstream.proxy.clone(),
+                ))
+                .rustls_0_23(upstream.tls.clone())
+                .limit(upstream.max_connections)
+                .conn_keep_alive(upstream.keep_alive)
+                .conn_lifetime(upstream.connection_lifetime)
+                .initial_window_size(upstream.stream_window)
+                .initial_connection_window_size(upstream.connection_window)
+                .timeout(upstream.connect_timeout),
+        )
+        // Within the redirects, to send and keep the cookies of every hop
+        .wrap(CookieJar::new(upstream.cookies.clone()))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use awc::http::StatusCode;
+
+    fn slot(index: u64, start_time: chrono::DateTime<chrono::Utc>) -> AdSlot {
+        AdSlot {
+            id: Uuid::new_v4(),
+            index,
+            start_time,
+            duration: Duration::from_secs(30),
+            pod_num: 1,
+            template: None,
+            ad_server_params: Vec::new(),
+            asset_uri: None,
+            replacement: None,
+            offset: Duration::ZERO,
+        }
+    }
+
+    fn ad(channel: &str, slot: Option<Uuid>, requested_at: chrono::DateTime<chrono::Utc>) -> Ad {
+        Ad {
+            ad_id: Uuid::new_v4(),
+            requested_at,
+            channel: channel.to_string(),
+            slot,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn drops_the_ads_along_with_their_slot() {
+        let now = chrono::Utc::now();
+        let available_ads = AvailableAds::default();
+        let available_slots = AvailableAdSlots::new(&available_ads);
+        let (ended, upcoming, gone) = (slot(1, now - chrono::Duration::minutes(10)), slot(2, now), slot(3, now));
+        available_slots.schedule(ended.clone());
+        available_slots.schedule(upcoming.clone());
+
+        let expired = available_ads.keep(ad("news", Some(ended.id), now));
+        let served = available_ads.keep(ad("news", Some(upcoming.id), now));
+        let orphaned = available_ads.keep(ad("news", Some(gone.id), now));
+        let other_channel = available_ads.keep(ad("sports", Some(gone.id), now));
+        let unslotted = available_ads.keep(ad("news", None, now - chrono::Duration::hours(2)));
+        let recent = available_ads.keep(ad("news", None, now));
+
+        available_slots.expire(now - chrono::Duration::minutes(1));
+        assert!(!available_ads.linears.contains_key(&expired.ad_id));
+        assert_eq!(available_ads.linears.len(), 5);
+
+        assert_eq!(available_slots.collect_ads("news"), (1, 1));
+        assert!(!available_ads.linears.contains_key(&orphaned.ad_id));
+        assert!(!available_ads.linears.contains_key(&unslotted.ad_id));
+        for kept in [&served, &other_channel, &recent] {
+            assert!(available_ads.linears.contains_key(&kept.ad_id));
+        }
+
+        available_slots.cancel(upcoming.index);
+        assert!(!available_ads.linears.contains_key(&served.ad_id));
+        assert_eq!(available_ads.linears.len(), 2);
+    }
+    #[test]
+    fn extends_a_break_only_when_it_overlaps_no_other() {
+        let start = chrono::Utc::now() + chrono::Duration::seconds(60);
+        let ad_breaks = AdBreakSettings {
+            ad_server_url: Url::parse("http://ads.example.com/vast").unwrap(),
+            target_ad_duration: 10,
+            target_repeating_cycle: 30,
+            target_ad_number: 1000,
+        };
+        let url = Url::parse("http://origin.example.com/index.m3u8").unwrap();
+        let config = ServerConfig::new(url.clone(), url, ad_breaks).with_pod_fill(PodFillPolicy::Extend, None);
+        let origin_cache = OriginCache::new(Duration::ZERO, 1024, web::Data::new(Metrics::default()));
+        let stream = StreamState::new(config, origin_cache, &AvailableAds::default());
+        let (first, second) = (slot(1, start), slot(2, start + chrono::Duration::seconds(40)));
+        stream.available_slots.schedule(first.clone());
+        stream.available_slots.schedule(second.clone());
+        let pod = |duration: f64| vec![object! { "URI": "http://ads.example.com/ad.m3u8", "DURATION": duration }];
+
+        let mut assets = pod(35.0);
+        fit_pod(&mut assets, &[false], &first, &stream);
+        assert_eq!(stream.available_slots.slots.get(&1).unwrap().duration, Duration::from_secs(35));
+
+        // 45s would run into the next break, the pod is served as is
+        let mut assets = pod(45.0);
+        let extended = stream.available_slots.slots.get(&1).unwrap().clone();
+        fit_pod(&mut assets, &[false], &extended, &stream);
+        assert_eq!(*stream.available_slots.slots.get(&1).unwrap(), extended);
+        assert_eq!(*stream.available_slots.slots.get(&2).unwrap(), second);
+        assert_eq!(pod_duration(&assets), 45.0);
+    }
+
+    #[test]
+    fn merges_a_break_only_into_an_unannounced_slot_it_leaves_apart() {
+        let now = chrono::Utc::now();
+        let merge = |available_slots: &AvailableAdSlots, ad_slot: AdSlot, scheduled: &AdSlot| {
+            handle_overlapping_slot(available_slots, &SlotOverlapPolicy::Merge, ad_slot, scheduled.clone()).status()
+        };
+        let available_slots = AvailableAdSlots::new(&AvailableAds::default());
+        let scheduled = slot(1, now + chrono::Duration::seconds(60));
+        available_slots.schedule(scheduled.clone());
+        available_slots.schedule(slot(2, now + chrono::Duration::seconds(100)));
+
+        // Widened to 120s, the break would run into the next one
+        let status = merge(&available_slots, slot(3, now + chrono::Duration::seconds(80)), &scheduled);
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(*available_slots.slots.get(&1).unwrap(), scheduled);
+
+        let status = merge(&available_slots, slot(3, now + chrono::Duration::seconds(50)), &scheduled);
+        assert_eq!(status, StatusCode::OK);
+        let merged = available_slots.slots.get(&1).unwrap().clone();
+        assert_eq!(merged.start_time, now + chrono::Duration::seconds(50));
+        assert_eq!(merged.duration, Duration::from_secs(40));
+        assert_eq!(available_slots.slots.len(), 2);
+
+        // The players may have seen the DATERANGE already
+        available_slots.lifecycle.announce(1);
+        let status = merge(&available_slots, slot(3, now + chrono::Duration::seconds(40)), &merged);
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(*available_slots.slots.get(&1).unwrap(), merged);
+    }
+}
@@ -0,0 +1,138 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+// First file descriptor passed by systemd (SD_LISTEN_FDS_START)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// A socket to accept the connections on, instead of binding the listen address
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "tcp socket".to_string(),
+            },
+            #[cfg(unix)]
+            Self::Unix(listener) => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix socket".to_string(),
+                },
+                Err(_) => "unix socket".to_string(),
+            },
+        }
+    }
+}
+
+/// Resolve the listen addresses, IP literals with or without brackets
+/// (`[::]`, `::1`) or host names, to the socket addresses to bind
+pub fn resolve_addrs(addrs: &[String], port: u16) -> io::Result<Vec<SocketAddr>> {
+    let mut resolved = Vec::new();
+    for addr in addrs {
+        let host = addr.trim().trim_start_matches('[').trim_end_matches(']');
+        let socket_addrs = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => (host, port)
+                .to_socket_addrs()
+                .map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Failed to resolve the listen address {addr}: {err}"),
+                    )
+                })?
+                .collect(),
+        };
+        for socket_addr in socket_addrs {
+            if !resolved.contains(&socket_addr) {
+                resolved.push(socket_addr);
+            }
+        }
+    }
+
+    if resolved.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No listen address"));
+    }
+    Ok(resolved)
+}
+
+/// Bind a TCP socket. IPv6 sockets only accept IPv6 connections, so `::` and
+/// `0.0.0.0` can be bound on the same port.
+pub fn bind_tcp(addr: SocketAddr, backlog: u32) -> io::Result<Listener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.try_into().unwrap_or(i32::MAX))?;
+
+    Ok(Listener::Tcp(socket.into()))
+}
+
+/// Bind a Unix domain socket, replacing the socket file left by a previous run
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> io::Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(Listener::Unix(UnixListener::bind(path)?))
+}
+
+#[cfg(not(unix))]
+pub fn bind_unix(_path: &std::path::Path) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+/// The sockets passed by systemd socket activation (`LISTEN_FDS`), empty when
+/// the proxy wasn't started by a socket unit
+#[cfg(unix)]
+pub fn systemd_listeners() -> io::Result<Vec<Listener>> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    // The variables are meant for the process systemd started, not for its children
+    let is_for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = match std::env::var("LISTEN_FDS") {
+        Ok(count) if is_for_us => count.parse::<i32>().map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid LISTEN_FDS {count}: {err}"))
+        })?,
+        _ => return Ok(Vec::new()),
+    };
+
+    let listeners = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd hands these descriptors over to this process, nothing else owns them
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // Only sockets of the inet families have an address a TcpListener can read
+            match listener.local_addr() {
+                Ok(_) => Listener::Tcp(listener),
+                Err(_) => Listener::Unix(unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) }),
+            }
+        })
+        .collect();
+
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub fn systemd_listeners() -> io::Result<Vec<Listener>> {
+    Ok(Vec::new())
+}
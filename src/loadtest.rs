@@ -0,0 +1,313 @@
+use actix_web::{App, HttpServer, web};
+use ad_proxy::mock_origin::{self, MockOrigin};
+use awc::Client;
+use clap::Parser;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use url::Url;
+
+const MASTER_PLAYLIST: &str = "/loadtest/master.m3u8";
+const VAST_PATH: &str = "/vast";
+
+/// Simulates concurrent live sessions against a running ad proxy and reports
+/// request latency percentiles. A mock origin and ad server are started
+/// unless --no-mock is set, e.g.:
+///   loadtest --sessions 500
+///   ad_proxy 127.0.0.1 3333 http://127.0.0.1:8090/vast http://127.0.0.1:8090/loadtest/master.m3u8
+#[derive(Parser, Debug)]
+#[clap(verbatim_doc_comment)]
+struct LoadTestArguments {
+    /// Ad proxy URL (protocol://ip:port)
+    #[clap(long, default_value = "http://127.0.0.1:3333")]
+    proxy: String,
+
+    /// Master playlist path requested from the proxy
+    #[clap(long, default_value = MASTER_PLAYLIST)]
+    master_path: String,
+
+    /// Number of concurrent sessions
+    #[clap(long, default_value_t = 100)]
+    sessions: usize,
+
+    /// Test duration in seconds
+    #[clap(long, default_value_t = 60)]
+    duration: u64,
+
+    /// Delay in milliseconds between session starts
+    #[clap(long, default_value_t = 10)]
+    ramp_up_ms: u64,
+
+    /// Address the mock origin and ad server listen on
+    #[clap(long, default_value = "127.0.0.1")]
+    mock_addr: String,
+
+    /// Port the mock origin and ad server listen on
+    #[clap(long, default_value_t = 8090)]
+    mock_port: u16,
+
+    /// Don't start the mock origin and ad server (test against a real setup)
+    #[clap(long)]
+    no_mock: bool,
+
+    /// Segment duration of the mock live stream in seconds
+    /// Sessions poll the media playlist at this interval
+    #[clap(long, verbatim_doc_comment, default_value_t = 2)]
+    segment_duration: u64,
+
+    /// Number of segments in the mock live playlist window
+    #[clap(long, default_value_t = 10)]
+    window: u64,
+
+    /// Seconds to wait for the proxy to come up before starting the sessions
+    #[clap(long, default_value_t = 30)]
+    wait_for_proxy: u64,
+
+    /// Artificial ad server delay in milliseconds
+    #[clap(long, default_value_t = 0)]
+    vast_delay_ms: u64,
+}
+
+// Latencies and errors per request kind, shared by all sessions of the thread
+#[derive(Default)]
+struct Stats {
+    latencies: BTreeMap<&'static str, Vec<Duration>>,
+    errors: BTreeMap<&'static str, u64>,
+}
+
+type SharedStats = Rc<RefCell<Stats>>;
+
+fn record(stats: &SharedStats, kind: &'static str, started: Instant, ok: bool) {
+    let mut stats = stats.borrow_mut();
+    if ok {
+        stats.latencies.entry(kind).or_default().push(started.elapsed());
+    } else {
+        *stats.errors.entry(kind).or_default() += 1;
+    }
+}
+
+// Fetch a URL and return its body if the request succeeded
+async fn timed_get(
+    client: &Client,
+    stats: &SharedStats,
+    kind: &'static str,
+    url: &str,
+    session_id: &str,
+) -> Option<String> {
+    let started = Instant::now();
+    let body = match client
+        .get(url)
+        .insert_header(("x-playback-session-id", session_id))
+        .send()
+        .await
+    {
+        Ok(mut res) if res.status().is_success() => res.body().limit(16 * 1024 * 1024).await.ok(),
+        Ok(res) => {
+            log::debug!("{kind} request {url} returned {}", res.status());
+            None
+        }
+        Err(err) => {
+            log::debug!("{kind} request {url} failed: {err}");
+            None
+        }
+    };
+    record(stats, kind, started, body.is_some());
+
+    body.map(|body| String::from_utf8_lossy(&body).into_owned())
+}
+
+// URIs of the interstitial asset lists in a media playlist
+fn asset_list_urls(playlist: &str) -> Vec<(String, String)> {
+    playlist
+        .lines()
+        .filter(|line| line.starts_with("#EXT-X-DATERANGE"))
+        .filter_map(|line| {
+            let id = attribute(line, "ID")?;
+            let asset_list = attribute(line, "X-ASSET-LIST")?;
+            Some((id.to_string(), asset_list.to_string()))
+        })
+        .collect()
+}
+
+fn attribute<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let start = line.find(&format!("{name}=\""))? + name.len() + 2;
+    let end = line[start..].find('"')? + start;
+    Some(&line[start..end])
+}
+
+// A session fetches the master playlist, then polls the first variant and
+// fetches the asset list of every new interstitial like a player would
+async fn run_session(
+    index: usize,
+    master_url: Url,
+    poll_interval: Duration,
+    deadline: Instant,
+    stats: SharedStats,
+) {
+    let client = Client::builder().timeout(Duration::from_secs(10)).finish();
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let user_id = format!("loadtest-{index}");
+
+    let Some(master) = timed_get(&client, &stats, "master", master_url.as_str(), &session_id).await
+    else {
+        return;
+    };
+    let Some(media_url) = master
+        .lines()
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .and_then(|uri| master_url.join(uri.trim()).ok())
+    else {
+        log::warn!("Session {index}: no variant in the master playlist");
+        return;
+    };
+
+    let mut fetched_interstitials = HashSet::new();
+    while Instant::now() < deadline {
+        let next_poll = Instant::now() + poll_interval;
+        if let Some(media) = timed_get(&client, &stats, "media", media_url.as_str(), &session_id).await {
+            for (id, asset_list) in asset_list_urls(&media) {
+                if !fetched_interstitials.insert(id) {
+                    continue;
+                }
+                let Ok(mut asset_list_url) = media_url.join(&asset_list) else {
+                    continue;
+                };
+                asset_list_url
+                    .query_pairs_mut()
+                    .append_pair("_HLS_primary_id", &user_id);
+                timed_get(&client, &stats, "asset_list", asset_list_url.as_str(), &session_id).await;
+            }
+        }
+        actix_web::rt::time::sleep_until(next_poll.min(deadline).into()).await;
+    }
+}
+
+// The proxy inspects the origin on startup, so it may be started after the mock
+async fn wait_for_proxy(master_url: &Url, timeout: Duration) -> bool {
+    let client = Client::default();
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Ok(res) = client.get(master_url.as_str()).send().await {
+            if res.status().is_success() {
+                return true;
+            }
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((percentile / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn print_report(stats: &Stats, elapsed: Duration) {
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    println!(
+        "{:<12} {:>9} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "request", "count", "errors", "req/s", "p50 ms", "p95 ms", "p99 ms", "max ms"
+    );
+    let kinds = stats
+        .latencies
+        .keys()
+        .chain(stats.errors.keys())
+        .collect::<std::collections::BTreeSet<_>>();
+    for kind in kinds {
+        let mut latencies = stats.latencies.get(kind).cloned().unwrap_or_default();
+        latencies.sort_unstable();
+        let errors = stats.errors.get(kind).copied().unwrap_or_default();
+        println!(
+            "{:<12} {:>9} {:>7} {:>9.1} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+            kind,
+            latencies.len(),
+            errors,
+            latencies.len() as f64 / elapsed.as_secs_f64(),
+            ms(percentile(&latencies, 50.0)),
+            ms(percentile(&latencies, 95.0)),
+            ms(percentile(&latencies, 99.0)),
+            ms(latencies.last().copied().unwrap_or_default()),
+        );
+    }
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    dotenvy::dotenv().ok();
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let args = LoadTestArguments::parse();
+
+    let mock_server = if args.no_mock {
+        None
+    } else {
+        let mock = MockOrigin {
+            vast_delay: Duration::from_millis(args.vast_delay_ms),
+            ..MockOrigin::new(args.segment_duration, args.window)
+        };
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(mock.clone()))
+                .route(VAST_PATH, web::get().to(mock_origin::vast))
+                .service(web::scope("/loadtest").configure(|cfg| mock.configure(cfg)))
+        })
+        .workers(2)
+        .disable_signals()
+        .bind((args.mock_addr.as_str(), args.mock_port))?
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        log::info!(
+            "Mock origin at http://{0}:{1}{MASTER_PLAYLIST}, mock ad server at http://{0}:{1}{VAST_PATH}",
+            args.mock_addr,
+            args.mock_port
+        );
+        Some(handle)
+    };
+
+    let master_url = Url::parse(&args.proxy)
+        .and_then(|proxy| proxy.join(&args.master_path))
+        .map_err(std::io::Error::other)?;
+    let poll_interval = Duration::from_secs(args.segment_duration.max(1));
+    if !wait_for_proxy(&master_url, Duration::from_secs(args.wait_for_proxy)).await {
+        return Err(std::io::Error::other(format!("{master_url} didn't respond in time")));
+    }
+    log::info!(
+        "Starting {} sessions against {master_url} for {} seconds",
+        args.sessions,
+        args.duration
+    );
+
+    let stats = SharedStats::default();
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(args.duration);
+    let mut sessions = Vec::with_capacity(args.sessions);
+    for index in 0..args.sessions {
+        sessions.push(actix_web::rt::spawn(run_session(
+            index,
+            master_url.clone(),
+            poll_interval,
+            deadline,
+            stats.clone(),
+        )));
+        if args.ramp_up_ms > 0 {
+            actix_web::rt::time::sleep(Duration::from_millis(args.ramp_up_ms)).await;
+        }
+    }
+    for session in sessions {
+        let _ = session.await;
+    }
+
+    print_report(&stats.borrow(), started.elapsed());
+
+    if let Some(handle) = mock_server {
+        handle.stop(true).await;
+    }
+
+    Ok(())
+}
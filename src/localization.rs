@@ -0,0 +1,223 @@
+use crate::utils::{get_header_value, get_query_param};
+use actix_web::HttpRequest;
+use actix_web::http::header;
+use url::Url;
+
+// The CreativeExtension type naming the language of a creative, e.g.
+// <CreativeExtension type="language">sv</CreativeExtension>
+const LANGUAGE_EXTENSION: &str = "language";
+
+/// Localized ads (`--localize-ads`). The viewer's languages are the locale of
+/// the session (`locale_param` of the asset list requests, e.g. `locale=sv-SE`)
+/// or else the player's `Accept-Language`. They are sent to the ad server as
+/// its `Accept-Language` and as `language_param`, and of the language variants
+/// of an ad the creatives in the viewer's language are played
+#[derive(Clone, Debug, Default)]
+pub struct Localization {
+    enabled: bool,
+    locale_param: Option<String>,
+    language_param: Option<String>,
+}
+
+impl Localization {
+    pub fn new(enabled: bool, locale_param: Option<String>, language_param: Option<String>) -> Result<Self, String> {
+        if !enabled && (locale_param.is_some() || language_param.is_some()) {
+            return Err("--locale-param and --ad-language-param need --localize-ads".to_string());
+        }
+        Ok(Self {
+            enabled,
+            locale_param,
+            language_param,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The viewer's language tags, the preferred one first
+    pub fn languages(&self, req: &HttpRequest) -> Vec<String> {
+        if !self.enabled {
+            return Vec::new();
+        }
+        self.locale_param
+            .as_ref()
+            .and_then(|param| get_query_param(req, param))
+            .or_else(|| get_header_value(req, header::ACCEPT_LANGUAGE.as_str()))
+            .map(|value| parse_accept_language(&value))
+            .unwrap_or_default()
+    }
+
+    /// Pass the viewer's languages on to the ad server, as the value of its
+    /// `Accept-Language` header
+    pub fn localize_request(&self, ad_url: &mut Url, languages: &[String]) -> Option<String> {
+        let language = languages.first()?;
+        if let Some(param) = &self.language_param {
+            ad_url.query_pairs_mut().append_pair(param, language);
+        }
+        Some(languages.join(", "))
+    }
+
+    /// Drop the language variants of the ads other than the one closest to the
+    /// viewer's languages. An ad without a variant in one of them plays its
+    /// first one, and the creatives without a language are all kept
+    pub fn select(&self, vast: &mut vast4_rs::Vast, languages: &[String]) {
+        if !self.enabled {
+            return;
+        }
+        for in_line in vast.ads.iter_mut().filter_map(|ad| ad.in_line.as_mut()) {
+            // The linear and the companion creatives are variants of their own kind
+            for linear in [true, false] {
+                let variants = in_line
+                    .creatives
+                    .creatives
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, creative)| creative.linear.is_some() == linear)
+                    .filter_map(|(index, creative)| Some((index, creative_language(creative)?)))
+                    .collect::<Vec<_>>();
+                if variants.len() < 2 {
+                    continue;
+                }
+                let chosen = variants
+                    .iter()
+                    .filter_map(|(index, language)| Some((*index, match_rank(language, languages)?)))
+                    .min_by_key(|(_, rank)| *rank)
+                    .map_or(variants[0].0, |(index, _)| index);
+                let dropped = variants
+                    .iter()
+                    .map(|(index, _)| *index)
+                    .filter(|index| *index != chosen)
+                    .collect::<Vec<_>>();
+                in_line.creatives.creatives = std::mem::take(&mut in_line.creatives.creatives)
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(index, _)| !dropped.contains(index))
+                    .map(|(_, creative)| creative)
+                    .collect();
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "enabled": self.enabled,
+            "locale_param": self.locale_param.as_deref(),
+            "language_param": self.language_param.as_deref(),
+        }
+    }
+}
+
+/// The language tags of an `Accept-Language` value by decreasing quality, e.g.
+/// `sv-SE, en;q=0.8` gives `sv-SE` and `en`. Wildcards and refused languages
+/// (`q=0`) are left out
+pub fn parse_accept_language(value: &str) -> Vec<String> {
+    let mut languages = value
+        .split(',')
+        .filter_map(|language| {
+            let mut parts = language.split(';');
+            let tag = parts.next()?.trim().replace('_', "-");
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let is_tag = !tag.is_empty() && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+            (is_tag && quality > 0.0).then_some((tag, quality))
+        })
+        .collect::<Vec<_>>();
+    // A stable sort keeps the order of the languages of the same quality
+    languages.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    languages.into_iter().map(|(tag, _)| tag).collect()
+}
+
+// The language of a creative, from its language CreativeExtension
+fn creative_language(creative: &vast4_rs::Creative) -> Option<String> {
+    creative
+        .creative_extensions
+        .as_ref()?
+        .creative_extensions
+        .iter()
+        .find(|extension| {
+            extension
+                .mime_type
+                .as_deref()
+                .is_some_and(|kind| kind.eq_ignore_ascii_case(LANGUAGE_EXTENSION))
+        })
+        .map(|extension| {
+            let language = extension.xml.trim();
+            let language = language
+                .strip_prefix("<![CDATA[")
+                .and_then(|language| language.strip_suffix("]]>"))
+                .unwrap_or(language);
+            language.trim().replace('_', "-")
+        })
+        .filter(|language| !language.is_empty())
+}
+
+// How well a language matches the viewer's languages, lower is better: the
+// exact tag of a preferred language beats its primary language (en-GB for en-US)
+fn match_rank(language: &str, languages: &[String]) -> Option<usize> {
+    let primary = |tag: &str| tag.split('-').next().unwrap_or_default().to_ascii_lowercase();
+    languages.iter().enumerate().find_map(|(index, viewer)| {
+        if viewer.eq_ignore_ascii_case(language) {
+            Some(index * 2)
+        } else if primary(viewer) == primary(language) {
+            Some(index * 2 + 1)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_languages_by_quality() {
+        assert_eq!(
+            parse_accept_language("en;q=0.8, sv-SE, fr;q=0, *;q=0.1, de;q=0.8"),
+            ["sv-SE", "en", "de"]
+        );
+        assert_eq!(parse_accept_language("sv_SE"), ["sv-SE"]);
+        assert!(parse_accept_language("").is_empty());
+    }
+
+    #[test]
+    fn selects_the_creative_in_the_viewers_language() {
+        let creative = |id: &str, language: &str| {
+            format!(
+                r#"<Creative id="{id}"><CreativeExtensions><CreativeExtension type="language">{language}</CreativeExtension></CreativeExtensions><Linear><Duration>00:00:10</Duration></Linear></Creative>"#
+            )
+        };
+        let xml = format!(
+            r#"<VAST version="4.1"><Ad id="1"><InLine><AdSystem>test</AdSystem><AdTitle>ad</AdTitle><Creatives>{}{}{}<Creative id="plain"><Linear><Duration>00:00:10</Duration></Linear></Creative></Creatives></InLine></Ad></VAST>"#,
+            creative("en", "en"),
+            creative("sv", "sv"),
+            creative("de", "de-DE"),
+        );
+        let localization = Localization::new(true, None, None).unwrap();
+        let ids = |languages: &[&str]| {
+            let mut vast: vast4_rs::Vast = vast4_rs::from_str(&xml).unwrap();
+            localization.select(
+                &mut vast,
+                &languages
+                    .iter()
+                    .map(|language| language.to_string())
+                    .collect::<Vec<_>>(),
+            );
+            vast.ads[0]
+                .in_line
+                .as_ref()
+                .unwrap()
+                .creatives
+                .creatives
+                .iter()
+                .map(|creative| creative.id.as_deref().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&["sv-SE", "en"]), ["sv", "plain"]);
+        assert_eq!(ids(&["de-AT", "de-DE"]), ["de", "plain"]);
+        assert_eq!(ids(&["fi"]), ["en", "plain"]);
+    }
+}
@@ -0,0 +1,371 @@
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Upper bounds (in seconds) of the latency histogram buckets
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// The latest samples the rolling percentiles of a summary are taken from
+const SUMMARY_WINDOW: usize = 1000;
+// The percentiles of the summaries
+const SUMMARY_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+// Beyond this many series of a summary, the least recently updated is dropped
+const MAX_SUMMARY_SERIES: usize = 256;
+
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(value.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The latest samples of a latency, for its rolling percentiles
+#[derive(Clone, Debug, Default)]
+pub struct LatencyWindow {
+    samples: VecDeque<Duration>,
+    count: u64,
+    sum: Duration,
+}
+
+impl LatencyWindow {
+    pub fn record(&mut self, value: Duration) {
+        if self.samples.len() == SUMMARY_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(value);
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// The nearest-rank percentile of the latest samples, None without any
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let mut samples = self.samples.iter().copied().collect::<Vec<_>>();
+        samples.sort();
+        let rank = ((quantile * samples.len() as f64).ceil() as usize).clamp(1, samples.len().max(1));
+        samples.get(rank - 1).copied()
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let seconds = |quantile| self.quantile(quantile).map(|value| value.as_secs_f64());
+        json::object! {
+            "count": self.count,
+            "p50": seconds(0.5),
+            "p90": seconds(0.9),
+            "p99": seconds(0.99),
+            "max": seconds(1.0),
+        }
+    }
+}
+
+struct Summary {
+    window: LatencyWindow,
+    updated_at: Instant,
+}
+
+/// A minimal registry of labelled counters, latency histograms and summaries
+/// of rolling latency percentiles, rendered in the Prometheus text exposition
+/// format on `/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    counters: DashMap<(&'static str, String), AtomicU64>,
+    histograms: DashMap<(&'static str, String), Histogram>,
+    summaries: DashMap<(&'static str, String), Summary>,
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &'static str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1);
+    }
+
+    pub fn add(&self, name: &'static str, labels: &[(&str, &str)], value: u64) {
+        self.counters
+            .entry((name, render_labels(labels)))
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn observe(&self, name: &'static str, labels: &[(&str, &str)], value: Duration) {
+        self.histograms
+            .entry((name, render_labels(labels)))
+            .or_insert_with(Histogram::new)
+            .observe(value);
+    }
+
+    /// Record a latency in the rolling percentiles of a summary, for series
+    /// which come and go, e.g. one per ad slot
+    pub fn observe_summary(&self, name: &'static str, labels: &[(&str, &str)], value: Duration) {
+        let key = (name, render_labels(labels));
+        if !self.summaries.contains_key(&key) {
+            let oldest = self
+                .summaries
+                .iter()
+                .filter(|entry| entry.key().0 == name)
+                .map(|entry| (entry.updated_at, entry.key().clone()))
+                .collect::<Vec<_>>();
+            if oldest.len() >= MAX_SUMMARY_SERIES {
+                if let Some((_, key)) = oldest.into_iter().min() {
+                    self.summaries.remove(&key);
+                }
+            }
+        }
+        let mut summary = self.summaries.entry(key).or_insert_with(|| Summary {
+            window: LatencyWindow::default(),
+            updated_at: Instant::now(),
+        });
+        summary.window.record(value);
+        summary.updated_at = Instant::now();
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        let mut counters = self
+            .counters
+            .iter()
+            .map(|entry| {
+                let ((name, labels), value) = entry.pair();
+                (*name, labels.clone(), value.load(Ordering::Relaxed))
+            })
+            .collect::<Vec<_>>();
+        counters.sort();
+
+        let mut last_name = "";
+        for (name, labels, value) in counters {
+            if name != last_name {
+                let _ = writeln!(output, "# TYPE {name} counter");
+                last_name = name;
+            }
+            let _ = writeln!(output, "{name}{} {value}", wrap_labels(&labels));
+        }
+
+        let mut histograms = self
+            .histograms
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        histograms.sort();
+
+        let mut last_name = "";
+        for key in histograms {
+            let Some(histogram) = self.histograms.get(&key) else {
+                continue;
+            };
+            let (name, labels) = key;
+            if name != last_name {
+                let _ = writeln!(output, "# TYPE {name} histogram");
+                last_name = name;
+            }
+
+            let separator = if labels.is_empty() { "" } else { "," };
+            let mut cumulative = 0;
+            for (bound, bucket) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    output,
+                    "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(output, "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {count}");
+            let _ = writeln!(output, "{name}_sum{} {sum}", wrap_labels(&labels));
+            let _ = writeln!(output, "{name}_count{} {count}", wrap_labels(&labels));
+        }
+
+        let mut summaries = self
+            .summaries
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.window.clone()))
+            .collect::<Vec<_>>();
+        summaries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut last_name = "";
+        for ((name, labels), window) in summaries {
+            if name != last_name {
+                let _ = writeln!(output, "# TYPE {name} summary");
+                last_name = name;
+            }
+
+            let separator = if labels.is_empty() { "" } else { "," };
+            for quantile in SUMMARY_QUANTILES {
+                let value = window.quantile(quantile).map_or(f64::NAN, |value| value.as_secs_f64());
+                let _ = writeln!(output, "{name}{{{labels}{separator}quantile=\"{quantile}\"}} {value}");
+            }
+            let _ = writeln!(output, "{name}_sum{} {}", wrap_labels(&labels), window.sum.as_secs_f64());
+            let _ = writeln!(output, "{name}_count{} {}", wrap_labels(&labels), window.count);
+        }
+
+        output
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("{key}=\"{value}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn wrap_labels(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{labels}}}")
+    }
+}
+
+/// Splits the time spent on a single playlist response into stages
+/// (origin fetch, parsing, interstitial insertion, serialization).
+pub struct StageTimer {
+    playlist: &'static str,
+    started_at: Instant,
+    last_mark: Instant,
+    stages: Vec<(&'static str, Duration)>,
+}
+
+impl StageTimer {
+    pub fn start(playlist: &'static str) -> Self {
+        let now = Instant::now();
+        Self {
+            playlist,
+            started_at: now,
+            last_mark: now,
+            stages: Vec::with_capacity(4),
+        }
+    }
+
+    // The playlist type is only known after parsing in origin host mode
+    pub fn set_playlist(&mut self, playlist: &'static str) {
+        self.playlist = playlist;
+    }
+
+    // Attribute the time since the previous mark to the given stage
+    pub fn mark(&mut self, stage: &'static str) {
+        let now = Instant::now();
+        self.stages.push((stage, now - self.last_mark));
+        self.last_mark = now;
+    }
+
+    // Record all stages and return the value for the `Server-Timing` header
+    pub fn finish(&self, metrics: &Metrics) -> String {
+        let total = self.started_at.elapsed();
+        metrics.inc("playlist_responses_total", &[("playlist", self.playlist)]);
+        for (stage, duration) in &self.stages {
+            metrics.observe(
+                "playlist_stage_duration_seconds",
+                &[("playlist", self.playlist), ("stage", stage)],
+                *duration,
+            );
+        }
+        metrics.observe(
+            "playlist_response_duration_seconds",
+            &[("playlist", self.playlist)],
+            total,
+        );
+
+        self.stages
+            .iter()
+            .map(|(stage, duration)| (*stage, *duration))
+            .chain(std::iter::once(("total", total)))
+            .map(|(stage, duration)| {
+                format!("{stage};dur={:.3}", duration.as_secs_f64() * 1000.0)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_nearest_rank_percentiles() {
+        let mut window = LatencyWindow::default();
+        assert_eq!(window.quantile(0.5), None);
+
+        window.record(Duration::from_millis(7));
+        for quantile in [0.5, 0.9, 0.99, 1.0] {
+            assert_eq!(window.quantile(quantile), Some(Duration::from_millis(7)));
+        }
+
+        let mut window = LatencyWindow::default();
+        for millis in (1..=100).rev() {
+            window.record(Duration::from_millis(millis));
+        }
+        assert_eq!(window.quantile(0.5), Some(Duration::from_millis(50)));
+        assert_eq!(window.quantile(0.9), Some(Duration::from_millis(90)));
+        assert_eq!(window.quantile(0.99), Some(Duration::from_millis(99)));
+        assert_eq!(window.quantile(1.0), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn drops_the_least_recently_updated_series() {
+        let metrics = Metrics::default();
+        let observe = |slot: &str| metrics.observe_summary("latency_seconds", &[("slot", slot)], Duration::from_millis(10));
+        let pause = || std::thread::sleep(Duration::from_millis(2));
+        observe("first");
+        pause();
+        observe("second");
+        pause();
+        for index in 2..MAX_SUMMARY_SERIES {
+            observe(&format!("slot{index}"));
+        }
+        pause();
+        // Updated again, the first series outlives the second one
+        observe("first");
+        pause();
+        observe("new");
+
+        assert_eq!(metrics.summaries.len(), MAX_SUMMARY_SERIES);
+        let output = metrics.render();
+        assert!(output.contains("latency_seconds_count{slot=\"first\"} 2"));
+        assert!(output.contains("latency_seconds_count{slot=\"new\"} 1"));
+        assert!(!output.contains("slot=\"second\""));
+    }
+
+    #[test]
+    fn renders_the_summaries() {
+        let metrics = Metrics::default();
+        for millis in [100, 200, 300] {
+            metrics.observe_summary("latency_seconds", &[("slot", "ad_slot1")], Duration::from_millis(millis));
+        }
+        let output = metrics.render();
+        let expected = "# TYPE latency_seconds summary\n\
+            latency_seconds{slot=\"ad_slot1\",quantile=\"0.5\"} 0.2\n\
+            latency_seconds{slot=\"ad_slot1\",quantile=\"0.9\"} 0.3\n\
+            latency_seconds{slot=\"ad_slot1\",quantile=\"0.99\"} 0.3\n\
+            latency_seconds_sum{slot=\"ad_slot1\"} 0.6\n\
+            latency_seconds_count{slot=\"ad_slot1\"} 3\n";
+        assert!(output.contains(expected), "{output}");
+    }
+}
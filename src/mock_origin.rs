@@ -0,0 +1,111 @@
+use crate::utils::get_or_head;
+use actix_web::dev::ServerHandle;
+use actix_web::http::header;
+use actix_web::{App, HttpResponse, HttpServer, web};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::io;
+use std::time::Duration;
+use url::Url;
+
+/// A live stream synthesized from the wall clock, with a VAST response to
+/// fill its ad breaks, to exercise the proxy without an encoder or origin
+#[derive(Clone, Debug)]
+pub struct MockOrigin {
+    /// Segment duration in seconds
+    pub segment_duration: u64,
+    /// Number of segments in the sliding window
+    pub window: u64,
+    /// Delay before the VAST response is sent
+    pub vast_delay: Duration,
+}
+
+impl MockOrigin {
+    pub fn new(segment_duration: u64, window: u64) -> Self {
+        Self {
+            segment_duration: segment_duration.max(1),
+            window: window.max(1),
+            vast_delay: Duration::ZERO,
+        }
+    }
+
+    /// Register master.m3u8, media.m3u8, vast and the segments in the current scope
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::new(self.clone()))
+            .route("/master.m3u8", get_or_head().to(master_playlist))
+            .route("/media.m3u8", get_or_head().to(media_playlist))
+            .route("/vast", web::get().to(vast))
+            .route("/{segment}", get_or_head().to(segment));
+    }
+
+    /// Serve the mock under /mock/ on a free port of the loopback interface,
+    /// returns its base URL
+    pub fn start(&self) -> io::Result<(Url, ServerHandle)> {
+        let mock = self.clone();
+        let server = HttpServer::new(move || {
+            App::new().service(web::scope("/mock").configure(|cfg| mock.configure(cfg)))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))?;
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let url = Url::parse(&format!("http://{addr}/mock/")).map_err(io::Error::other)?;
+        Ok((url, handle))
+    }
+}
+
+pub async fn master_playlist() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/vnd.apple.mpegurl")
+        .body("#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-STREAM-INF:BANDWIDTH=1300000,RESOLUTION=718x404\nmedia.m3u8\n")
+}
+
+// A sliding window live playlist anchored to the wall clock
+pub async fn media_playlist(config: web::Data<MockOrigin>) -> HttpResponse {
+    let now = Utc::now().timestamp() as u64;
+    let last_sequence = now / config.segment_duration;
+    let first_sequence = last_sequence.saturating_sub(config.window);
+
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{first_sequence}\n",
+        config.segment_duration
+    );
+    for sequence in first_sequence..last_sequence {
+        if sequence == first_sequence {
+            let pdt = DateTime::<Utc>::from_timestamp((sequence * config.segment_duration) as i64, 0)
+                .unwrap_or_default();
+            playlist.push_str(&format!(
+                "#EXT-X-PROGRAM-DATE-TIME:{}\n",
+                pdt.to_rfc3339_opts(SecondsFormat::Millis, true)
+            ));
+        }
+        playlist.push_str(&format!(
+            "#EXTINF:{}.000,\nsegment_{sequence}.ts\n",
+            config.segment_duration
+        ));
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/vnd.apple.mpegurl")
+        .insert_header((header::CACHE_CONTROL, "max-age=1"))
+        .body(playlist)
+}
+
+pub async fn segment() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("video/mp2t")
+        .body(vec![0u8; 188 * 64])
+}
+
+pub async fn vast(config: web::Data<MockOrigin>) -> HttpResponse {
+    if !config.vast_delay.is_zero() {
+        actix_web::rt::time::sleep(config.vast_delay).await;
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(include_str!("../test_data/vast4.0_transcoded.xml"))
+}
@@ -0,0 +1,678 @@
+use crate::faults::FaultInjector;
+use crate::in_flight::{InFlight, Turn};
+use crate::metrics::Metrics;
+use actix_web::http::StatusCode;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpResponse, ResponseError};
+use awc::Client;
+use awc::error::{PayloadError, SendRequestError};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How often expired playlists are purged
+const PURGE_INTERVAL: Duration = Duration::from_secs(30);
+// How long expired playlists with an ETag or Last-Modified are kept to revalidate them
+const REVALIDATION_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub enum FetchError {
+    Send(String),
+    // The origin didn't answer in time
+    Timeout,
+    // The origin answered with an error status
+    Status(StatusCode),
+    // PayloadError isn't Clone, keep its message only
+    Payload(String),
+    // The playlist exceeds the maximum size in bytes
+    TooLarge(usize),
+    // A failure injected by --fault-error-rate
+    Injected,
+}
+
+impl FetchError {
+    /// Whether the last good playlist may be served instead: the origin is
+    /// down or failing, not saying the playlist doesn't exist
+    fn is_transient(&self) -> bool {
+        match self {
+            FetchError::Status(status) => status.is_server_error(),
+            FetchError::TooLarge(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Send(err) => write!(f, "{err}"),
+            FetchError::Timeout => write!(f, "Timeout waiting for the origin"),
+            FetchError::Status(status) => write!(f, "Origin answered {status}"),
+            FetchError::Payload(err) => write!(f, "{err}"),
+            FetchError::TooLarge(max_size) => {
+                write!(f, "Origin playlist exceeds the maximum size of {max_size} bytes")
+            }
+            FetchError::Injected => write!(f, "Injected origin failure (503 Service Unavailable)"),
+        }
+    }
+}
+
+impl From<SendRequestError> for FetchError {
+    fn from(err: SendRequestError) -> Self {
+        match err {
+            SendRequestError::Timeout => FetchError::Timeout,
+            // SendRequestError isn't Send, keep its message only
+            err => FetchError::Send(err.to_string()),
+        }
+    }
+}
+
+/// The players get the status of the origin for its client errors and server
+/// errors, and a gateway error when it can't be reached
+impl ResponseError for FetchError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            FetchError::Status(status) => *status,
+            FetchError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            FetchError::Injected => StatusCode::SERVICE_UNAVAILABLE,
+            FetchError::Send(_) | FetchError::Payload(_) | FetchError::TooLarge(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // The errors must not be cached in place of the next playlist
+        HttpResponse::build(self.status_code())
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .content_type(mime::TEXT_PLAIN_UTF_8)
+            .body(self.to_string())
+    }
+}
+
+// The validators of an origin playlist, sent back with a conditional GET
+#[derive(Clone, Default)]
+struct Validators {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl Validators {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        Self {
+            etag: headers.get(header::ETAG).cloned(),
+            last_modified: headers.get(header::LAST_MODIFIED).cloned(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// The caching headers of an origin playlist, passed on to the players along
+/// with an `Age` counting the time spent in the cache
+#[derive(Clone, Debug, Default)]
+pub struct CacheHeaders {
+    cache_control: Option<HeaderValue>,
+    expires: Option<HeaderValue>,
+    // The Age of the origin response in seconds
+    age: Option<u64>,
+    fetched_at: Option<Instant>,
+}
+
+impl CacheHeaders {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        Self {
+            cache_control: headers.get(header::CACHE_CONTROL).cloned(),
+            expires: headers.get(header::EXPIRES).cloned(),
+            age: headers
+                .get(header::AGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok()),
+            fetched_at: Some(Instant::now()),
+        }
+    }
+
+    // Those of a 304 Not Modified replace the cached ones
+    fn update(self, newer: Self) -> Self {
+        Self {
+            cache_control: newer.cache_control.or(self.cache_control),
+            expires: newer.expires.or(self.expires),
+            age: newer.age,
+            fetched_at: newer.fetched_at,
+        }
+    }
+
+    pub fn cache_control(&self) -> Option<&HeaderValue> {
+        self.cache_control.as_ref()
+    }
+
+    pub fn expires(&self) -> Option<&HeaderValue> {
+        self.expires.as_ref()
+    }
+
+    /// The Age of the origin response plus the seconds it has been cached,
+    /// None if it's fresh from an origin sending no Age
+    pub fn age(&self) -> Option<u64> {
+        let cached = self.fetched_at.map_or(0, |fetched_at| fetched_at.elapsed().as_secs());
+        match self.age {
+            Some(age) => Some(age + cached),
+            None => Some(cached).filter(|cached| *cached > 0),
+        }
+    }
+}
+
+/// A playlist of the origin with its caching headers
+#[derive(Clone, Debug)]
+pub struct OriginPlaylist {
+    pub body: Bytes,
+    pub cache_headers: CacheHeaders,
+}
+
+struct CachedPlaylist {
+    body: Bytes,
+    validators: Validators,
+    cache_headers: CacheHeaders,
+    fetched_at: Instant,
+    ttl: Duration,
+}
+
+impl CachedPlaylist {
+    fn is_fresh(&self) -> bool {
+        self.fetched_at.elapsed() < self.ttl
+    }
+
+    fn can_revalidate(&self) -> bool {
+        !self.validators.is_empty() && self.fetched_at.elapsed() < self.ttl + REVALIDATION_WINDOW
+    }
+}
+
+// A playlist fetched from the origin, or revalidated with a 304 Not Modified
+struct Fetched {
+    body: Bytes,
+    validators: Validators,
+    cache_headers: CacheHeaders,
+    // How long it may be cached, None if it must not be stored at all
+    ttl: Option<Duration>,
+    revalidated: bool,
+}
+
+impl Fetched {
+    fn into_playlist(self) -> OriginPlaylist {
+        OriginPlaylist {
+            body: self.body,
+            cache_headers: self.cache_headers,
+        }
+    }
+}
+
+/// A short-lived cache of origin playlists shared by all workers.
+/// Concurrent requests for the same URL are coalesced into a single origin
+/// fetch whose result they all get, so N viewers cause one upstream request
+/// per refresh interval, whether the playlist can be cached or not.
+/// A transient failure of the origin is retried once after `retry_delay` and
+/// up to as long again. With a `stale_if_error` grace period, the last good
+/// playlist of a URL is served when the retry fails too, while the origin
+/// fails for at most that long
+#[derive(Clone)]
+pub struct OriginCache {
+    default_ttl: Duration,
+    max_body_size: usize,
+    retry_delay: Option<Duration>,
+    stale_if_error: Duration,
+    entries: Arc<DashMap<String, CachedPlaylist>>,
+    // The last playlist fetched of each URL, and when
+    last_good: Arc<DashMap<String, (OriginPlaylist, Instant)>>,
+    in_flight: InFlight<Result<OriginPlaylist, FetchError>>,
+    last_purge: Arc<Mutex<Instant>>,
+    metrics: web::Data<Metrics>,
+    faults: FaultInjector,
+}
+
+impl OriginCache {
+    pub fn new(default_ttl: Duration, max_body_size: usize, metrics: web::Data<Metrics>) -> Self {
+        Self {
+            default_ttl,
+            max_body_size,
+            retry_delay: None,
+            stale_if_error: Duration::ZERO,
+            entries: Arc::new(DashMap::new()),
+            last_good: Arc::new(DashMap::new()),
+            in_flight: InFlight::default(),
+            last_purge: Arc::new(Mutex::new(Instant::now())),
+            metrics,
+            faults: FaultInjector::default(),
+        }
+    }
+
+    /// Inject these failures into the origin fetches
+    pub fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Retry the fetches failing with a 5xx, a timeout or a connection error
+    /// once, after this delay plus a random jitter of up to as long. None
+    /// doesn't retry
+    pub fn with_retry(mut self, retry_delay: Option<Duration>) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Serve the last good playlist for this long while the origin fails
+    pub fn with_stale_if_error(mut self, stale_if_error: Duration) -> Self {
+        self.stale_if_error = stale_if_error;
+        self
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "default_ttl_ms": self.default_ttl.as_millis() as u64,
+            "max_body_size": self.max_body_size,
+            "retry_delay_ms": self.retry_delay.map(|delay| delay.as_millis() as u64),
+            "stale_if_error": self.stale_if_error.as_secs(),
+            "entries": self.entries.len(),
+            "last_good": self.last_good.len(),
+        }
+    }
+
+    /// Fetch a playlist from the origin, or serve it from the cache while fresh
+    pub async fn fetch(&self, client: &Client, url: &str) -> Result<Bytes, FetchError> {
+        self.fetch_with_headers(client, url, &[]).await.map(|playlist| playlist.body)
+    }
+
+    /// Fetch a playlist with the headers of the player forwarded to the origin.
+    /// The playlists fetched with different header values are cached apart.
+    /// Expired playlists with an ETag or Last-Modified are revalidated with
+    /// If-None-Match and If-Modified-Since, a 304 keeps the cached body
+    pub async fn fetch_with_headers(
+        &self,
+        client: &Client,
+        url: &str,
+        headers: &[(HeaderName, HeaderValue)],
+    ) -> Result<OriginPlaylist, FetchError> {
+        let result = self.fetch_fresh(client, url, headers).await;
+        if self.stale_if_error.is_zero() {
+            return result;
+        }
+
+        let key = cache_key(url, headers);
+        match result {
+            Ok(playlist) => {
+                self.last_good.insert(key, (playlist.clone(), Instant::now()));
+                self.purge();
+                Ok(playlist)
+            }
+            Err(err) if err.is_transient() => {
+                let last_good = self
+                    .last_good
+                    .get(&key)
+                    .filter(|entry| entry.1.elapsed() < self.stale_if_error)
+                    .map(|entry| entry.0.clone());
+                let Some(playlist) = last_good else {
+                    return Err(err);
+                };
+                log::warn!("Serving the last good playlist of {url}: {err}");
+                self.metrics.inc("origin_cache_requests_total", &[("result", "stale")]);
+                Ok(playlist)
+            }
+            Err(err) => {
+                // The playlist is gone, not to be served again
+                self.last_good.remove(&key);
+                Err(err)
+            }
+        }
+    }
+
+    async fn fetch_fresh(
+        &self,
+        client: &Client,
+        url: &str,
+        headers: &[(HeaderName, HeaderValue)],
+    ) -> Result<OriginPlaylist, FetchError> {
+        if self.default_ttl.is_zero() {
+            return self.fetch_retrying(client, url, headers, None).await.map(Fetched::into_playlist);
+        }
+
+        let key = cache_key(url, headers);
+        loop {
+            if let Some(playlist) = self.cached(&key) {
+                self.metrics.inc("origin_cache_requests_total", &[("result", "hit")]);
+                return Ok(playlist);
+            }
+
+            // Only one request per URL goes to the origin, the others get its result
+            let leader = match self.in_flight.join(&key) {
+                Turn::Lead(leader) => leader,
+                Turn::Wait(waiter) => match waiter.result().await {
+                    Some(result) => {
+                        self.metrics.inc("origin_cache_requests_total", &[("result", "coalesced")]);
+                        return result;
+                    }
+                    // The leading request was dropped before the origin answered
+                    None => continue,
+                },
+            };
+            // The previous request may have cached it in the meantime
+            if let Some(playlist) = self.cached(&key) {
+                self.metrics.inc("origin_cache_requests_total", &[("result", "coalesced")]);
+                leader.finish(Ok(playlist.clone()));
+                return Ok(playlist);
+            }
+
+            let result = self.fetch_retrying(client, url, headers, self.stale(&key)).await;
+            let label = match &result {
+                Ok(fetched) if fetched.revalidated => "revalidated",
+                _ => "miss",
+            };
+            self.metrics.inc("origin_cache_requests_total", &[("result", label)]);
+            if let Ok(fetched) = &result {
+                self.store(&key, fetched);
+            }
+            let result = result.map(Fetched::into_playlist);
+            leader.finish(result.clone());
+
+            return result;
+        }
+    }
+
+    // Fetch from the origin, once more if it hiccups
+    async fn fetch_retrying(
+        &self,
+        client: &Client,
+        url: &str,
+        headers: &[(HeaderName, HeaderValue)],
+        stale: Option<(OriginPlaylist, Validators)>,
+    ) -> Result<Fetched, FetchError> {
+        let fetch = |stale| fetch_from_origin(client, url, headers, stale, self.default_ttl, self.max_body_size, &self.faults);
+        let err = match fetch(stale.clone()).await {
+            Err(err) if err.is_transient() => err,
+            result => return result,
+        };
+        let Some(retry_delay) = self.retry_delay else {
+            return Err(err);
+        };
+
+        // Jittered so the retries of the channels don't hit the origin together
+        let jitter_ms = rand::rng().random_range(0..=retry_delay.as_millis() as u64);
+        let delay = retry_delay + Duration::from_millis(jitter_ms);
+        log::info!("Retrying the origin playlist {url} in {}ms: {err}", delay.as_millis());
+        actix_web::rt::time::sleep(delay).await;
+        let result = fetch(stale).await;
+        let label = if result.is_ok() { "recovered" } else { "failed" };
+        self.metrics.inc("origin_retries_total", &[("result", label)]);
+        result
+    }
+
+    fn cached(&self, url: &str) -> Option<OriginPlaylist> {
+        self.entries
+            .get(url)
+            .filter(|entry| entry.is_fresh())
+            .map(|entry| OriginPlaylist {
+                body: entry.body.clone(),
+                cache_headers: entry.cache_headers.clone(),
+            })
+    }
+
+    // An expired playlist to revalidate with the origin
+    fn stale(&self, url: &str) -> Option<(OriginPlaylist, Validators)> {
+        self.entries.get(url).filter(|entry| entry.can_revalidate()).map(|entry| {
+            let playlist = OriginPlaylist {
+                body: entry.body.clone(),
+                cache_headers: entry.cache_headers.clone(),
+            };
+            (playlist, entry.validators.clone())
+        })
+    }
+
+    fn store(&self, url: &str, fetched: &Fetched) {
+        // Playlists which must be revalidated are kept as long as they can be
+        let Some(ttl) = fetched.ttl.filter(|ttl| !ttl.is_zero() || !fetched.validators.is_empty()) else {
+            self.entries.remove(url);
+            return;
+        };
+
+        self.entries.insert(
+            url.to_string(),
+            CachedPlaylist {
+                body: fetched.body.clone(),
+                validators: fetched.validators.clone(),
+                cache_headers: fetched.cache_headers.clone(),
+                fetched_at: Instant::now(),
+                ttl,
+            },
+        );
+
+        self.purge();
+    }
+
+    // Drop the expired playlists now and then
+    fn purge(&self) {
+        let mut last_purge = self.last_purge.lock();
+        if last_purge.elapsed() >= PURGE_INTERVAL {
+            *last_purge = Instant::now();
+            drop(last_purge);
+            self.entries.retain(|_, entry| entry.is_fresh() || entry.can_revalidate());
+            self.last_good.retain(|_, (_, fetched_at)| fetched_at.elapsed() < self.stale_if_error);
+        }
+    }
+}
+
+// The URL alone without forwarded headers, so the entries stay keyed by URL
+fn cache_key(url: &str, headers: &[(HeaderName, HeaderValue)]) -> String {
+    headers.iter().fold(url.to_string(), |mut key, (name, value)| {
+        key.push('\n');
+        key.push_str(name.as_str());
+        key.push(':');
+        key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+        key
+    })
+}
+
+// Fetch the playlist along with how long it may be cached, conditionally if a
+// stale copy is at hand
+async fn fetch_from_origin(
+    client: &Client,
+    url: &str,
+    headers: &[(HeaderName, HeaderValue)],
+    stale: Option<(OriginPlaylist, Validators)>,
+    default_ttl: Duration,
+    max_body_size: usize,
+    faults: &FaultInjector,
+) -> Result<Fetched, FetchError> {
+    faults.delay().await;
+    if faults.fail() {
+        log::debug!("Injecting a failure of the origin request {url}");
+        return Err(FetchError::Injected);
+    }
+    let mut req = client.get(url);
+    for (name, value) in headers {
+        req = req.insert_header((name.clone(), value.clone()));
+    }
+    if let Some((_, validators)) = &stale {
+        if let Some(etag) = &validators.etag {
+            req = req.insert_header((header::IF_NONE_MATCH, etag.clone()));
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            req = req.insert_header((header::IF_MODIFIED_SINCE, last_modified.clone()));
+        }
+    }
+    let mut res = req.send().await?;
+    let cache_control = res
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let ttl = || cache_control.as_deref().map_or(Some(default_ttl), |value| parse_cache_control(value, default_ttl));
+
+    if let Some((playlist, validators)) = stale.filter(|_| res.status() == StatusCode::NOT_MODIFIED) {
+        log::debug!("Origin playlist {url} not modified");
+        // A 304 may update the validators of the cached playlist
+        let updated = Validators::from_headers(res.headers());
+        let validators = Validators {
+            etag: updated.etag.or(validators.etag),
+            last_modified: updated.last_modified.or(validators.last_modified),
+        };
+        return Ok(Fetched {
+            body: playlist.body,
+            validators,
+            cache_headers: playlist.cache_headers.update(CacheHeaders::from_headers(res.headers())),
+            ttl: ttl(),
+            revalidated: true,
+        });
+    }
+    // The limit is checked against Content-Length before anything is buffered
+    let body = res
+        .body()
+        .limit(max_body_size)
+        .await
+        .map_err(|err| match err {
+            PayloadError::Overflow => {
+                log::error!("Origin playlist {url} exceeds {max_body_size} bytes");
+                FetchError::TooLarge(max_body_size)
+            }
+            err => FetchError::Payload(err.to_string()),
+        })?;
+
+    if !res.status().is_success() {
+        log::error!("Origin answered {} for {url}", res.status());
+        return Err(FetchError::Status(res.status()));
+    }
+    let (ttl, validators) = (ttl(), Validators::from_headers(res.headers()));
+
+    Ok(Fetched {
+        body: faults.truncate_playlist(body),
+        validators,
+        cache_headers: CacheHeaders::from_headers(res.headers()),
+        ttl,
+        revalidated: false,
+    })
+}
+
+// Prefer s-maxage over max-age as this is a shared cache. None if the playlist
+// must not be stored, zero if it must be revalidated before each use
+fn parse_cache_control(value: &str, default_ttl: Duration) -> Option<Duration> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in value.split(',').map(str::trim) {
+        let (name, argument) = directive.split_once('=').unwrap_or((directive, ""));
+        let seconds = || argument.trim_matches('"').parse::<u64>().ok();
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "private" => return None,
+            "no-cache" => return Some(Duration::ZERO),
+            "max-age" => max_age = seconds(),
+            "s-maxage" => s_maxage = seconds(),
+            _ => {}
+        }
+    }
+
+    Some(s_maxage.or(max_age).map_or(default_ttl, Duration::from_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Fails every other request with a 503
+    fn start_flaky_origin() -> (String, actix_web::dev::ServerHandle) {
+        let requests = web::Data::new(AtomicUsize::new(0));
+        let server = HttpServer::new(move || {
+            App::new().app_data(requests.clone()).default_service(web::get().to(
+                |requests: web::Data<AtomicUsize>| async move {
+                    match requests.fetch_add(1, Ordering::SeqCst) % 2 {
+                        0 => HttpResponse::ServiceUnavailable().finish(),
+                        _ => HttpResponse::Ok().body("#EXTM3U\n"),
+                    }
+                },
+            ))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        (format!("http://{addr}/index.m3u8"), handle)
+    }
+
+    // Answers every request after a while with this status and Cache-Control,
+    // counting the requests
+    fn start_slow_origin(
+        status: StatusCode,
+        cache_control: &'static str,
+    ) -> (String, web::Data<AtomicUsize>, actix_web::dev::ServerHandle) {
+        let requests = web::Data::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let server = HttpServer::new(move || {
+            App::new().app_data(requests.clone()).default_service(web::get().to(
+                move |requests: web::Data<AtomicUsize>| async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+                    HttpResponse::build(status)
+                        .insert_header((header::CACHE_CONTROL, cache_control))
+                        .body("#EXTM3U\n")
+                },
+            ))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        (format!("http://{addr}/index.m3u8"), counter, handle)
+    }
+
+    #[actix_web::test]
+    async fn concurrent_fetches_share_one_origin_request() {
+        let cases = [
+            (StatusCode::OK, "max-age=10"),
+            (StatusCode::OK, "no-cache"),
+            (StatusCode::SERVICE_UNAVAILABLE, "no-store"),
+        ];
+        for (status, cache_control) in cases {
+            let (url, requests, handle) = start_slow_origin(status, cache_control);
+            let cache = OriginCache::new(Duration::from_secs(10), 1024, web::Data::new(Metrics::default()));
+            let client = Client::default();
+
+            let results = futures_util::future::join_all((0..5).map(|_| cache.fetch(&client, &url))).await;
+            handle.stop(false).await;
+            assert_eq!(requests.load(Ordering::SeqCst), 1, "{status} {cache_control}");
+            for result in results {
+                match result {
+                    Ok(body) => assert!(status.is_success() && body == "#EXTM3U\n"),
+                    Err(err) => assert!(matches!(err, FetchError::Status(err_status) if err_status == status)),
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn retries_a_failed_playlist_once() {
+        let (url, handle) = start_flaky_origin();
+        let metrics = web::Data::new(Metrics::default());
+        let client = Client::default();
+
+        let cache = OriginCache::new(Duration::ZERO, 1024, metrics.clone()).with_retry(Some(Duration::from_millis(10)));
+        assert_eq!(cache.fetch(&client, &url).await.unwrap(), "#EXTM3U\n");
+        assert_eq!(cache.fetch(&client, &url).await.unwrap(), "#EXTM3U\n");
+
+        let cache = OriginCache::new(Duration::ZERO, 1024, metrics).with_retry(None);
+        assert!(matches!(
+            cache.fetch(&client, &url).await,
+            Err(FetchError::Status(StatusCode::SERVICE_UNAVAILABLE))
+        ));
+        handle.stop(false).await;
+    }
+}
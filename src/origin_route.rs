@@ -0,0 +1,128 @@
+use std::sync::Arc;
+use url::Url;
+
+#[derive(Debug, Clone)]
+struct Route {
+    forward_url: Url,
+    // The path of the master playlist at the origin, the players' until a swap
+    master_playlist_path: Option<String>,
+    swapped_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Where the requests of a stream are forwarded: the origin and the path of
+/// its master playlist. Swapping it for a backup packager keeps the paths the
+/// players request, the ones under the directory of their master playlist
+/// being mapped to the directory of the new one
+#[derive(Debug, Clone)]
+pub struct OriginRoute {
+    route: Arc<parking_lot::RwLock<Route>>,
+    // The master playlist the players request
+    player_master_path: Option<String>,
+}
+
+// The directory of a path, with its trailing slash
+fn directory(path: &str) -> &str {
+    &path[..path.rfind('/').map_or(0, |end| end + 1)]
+}
+
+impl OriginRoute {
+    pub fn new(forward_url: Url, master_playlist_path: Option<String>) -> Self {
+        Self {
+            route: Arc::new(parking_lot::RwLock::new(Route {
+                forward_url,
+                master_playlist_path: master_playlist_path.clone(),
+                swapped_at: None,
+            })),
+            player_master_path: master_playlist_path,
+        }
+    }
+
+    /// The origin the requests are forwarded to
+    pub fn forward_url(&self) -> Url {
+        self.route.read().forward_url.clone()
+    }
+
+    /// The URL of the master playlist at the origin, None in origin host mode
+    pub fn master_playlist_url(&self) -> Option<Url> {
+        let route = self.route.read();
+        let path = route.master_playlist_path.as_deref().filter(|path| !path.is_empty())?;
+        route.forward_url.join(path).ok()
+    }
+
+    /// The origin URL of the path and the query of a request
+    pub fn origin_url(&self, path: &str, query: Option<&str>) -> Url {
+        let route = self.route.read().clone();
+        let mut origin_url = route.forward_url.clone();
+        origin_url.set_query(query);
+        let path = match (self.player_master_path.as_deref(), route.master_playlist_path.as_deref()) {
+            (Some(player), Some(origin)) if player != origin => {
+                if path == player {
+                    origin.to_string()
+                } else {
+                    match path.strip_prefix(directory(player)) {
+                        Some(rest) => format!("{}{rest}", directory(origin)),
+                        None => path.to_string(),
+                    }
+                }
+            }
+            _ => path.to_string(),
+        };
+        origin_url.set_path(&path);
+        origin_url
+    }
+
+    /// Forward the requests to another origin: the master playlist URL in
+    /// playlist mode, the origin host in origin host mode
+    pub fn swap(&self, url: &Url) -> Result<(), String> {
+        if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+            return Err(format!("{url} is not an HTTP(S) URL"));
+        }
+        let mut route = self.route.write();
+        if self.player_master_path.is_some() {
+            let mut forward_url = url.clone();
+            forward_url.set_path("/");
+            forward_url.set_query(None);
+            route.forward_url = forward_url;
+            route.master_playlist_path = Some(url.path().to_string());
+        } else {
+            route.forward_url = url.clone();
+        }
+        route.swapped_at = Some(chrono::Utc::now());
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let route = self.route.read();
+        json::object! {
+            "forward_url": route.forward_url.as_str(),
+            "master_playlist_path": route.master_playlist_path.as_deref(),
+            "swapped_at": route.swapped_at.map(|time| time.to_rfc3339()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_the_player_paths_to_the_new_origin() {
+        let route = OriginRoute::new(Url::parse("https://primary.example.com/").unwrap(), Some("/live/master.m3u8".to_string()));
+        assert_eq!(
+            route.origin_url("/live/720p/media.m3u8", Some("_HLS_msn=10")).as_str(),
+            "https://primary.example.com/live/720p/media.m3u8?_HLS_msn=10"
+        );
+
+        route.swap(&Url::parse("https://backup.example.com/ch1/index.m3u8").unwrap()).unwrap();
+        assert_eq!(
+            route.origin_url("/live/720p/media.m3u8", Some("_HLS_msn=10")).as_str(),
+            "https://backup.example.com/ch1/720p/media.m3u8?_HLS_msn=10"
+        );
+        assert_eq!(route.origin_url("/live/master.m3u8", None).as_str(), "https://backup.example.com/ch1/index.m3u8");
+        assert_eq!(
+            route.master_playlist_url().unwrap().as_str(),
+            "https://backup.example.com/ch1/index.m3u8"
+        );
+        assert!(route.swap(&Url::parse("file:///master.m3u8").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use dashmap::DashSet;
+use std::sync::Arc;
+
+// Beyond this many, the shapes of the playlists found by their content aren't kept
+const MAX_LEARNED_SHAPES: usize = 10_000;
+// The content types of the HLS playlists
+const PLAYLIST_CONTENT_TYPES: [&str; 4] = [
+    "application/vnd.apple.mpegurl",
+    "application/x-mpegurl",
+    "audio/mpegurl",
+    "audio/x-mpegurl",
+];
+
+/// Tells the playlists of the origins serving them without a .m3u8 extension,
+/// e.g. packagers taking the asset and the format as query parameters: the
+/// requests matching one of the --playlist-pattern, and those of the same
+/// shape as a request the origin answered with a playlist
+#[derive(Clone, Debug, Default)]
+pub struct PlaylistDetection {
+    patterns: Vec<String>,
+    learned: Arc<DashSet<String>>,
+}
+
+// The path and the names of the query parameters of a request, their values
+// being often tokens of the session
+fn shape(path: &str, query: Option<&str>) -> String {
+    let mut names = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .map(|(name, _)| name.into_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    format!("{path}?{}", names.join("&"))
+}
+
+/// Whether a Content-Type is the one of an HLS playlist
+pub fn is_playlist_content_type(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    PLAYLIST_CONTENT_TYPES.iter().any(|playlist| media_type.eq_ignore_ascii_case(playlist))
+}
+
+/// Whether the first bytes of a body are the ones of an HLS playlist
+pub fn is_playlist_content(body: &[u8]) -> bool {
+    let body = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(body);
+    body.trim_ascii_start().starts_with(b"#EXTM3U")
+}
+
+impl PlaylistDetection {
+    pub fn new(patterns: Vec<String>) -> Self {
+        Self {
+            patterns,
+            learned: Arc::default(),
+        }
+    }
+
+    /// The same patterns, without the playlists learned by another stream
+    pub fn unlearned(&self) -> Self {
+        Self::new(self.patterns.clone())
+    }
+
+    /// Whether the request is of a playlist without telling it by its extension
+    pub fn is_playlist(&self, path: &str, query: Option<&str>) -> bool {
+        let target = match query {
+            Some(query) => format!("{path}?{query}"),
+            None => path.to_string(),
+        };
+        self.patterns.iter().any(|pattern| target.contains(pattern.as_str()))
+            || (!self.learned.is_empty() && self.learned.contains(&shape(path, query)))
+    }
+
+    /// Take the next requests of the same shape as playlists
+    pub fn learn(&self, path: &str, query: Option<&str>) {
+        if self.learned.len() >= MAX_LEARNED_SHAPES {
+            log::warn!("Not learning the playlist {path}, {MAX_LEARNED_SHAPES} playlists are known already");
+            return;
+        }
+        if self.learned.insert(shape(path, query)) {
+            log::info!("{path} is a playlist, as told by its content");
+        }
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "patterns": self.patterns.clone(),
+            "learned_playlists": self.learned.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_the_playlists_without_an_extension() {
+        let detection = PlaylistDetection::new(vec!["format=hls".to_string()]);
+        assert!(detection.is_playlist("/packager", Some("asset=news&format=hls")));
+        assert!(!detection.is_playlist("/packager", Some("asset=news&segment=10")));
+
+        detection.learn("/origin/media", Some("token=abc&variant=1"));
+        assert!(detection.is_playlist("/origin/media", Some("variant=2&token=def")));
+        assert!(!detection.is_playlist("/origin/media", Some("variant=2&segment=10&token=def")));
+
+        assert!(is_playlist_content_type("application/vnd.apple.mpegURL; charset=utf-8"));
+        assert!(!is_playlist_content_type("video/mp2t"));
+        assert!(is_playlist_content(b"\xEF\xBB\xBF#EXTM3U\n#EXT-X-VERSION:3\n"));
+        assert!(!is_playlist_content(b"\x47\x40\x00"));
+    }
+}
@@ -0,0 +1,83 @@
+use crate::metrics::Metrics;
+use hls_m3u8::MediaPlaylist;
+
+/// What a refresh of a media playlist carried and what was inserted into it,
+/// counted by the playlist_* metrics so that breaks silently left out, e.g.
+/// by gaps in the program date times, show up in the monitoring
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlaylistStats {
+    pub segments: u64,
+    // The EXT-X-PROGRAM-DATE-TIME tags of the origin
+    pub program_date_times: u64,
+    // The EXT-X-PROGRAM-DATE-TIME tags added by the proxy
+    pub synthesized_program_date_times: u64,
+    pub discontinuities: u64,
+    pub date_ranges: u64,
+    // The slots starting within the playlist that were cued, and those that weren't
+    pub matched_slots: u64,
+    pub unmatched_slots: u64,
+    // Why no break was inserted, None when the playlist was processed
+    pub skipped: Option<&'static str>,
+}
+
+impl PlaylistStats {
+    /// The segments and the tags of a media playlist of the origin
+    pub fn new(m3u8: &MediaPlaylist) -> Self {
+        let mut stats = Self::default();
+        for (_, segment) in m3u8.segments.iter() {
+            stats.segments += 1;
+            stats.program_date_times += segment.program_date_time.is_some() as u64;
+            stats.discontinuities += segment.has_discontinuity as u64;
+        }
+        stats
+    }
+
+    pub fn skip(mut self, reason: &'static str) -> Self {
+        self.skipped = Some(reason);
+        self
+    }
+
+    pub fn record(&self, metrics: &Metrics, channel: &str) {
+        let result = self.skipped.unwrap_or("processed");
+        metrics.inc("playlist_refreshes_total", &[("channel", channel), ("result", result)]);
+        metrics.add("playlist_segments_total", &[("channel", channel)], self.segments);
+        metrics.add(
+            "playlist_program_date_times_total",
+            &[("channel", channel), ("source", "origin")],
+            self.program_date_times,
+        );
+        metrics.add(
+            "playlist_program_date_times_total",
+            &[("channel", channel), ("source", "synthesized")],
+            self.synthesized_program_date_times,
+        );
+        metrics.add("playlist_discontinuities_total", &[("channel", channel)], self.discontinuities);
+        metrics.add("playlist_date_ranges_inserted_total", &[("channel", channel)], self.date_ranges);
+        metrics.add("playlist_slots_total", &[("channel", channel), ("result", "matched")], self.matched_slots);
+        metrics.add("playlist_slots_total", &[("channel", channel), ("result", "unmatched")], self.unmatched_slots);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_the_tags_of_the_playlist() {
+        let m3u8 = MediaPlaylist::try_from(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:1\n\
+             #EXT-X-PROGRAM-DATE-TIME:2026-10-15T12:00:00.000Z\n#EXTINF:4.0,\nsegment1.ts\n\
+             #EXTINF:4.0,\nsegment2.ts\n\
+             #EXT-X-DISCONTINUITY\n#EXTINF:4.0,\nsegment3.ts\n",
+        )
+        .unwrap();
+        let stats = PlaylistStats::new(&m3u8);
+        assert_eq!((stats.segments, stats.program_date_times, stats.discontinuities), (3, 1, 1));
+
+        let metrics = Metrics::default();
+        stats.skip("no_program_date_time").record(&metrics, "news");
+        let rendered = metrics.render();
+        assert!(rendered.contains(r#"playlist_refreshes_total{channel="news",result="no_program_date_time"} 1"#));
+        assert!(rendered.contains(r#"playlist_segments_total{channel="news"} 3"#));
+    }
+}
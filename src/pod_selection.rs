@@ -0,0 +1,95 @@
+// The duration step of the selection, in seconds
+const STEP: f64 = 0.001;
+
+/// A creative of a pod up for selection: the ads of a VAST pod (with a
+/// `sequence`) are mandatory, those of its buffet fill the rest of the break.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub duration: f64,
+    pub mandatory: bool,
+}
+
+/// The indexes of the candidates, in their order, making the pod lasting the
+/// closest to `slot_duration` without going over it by more than `tolerance`.
+/// The mandatory candidates are always kept, even when they alone are too long.
+/// Between combinations of the same length, the earlier buffet ads win.
+pub fn select_pod(candidates: &[Candidate], slot_duration: f64, tolerance: f64) -> Vec<usize> {
+    let steps = |duration: f64| (duration.max(0.0) / STEP).round() as usize;
+    let mandatory = candidates
+        .iter()
+        .filter(|candidate| candidate.mandatory)
+        .map(|candidate| candidate.duration)
+        .sum::<f64>();
+    let budget = steps(slot_duration + tolerance - mandatory);
+
+    // The optional candidate ending a fill of each length and the length before it
+    let mut fills: Vec<Option<(usize, usize)>> = vec![None; budget + 1];
+    let mut reached = vec![false; budget + 1];
+    reached[0] = true;
+    for (index, candidate) in candidates.iter().enumerate().filter(|(_, candidate)| !candidate.mandatory) {
+        let length = steps(candidate.duration);
+        if length == 0 || length > budget {
+            continue;
+        }
+        // Downwards so that each candidate is used once
+        for end in (length..=budget).rev() {
+            if !reached[end] && reached[end - length] {
+                reached[end] = true;
+                fills[end] = Some((index, end - length));
+            }
+        }
+    }
+
+    // The longest fill, or the one closest above the break within the tolerance
+    let target = steps(slot_duration - mandatory);
+    let best = (0..=budget)
+        .filter(|end| reached[*end])
+        .min_by_key(|end| (end.abs_diff(target), *end > target))
+        .unwrap_or_default();
+    let mut selected = candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, candidate)| candidate.mandatory)
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    let mut end = best;
+    while let Some((index, previous)) = fills[end] {
+        selected.push(index);
+        end = previous;
+    }
+    selected.sort_unstable();
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffet(durations: &[f64]) -> Vec<Candidate> {
+        durations.iter().map(|duration| Candidate { duration: *duration, mandatory: false }).collect()
+    }
+
+    #[test]
+    fn fills_the_break_with_the_best_combination() {
+        let candidates = buffet(&[20.0, 15.0, 10.0, 15.0]);
+        assert_eq!(select_pod(&candidates, 30.0, 0.0), vec![0, 2]);
+        assert_eq!(select_pod(&candidates, 25.0, 0.0), vec![1, 2]);
+        assert_eq!(select_pod(&candidates, 45.0, 0.0), vec![0, 1, 2]);
+        assert_eq!(select_pod(&candidates, 5.0, 0.0), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn goes_over_the_break_within_the_tolerance() {
+        let candidates = buffet(&[20.5, 15.0]);
+        assert_eq!(select_pod(&candidates, 20.0, 0.0), vec![1]);
+        assert_eq!(select_pod(&candidates, 20.0, 1.0), vec![0]);
+    }
+
+    #[test]
+    fn keeps_the_sequenced_ads() {
+        let mut candidates = buffet(&[10.0, 15.0, 5.0]);
+        candidates[1].mandatory = true;
+        assert_eq!(select_pod(&candidates, 20.0, 0.0), vec![1, 2]);
+        assert_eq!(select_pod(&candidates, 10.0, 0.0), vec![1]);
+    }
+}
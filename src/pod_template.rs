@@ -0,0 +1,88 @@
+/// A named ad break scheduled with `/command?in=30&template=midroll`
+/// (`--pod-template name=duration[,key=value...]`). `pod` sets the number of
+/// creatives, the other settings are added to the ad server requests of the
+/// break, e.g. `midroll=30,pod=3,genre=sports`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PodTemplate {
+    pub name: String,
+    /// Duration in seconds
+    pub duration: f64,
+    pub pod_num: Option<u64>,
+    pub ad_server_params: Vec<(String, String)>,
+}
+
+impl PodTemplate {
+    /// Parse the `--pod-template` values, the names have to be unique
+    pub fn parse_all(values: &[String]) -> Result<Vec<Self>, String> {
+        let mut templates: Vec<Self> = Vec::new();
+        for value in values {
+            let template = Self::parse(value)?;
+            if templates.iter().any(|other| other.name == template.name) {
+                return Err(format!("Pod template {} is given more than once", template.name));
+            }
+            templates.push(template);
+        }
+        Ok(templates)
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = value.split(',');
+        let (name, duration) = parts
+            .next()
+            .and_then(|part| part.split_once('='))
+            .ok_or_else(|| format!("Invalid pod template '{value}', expected name=duration[,key=value...]"))?;
+        let name = name.trim();
+        let is_valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_valid_name {
+            return Err(format!("Invalid pod template name '{name}'"));
+        }
+        let duration = duration
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|duration| duration.is_finite() && *duration > 0.0)
+            .ok_or_else(|| format!("Invalid duration of pod template {name}: {}", duration.trim()))?;
+
+        let mut template = Self {
+            name: name.to_string(),
+            duration,
+            pod_num: None,
+            ad_server_params: Vec::new(),
+        };
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid setting '{part}' of pod template {name}"))?;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "pod" => {
+                    template.pod_num = Some(
+                        value
+                            .parse::<u64>()
+                            .map_err(|err| format!("Invalid pod of pod template {name}: {err}"))?,
+                    )
+                }
+                "" => return Err(format!("Invalid setting '{part}' of pod template {name}")),
+                _ => template.ad_server_params.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        Ok(template)
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut params = json::JsonValue::new_object();
+        for (key, value) in &self.ad_server_params {
+            params[key.as_str()] = value.as_str().into();
+        }
+        json::object! {
+            "name": self.name.as_str(),
+            "duration": self.duration,
+            "pod_num": self.pod_num,
+            "ad_server_params": params,
+        }
+    }
+}
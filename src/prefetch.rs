@@ -0,0 +1,87 @@
+use crate::metrics::Metrics;
+use actix_web::web::{self, Bytes};
+use dashmap::DashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+// The prefetched pods of a session not asked for within this long are dropped
+const PREFETCH_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+// The VAST of an ad server request, once it has been answered
+type PrefetchedPod = Arc<OnceCell<Option<Bytes>>>;
+
+/// Requests the pods of all the breaks of a VOD session in parallel on its
+/// first media playlist, so that the asset lists don't wait for the ad server
+/// at each break. The pods are kept by session and ad server URL, an asset
+/// list requesting another URL (e.g. under a rule) calls the ad server itself.
+#[derive(Clone, Default)]
+pub struct PodPrefetch {
+    enabled: bool,
+    // The sessions whose pods were prefetched, and when
+    sessions: Arc<DashMap<String, Instant>>,
+    pods: Arc<DashMap<(String, String), PrefetchedPod>>,
+    metrics: web::Data<Metrics>,
+}
+
+impl PodPrefetch {
+    pub fn new(enabled: bool, metrics: web::Data<Metrics>) -> Self {
+        Self {
+            enabled,
+            sessions: Arc::default(),
+            pods: Arc::default(),
+            metrics,
+        }
+    }
+
+    /// Whether the pods of the session are still to be prefetched, they are
+    /// taken as being so from then on
+    pub fn start(&self, session: &str) -> bool {
+        if !self.enabled || self.sessions.contains_key(session) {
+            return false;
+        }
+        self.sessions.retain(|_, started_at| started_at.elapsed() < PREFETCH_TTL);
+        self.pods.retain(|(session, _), _| self.sessions.contains_key(session));
+        self.sessions.insert(session.to_string(), Instant::now());
+        true
+    }
+
+    /// Request the pod of an ad server URL for the session in the background
+    pub fn prefetch<Fut>(&self, session: &str, ad_url: &str, fetch: Fut)
+    where
+        Fut: Future<Output = Option<Bytes>> + 'static,
+    {
+        let pod = PrefetchedPod::default();
+        self.pods.insert((session.to_string(), ad_url.to_string()), pod.clone());
+        self.metrics.inc("vod_prefetch_requests_total", &[]);
+        actix_web::rt::spawn(async move {
+            pod.get_or_init(|| fetch).await;
+        });
+    }
+
+    /// The prefetched pod of the ad server URL for the session, waiting for it
+    /// if it is still being fetched. It is served once, a later request of the
+    /// break calls the ad server again
+    pub async fn take(&self, session: &str, ad_url: &str) -> Option<Bytes> {
+        if !self.enabled {
+            return None;
+        }
+        let Some((_, pod)) = self.pods.remove(&(session.to_string(), ad_url.to_string())) else {
+            self.metrics.inc("vod_prefetch_asset_lists_total", &[("result", "miss")]);
+            return None;
+        };
+        let vast = pod.get_or_init(|| async { None }).await.clone();
+        let result = if vast.is_some() { "hit" } else { "failed" };
+        self.metrics.inc("vod_prefetch_asset_lists_total", &[("result", result)]);
+        vast
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "enabled": self.enabled,
+            "sessions": self.sessions.len(),
+            "pods": self.pods.len(),
+        }
+    }
+}
@@ -0,0 +1,661 @@
+use crate::compatibility::{VideoFormat, video_codec_family};
+use crate::metrics::Metrics;
+use crate::utils::is_hls_playlist;
+use actix_web::http::{StatusCode, header};
+use actix_web::web::{self, Bytes};
+use awc::Client;
+use clap::ValueEnum;
+use dashmap::DashMap;
+use hls_m3u8::tags::VariantStream;
+use hls_m3u8::{MasterPlaylist, MediaPlaylist};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::Url;
+
+// The first request of an MP4 covers the ftyp and moov of most faststart files
+const FIRST_RANGE: u64 = 64 * 1024;
+// Larger moov boxes are not fetched
+const MAX_BOX_SIZE: u64 = 16 * 1024 * 1024;
+// Give up on finding the moov after this many top-level boxes
+const MAX_BOXES: usize = 32;
+// Give up on indexing the fragments after this many top-level boxes
+const MAX_FRAGMENT_BOXES: usize = 1024;
+const MAX_PLAYLIST_SIZE: usize = 2 * 1024 * 1024;
+// A media file that couldn't be probed is probed again after this long
+const RETRY_FAILED_AFTER: Duration = Duration::from_secs(60);
+// Probed durations this far from the VAST duration are logged
+const MISMATCH_THRESHOLD: f64 = 1.0;
+// The asset list waits this long for the probes, slower ones finish in the background
+const PROBE_DEADLINE: Duration = Duration::from_secs(2);
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum DurationProbing {
+    /// Trust the VAST durations
+    Off,
+    /// Probe the creatives with a missing or zero VAST duration
+    #[default]
+    Missing,
+    /// Probe all creatives and prefer the probed durations over the VAST ones
+    Always,
+}
+
+impl DurationProbing {
+    pub fn to_str(&self) -> &str {
+        match self {
+            DurationProbing::Off => "off",
+            DurationProbing::Missing => "missing",
+            DurationProbing::Always => "always",
+        }
+    }
+}
+
+struct Probe {
+    duration: Option<f64>,
+    // The video track of a probed MP4
+    format: Option<VideoFormat>,
+    probed_at: Instant,
+}
+
+/// A fragment of a fragmented MP4, its moof box and media data
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragment {
+    pub offset: u64,
+    pub length: u64,
+    /// Duration in seconds
+    pub duration: f64,
+}
+
+/// The byte ranges of a fragmented MP4, to package it in several segments
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragments {
+    /// Length of the boxes before the first fragment, the ftyp and the moov
+    pub init_length: u64,
+    pub fragments: Vec<Fragment>,
+}
+
+struct FragmentIndex {
+    // None for MP4s that aren't fragmented, or couldn't be indexed
+    fragments: Option<Fragments>,
+    failed: bool,
+    indexed_at: Instant,
+}
+
+/// Determines the real duration of the creatives from their media files, the
+/// moov box of an MP4 or the segments of an HLS playlist, since the VAST
+/// `<Duration>` is sometimes missing or wrong. The durations, and the video
+/// formats of the MP4s, are kept per media file for the lifetime of the proxy.
+/// When the playback progress is inferred from the segment requests, the
+/// fragments of fragmented MP4s are indexed as well, so that they can be
+/// packaged in several segments.
+#[derive(Clone, Default)]
+pub struct DurationProber {
+    mode: DurationProbing,
+    durations: Arc<DashMap<String, Probe>>,
+    fragments: Arc<DashMap<String, FragmentIndex>>,
+    metrics: web::Data<Metrics>,
+}
+
+impl DurationProber {
+    pub fn new(mode: DurationProbing, metrics: web::Data<Metrics>) -> Self {
+        Self {
+            mode,
+            metrics,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.mode != DurationProbing::Off
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "mode": self.mode.to_str(),
+            "probed": self.durations.iter().filter(|probe| probe.duration.is_some()).count(),
+            "failed": self.durations.iter().filter(|probe| probe.duration.is_none()).count(),
+            "fragmented": self.fragments.iter().filter(|index| index.fragments.is_some()).count(),
+        }
+    }
+
+    /// The probed duration of the media file in seconds, if it was probed
+    pub fn duration(&self, media_url: &str) -> Option<f64> {
+        self.durations.get(media_url).and_then(|probe| probe.duration)
+    }
+
+    /// The video format of the media file, if it's a probed MP4
+    pub fn format(&self, media_url: &str) -> Option<VideoFormat> {
+        self.durations.get(media_url).and_then(|probe| probe.format.clone())
+    }
+
+    /// The fragments of the media file, if it's an indexed fragmented MP4
+    pub fn fragments(&self, media_url: &str) -> Option<Fragments> {
+        self.fragments.get(media_url).and_then(|index| index.fragments.clone())
+    }
+
+    /// Probe the media files of the creatives, given with their VAST durations,
+    /// and index the fragments of the MP4s if `index_fragments` is set.
+    /// Waits for the probes up to a short deadline, the ones still running then
+    /// finish in the background and are used by the following asset lists.
+    pub async fn probe_all(&self, client: &Client, media_files: Vec<(String, f64)>, index_fragments: bool) {
+        let prober = self.clone();
+        let client = client.clone();
+        let probes = actix_web::rt::spawn(async move {
+            futures_util::future::join_all(media_files.iter().map(|(media_url, duration)| async {
+                prober.probe(&client, media_url, *duration).await;
+                if index_fragments {
+                    prober.index(&client, media_url).await;
+                }
+            }))
+            .await;
+        });
+        if actix_web::rt::time::timeout(PROBE_DEADLINE, probes).await.is_err() {
+            log::warn!("Creative durations not probed within {}ms, continuing with the VAST durations", PROBE_DEADLINE.as_millis());
+        }
+    }
+
+    /// Probe the media file of a creative, unless its VAST duration is trusted
+    /// or it was probed already
+    pub async fn probe(&self, client: &Client, media_url: &str, vast_duration: f64) {
+        match self.mode {
+            DurationProbing::Off => return,
+            DurationProbing::Missing if vast_duration > 0.0 => return,
+            _ => {}
+        }
+        if let Some(probe) = self.durations.get(media_url) {
+            if probe.duration.is_some() || probe.probed_at.elapsed() < RETRY_FAILED_AFTER {
+                return;
+            }
+        }
+
+        let result = match Url::parse(media_url) {
+            Ok(url) if is_hls_playlist(url.path()) => probe_playlist(client, url).await.map(|duration| (duration, None)),
+            Ok(url) => probe_mp4(client, &url).await,
+            Err(err) => Err(err.to_string()),
+        };
+        let (duration, format) = match result {
+            Ok((duration, format)) => {
+                if vast_duration <= 0.0 {
+                    log::info!("Probed duration {duration:.3}s of {media_url}, the VAST has none");
+                } else if (duration - vast_duration).abs() >= MISMATCH_THRESHOLD {
+                    log::warn!("Probed duration {duration:.3}s of {media_url} differs from the VAST duration {vast_duration}s");
+                }
+                self.metrics.inc("duration_probes_total", &[("result", "ok")]);
+                (Some(duration), format)
+            }
+            Err(err) => {
+                log::error!("Failed to probe the duration of {media_url}: {err}");
+                self.metrics.inc("duration_probes_total", &[("result", "failed")]);
+                (None, None)
+            }
+        };
+        self.durations.insert(
+            media_url.to_string(),
+            Probe {
+                duration,
+                format,
+                probed_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Index the fragments of an MP4 media file, unless it was indexed already
+    pub async fn index(&self, client: &Client, media_url: &str) {
+        if let Some(index) = self.fragments.get(media_url) {
+            if !index.failed || index.indexed_at.elapsed() < RETRY_FAILED_AFTER {
+                return;
+            }
+        }
+
+        let result = match Url::parse(media_url) {
+            Ok(url) if is_hls_playlist(url.path()) => return,
+            Ok(url) => index_fragments(client, &url).await,
+            Err(err) => Err(err.to_string()),
+        };
+        let (fragments, failed) = match result {
+            Ok(Some(fragments)) => {
+                log::info!("Indexed {} fragments of {media_url}", fragments.fragments.len());
+                (Some(fragments), false)
+            }
+            Ok(None) => (None, false),
+            Err(err) => {
+                log::error!("Failed to index the fragments of {media_url}: {err}");
+                (None, true)
+            }
+        };
+        self.fragments.insert(
+            media_url.to_string(),
+            FragmentIndex {
+                fragments,
+                failed,
+                indexed_at: Instant::now(),
+            },
+        );
+    }
+}
+
+// The sum of the segment durations, of the first variant of a master playlist
+async fn probe_playlist(client: &Client, url: Url) -> Result<f64, String> {
+    let body = fetch(client, &url, None, MAX_PLAYLIST_SIZE).await?;
+    let text = String::from_utf8_lossy(&body);
+    let media = match MasterPlaylist::try_from(text.as_ref()) {
+        Ok(master) => {
+            let Some(VariantStream::ExtXStreamInf { uri, .. }) = master.variant_streams.first() else {
+                return Err("The master playlist has no variant streams".to_string());
+            };
+            let variant_url = url.join(uri).map_err(|err| err.to_string())?;
+            fetch(client, &variant_url, None, MAX_PLAYLIST_SIZE).await?
+        }
+        Err(_) => body,
+    };
+    let text = String::from_utf8_lossy(&media);
+    let playlist = MediaPlaylist::try_from(text.as_ref()).map_err(|err| format!("Invalid media playlist: {err}"))?;
+
+    Ok(playlist
+        .segments
+        .iter()
+        .map(|(_, segment)| segment.duration.duration().as_secs_f64())
+        .sum())
+}
+
+// The movie duration and video format of the moov box, fetching only the
+// top-level boxes up to it
+async fn probe_mp4(client: &Client, url: &Url) -> Result<(f64, Option<VideoFormat>), String> {
+    let mut chunk = fetch(client, url, Some((0, FIRST_RANGE)), MAX_BOX_SIZE as usize).await?;
+    let mut chunk_start = 0;
+    let mut offset = 0;
+    let mut ftyp = None;
+
+    for _ in 0..MAX_BOXES {
+        if offset + 16 > chunk_start + chunk.len() as u64 {
+            chunk = fetch(client, url, Some((offset, FIRST_RANGE)), MAX_BOX_SIZE as usize).await?;
+            chunk_start = offset;
+        }
+        let local = (offset - chunk_start) as usize;
+        let (name, size) = box_header(&chunk[local..])?;
+        if name == *b"ftyp" || name == *b"moov" {
+            if size > MAX_BOX_SIZE {
+                return Err(format!("The {} box is too large ({size} bytes)", String::from_utf8_lossy(&name)));
+            }
+            let data = if local as u64 + size <= chunk.len() as u64 {
+                chunk.slice(local..local + size as usize)
+            } else {
+                fetch(client, url, Some((offset, size)), MAX_BOX_SIZE as usize).await?
+            };
+            if name == *b"ftyp" {
+                ftyp = Some(data);
+            } else {
+                let ftyp = ftyp.ok_or("No ftyp box before the moov box")?;
+                return Ok((movie_duration(&ftyp, &data)?, video_format(&ftyp, &data)));
+            }
+        }
+        offset = offset.checked_add(size).ok_or("Invalid box size")?;
+    }
+    Err(format!("No moov box within the first {MAX_BOXES} boxes"))
+}
+
+// The byte ranges and durations of the fragments, walking the top-level
+// boxes and fetching the moov and moof ones. None if the MP4 has no fragments
+async fn index_fragments(client: &Client, url: &Url) -> Result<Option<Fragments>, String> {
+    let mut chunk = fetch(client, url, Some((0, FIRST_RANGE)), MAX_BOX_SIZE as usize).await?;
+    let mut chunk_start = 0;
+    let mut offset = 0;
+    let mut header = Vec::new();
+    let mut moofs = Vec::new();
+
+    for _ in 0..MAX_FRAGMENT_BOXES {
+        if offset + 16 > chunk_start + chunk.len() as u64 {
+            chunk = fetch(client, url, Some((offset, FIRST_RANGE)), MAX_BOX_SIZE as usize).await?;
+            chunk_start = offset;
+        }
+        let local = (offset - chunk_start) as usize;
+        if local >= chunk.len() {
+            // The end of the file
+            break;
+        }
+        let (name, size) = box_header(&chunk[local..])?;
+        if name == *b"ftyp" || name == *b"moov" || name == *b"moof" {
+            if size > MAX_BOX_SIZE {
+                return Err(format!("The {} box is too large ({size} bytes)", String::from_utf8_lossy(&name)));
+            }
+            let data = if local as u64 + size <= chunk.len() as u64 {
+                chunk.slice(local..local + size as usize)
+            } else {
+                fetch(client, url, Some((offset, size)), MAX_BOX_SIZE as usize).await?
+            };
+            if name == *b"moof" {
+                moofs.push(offset);
+            } else if !moofs.is_empty() {
+                return Err(format!("The {} box follows the fragments", String::from_utf8_lossy(&name)));
+            }
+            header.extend_from_slice(&data);
+        }
+        offset = offset.checked_add(size).ok_or("Invalid box size")?;
+    }
+    let Some(&init_length) = moofs.first() else {
+        return Ok(None);
+    };
+
+    let durations = fragment_durations(&header)?;
+    let ends = moofs.iter().skip(1).copied().chain([offset]);
+    let fragments = moofs
+        .iter()
+        .zip(ends)
+        .zip(durations)
+        .map(|((&start, end), duration)| Fragment {
+            offset: start,
+            length: end - start,
+            duration,
+        })
+        .collect();
+
+    Ok(Some(Fragments { init_length, fragments }))
+}
+
+// The duration of each moof in the ftyp, moov and moof boxes, taken from the
+// first track fragment of each
+fn fragment_durations(header: &[u8]) -> Result<Vec<f64>, String> {
+    let size = header.len() as u64;
+    let mp4 = mp4::Mp4Reader::read_header(Cursor::new(header), size).map_err(|err| err.to_string())?;
+    let default_duration = mp4.moov.mvex.as_ref().map_or(0, |mvex| mvex.trex.default_sample_duration);
+
+    mp4.moofs
+        .iter()
+        .map(|moof| {
+            let traf = moof.trafs.first().ok_or("A moof box has no track fragment")?;
+            let timescale = mp4
+                .tracks()
+                .get(&traf.tfhd.track_id)
+                .map(|track| track.timescale())
+                .filter(|timescale| *timescale > 0)
+                .ok_or("A track fragment has no track")?;
+            let trun = traf.trun.as_ref().ok_or("A track fragment has no samples")?;
+            let duration: u64 = if trun.sample_durations.is_empty() {
+                let sample_duration = traf.tfhd.default_sample_duration.unwrap_or(default_duration);
+                trun.sample_count as u64 * sample_duration as u64
+            } else {
+                trun.sample_durations.iter().map(|duration| *duration as u64).sum()
+            };
+            Ok(duration as f64 / timescale as f64)
+        })
+        .collect()
+}
+
+// The type and the size of the box at the start of the data
+fn box_header(data: &[u8]) -> Result<([u8; 4], u64), String> {
+    if data.len() < 8 {
+        return Err("No moov box in the file".to_string());
+    }
+    let name = [data[4], data[5], data[6], data[7]];
+    let (size, header_size) = match u32::from_be_bytes([data[0], data[1], data[2], data[3]]) {
+        // A 64-bit size follows the type
+        1 => match data.get(8..16) {
+            Some(large_size) => (u64::from_be_bytes(large_size.try_into().unwrap()), 16),
+            None => return Err(format!("Truncated {} box header", String::from_utf8_lossy(&name))),
+        },
+        // The box reaches the end of the file, which isn't known here
+        0 => return Err(format!("The {} box has no size", String::from_utf8_lossy(&name))),
+        size => (size as u64, 8),
+    };
+    if size < header_size {
+        return Err(format!("Invalid {} box size {size}", String::from_utf8_lossy(&name)));
+    }
+    Ok((name, size))
+}
+
+fn movie_duration(ftyp: &[u8], moov: &[u8]) -> Result<f64, String> {
+    let header = [ftyp, moov].concat();
+    let size = header.len() as u64;
+    let mp4 = mp4::Mp4Reader::read_header(Cursor::new(header), size).map_err(|err| err.to_string())?;
+    let duration = mp4.duration().as_secs_f64();
+    if duration <= 0.0 {
+        return Err("The movie has no duration".to_string());
+    }
+    Ok(duration)
+}
+
+// The format of the first video track, the frame rate is the average one
+fn video_format(ftyp: &[u8], moov: &[u8]) -> Option<VideoFormat> {
+    let header = [ftyp, moov].concat();
+    let size = header.len() as u64;
+    let mp4 = mp4::Mp4Reader::read_header(Cursor::new(header), size).ok()?;
+    let track = mp4
+        .tracks()
+        .values()
+        .find(|track| matches!(track.track_type(), Ok(mp4::TrackType::Video)))?;
+    let dimension = |value: u16| Some(value as usize).filter(|value| *value > 0);
+    Some(VideoFormat {
+        codec: track.box_type().ok().and_then(|fourcc| video_codec_family(&fourcc.to_string())),
+        width: dimension(track.width()),
+        height: dimension(track.height()),
+        frame_rate: Some(track.frame_rate()).filter(|frame_rate| *frame_rate > 0.0),
+    })
+}
+
+async fn fetch(client: &Client, url: &Url, range: Option<(u64, u64)>, max_size: usize) -> Result<Bytes, String> {
+    let mut request = client.get(url.as_str());
+    if let Some((start, length)) = range {
+        request = request.insert_header((header::RANGE, format!("bytes={start}-{}", start + length - 1)));
+    }
+    let mut response = request.send().await.map_err(|err| err.to_string())?;
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // The range starts at the end of the file
+        return Ok(Bytes::new());
+    }
+    // A server ignoring the range sends the file from its start
+    let ranged = response.status() == StatusCode::PARTIAL_CONTENT;
+    if !response.status().is_success() || (range.is_some_and(|(start, _)| start > 0) && !ranged) {
+        return Err(format!("{url} answered with status {}", response.status()));
+    }
+    let body = response.body().limit(max_size).await.map_err(|err| err.to_string())?;
+
+    Ok(match range {
+        Some((_, length)) if !ranged => body.slice(..body.len().min(length as usize)),
+        _ => body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpRequest, HttpResponse, HttpServer};
+
+    fn mp4_box(name: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        [&(payload.len() as u32 + 8).to_be_bytes(), name.as_slice(), payload].concat()
+    }
+
+    fn ftyp() -> Vec<u8> {
+        mp4_box(b"ftyp", &[b"isom".as_slice(), &512u32.to_be_bytes(), b"isom"].concat())
+    }
+
+    // A moov with nothing but the version 0 mvhd of a movie of this duration
+    fn moov(timescale: u32, duration: u32) -> Vec<u8> {
+        let mut mvhd = vec![0; 12];
+        mvhd.extend(timescale.to_be_bytes());
+        mvhd.extend(duration.to_be_bytes());
+        mvhd.extend(0x0001_0000u32.to_be_bytes());
+        mvhd.extend(0x0100u16.to_be_bytes());
+        mvhd.extend([0; 10]);
+        for value in [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+            mvhd.extend(value.to_be_bytes());
+        }
+        mvhd.extend([0; 24]);
+        mvhd.extend(2u32.to_be_bytes());
+        mp4_box(b"moov", &mp4_box(b"mvhd", &mvhd))
+    }
+
+    #[test]
+    fn box_header_reads_32_and_64_bit_sizes() {
+        assert_eq!(box_header(&ftyp()), Ok((*b"ftyp", 20)));
+
+        let large = [&1u32.to_be_bytes(), b"mdat".as_slice(), &(u32::MAX as u64 + 1).to_be_bytes()].concat();
+        assert_eq!(box_header(&large), Ok((*b"mdat", u32::MAX as u64 + 1)));
+    }
+
+    #[test]
+    fn box_header_rejects_invalid_headers() {
+        // Truncated header
+        assert!(box_header(b"\0\0\0\x08moo").is_err());
+        // Truncated 64-bit size
+        assert!(box_header(&[&1u32.to_be_bytes(), b"mdat".as_slice(), &[0; 4]].concat()).is_err());
+        // Size up to the end of the file
+        assert!(box_header(&[&0u32.to_be_bytes(), b"mdat".as_slice()].concat()).is_err());
+        // Sizes smaller than the header itself
+        assert!(box_header(&[&4u32.to_be_bytes(), b"free".as_slice()].concat()).is_err());
+        assert!(box_header(&[&1u32.to_be_bytes(), b"mdat".as_slice(), &8u64.to_be_bytes()].concat()).is_err());
+    }
+
+    #[test]
+    fn movie_duration_of_the_mvhd() {
+        assert_eq!(movie_duration(&ftyp(), &moov(1000, 15_500)), Ok(15.5));
+    }
+
+    #[test]
+    fn movie_duration_rejects_empty_and_broken_movies() {
+        assert!(movie_duration(&ftyp(), &moov(1000, 0)).is_err());
+        assert!(movie_duration(&ftyp(), &mp4_box(b"moov", &[])).is_err());
+        let moov = moov(1000, 15_500);
+        assert!(movie_duration(&ftyp(), &moov[..moov.len() / 2]).is_err());
+    }
+
+    // Serves the file, honouring single byte ranges
+    fn start_server(file: Vec<u8>) -> (Url, actix_web::dev::ServerHandle) {
+        let file = web::Data::new(file);
+        let server = HttpServer::new(move || {
+            App::new().app_data(file.clone()).default_service(web::get().to(
+                |req: HttpRequest, file: web::Data<Vec<u8>>| async move {
+                    let range = req
+                        .headers()
+                        .get(header::RANGE)
+                        .and_then(|range| range.to_str().ok()?.strip_prefix("bytes=")?.split_once('-'))
+                        .and_then(|(start, end)| Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?)));
+                    match range {
+                        Some((start, _)) if start >= file.len() => HttpResponse::RangeNotSatisfiable().finish(),
+                        Some((start, end)) => HttpResponse::PartialContent()
+                            .body(file[start..=end.min(file.len() - 1)].to_vec()),
+                        None => HttpResponse::Ok().body(file.to_vec()),
+                    }
+                },
+            ))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        (Url::parse(&format!("http://{addr}/ad.mp4")).unwrap(), handle)
+    }
+
+    async fn probe_file(file: Vec<u8>) -> Result<f64, String> {
+        let (url, handle) = start_server(file);
+        let result = probe_mp4(&Client::default(), &url).await;
+        handle.stop(false).await;
+        result.map(|(duration, _)| duration)
+    }
+
+    #[actix_web::test]
+    async fn probe_mp4_finds_the_moov_behind_the_media_data() {
+        let mdat = mp4_box(b"mdat", &vec![0; 2 * FIRST_RANGE as usize]);
+        let file = [ftyp(), mdat, moov(90_000, 900_000)].concat();
+        assert_eq!(probe_file(file).await, Ok(10.0));
+    }
+
+    fn full_box(name: &[u8; 4], flags: u32, payload: &[u8]) -> Vec<u8> {
+        mp4_box(name, &[&flags.to_be_bytes(), payload].concat())
+    }
+
+    // A moov of a single 90kHz track with 3000 ticks per sample by default,
+    // and a moof of each fragment given with its sample durations, if any
+    fn fragmented_header(samples: &[(u32, Vec<u32>)]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let mut tkhd = vec![0; 8];
+        tkhd.extend(1u32.to_be_bytes());
+        tkhd.extend([0; 68]);
+        let mut mdhd = vec![0; 8];
+        mdhd.extend(90_000u32.to_be_bytes());
+        mdhd.extend([0; 8]);
+        let hdlr = [&[0; 4], b"vide".as_slice(), &[0; 13]].concat();
+        let stbl = [
+            full_box(b"stsd", 0, &[0; 4]),
+            full_box(b"stts", 0, &[0; 4]),
+            full_box(b"stsc", 0, &[0; 4]),
+            full_box(b"stsz", 0, &[0; 8]),
+            full_box(b"stco", 0, &[0; 4]),
+        ]
+        .concat();
+        let minf = [mp4_box(b"dinf", &full_box(b"dref", 0, &[0; 4])), mp4_box(b"stbl", &stbl)].concat();
+        let mdia = [full_box(b"mdhd", 0, &mdhd), full_box(b"hdlr", 0, &hdlr), mp4_box(b"minf", &minf)].concat();
+        let trak = mp4_box(b"trak", &[full_box(b"tkhd", 0, &tkhd), mp4_box(b"mdia", &mdia)].concat());
+        let trex = [1u32, 1, 3000, 0, 0].iter().flat_map(|value| value.to_be_bytes()).collect::<Vec<_>>();
+        let mvex = mp4_box(b"mvex", &full_box(b"trex", 0, &trex));
+        let moov = moov(1000, 0);
+        let moov = mp4_box(b"moov", &[&moov[8..], trak.as_slice(), mvex.as_slice()].concat());
+
+        let moofs = samples
+            .iter()
+            .enumerate()
+            .map(|(index, (sample_count, sample_durations))| {
+                let flags = if sample_durations.is_empty() { 0 } else { 0x100 };
+                let trun = [sample_count]
+                    .into_iter()
+                    .chain(sample_durations)
+                    .flat_map(|value| value.to_be_bytes())
+                    .collect::<Vec<_>>();
+                let traf = [full_box(b"tfhd", 0, &1u32.to_be_bytes()), full_box(b"trun", flags, &trun)].concat();
+                let mfhd = full_box(b"mfhd", 0, &(index as u32 + 1).to_be_bytes());
+                mp4_box(b"moof", &[mfhd, mp4_box(b"traf", &traf)].concat())
+            })
+            .collect();
+        ([ftyp(), moov].concat(), moofs)
+    }
+
+    #[test]
+    fn fragment_durations_of_default_and_explicit_sample_durations() {
+        let (init, moofs) = fragmented_header(&[(60, vec![]), (2, vec![45_000, 90_000])]);
+        assert_eq!(fragment_durations(&[init, moofs.concat()].concat()), Ok(vec![2.0, 1.5]));
+    }
+
+    #[actix_web::test]
+    async fn index_fragments_of_a_fragmented_mp4() {
+        let (init, moofs) = fragmented_header(&[(60, vec![]), (30, vec![])]);
+        let first_fragment = [moofs[0].clone(), mp4_box(b"mdat", &vec![0; 2 * FIRST_RANGE as usize])].concat();
+        let second_fragment = [moofs[1].clone(), mp4_box(b"mdat", &[0; 256])].concat();
+        let file = [init.clone(), first_fragment.clone(), second_fragment.clone()].concat();
+        let (url, handle) = start_server(file);
+        let fragments = index_fragments(&Client::default(), &url).await;
+        handle.stop(false).await;
+
+        let init_length = init.len() as u64;
+        let first_length = first_fragment.len() as u64;
+        assert_eq!(
+            fragments,
+            Ok(Some(Fragments {
+                init_length,
+                fragments: vec![
+                    Fragment { offset: init_length, length: first_length, duration: 2.0 },
+                    Fragment { offset: init_length + first_length, length: second_fragment.len() as u64, duration: 1.0 },
+                ],
+            }))
+        );
+    }
+
+    #[actix_web::test]
+    async fn index_fragments_of_a_progressive_mp4() {
+        let (url, handle) = start_server([ftyp(), moov(1000, 15_500), mp4_box(b"mdat", &[0; 256])].concat());
+        let fragments = index_fragments(&Client::default(), &url).await;
+        handle.stop(false).await;
+        assert_eq!(fragments, Ok(None));
+    }
+
+    #[actix_web::test]
+    async fn probe_mp4_fails_without_a_moov() {
+        let file = [ftyp(), mp4_box(b"mdat", &[0; 256])].concat();
+        assert!(probe_file(file).await.is_err());
+
+        let huge_mdat = [&1u32.to_be_bytes(), b"mdat".as_slice(), &u64::MAX.to_be_bytes()].concat();
+        assert!(probe_file([ftyp(), huge_mdat].concat()).await.is_err());
+    }
+}
@@ -0,0 +1,32 @@
+// Progress events and the share of the creative that must have been played
+const QUARTILE_EVENTS: [(&str, f64); 4] = [
+    ("start", 0.0),
+    ("firstQuartile", 0.25),
+    ("midpoint", 0.5),
+    ("thirdQuartile", 0.75),
+];
+const COMPLETE_EVENT: &str = "complete";
+
+/// Infers the creative playback progress from a request for segment `index`
+/// out of `count` segments and returns the tracking events reached by then
+pub fn reached_events(index: usize, count: usize) -> Vec<&'static str> {
+    if count == 0 {
+        return Vec::new();
+    }
+
+    // A request for segment `index` means all previous segments have been played
+    let played = index as f64 / count as f64;
+    let mut events = QUARTILE_EVENTS
+        .iter()
+        .filter(|(_, threshold)| played >= *threshold)
+        .map(|(event, _)| *event)
+        .collect::<Vec<_>>();
+
+    // Fetching the last segment is the best available hint that the creative
+    // plays out. With a single segment there's nothing to infer it from.
+    if count > 1 && index + 1 >= count {
+        events.push(COMPLETE_EVENT);
+    }
+
+    events
+}
@@ -0,0 +1,167 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+// The entries waiting to be written, beyond which new ones are dropped rather
+// than holding up the requests
+const QUEUE_SIZE: usize = 10_000;
+
+/// When the request log file is rotated, and how many of the previous files are kept
+#[derive(Clone, Debug)]
+pub struct Rotation {
+    pub max_size: u64,
+    // None to only rotate by size
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+}
+
+/// The access and decision log: one JSON object per line written to a file by
+/// a thread of its own, independent of the logger. The file is rotated to
+/// <path>.1 (the latest) up to <path>.<keep> by size and age
+#[derive(Clone)]
+pub struct RequestLog {
+    sender: SyncSender<String>,
+    path: PathBuf,
+    dropped: Arc<AtomicU64>,
+}
+
+struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    writer: BufWriter<File>,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl LogFile {
+    fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            writer: BufWriter::new(file),
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+
+    // The file of the rotated log `index` rotations ago
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn needs_rotation(&self) -> bool {
+        self.size > 0
+            && (self.size >= self.rotation.max_size
+                || self.rotation.max_age.is_some_and(|max_age| self.opened_at.elapsed() >= max_age))
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // The oldest file is overwritten by the one before it
+            for index in (1..self.rotation.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        *self = Self::open(&self.path, self.rotation.clone())?;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.needs_rotation() {
+            self.rotate()?;
+        }
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+// Write the entries as they come, flushing whenever none is waiting
+fn write_entries(mut file: LogFile, receiver: Receiver<String>) {
+    while let Ok(line) = receiver.recv() {
+        if let Err(err) = file.write(&line) {
+            log::error!("Failed to write the request log {}: {err}", file.path.display());
+        }
+        while let Ok(line) = receiver.try_recv() {
+            if let Err(err) = file.write(&line) {
+                log::error!("Failed to write the request log {}: {err}", file.path.display());
+            }
+        }
+        if let Err(err) = file.writer.flush() {
+            log::error!("Failed to write the request log {}: {err}", file.path.display());
+        }
+    }
+}
+
+impl RequestLog {
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let file = LogFile::open(path, rotation)?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("request-log".to_string())
+            .spawn(move || write_entries(file, receiver))?;
+        Ok(Self {
+            sender,
+            path: path.to_path_buf(),
+            dropped: Arc::default(),
+        })
+    }
+
+    /// Log an entry of `kind` (access, decision), timestamped now
+    pub fn record(&self, kind: &str, mut entry: json::JsonValue) {
+        entry["type"] = kind.into();
+        entry["at"] = chrono::Utc::now()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+            .into();
+        match self.sender.try_send(entry.dump()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                // Reported once in a while, the log is falling behind
+                if self.dropped.fetch_add(1, Ordering::Relaxed) % 1000 == 0 {
+                    log::warn!("The request log {} is falling behind, dropping entries", self.path.display());
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_the_file_by_size() {
+        let dir = std::env::temp_dir().join(format!("request-log-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("requests.log");
+        let rotation = Rotation { max_size: 10, max_age: None, keep: 2 };
+        let mut file = LogFile::open(&path, rotation).unwrap();
+        for line in ["first entry", "second entry", "third entry", "fourth entry"] {
+            file.write(line).unwrap();
+        }
+        file.writer.flush().unwrap();
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth entry\n");
+        assert_eq!(read(file.rotated_path(1)), "third entry\n");
+        assert_eq!(read(file.rotated_path(2)), "second entry\n");
+        assert!(!file.rotated_path(3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+use actix_web::HttpRequest;
+use chrono::{DateTime, NaiveTime, Utc};
+use std::net::IpAddr;
+use std::path::Path;
+use toml_edit::{DocumentMut, Item, Table, Value};
+use url::Url;
+
+/// What a matching rule does to the ad breaks
+#[derive(Clone, Debug, PartialEq)]
+pub enum RulePolicy {
+    /// No ad breaks in the playlists, empty asset lists
+    NoAds,
+    /// The breaks play this slate stream instead of ads
+    Slate(Url),
+    /// The pods are requested from this ad server
+    AdServer(Url),
+}
+
+impl RulePolicy {
+    pub fn to_str(&self) -> &str {
+        match self {
+            RulePolicy::NoAds => "no-ads",
+            RulePolicy::Slate(_) => "slate",
+            RulePolicy::AdServer(_) => "ad-server",
+        }
+    }
+}
+
+// An IP network like 10.0.0.0/8 or 2001:db8::/32
+#[derive(Clone, Debug, PartialEq)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(value: &str) -> Result<Self, String> {
+        let (address, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let network = address
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|err| format!("Invalid network '{value}': {err}"))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.trim() {
+            "" => max_prefix,
+            prefix => prefix.parse::<u32>().ok().filter(|prefix| *prefix <= max_prefix).ok_or_else(|| {
+                format!("Invalid prefix length of network '{value}'")
+            })?,
+        };
+        Ok(Self { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener are IPv4-mapped
+        let ip = match ip {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            ip => ip,
+        };
+        let (network, ip, bits) = match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (u32::from(network) as u128, u32::from(ip) as u128, 32),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        let mask = match self.prefix {
+            0 => 0,
+            prefix => (u128::MAX << (bits - prefix)) & (u128::MAX >> (128 - bits)),
+        };
+        network & mask == ip & mask
+    }
+}
+
+/// A rule of the `--rules-file`: the requests matching all of its conditions
+/// get its policy. Conditions left out match any request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    // Country codes of the --country-header, in upper case
+    countries: Vec<String>,
+    networks: Vec<Cidr>,
+    // Channel names, empty for the stream served at the root
+    channels: Vec<String>,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    // A time window of every day in UTC, may wrap around midnight
+    daily: Option<(NaiveTime, NaiveTime)>,
+    pub policy: RulePolicy,
+}
+
+impl Rule {
+    fn parse(table: &Table, index: usize, has_country_header: bool) -> Result<Self, String> {
+        let name = string(table, "name")?.unwrap_or_else(|| format!("rule{index}"));
+        let context = |err: String| format!("Rule {name}: {err}");
+        let countries = strings(table, "countries")
+            .map_err(context)?
+            .into_iter()
+            .map(|country| country.trim().to_ascii_uppercase())
+            .collect::<Vec<_>>();
+        if !countries.is_empty() && !has_country_header {
+            return Err(context("countries need the --country-header of the viewer's country".to_string()));
+        }
+        let networks = strings(table, "networks")
+            .map_err(context)?
+            .iter()
+            .map(|network| Cidr::parse(network))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(context)?;
+        let channels = strings(table, "channels").map_err(context)?;
+        let date_time = |key: &str| {
+            string(table, key)?
+                .map(|value| {
+                    DateTime::parse_from_rfc3339(&value)
+                        .map(|date_time| date_time.to_utc())
+                        .map_err(|err| format!("Invalid {key} '{value}': {err}"))
+                })
+                .transpose()
+        };
+        let start = date_time("start").map_err(context)?;
+        let end = date_time("end").map_err(context)?;
+        let daily = string(table, "daily")
+            .map_err(context)?
+            .map(|value| {
+                let (from, to) = value
+                    .split_once('-')
+                    .ok_or_else(|| format!("Invalid daily window '{value}', expected hh:mm-hh:mm"))?;
+                let time = |time: &str| {
+                    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                        .map_err(|err| format!("Invalid daily window '{value}': {err}"))
+                };
+                Ok::<_, String>((time(from)?, time(to)?))
+            })
+            .transpose()
+            .map_err(context)?;
+
+        let url = |key: &str| {
+            let value = string(table, key)?.ok_or_else(|| format!("The policy needs a {key}"))?;
+            Url::parse(&value).map_err(|err| format!("Invalid {key} '{value}': {err}"))
+        };
+        let policy = match string(table, "policy").map_err(context)?.as_deref() {
+            Some("no-ads") => RulePolicy::NoAds,
+            Some("slate") => RulePolicy::Slate(url("slate_url").map_err(context)?),
+            Some("ad-server") => RulePolicy::AdServer(url("ad_server_url").map_err(context)?),
+            Some(policy) => {
+                return Err(context(format!("Unknown policy '{policy}', expected no-ads, slate or ad-server")));
+            }
+            None => return Err(context("Missing policy".to_string())),
+        };
+
+        Ok(Self {
+            name,
+            countries,
+            networks,
+            channels,
+            start,
+            end,
+            daily,
+            policy,
+        })
+    }
+
+    fn matches(&self, country: Option<&str>, ip: Option<IpAddr>, channel: &str, now: DateTime<Utc>) -> bool {
+        let country_matches = self.countries.is_empty()
+            || country.is_some_and(|country| self.countries.iter().any(|other| other.eq_ignore_ascii_case(country)));
+        let network_matches =
+            self.networks.is_empty() || ip.is_some_and(|ip| self.networks.iter().any(|network| network.contains(ip)));
+        let channel_matches = self.channels.is_empty() || self.channels.iter().any(|other| other == channel);
+        let window_matches = self.start.is_none_or(|start| now >= start) && self.end.is_none_or(|end| now < end);
+        let daily_matches = self.daily.is_none_or(|(from, to)| {
+            let time = now.time();
+            match from <= to {
+                true => from <= time && time < to,
+                false => time >= from || time < to,
+            }
+        });
+        country_matches && network_matches && channel_matches && window_matches && daily_matches
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "name": self.name.as_str(),
+            "countries": self.countries.clone(),
+            "networks": self
+                .networks
+                .iter()
+                .map(|network| format!("{}/{}", network.network, network.prefix))
+                .collect::<Vec<_>>(),
+            "channels": self.channels.clone(),
+            "start": self.start.map(|start| start.to_rfc3339()),
+            "end": self.end.map(|end| end.to_rfc3339()),
+            "daily": self.daily.map(|(from, to)| format!("{}-{}", from.format("%H:%M"), to.format("%H:%M"))),
+            "policy": self.policy.to_str(),
+        }
+    }
+}
+
+/// Blackout and regional restriction rules (`--rules-file`), evaluated in
+/// order for each playlist and asset list request, the first matching rule
+/// applies. The viewer's country is read from the `--country-header` set by
+/// the CDN, and its address from the connection or `X-Forwarded-For`
+#[derive(Clone, Debug, Default)]
+pub struct InsertionRules {
+    rules: Vec<Rule>,
+    country_header: Option<String>,
+}
+
+impl InsertionRules {
+    pub fn load(path: Option<&Path>, country_header: Option<String>) -> Result<Self, String> {
+        let Some(path) = path else {
+            return Ok(Self { rules: Vec::new(), country_header });
+        };
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read rules file {}: {err}", path.display()))?;
+        let document = content
+            .parse::<DocumentMut>()
+            .map_err(|err| format!("Invalid rules file {}: {err}", path.display()))?;
+        let rules = match document.get("rule") {
+            Some(Item::ArrayOfTables(tables)) => tables
+                .iter()
+                .enumerate()
+                .map(|(index, table)| Rule::parse(table, index, country_header.is_some()))
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(_) => return Err(format!("Invalid rules file {}: expected [[rule]] tables", path.display())),
+            None => Vec::new(),
+        };
+        Ok(Self { rules, country_header })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The first rule matching a request of the channel
+    pub fn evaluate(&self, req: &HttpRequest, channel: &str) -> Option<&Rule> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let country = self
+            .country_header
+            .as_ref()
+            .and_then(|header| req.headers().get(header.as_str()))
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim);
+        // An address of X-Forwarded-For has no port
+        let ip = req.connection_info().realip_remote_addr().and_then(|address| {
+            address
+                .parse::<std::net::SocketAddr>()
+                .map(|address| address.ip())
+                .or_else(|_| address.parse::<IpAddr>())
+                .ok()
+        });
+        let now = Utc::now();
+        self.rules.iter().find(|rule| rule.matches(country, ip, channel, now))
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "country_header": self.country_header.as_deref(),
+            "rules": self.rules.iter().map(Rule::to_json).collect::<Vec<_>>(),
+        }
+    }
+}
+
+fn string(table: &Table, key: &str) -> Result<Option<String>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Item::Value(Value::String(value))) => Ok(Some(value.value().clone())),
+        Some(Item::Value(Value::Datetime(value))) => Ok(Some(value.value().to_string())),
+        Some(_) => Err(format!("'{key}' must be a string")),
+    }
+}
+
+fn strings(table: &Table, key: &str) -> Result<Vec<String>, String> {
+    match table.get(key) {
+        None => Ok(Vec::new()),
+        Some(Item::Value(Value::Array(array))) => array
+            .iter()
+            .map(|value| {
+                value
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("'{key}' must be a list of strings"))
+            })
+            .collect(),
+        Some(_) => Err(format!("'{key}' must be a list of strings")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let network = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(network.contains("10.1.255.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!network.contains("10.2.0.1".parse().unwrap()));
+        assert!(Cidr::parse("2001:db8::/32").unwrap().contains("2001:db8:1::1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains("192.0.2.1".parse().unwrap()));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn daily_windows_wrap_around_midnight() {
+        let document = "name = \"night\"\ndaily = \"22:00-02:00\"\npolicy = \"no-ads\"\n".parse::<DocumentMut>().unwrap();
+        let rule = Rule::parse(document.as_table(), 0, false).unwrap();
+        let at = |time: &str| DateTime::parse_from_rfc3339(time).unwrap().to_utc();
+        assert!(rule.matches(None, None, "", at("2026-10-14T23:30:00Z")));
+        assert!(rule.matches(None, None, "", at("2026-10-15T01:59:00Z")));
+        assert!(!rule.matches(None, None, "", at("2026-10-15T12:00:00Z")));
+    }
+}
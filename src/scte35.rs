@@ -0,0 +1,103 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::time::Duration;
+
+/// Scheme of the DASH events carrying a binary SCTE-35 splice_info_section
+pub const SCTE35_EVENT_SCHEME: &str = "urn:scte:scte35:2013:bin";
+
+const TABLE_ID: u8 = 0xFC;
+const SPLICE_INSERT: u8 = 0x05;
+// The 90 kHz clock of the splice times and durations
+const CLOCK_RATE: f64 = 90_000.0;
+
+/// The splice_info_section of a cue-out: an immediate splice_insert leaving the
+/// network for `duration` and returning automatically. The start of the break
+/// is given by where the marker is placed, the DATERANGE START-DATE or the
+/// event presentation time
+pub fn splice_out(event_id: u32, duration: Duration) -> Vec<u8> {
+    let ticks = ((duration.as_secs_f64() * CLOCK_RATE).round() as u64) & 0x1_FFFF_FFFF;
+
+    let mut command = Vec::with_capacity(15);
+    command.extend_from_slice(&event_id.to_be_bytes());
+    // splice_event_cancel_indicator 0, reserved
+    command.push(0x7F);
+    // out_of_network, program_splice, duration and splice_immediate flags,
+    // event_id_compliance_flag and reserved
+    command.push(0xFF);
+    // break_duration: auto_return, reserved, 33 bits of duration
+    command.push(0xFE | (ticks >> 32) as u8);
+    command.extend_from_slice(&(ticks as u32).to_be_bytes());
+    // unique_program_id, avail_num, avails_expected
+    command.extend_from_slice(&[0, 0, 0, 0]);
+
+    let mut section = vec![TABLE_ID, 0, 0];
+    // protocol_version
+    section.push(0);
+    // encrypted_packet, encryption_algorithm and pts_adjustment
+    section.extend_from_slice(&[0; 5]);
+    // cw_index
+    section.push(0);
+    // tier 0xFFF and splice_command_length
+    let command_length = command.len() as u16;
+    section.extend_from_slice(&[0xFF, 0xF0 | (command_length >> 8) as u8, command_length as u8]);
+    section.push(SPLICE_INSERT);
+    section.extend_from_slice(&command);
+    // descriptor_loop_length
+    section.extend_from_slice(&[0, 0]);
+
+    // section_syntax_indicator 0, private_indicator 0, sap_type 3 (not specified)
+    let section_length = (section.len() - 3 + 4) as u16;
+    section[1] = 0x30 | (section_length >> 8) as u8;
+    section[2] = section_length as u8;
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+/// The hexadecimal form of the SCTE35-OUT attribute of an EXT-X-DATERANGE
+pub fn to_hex(section: &[u8]) -> String {
+    let digits = section.iter().map(|byte| format!("{byte:02X}")).collect::<String>();
+    format!("0x{digits}")
+}
+
+/// The base64 form of a DASH SCTE-35 event
+pub fn to_base64(section: &[u8]) -> String {
+    BASE64.encode(section)
+}
+
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    data.iter().fold(0xFFFF_FFFF, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u32) << 24), |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_matches_the_mpeg2_check_value() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_E6E7);
+    }
+
+    #[test]
+    fn splice_out_section_is_well_formed() {
+        let section = splice_out(7, Duration::from_secs(30));
+        assert_eq!(section.len(), 35);
+        assert_eq!(section[0], TABLE_ID);
+        let section_length = (((section[1] & 0x0F) as usize) << 8) | section[2] as usize;
+        assert_eq!(section_length, section.len() - 3);
+        assert_eq!(section[13], SPLICE_INSERT);
+        assert_eq!(&section[14..18], &7u32.to_be_bytes());
+        let ticks = (((section[20] & 0x01) as u64) << 32) | u32::from_be_bytes(section[21..25].try_into().unwrap()) as u64;
+        assert_eq!(ticks, 30 * 90_000);
+        // The CRC of a section including its CRC is 0
+        assert_eq!(crc32_mpeg2(&section), 0);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::utils::{get_advertiser_for_creative, get_categories_for_creative};
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Keeps competing creatives apart: no two creatives of the same `<Category>`
+/// or `<Advertiser>` in a pod, and with `adjacent` none of those of the previous
+/// pod of the session either. The categories of a group count as one category.
+/// Creatives of an excluded category aren't served at all.
+#[derive(Clone, Debug, Default)]
+pub struct CompetitiveSeparation {
+    enabled: bool,
+    adjacent: bool,
+    groups: Vec<Vec<String>>,
+    excluded: Vec<String>,
+    // The competition keys of the last pod served to each session
+    last_pods: Arc<DashMap<String, HashSet<String>>>,
+}
+
+impl CompetitiveSeparation {
+    pub fn new(enabled: bool, adjacent: bool, groups: Vec<Vec<String>>, excluded: Vec<String>) -> Self {
+        Self {
+            enabled: enabled || adjacent,
+            adjacent,
+            groups,
+            excluded,
+            last_pods: Arc::default(),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled || !self.excluded.is_empty()
+    }
+
+    /// Start separating the creatives of a pod served to a session
+    pub fn pod(&self, session: &str) -> PodSeparation<'_> {
+        let previous = if self.adjacent {
+            self.last_pods.get(session).map(|keys| keys.clone()).unwrap_or_default()
+        } else {
+            HashSet::new()
+        };
+        PodSeparation {
+            separation: self,
+            session: session.to_string(),
+            previous,
+            served: HashSet::new(),
+        }
+    }
+
+    // What a creative competes on, a category standing for its group
+    fn keys(&self, categories: &[String], advertiser: Option<String>) -> Vec<String> {
+        let category_key = |category: &String| {
+            let group = self
+                .groups
+                .iter()
+                .find(|group| group.iter().any(|member| member.eq_ignore_ascii_case(category)))
+                .and_then(|group| group.first());
+            format!("category {}", group.unwrap_or(category).to_ascii_lowercase())
+        };
+        categories
+            .iter()
+            .map(category_key)
+            .chain(advertiser.map(|advertiser| format!("advertiser {}", advertiser.to_ascii_lowercase())))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "enabled": self.enabled,
+            "adjacent_pods": self.adjacent,
+            "groups": self.groups.clone(),
+            "excluded_categories": self.excluded.clone(),
+        }
+    }
+}
+
+/// The creatives of a pod admitted so far, in the order of the asset list
+pub struct PodSeparation<'a> {
+    separation: &'a CompetitiveSeparation,
+    session: String,
+    previous: HashSet<String>,
+    served: HashSet<String>,
+}
+
+impl PodSeparation<'_> {
+    /// Whether the creative can follow the ones admitted before it, admitting it
+    pub fn admit(&mut self, vast: &vast4_rs::Vast, creative: &vast4_rs::Creative) -> bool {
+        let admitted = self.admits(vast, creative);
+        if admitted {
+            self.record(vast, creative);
+        }
+        admitted
+    }
+
+    /// Whether the creative can follow the ones admitted before it, without
+    /// admitting it yet: it may still be left out for another reason
+    pub fn admits(&self, vast: &vast4_rs::Vast, creative: &vast4_rs::Creative) -> bool {
+        let separation = self.separation;
+        if !separation.is_enabled() {
+            return true;
+        }
+        let id = creative.ad_id.as_deref().or(creative.id.as_deref()).unwrap_or_default();
+        let categories = get_categories_for_creative(vast, creative);
+        let excluded = categories
+            .iter()
+            .find(|category| separation.excluded.iter().any(|excluded| excluded.eq_ignore_ascii_case(category)));
+        if let Some(category) = excluded {
+            log::info!("Dropping creative {id} of the excluded category {category}");
+            return false;
+        }
+        if !separation.enabled {
+            return true;
+        }
+
+        let keys = separation.keys(&categories, get_advertiser_for_creative(vast, creative));
+        if let Some(key) = keys.iter().find(|key| self.served.contains(*key)) {
+            log::info!("Dropping creative {id}, competing with an earlier creative of the pod on {key}");
+            return false;
+        }
+        if let Some(key) = keys.iter().find(|key| self.previous.contains(*key)) {
+            log::info!("Dropping creative {id}, competing with the previous pod of session {} on {key}", self.session);
+            return false;
+        }
+        true
+    }
+
+    /// Admit a creative kept in the pod, the next ones can't compete with it
+    pub fn record(&mut self, vast: &vast4_rs::Vast, creative: &vast4_rs::Creative) {
+        if !self.separation.enabled {
+            return;
+        }
+        let categories = get_categories_for_creative(vast, creative);
+        self.served.extend(self.separation.keys(&categories, get_advertiser_for_creative(vast, creative)));
+    }
+
+    /// Keep what the pod competes on for the next pod of the session
+    pub fn finish(self) {
+        if self.separation.adjacent {
+            self.separation.last_pods.insert(self.session, self.served);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admits_a_competitor_of_a_creative_left_out() {
+        let vast: vast4_rs::Vast = vast4_rs::from_str(
+            r#"<VAST version="4.1">
+                <Ad id="first"><InLine><AdSystem>ads</AdSystem><AdTitle>First</AdTitle>
+                    <Impression><![CDATA[https://example.com/impression]]></Impression>
+                    <Category authority="iab">IAB2</Category>
+                    <Creatives><Creative id="c1"><Linear><Duration>00:00:15</Duration></Linear></Creative></Creatives>
+                </InLine></Ad>
+                <Ad id="second"><InLine><AdSystem>ads</AdSystem><AdTitle>Second</AdTitle>
+                    <Impression><![CDATA[https://example.com/impression]]></Impression>
+                    <Category authority="iab">IAB2</Category>
+                    <Creatives><Creative id="c2"><Linear><Duration>00:00:15</Duration></Linear></Creative></Creatives>
+                </InLine></Ad>
+            </VAST>"#,
+        )
+        .unwrap();
+        let creative = |index: usize| &vast.ads[index].in_line.as_ref().unwrap().creatives.creatives[0];
+        let separation = CompetitiveSeparation::new(true, false, Vec::new(), Vec::new());
+
+        // The first creative is checked but left out, e.g. without a compatible media file
+        let mut pod = separation.pod("session");
+        assert!(pod.admits(&vast, creative(0)));
+        assert!(pod.admit(&vast, creative(1)));
+        assert!(!pod.admits(&vast, creative(0)));
+    }
+}
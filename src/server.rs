@@ -0,0 +1,1024 @@
+use crate::ad_pod_cache::AdPodCache;
+use crate::ad_selection::AdSelection;
+use crate::admission::SessionAdmission;
+use crate::beacon::BeaconDispatcher;
+use crate::break_schedule::{BreakSchedule, PlannedBreak};
+use crate::channel::ChannelSpec;
+use crate::cli::{
+    CliArguments, CliCommand, ConfigReloader, ad_break_settings, config_file_path, fault_injector, parse_arguments,
+    parse_default_values, transcoder_settings, variant_ladder, watch_config_file,
+};
+use crate::creative_cache::CreativeCache;
+use crate::dash::DASH_CONTENT_TYPE;
+use crate::device::DeviceProfiles;
+use crate::dns::DnsResolver;
+use crate::egress_proxy::EgressProxy;
+use crate::entitlement::AdFreeEntitlement;
+use crate::experiments::Experiments;
+use crate::handlers::{
+    handle_click, handle_creative_media, handle_dash_ad_period, handle_debug_vast, handle_esni, handle_gc,
+    handle_media_stream, handle_metrics, handle_origin_swap, handle_reset_epoch, handle_status, handle_timeline,
+    handle_tracking, handle_version, ingest_planned_breaks, parse_cache_control_override,
+};
+use crate::header_forwarding::HeaderForwarding;
+use crate::hooks::{PlaylistHook, PlaylistHooks};
+use crate::listener::Listener;
+use crate::localization::Localization;
+use crate::metrics::{Metrics, PROMETHEUS_CONTENT_TYPE};
+use crate::mock_origin::MockOrigin;
+use crate::origin_cache::OriginCache;
+use crate::playlist_detection::PlaylistDetection;
+use crate::pod_template::PodTemplate;
+use crate::prefetch::PodPrefetch;
+use crate::probe::DurationProber;
+use crate::request_log::{RequestLog, Rotation};
+use crate::rules::InsertionRules;
+use crate::separation::CompetitiveSeparation;
+use crate::session_asset_lists::SessionAssetLists;
+use crate::shutdown::ShutdownState;
+use crate::test_pods::TestPods;
+use crate::transcoder::Transcoder;
+use crate::utils::{
+    BumperFilter, base_url, get_header_value, get_or_head, is_fragmented_mp4_vod_media_playlist, is_hls_playlist,
+    parse_time_zone, playback_session_id, rustls_config, rustls_server_config,
+};
+use crate::vast_validation::VastValidator;
+use crate::{
+    ASSET_LIST_FILE, AdBreakSettings, AdServerClient, AvailableAds, CLICK_PREFIX, COMMAND_PREFIX,
+    CREATIVE_PLAYLIST_FILE, CREATIVE_PREFIX, DASH_AD_PERIOD_PATH, DEBUG_VAST_PATH, ESNI_PATH, GC_PATH,
+    HLS_INTERSTITIAL_ID, HLS_PLAYLIST_CONTENT_TYPE, InsertionMode, MAX_SCHEDULE_SIZE, METRICS_PREFIX, ORIGIN_PATH,
+    PodFillPolicy, RESET_EPOCH_PATH, SESSION_ID_TEMPLATE, STATUS_PREFIX, ServerConfig, StreamState, TIMELINE_PATH,
+    TRACKING_PREFIX, TestAsset, UpstreamOptions, UserDefinedQueryParams, VERSION_PATH, build_info, epg, esni,
+    handle_commands, handle_interstitials, listener, make_https_client, parse_headers, parse_interstitials_route,
+    shutdown,
+};
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::{App, Error, HttpResponse, HttpServer, error, guard, middleware, web};
+use awc::Client;
+use awc::http::header;
+use hls_m3u8::tags::VariantStream;
+use hls_m3u8::{MasterPlaylist, MediaPlaylist};
+use json::object;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+async fn inspect_master_playlist(
+    upstream: &UpstreamOptions,
+    master_playlist_url: &Url,
+) -> Result<(), Error> {
+    if !is_hls_playlist(master_playlist_url.as_str()) {
+        return Err(error::ErrorBadRequest("Illegal master playlist URL".to_string()));
+    }
+
+    log::info!("Inspecting source stream at: {}", master_playlist_url);
+    let client = make_https_client(upstream);
+    let payload = client
+        .get(master_playlist_url.as_str())
+        .send()
+        .await
+        .map_err(error::ErrorBadRequest)?
+        .body()
+        .await
+        .map_err(error::ErrorBadRequest)?;
+ 
+    let m3u8 = std::str::from_utf8(&payload).map_err(error::ErrorBadRequest)?;
+    
+    // Try to parse the master playlist
+    MasterPlaylist::try_from(m3u8).map_err(|err| {
+        error::ErrorBadRequest(format!("Invalid master playlist: {}", err))
+    })?;
+
+    Ok(())
+}
+
+async fn parse_test_asset_url(upstream: &UpstreamOptions, path: &str) -> Option<TestAsset> {
+    if path.is_empty() || !is_hls_playlist(path) {
+        log::error!("Test asset URL is not a valid HLS playlist: {path}");
+        return None;
+    }
+
+    log::info!("Parsing test asset URL: {path}");
+    let url = Url::parse(path).ok()?;
+    let client = make_https_client(upstream);
+    let payload = client.get(url.as_str()).send().await.ok()?.body().await.ok()?;
+    let text = std::str::from_utf8(&payload).ok()?;
+
+    // If the URL points to a master playlist, resolve the first variant to a media playlist.
+    let (media_url, media_text) = if let Ok(master) = MasterPlaylist::try_from(text) {
+        let variant_uri = master.variant_streams.iter().find_map(|v| {
+            if let VariantStream::ExtXStreamInf { uri, .. } = v { Some(uri.as_ref()) } else { None }
+        })?;
+        let media_url = url.join(variant_uri).ok()?;
+        log::info!("Test asset is a master playlist; using first variant: {media_url}");
+        let payload2 = client.get(media_url.as_str()).send().await.ok()?.body().await.ok()?;
+        let text2 = String::from_utf8(payload2.to_vec()).ok()?;
+        (media_url, text2)
+    } else {
+        (url.clone(), text.to_string())
+    };
+
+    let m3u8 = MediaPlaylist::try_from(media_text.as_str()).ok()?;
+    if !is_fragmented_mp4_vod_media_playlist(&m3u8) {
+        log::error!("Test asset at {media_url} is not a valid fragmented MP4 VoD media playlist.");
+        return None;
+    }
+
+    let duration = m3u8.segments.iter().map(|(_, s)| s.duration.duration().as_secs()).sum();
+    // Use the original (master) URL so the player can pick its own variant.
+    Some(TestAsset::new(url, duration))
+}
+
+// The ad break settings of a channel, its own values take precedence
+fn channel_ad_breaks(
+    spec: &ChannelSpec,
+    defaults: &AdBreakSettings,
+    test_asset: &Option<TestAsset>,
+) -> AdBreakSettings {
+    AdBreakSettings {
+        ad_server_url: spec.ad_server_url.clone().unwrap_or_else(|| defaults.ad_server_url.clone()),
+        // The duration of the test asset is used as is
+        target_ad_duration: spec
+            .ad_duration
+            .filter(|_| test_asset.is_none())
+            .unwrap_or(defaults.target_ad_duration),
+        target_repeating_cycle: spec.repeating_cycle.unwrap_or(defaults.target_repeating_cycle),
+        target_ad_number: spec.ad_number.unwrap_or(defaults.target_ad_number),
+    }
+}
+
+// The compressed playlists, manifests and JSON documents are buffered to be
+// sent with their Content-Length rather than chunked. The media passed through
+// keeps streaming
+async fn sized_manifests(
+    req: actix_web::dev::ServiceRequest,
+    next: middleware::Next<impl MessageBody + 'static>,
+) -> Result<actix_web::dev::ServiceResponse<impl MessageBody>, Error> {
+    let res = next.call(req).await?;
+    let compressed = res
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding != "identity");
+    let is_manifest = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            [HLS_PLAYLIST_CONTENT_TYPE, DASH_CONTENT_TYPE, "application/json", PROMETHEUS_CONTENT_TYPE]
+                .iter()
+                .any(|manifest| content_type.starts_with(manifest))
+        });
+    if !compressed || !is_manifest || res.response().body().size() != BodySize::Stream {
+        return Ok(res.map_into_left_body());
+    }
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let body = actix_web::body::to_bytes(body)
+        .await
+        .map_err(|err| error::ErrorInternalServerError(err.into()))?;
+    Ok(actix_web::dev::ServiceResponse::new(req, res.set_body(body)).map_into_right_body())
+}
+
+// The access entries of the request log
+async fn log_requests(
+    req: actix_web::dev::ServiceRequest,
+    next: middleware::Next<impl MessageBody + 'static>,
+) -> Result<actix_web::dev::ServiceResponse<impl MessageBody>, Error> {
+    let Some(request_log) = req.app_data::<web::Data<Option<RequestLog>>>().and_then(|log| log.as_ref().clone()) else {
+        return next.call(req).await;
+    };
+    let started = std::time::Instant::now();
+    let mut entry = object! {
+        "method": req.method().as_str(),
+        "path": req.path(),
+        "query": req.query_string(),
+        "remote": req.connection_info().realip_remote_addr(),
+        "user_agent": get_header_value(req.request(), header::USER_AGENT.as_str()),
+        "session": playback_session_id(req.request()),
+    };
+    let res = next.call(req).await;
+    entry["status"] = match &res {
+        Ok(res) => res.status().as_u16(),
+        Err(err) => err.as_response_error().status_code().as_u16(),
+    }
+    .into();
+    entry["duration_ms"] = (started.elapsed().as_secs_f64() * 1000.0).into();
+    request_log.record("access", entry);
+    res
+}
+
+/// A stream served by the proxy, either at the root or as a channel under
+/// its own path with its own slots
+#[derive(Clone)]
+pub(crate) struct Channel {
+    spec: Option<ChannelSpec>,
+    stream: StreamState,
+}
+
+impl Channel {
+    // The stream routes, with the channel's data taking precedence over the app's
+    fn configure(&self, cfg: &mut web::ServiceConfig) {
+        let route = &self.stream.config.interstitials_route;
+        cfg.app_data(web::Data::new(self.stream.clone()))
+            .route(COMMAND_PREFIX, get_or_head().to(handle_commands))
+            .route(STATUS_PREFIX, get_or_head().to(handle_status))
+            .route(VERSION_PATH, get_or_head().to(handle_version))
+            .route(DEBUG_VAST_PATH, get_or_head().to(handle_debug_vast))
+            .route(TIMELINE_PATH, get_or_head().to(handle_timeline))
+            .route(RESET_EPOCH_PATH, web::post().to(handle_reset_epoch))
+            .route(ESNI_PATH, web::post().to(handle_esni))
+            .route(ORIGIN_PATH, web::post().to(handle_origin_swap))
+            .route(GC_PATH, web::post().to(handle_gc))
+            // Without an interstitial ID, a request of the playlist is the origin's
+            .route(
+                &self.stream.config.interstitial_playlist(),
+                get_or_head()
+                    .guard(guard::fn_guard(|ctx| {
+                        ctx.head().uri.query().is_some_and(|query| {
+                            url::form_urlencoded::parse(query.as_bytes()).any(|(key, _)| key == HLS_INTERSTITIAL_ID)
+                        })
+                    }))
+                    .to(handle_interstitials),
+            )
+            .route(
+                &format!("/{route}/{{slot}}/{{session}}/{ASSET_LIST_FILE}"),
+                get_or_head().to(handle_interstitials),
+            )
+            .route(
+                &format!("/{route}/{{slot}}/{{ad_id}}/{CREATIVE_PLAYLIST_FILE}"),
+                get_or_head().to(handle_interstitials),
+            )
+            .route(&format!("{CLICK_PREFIX}/{{ad_id}}"), get_or_head().to(handle_click))
+            .route(DASH_AD_PERIOD_PATH, get_or_head().to(handle_dash_ad_period))
+            .route(&format!("{CREATIVE_PREFIX}/{{ad_id}}/{{path:.*}}"), get_or_head().to(handle_creative_media))
+            .default_service(web::to(handle_media_stream));
+    }
+
+    // Apply reloaded ad break settings to the new requests of the channel
+    pub(crate) fn set_ad_breaks(&self, defaults: &AdBreakSettings, test_asset: &Option<TestAsset>) {
+        let ad_breaks = match &self.spec {
+            Some(spec) => channel_ad_breaks(spec, defaults, test_asset),
+            None => defaults.clone(),
+        };
+        let StreamState { config, available_slots, ad_pod_cache, session_asset_lists, .. } = &self.stream;
+        let previous_ad_breaks = config.ad_breaks();
+        if previous_ad_breaks == ad_breaks {
+            return;
+        }
+        let rescheduled = config.insertion_mode == InsertionMode::Static
+            && (previous_ad_breaks.target_ad_duration != ad_breaks.target_ad_duration
+                || previous_ad_breaks.target_repeating_cycle != ad_breaks.target_repeating_cycle
+                || previous_ad_breaks.target_ad_number != ad_breaks.target_ad_number);
+        config.set_ad_breaks(ad_breaks);
+        if rescheduled {
+            // The static slots are regenerated with the new values on the next refresh
+            available_slots.clear();
+        }
+        // Ad pods of the previous endpoint or schedule are not reused
+        ad_pod_cache.clear();
+        session_asset_lists.clear();
+    }
+}
+
+// The content of a schedule, read from the disk for a file URL
+async fn fetch_schedule(client: &Client, url: &Url) -> Result<String, String> {
+    if url.scheme() == "file" {
+        let path = url.to_file_path().map_err(|_| "not a file path".to_string())?;
+        return std::fs::read_to_string(path).map_err(|err| err.to_string());
+    }
+    let mut res = client.get(url.as_str()).send().await.map_err(|err| err.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("status {}", res.status()));
+    }
+    let body = res.body().limit(MAX_SCHEDULE_SIZE).await.map_err(|err| err.to_string())?;
+    String::from_utf8(body.to_vec()).map_err(|err| err.to_string())
+}
+
+// Fetch the schedule every interval and schedule its breaks
+fn poll_break_schedule<F>(
+    stream: StreamState,
+    schedule: BreakSchedule,
+    replace: bool,
+    upstream: UpstreamOptions,
+    metrics: web::Data<Metrics>,
+    parse: F,
+) where
+    F: Fn(&str) -> Result<Vec<PlannedBreak>, String> + 'static,
+{
+    let Some(url) = schedule.url().cloned() else {
+        return;
+    };
+    let source = schedule.source();
+    if stream.config.insertion_mode == InsertionMode::Static {
+        log::warn!("Not polling the {source} schedule {url}, breaks can't be added in static mode");
+        return;
+    }
+    log::info!("Polling the {source} schedule {url} every {}s", schedule.interval().as_secs());
+    actix_web::rt::spawn(async move {
+        let client = make_https_client(&upstream);
+        loop {
+            match fetch_schedule(&client, &url).await.and_then(|content| parse(&content)) {
+                Ok(breaks) => {
+                    ingest_planned_breaks(&stream, &schedule, breaks, replace, &metrics);
+                }
+                Err(err) => {
+                    log::warn!("Failed to poll the {source} schedule {url}: {err}");
+                    metrics.inc("schedule_poll_failures_total", &[("source", source)]);
+                }
+            }
+            actix_web::rt::time::sleep(schedule.interval()).await;
+        }
+    });
+}
+
+// Poll the SCTE-224 schedule and the EPG of each stream having them, an EPG
+// being the whole plan of its breaks
+fn poll_break_schedules(
+    channels: Vec<Channel>,
+    upstream: UpstreamOptions,
+    metrics: web::Data<Metrics>,
+    boundary_break: f64,
+) {
+    for channel in channels {
+        let stream = channel.stream;
+        poll_break_schedule(
+            stream.clone(),
+            stream.esni.clone(),
+            false,
+            upstream.clone(),
+            metrics.clone(),
+            |xml| esni::parse_schedule(xml, chrono::Utc::now()),
+        );
+        poll_break_schedule(
+            stream.clone(),
+            stream.epg.clone(),
+            true,
+            upstream.clone(),
+            metrics.clone(),
+            move |content| epg::parse_epg(content, boundary_break),
+        );
+    }
+}
+
+/// Builder of a proxy server embedded in another service. The options are
+/// given as on the command line of the `ad_proxy` binary, e.g.
+/// `.arg("--base-path", "/ads")`, and are checked by [`AdProxyServer::run`].
+/// Environment variables and the `--config` file apply as well.
+///
+/// The server has to run on an actix system (`#[actix_web::main]`) and stops
+/// on SIGINT and SIGTERM like the binary does.
+#[derive(Debug, Clone)]
+pub struct AdProxyServer {
+    listen_addr: String,
+    listen_port: u16,
+    ad_server_endpoint: Option<String>,
+    master_playlist_url: Option<String>,
+    options: Vec<OsString>,
+    hooks: PlaylistHooks,
+}
+
+impl AdProxyServer {
+    pub fn new(listen_addr: &str, listen_port: u16) -> Self {
+        Self {
+            listen_addr: listen_addr.to_string(),
+            listen_port,
+            ad_server_endpoint: None,
+            master_playlist_url: None,
+            options: Vec::new(),
+            hooks: PlaylistHooks::default(),
+        }
+    }
+
+    pub fn ad_server_endpoint(mut self, url: &str) -> Self {
+        self.ad_server_endpoint = Some(url.to_string());
+        self
+    }
+
+    /// The stream served at the root, it needs an ad server endpoint
+    pub fn master_playlist_url(mut self, url: &str) -> Self {
+        self.master_playlist_url = Some(url.to_string());
+        self
+    }
+
+    pub fn origin_host(self, url: &str) -> Self {
+        self.arg("--origin-host", url)
+    }
+
+    pub fn insertion_mode(self, mode: InsertionMode) -> Self {
+        self.arg("--ad-insertion-mode", mode.to_str())
+    }
+
+    pub fn interstitials_address(self, url: &str) -> Self {
+        self.arg("--interstitials-address", url)
+    }
+
+    /// Any other option taking a value, e.g. `.arg("--channel", "news=...")`
+    pub fn arg(mut self, name: &str, value: impl Into<OsString>) -> Self {
+        self.options.push(name.into());
+        self.options.push(value.into());
+        self
+    }
+
+    /// An option without a value, e.g. `.flag("--infer-quartiles")`
+    pub fn flag(mut self, name: &str) -> Self {
+        self.options.push(name.into());
+        self
+    }
+
+    /// Change the playlists of every stream before they are served, see [`PlaylistHook`]
+    pub fn hook(mut self, hook: impl PlaylistHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Parse the options and serve until the server is stopped
+    pub async fn run(self) -> io::Result<()> {
+        if self.master_playlist_url.is_some() && self.ad_server_endpoint.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A master playlist URL needs an ad server endpoint",
+            ));
+        }
+
+        let arguments = [
+            Some(OsString::from(env!("CARGO_PKG_NAME"))),
+            Some(self.listen_addr.into()),
+            Some(self.listen_port.to_string().into()),
+            self.ad_server_endpoint.map(OsString::from),
+            self.master_playlist_url.map(OsString::from),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.options)
+        .collect::<Vec<_>>();
+
+        let config_path = config_file_path(&arguments);
+        let invalid = |err: clap::Error| io::Error::new(io::ErrorKind::InvalidInput, err.to_string());
+        match parse_arguments(&arguments, config_path.as_deref()).map_err(invalid)? {
+            (CliCommand::Serve(args), settings) => {
+                serve(args, settings, config_path, arguments, self.hooks).await
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Unexpected subcommand")),
+        }
+    }
+}
+
+pub(crate) async fn serve(
+    mut args: CliArguments,
+    settings: BTreeMap<String, String>,
+    config_path: Option<PathBuf>,
+    arguments: Vec<OsString>,
+    hooks: PlaylistHooks,
+) -> io::Result<()> {
+    let (default_ad_duration, default_repeating_cycle, default_ad_number) =
+        parse_default_values(&args);
+
+    let metrics = web::Data::new(Metrics::default());
+    let dns_overrides = DnsResolver::parse_overrides(&args.resolve)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let resolver = DnsResolver::new(
+        dns_overrides,
+        Duration::from_secs(args.dns_ttl),
+        Duration::from_secs(args.dns_negative_ttl),
+        metrics.clone(),
+    );
+    let env_proxy = |name: &str| std::env::var(name).ok();
+    let proxy = EgressProxy::new(
+        args.http_proxy.clone().or_else(|| env_proxy("http_proxy")).as_deref(),
+        args.https_proxy.clone().or_else(|| env_proxy("https_proxy")).as_deref(),
+        args.no_proxy.clone().or_else(|| env_proxy("no_proxy")).as_deref(),
+    )
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    if let Some(proxy) = proxy.describe() {
+        log::info!("Upstream requests go {proxy}");
+    }
+    let proxy = Arc::new(proxy);
+    if args.insecure_upstream_tls {
+        log::warn!("TLS certificates of the origin and the ad server are not verified");
+    }
+    let tls = rustls_config(args.upstream_ca.as_deref(), args.insecure_upstream_tls, None)
+        .inspect_err(|err| log::error!("Failed to load the upstream CA certificates: {err}"))?;
+    // Only the ad server connections present a client certificate
+    let ad_server_tls = match (&args.ad_server_client_cert, &args.ad_server_client_key) {
+        (Some(cert), Some(key)) => rustls_config(
+            args.upstream_ca.as_deref(),
+            args.insecure_upstream_tls,
+            Some((cert.as_path(), key.as_path())),
+        )
+        .inspect_err(|err| {
+            log::error!("Failed to load the ad server client certificate {}: {err}", cert.display());
+        })?,
+        _ => tls.clone(),
+    };
+    let ad_server_upstream = UpstreamOptions {
+        user_agent: args.ad_server_user_agent.clone(),
+        headers: parse_headers(&args.ad_server_header)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+        ..UpstreamOptions::new(ad_server_tls, resolver.clone(), proxy.clone(), &args)
+    };
+    let upstream = UpstreamOptions::new(tls, resolver, proxy, &args);
+
+    // The mock origin stands in for the master playlist URL, and for the ad server unless one is given
+    let mut mock_ad_server = None;
+    if args.mock_origin {
+        let (mock_url, _) = MockOrigin::new(args.mock_segment_duration, args.mock_window).start()?;
+        log::warn!("Proxying the mock origin at {mock_url}, for testing only");
+        args.master_playlist_url = Some(format!("{mock_url}master.m3u8"));
+        if args.ad_server_endpoint.is_none() {
+            mock_ad_server = Some(format!("{mock_url}vast"));
+            args.ad_server_endpoint = mock_ad_server.clone();
+        }
+    }
+
+    // Determine mode and set forward_url and master_playlist_path of the stream served at the root
+    let root_stream = if let Some(ref origin) = args.origin_host {
+        // Origin host mode
+        let url = Url::parse(origin).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid origin host URL {origin}: {err}"))
+        })?;
+        Some((url, None))
+    } else if let Some(ref master_playlist_url) = args.master_playlist_url {
+        // Specific playlist mode (existing behavior)
+        let master_url = Url::parse(master_playlist_url).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid master playlist URL {master_playlist_url}: {err}"),
+            )
+        })?;
+        inspect_master_playlist(&upstream, &master_url).await.map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to inspect master playlist: {err}"))
+        })?;
+        let forward_url = base_url(&master_url).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid forward URL of {master_url}: {err}"))
+        })?;
+        let playlist_path = master_url.path().to_string();
+        Some((forward_url, Some(playlist_path)))
+    } else {
+        None
+    };
+    let channel_specs =
+        ChannelSpec::parse_all(&args.channel).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let pod_templates =
+        PodTemplate::parse_all(&args.pod_template).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let experiments = Experiments::parse_all(&args.experiment_variant)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let origin_time_zone =
+        parse_time_zone(&args.origin_time_zone).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let origin_headers = HeaderForwarding::new(&args.forward_origin_header, &args.block_origin_header)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let device_profiles =
+        DeviceProfiles::parse_all(&args.device_profile).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let ad_free = AdFreeEntitlement::new(
+        args.ad_free_param.clone(),
+        args.ad_free_header.clone(),
+        args.entitlement_url.clone(),
+        Duration::from_secs(args.entitlement_cache_ttl),
+    );
+    let admission = SessionAdmission::new(
+        args.max_sessions,
+        args.max_sessions_per_ip,
+        Duration::from_secs(args.session_idle_timeout),
+        args.session_overflow,
+    );
+    let vast_validator = VastValidator::new(args.strict_vast, metrics.clone());
+    let esni_url = args
+        .esni_url
+        .as_deref()
+        .map(|url| Url::parse(url).map_err(|err| format!("Invalid SCTE-224 schedule URL {url}: {err}")))
+        .transpose()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let esni_poll_interval = Duration::from_secs(args.esni_poll_interval.max(1));
+    let epg_url = args
+        .epg_url
+        .as_deref()
+        .map(|url| Url::parse(url).map_err(|err| format!("Invalid EPG URL {url}: {err}")))
+        .transpose()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let epg_refresh_interval = Duration::from_secs(args.epg_refresh_interval.max(1));
+    let rules = InsertionRules::load(args.rules_file.as_deref(), args.country_header.clone())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let variant_ladder = variant_ladder(&args).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let playlist_cache_control = parse_cache_control_override(args.playlist_cache_control.as_deref())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let playlist_patterns = args
+        .playlist_pattern
+        .iter()
+        .filter(|pattern| !pattern.is_empty())
+        .cloned()
+        .collect::<Vec<_>>();
+    let segment_cache_control = parse_cache_control_override(args.segment_cache_control.as_deref())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    let test_asset = parse_test_asset_url(&upstream, &args.test_asset_url).await;
+    let test_pods = args
+        .test_assets_file
+        .as_deref()
+        .map(TestPods::load)
+        .transpose()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(rustls_server_config(cert, key).inspect_err(|err| {
+            log::error!("Failed to load the TLS certificate {}: {err}", cert.display());
+        })?),
+        _ => None,
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
+
+    let listen_addrs = args.listen_addrs();
+    let listen_host = match listen_addrs[0].parse::<std::net::Ipv6Addr>() {
+        Ok(ip) => format!("[{ip}]"),
+        Err(_) => listen_addrs[0].clone(),
+    };
+    let listen_url = format!("{scheme}://{listen_host}:{}", &args.listen_port);
+    let listen_url = Url::parse(&listen_url).map_err(|err| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid listen address {listen_url}: {err}"))
+    })?;
+
+    // Without leading or trailing slashes, "/" mounts the proxy at the root
+    let base_path = match args.base_path.trim_matches('/') {
+        "" => String::new(),
+        path => format!("/{path}"),
+    };
+    let interstitials_address = if args.interstitials_address.is_empty() {
+        format!("{scheme}://localhost:{}", &args.listen_port)
+    } else {
+        args.interstitials_address.clone()
+    };
+    let mut interstitials_address = Url::parse(&interstitials_address).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid interstitials address {interstitials_address}: {err}"),
+        )
+    })?;
+    // Asset list URLs are made relative to this address, it must end with a slash
+    let interstitials_path = interstitials_address.path().trim_end_matches('/').to_string();
+    if interstitials_path.ends_with(&base_path) {
+        interstitials_address.set_path(&format!("{interstitials_path}/"));
+    } else {
+        interstitials_address.set_path(&format!("{interstitials_path}{base_path}/"));
+    }
+
+    let ad_breaks = ad_break_settings(&args, &test_asset)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    log::info!(
+        "Starting ad proxy {} ({}) HTTP server at {listen_url}, interstitials' base URL: {interstitials_address}",
+        build_info::VERSION,
+        build_info::GIT_HASH
+    );
+    log::info!(
+        "Ad server endpoint: {}, {:?} insertion",
+        args.ad_server_endpoint.as_deref().unwrap_or("none (test asset mode)"),
+        args.ad_insertion_mode.to_str()
+    );
+    log::info!("Default ad duration: {}s, repeating cycle: {}s, ad number: {}",
+        default_ad_duration,
+        default_repeating_cycle,
+        default_ad_number
+    );
+
+    if let Some(ref asset) = test_asset {
+        log::info!("Test asset URL: {}, duration: {}s", asset.url, asset.duration);
+    }
+
+    if args.ad_insertion_mode==InsertionMode::Static && default_repeating_cycle < ad_breaks.target_ad_duration {
+        log::warn!("Ad duration is greater than the repeating cycle. This may cause issues for live streams.");
+    }
+
+    let transcoder = transcoder_settings(&args)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+        .map(|settings| {
+            log::info!("Transcoding MP4-only creatives with {}", settings.endpoint);
+            Transcoder::new(settings, metrics.clone())
+        });
+    let available_ads = AvailableAds::default()
+        .with_creative_cache(CreativeCache::new(
+            Duration::from_secs(args.creative_cache_ttl),
+            args.creative_cache_size,
+            metrics.clone(),
+        ))
+        .with_duration_prober(DurationProber::new(args.probe_durations, metrics.clone()))
+        .with_transcoder(transcoder);
+    let beacons = BeaconDispatcher::new(
+        args.beacon_max_attempts,
+        Duration::from_millis(args.beacon_retry_delay_ms),
+        args.beacon_retry_queue_size,
+        Duration::from_secs(args.beacon_dedup_ttl),
+        metrics.clone(),
+    );
+    let request_log = match &args.request_log {
+        Some(path) => {
+            let rotation = Rotation {
+                max_size: args.request_log_max_size * 1024 * 1024,
+                max_age: (args.request_log_max_age > 0).then(|| Duration::from_secs(args.request_log_max_age * 3600)),
+                keep: args.request_log_keep,
+            };
+            log::info!("Logging the requests to {}", path.display());
+            Some(RequestLog::open(path, rotation)?)
+        }
+        None => None,
+    };
+    let localization = Localization::new(args.localize_ads, args.locale_param.clone(), args.ad_language_param.clone())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let separation = CompetitiveSeparation::new(
+        args.competitive_separation,
+        args.competitive_separation_adjacent,
+        args.competitive_group
+            .iter()
+            .map(|group| group.split('|').map(str::trim).filter(|category| !category.is_empty()).map(str::to_string).collect())
+            .collect(),
+        args.excluded_category.iter().filter(|category| !category.is_empty()).cloned().collect(),
+    );
+    let interstitials_route = parse_interstitials_route(&args.interstitials_route)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let ad_selection_url = args
+        .ad_selection_url
+        .as_deref()
+        .map(|url| Url::parse(url).map_err(|err| format!("Invalid ad selection URL {url}: {err}")))
+        .transpose()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let ad_selection = AdSelection::new(
+        ad_selection_url,
+        Duration::from_millis(args.ad_selection_timeout_ms),
+        metrics.clone(),
+    );
+    let creative_signaling = Some(args.creative_signaling_version).filter(|_| !args.no_creative_signaling);
+    let asset_list_headers =
+        parse_headers(&args.asset_list_header).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let slate_url = args
+        .slate_url
+        .as_deref()
+        .map(|url| Url::parse(url).map_err(|err| format!("Invalid slate URL {url}: {err}")))
+        .transpose()
+        .and_then(|slate_url| match slate_url {
+            None if args.pod_fill_policy == PodFillPolicy::Pad => Err("--pod-fill-policy pad needs a --slate-url".to_string()),
+            slate_url => Ok(slate_url),
+        })
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let bumpers = BumperFilter {
+        keep: args.keep_bumpers,
+        patterns: args.bumper_pattern.iter().filter(|pattern| !pattern.is_empty()).cloned().collect(),
+    };
+    let faults = fault_injector(&args).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    if faults.is_enabled() {
+        log::warn!("Injecting faults into the upstream requests: {}", faults.to_json().dump());
+    }
+    let origin_cache = OriginCache::new(
+        Duration::from_millis(args.origin_cache_ttl_ms),
+        args.max_playlist_size,
+        metrics.clone(),
+    )
+    .with_faults(faults.clone())
+    .with_retry((!args.no_origin_retry).then(|| Duration::from_millis(args.origin_retry_delay_ms)))
+    .with_stale_if_error(Duration::from_secs(args.stale_if_error));
+    let asset_list_retry_ttl = Duration::from_secs(args.asset_list_retry_ttl);
+    if ad_breaks.session_targeting() && !args.no_asset_list_cache {
+        log::info!("Ad server endpoint uses {SESSION_ID_TEMPLATE}, the asset list cache is disabled");
+    }
+
+    // The options of all the streams, the origin and the ad breaks of each stream
+    // are set by for_stream
+    let shared_config = ServerConfig::new(interstitials_address.clone(), interstitials_address.clone(), ad_breaks.clone())
+        .with_test_asset(test_asset.clone())
+        .with_test_pods(test_pods.clone())
+        .with_infer_quartiles(args.infer_quartiles)
+        .with_compatibility_check(args.check_creative_compatibility)
+        .with_bumpers(bumpers.clone())
+        .with_competitive_separation(separation.clone())
+        .with_ad_selection(ad_selection.clone())
+        .with_strip_interactive(args.strip_interactive_creatives)
+        .with_localization(localization.clone())
+        .with_creative_proxy(args.proxy_creative_media)
+        .with_clear_ads(args.clear_ads)
+        .with_creative_signaling(creative_signaling)
+        .with_pod_fill(args.pod_fill_policy.clone(), slate_url.clone())
+        .with_pod_fill_tolerance(args.pod_fill_tolerance)
+        .with_slot_overlap(args.slot_overlap.clone())
+        .with_asset_url_format(args.asset_url_format.clone())
+        .with_interstitials_route(&interstitials_route)
+        .with_asset_list_headers(asset_list_headers.clone())
+        .with_slot_expiry_grace(Duration::from_secs(args.slot_expiry_grace))
+        .with_vod_rolls(args.vod_preroll, args.vod_postroll)
+        .with_pod_page_duration(args.pod_page_duration.map(Duration::from_secs))
+        .with_origin_headers(origin_headers.clone())
+        .with_cache_control(playlist_cache_control.clone(), segment_cache_control.clone())
+        .with_playlist_detection(PlaylistDetection::new(playlist_patterns))
+        .with_clock(origin_time_zone, args.clock_source.clone(), args.max_clock_skew.map(Duration::from_secs))
+        .with_pod_templates(pod_templates.clone(), args.default_pod_num)
+        .with_experiments(experiments.clone())
+        .with_dash_signaling(args.dash_signaling.clone())
+        .with_variant_ladder(variant_ladder.clone())
+        .with_device_profiles(device_profiles.clone())
+        .with_ad_free(ad_free.clone())
+        .with_admission(admission.clone())
+        .with_rules(rules.clone())
+        .with_scte35_markers(args.scte35_markers)
+        .with_server_timing(args.server_timing)
+        .with_max_vast_size(args.max_vast_size)
+        .with_vast_validator(vast_validator.clone())
+        .with_admin_token(args.admin_token.clone())
+        .with_hooks(hooks.clone())
+        .with_faults(faults.clone());
+
+    let join_error = |err: url::ParseError| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to join listen URL with playlist path: {err}"))
+    };
+    let root_channel = root_stream.map(|(forward_url, master_playlist_path)| {
+        log::info!("Forwarding the root stream to {forward_url}");
+        if let Some(ref playlist_path) = master_playlist_path {
+            let proxied_playlist_path = listen_url.join(&format!("{base_path}{playlist_path}")).map_err(join_error)?;
+            log::info!("Proxied stream will be available at: {proxied_playlist_path}");
+        } else {
+            log::info!("Origin host mode enabled - any stream path will be proxied");
+        }
+
+        let server_config = shared_config
+            .for_stream(forward_url, interstitials_address.clone(), ad_breaks.clone())
+            .with_path_prefix(&base_path)
+            .with_master_playlist_path(master_playlist_path)
+            .with_insertion_mode(args.ad_insertion_mode.clone());
+        let stream = StreamState::new(server_config, origin_cache.clone(), &available_ads)
+            .with_ad_pod_cache(AdPodCache::new(!args.no_asset_list_cache, metrics.clone()))
+            .with_session_asset_lists(SessionAssetLists::new(asset_list_retry_ttl, metrics.clone()))
+            .with_pod_prefetch(PodPrefetch::new(args.vod_prefetch_pods, metrics.clone()))
+            .with_esni(BreakSchedule::new("esni", esni_url.clone(), esni_poll_interval))
+            .with_epg(BreakSchedule::new("epg", epg_url.clone(), epg_refresh_interval));
+        Ok::<_, io::Error>(Channel { spec: None, stream })
+    });
+    let root_channel = root_channel.transpose()?;
+    let mut channels = Vec::new();
+    for spec in channel_specs {
+        let master_url = &spec.master_playlist_url;
+        inspect_master_playlist(&upstream, master_url).await.map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Failed to inspect master playlist of channel {}: {err}", spec.name),
+            )
+        })?;
+        let path_prefix = format!("{base_path}{}", spec.path_prefix());
+        let forward_url = base_url(master_url).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid forward URL of {master_url}: {err}"))
+        })?;
+        let proxied_playlist_path =
+            listen_url.join(&format!("{path_prefix}{}", master_url.path())).map_err(join_error)?;
+        let channel_interstitials_address = interstitials_address.join(&format!("{}/", spec.name)).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid interstitials address of channel {}: {err}", spec.name),
+            )
+        })?;
+        let insertion_mode = spec.insertion_mode.clone().unwrap_or(args.ad_insertion_mode.clone());
+        log::info!(
+            "Channel {} forwarding to {forward_url}, {:?} insertion, available at: {proxied_playlist_path}",
+            spec.name,
+            insertion_mode.to_str()
+        );
+
+        let channel_ad_breaks = channel_ad_breaks(&spec, &ad_breaks, &test_asset);
+        let server_config = shared_config
+            .for_stream(forward_url, channel_interstitials_address, channel_ad_breaks)
+            .with_path_prefix(&path_prefix)
+            .with_master_playlist_path(Some(master_url.path().to_string()))
+            .with_insertion_mode(insertion_mode)
+            .with_channel(&spec.name);
+        let stream = StreamState::new(server_config, origin_cache.clone(), &available_ads)
+            .with_ad_pod_cache(AdPodCache::new(!args.no_asset_list_cache, metrics.clone()))
+            .with_session_asset_lists(SessionAssetLists::new(asset_list_retry_ttl, metrics.clone()))
+            .with_pod_prefetch(PodPrefetch::new(args.vod_prefetch_pods, metrics.clone()))
+            .with_esni(BreakSchedule::new("esni", spec.esni_url.clone(), esni_poll_interval))
+            .with_epg(BreakSchedule::new("epg", spec.epg_url.clone(), epg_refresh_interval));
+        channels.push(Channel { spec: Some(spec), stream });
+    }
+
+    if let Some(path) = config_path {
+        log::info!("Settings loaded from {}", path.display());
+        let reloader = ConfigReloader::new(
+            path,
+            arguments,
+            settings,
+            test_asset,
+            mock_ad_server,
+            root_channel.iter().chain(channels.iter()).cloned().collect(),
+            Duration::from_secs(args.config_reload_interval),
+        );
+        watch_config_file(reloader);
+    }
+    poll_break_schedules(
+        root_channel.iter().chain(channels.iter()).cloned().collect(),
+        upstream.clone(),
+        metrics.clone(),
+        args.epg_boundary_break,
+    );
+    let user_defined_query_params = UserDefinedQueryParams::default();
+    let shutdown = ShutdownState::default();
+    let compress = !args.no_compression;
+    let workers = if args.workers == 0 {
+        std::thread::available_parallelism().map_or(2, |cores| cores.get())
+    } else {
+        args.workers
+    };
+    log::info!(
+        "Serving with {workers} workers, up to {} connections per worker",
+        args.max_connections
+    );
+
+    let (shutdown_state, flushed_beacons) = (shutdown.clone(), beacons.clone());
+    let mut server = HttpServer::new(move || {
+        let cors = actix_cors::Cors::permissive();
+
+        // create https client inside `HttpServer::new` closure to have one per worker thread
+        let client = make_https_client(&upstream);
+        let ad_server_client = make_https_client(&ad_server_upstream);
+
+        let app = App::new()
+            .app_data(web::Data::new(client))
+            .app_data(web::Data::new(AdServerClient(ad_server_client)))
+            .app_data(web::Data::new(available_ads.clone()))
+            .app_data(web::Data::new(user_defined_query_params.clone()))
+            .app_data(metrics.clone())
+            .app_data(web::Data::new(beacons.clone()))
+            .app_data(web::Data::new(shutdown.clone()))
+            .app_data(web::Data::new(request_log.clone()))
+            .wrap(middleware::Condition::new(compress, middleware::Compress::default()))
+            .wrap(middleware::Condition::new(compress, middleware::from_fn(sized_manifests)))
+            .wrap(middleware::Condition::new(request_log.is_some(), middleware::from_fn(log_requests)))
+            .wrap(middleware::Logger::default())
+            .wrap(cors);
+
+        // Everything is served under the base path
+        let mut scope = web::scope(&base_path)
+            .route(METRICS_PREFIX, get_or_head().to(handle_metrics))
+            .route(TRACKING_PREFIX, web::post().to(handle_tracking));
+        for channel in &channels {
+            let name = channel.spec.as_ref().map(ChannelSpec::path_prefix).unwrap_or_default();
+            scope = scope.service(web::scope(&name).configure(|cfg| channel.configure(cfg)));
+        }
+        scope = match &root_channel {
+            Some(channel) => scope.configure(|cfg| channel.configure(cfg)),
+            None => scope.default_service(web::to(HttpResponse::NotFound)),
+        };
+        app.service(scope)
+    })
+    .workers(workers)
+    .max_connections(args.max_connections)
+    // Signals are handled by the shutdown watcher to drain the sessions first
+    .disable_signals();
+
+    let mut listeners = listener::systemd_listeners()?;
+    if let Some(path) = &args.listen_unix {
+        listeners.push(listener::bind_unix(path).inspect_err(|err| {
+            log::error!("Failed to bind the unix socket {}: {err}", path.display());
+        })?);
+    }
+
+    if listeners.is_empty() {
+        for addr in listener::resolve_addrs(&listen_addrs, args.listen_port)? {
+            listeners.push(listener::bind_tcp(addr, args.backlog).inspect_err(|err| {
+                log::error!("Failed to bind {addr}: {err}");
+            })?);
+        }
+    }
+
+    for listener in listeners {
+        log::info!("Listening on {}", listener.describe());
+        server = match (listener, &tls) {
+            (Listener::Tcp(listener), Some(tls)) => server.listen_rustls_0_23(listener, tls.clone())?,
+            (Listener::Tcp(listener), None) if args.http2 => server.listen_auto_h2c(listener)?,
+            (Listener::Tcp(listener), None) => server.listen(listener)?,
+            #[cfg(unix)]
+            (Listener::Unix(_), Some(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "HTTPS is not supported on unix sockets",
+                ));
+            }
+            #[cfg(unix)]
+            (Listener::Unix(listener), None) => {
+                if args.http2 {
+                    log::warn!("HTTP/2 isn't supported on unix sockets, serving HTTP/1.1 only");
+                }
+                server.listen_uds(listener)?
+            }
+        };
+    }
+
+    let server = server.run();
+    shutdown::watch_signals(
+        server.handle(),
+        shutdown_state,
+        Duration::from_secs(args.drain_period),
+        flushed_beacons,
+        Duration::from_secs(args.beacon_flush_timeout),
+    );
+    let result = server.await;
+
+    if let Some(path) = &args.listen_unix {
+        std::fs::remove_file(path).ok();
+    }
+    result
+}
@@ -0,0 +1,138 @@
+use crate::metrics::Metrics;
+use actix_web::web;
+use dashmap::DashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// The slot and the session of an asset list
+type AssetListKey = (String, String);
+
+struct CachedAssetList {
+    body: String,
+    built_at: Instant,
+    slot_end: chrono::DateTime<chrono::Utc>,
+}
+
+/// Keeps the asset list served to a session for a slot, so a player retrying
+/// its request, e.g. after a timeout, gets the same ads instead of another ad
+/// server call and a second round of impressions. An asset list is served
+/// again for `ttl` after it was built and never beyond the end of its slot,
+/// and the retries arriving while it is being built wait for it
+#[derive(Clone, Default)]
+pub struct SessionAssetLists {
+    ttl: Duration,
+    asset_lists: Arc<DashMap<AssetListKey, CachedAssetList>>,
+    in_flight: Arc<DashMap<AssetListKey, Arc<tokio::sync::Mutex<()>>>>,
+    metrics: web::Data<Metrics>,
+}
+
+impl SessionAssetLists {
+    /// A `ttl` of zero builds the asset list of every request
+    pub fn new(ttl: Duration, metrics: web::Data<Metrics>) -> Self {
+        Self {
+            ttl,
+            asset_lists: Arc::new(DashMap::new()),
+            in_flight: Arc::new(DashMap::new()),
+            metrics,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "ttl": self.ttl.as_secs_f64(),
+            "cached_asset_lists": self.asset_lists.len(),
+        }
+    }
+
+    /// Drop all kept asset lists, e.g. when the slots are rescheduled
+    pub fn clear(&self) {
+        self.asset_lists.clear();
+    }
+
+    /// Return the asset list served to the session for the slot or build it.
+    /// A failed build isn't kept
+    pub async fn get_or_build<F, Fut, E>(
+        &self,
+        slot: &str,
+        session: &str,
+        slot_end: chrono::DateTime<chrono::Utc>,
+        build: F,
+    ) -> Result<String, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<String, E>>,
+    {
+        let key = (slot.to_string(), session.to_string());
+        if let Some(body) = self.cached(&key) {
+            self.metrics.inc("session_asset_list_requests_total", &[("result", "retry")]);
+            return Ok(body);
+        }
+
+        let lock = self.in_flight.entry(key.clone()).or_default().clone();
+        let _guard = lock.lock().await;
+        if let Some(body) = self.cached(&key) {
+            self.metrics.inc("session_asset_list_requests_total", &[("result", "coalesced")]);
+            return Ok(body);
+        }
+
+        self.metrics.inc("session_asset_list_requests_total", &[("result", "built")]);
+        let result = build().await;
+        if let Ok(body) = &result {
+            if slot_end > chrono::Utc::now() {
+                // Drop the asset lists no retry will get anymore
+                self.asset_lists.retain(|_, cached| self.is_fresh(cached));
+                self.asset_lists.insert(
+                    key.clone(),
+                    CachedAssetList {
+                        body: body.clone(),
+                        built_at: Instant::now(),
+                        slot_end,
+                    },
+                );
+            }
+        }
+        self.in_flight.remove(&key);
+
+        result
+    }
+
+    fn is_fresh(&self, cached: &CachedAssetList) -> bool {
+        cached.built_at.elapsed() < self.ttl && cached.slot_end > chrono::Utc::now()
+    }
+
+    fn cached(&self, key: &AssetListKey) -> Option<String> {
+        self.asset_lists
+            .get(key)
+            .filter(|cached| self.is_fresh(cached))
+            .map(|cached| cached.body.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn serves_the_retries_of_a_session_the_same_asset_list() {
+        let asset_lists = SessionAssetLists::new(Duration::from_secs(30), web::Data::new(Metrics::default()));
+        let slot_end = chrono::Utc::now() + chrono::Duration::seconds(60);
+        let build = |body: &str| {
+            let body = body.to_string();
+            move || async move { Ok::<_, ()>(body) }
+        };
+        assert_eq!(asset_lists.get_or_build("ad_slot1", "alice", slot_end, build("first")).await, Ok("first".to_string()));
+        assert_eq!(asset_lists.get_or_build("ad_slot1", "alice", slot_end, build("second")).await, Ok("first".to_string()));
+        // Other sessions and slots get their own
+        assert_eq!(asset_lists.get_or_build("ad_slot1", "bob", slot_end, build("third")).await, Ok("third".to_string()));
+        assert_eq!(asset_lists.get_or_build("ad_slot2", "alice", slot_end, build("fourth")).await, Ok("fourth".to_string()));
+        // The asset list of an ended slot isn't served again
+        let ended = chrono::Utc::now() - chrono::Duration::seconds(1);
+        asset_lists.get_or_build("ad_slot0", "alice", ended, build("fifth")).await.unwrap();
+        assert_eq!(asset_lists.get_or_build("ad_slot0", "alice", ended, build("sixth")).await, Ok("sixth".to_string()));
+    }
+}
@@ -0,0 +1,84 @@
+use crate::beacon::BeaconDispatcher;
+use actix_web::dev::ServerHandle;
+#[cfg(unix)]
+use futures_util::future::Either;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Set when a shutdown has started. New sessions are refused from then on,
+/// the existing ones keep being served until the drain period is over.
+#[derive(Clone, Default)]
+pub struct ShutdownState(Arc<AtomicBool>);
+
+impl ShutdownState {
+    pub fn is_draining(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn start_draining(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Drain the sessions for `drain_period` on SIGTERM before stopping the
+/// server, then wait up to `flush_timeout` for the beacons being delivered.
+/// SIGINT, or a second signal while draining, stops the server right away.
+pub fn watch_signals(
+    server: ServerHandle,
+    state: ShutdownState,
+    drain_period: Duration,
+    beacons: BeaconDispatcher,
+    flush_timeout: Duration,
+) {
+    actix_web::rt::spawn(async move {
+        if wait_for_signal().await == Signal::Interrupt {
+            log::info!("Received SIGINT, stopping the server");
+        } else {
+            state.start_draining();
+            log::info!("Received SIGTERM, draining the sessions for {drain_period:?}");
+            // Another signal cuts the drain period short
+            actix_web::rt::time::timeout(drain_period, wait_for_signal()).await.ok();
+        }
+
+        let pending = beacons.flush(flush_timeout).await;
+        if pending > 0 {
+            log::warn!("Stopping with {pending} beacons still being delivered");
+        }
+        server.stop(true).await;
+    });
+}
+
+#[derive(PartialEq)]
+enum Signal {
+    Interrupt,
+    Terminate,
+}
+
+async fn wait_for_signal() -> Signal {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                let interrupt = std::pin::pin!(tokio::signal::ctrl_c());
+                let terminate = std::pin::pin!(terminate.recv());
+                match futures_util::future::select(interrupt, terminate).await {
+                    Either::Left(_) => Signal::Interrupt,
+                    Either::Right(_) => Signal::Terminate,
+                }
+            }
+            Err(err) => {
+                log::error!("Failed to listen for SIGTERM: {err}");
+                tokio::signal::ctrl_c().await.ok();
+                Signal::Interrupt
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.ok();
+        Signal::Interrupt
+    }
+}
@@ -0,0 +1,236 @@
+use crate::metrics::LatencyWindow;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// The expired slots kept in the status, the most recent ones
+const MAX_EXPIRED_SLOTS: usize = 64;
+
+/// Where an ad slot is in its life
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlotState {
+    /// Waiting for a media playlist to reach it
+    Scheduled,
+    /// Inserted into the media playlists, no asset list asked for yet
+    Announced,
+    /// Its asset list was served
+    Fetched,
+    /// Its window left the playlists, it is matched no more
+    Expired,
+}
+
+impl SlotState {
+    pub fn to_str(&self) -> &str {
+        match self {
+            SlotState::Scheduled => "scheduled",
+            SlotState::Announced => "announced",
+            SlotState::Fetched => "fetched",
+            SlotState::Expired => "expired",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SlotRecord {
+    state: SlotState,
+    scheduled_at: DateTime<Utc>,
+    // The media playlists and manifests the slot was inserted into
+    announcements: u64,
+    first_announced_at: Option<DateTime<Utc>>,
+    // The asset lists served for it
+    fetches: u64,
+    last_fetched_at: Option<DateTime<Utc>>,
+    // From the interstitial requests to their asset lists
+    latency: LatencyWindow,
+    expired_at: Option<DateTime<Utc>>,
+}
+
+/// The hits of the ad slots by index, from their scheduling to their expiry.
+/// It also hands out the slot indexes, which are never reused so the players
+/// don't mistake a new break for an expired one of the same DATERANGE ID
+#[derive(Clone, Debug, Default)]
+pub struct SlotLifecycle {
+    records: Arc<DashMap<u64, SlotRecord>>,
+    next_index: Arc<AtomicU64>,
+    // The index the static schedule counts from, past the slots of the
+    // previous stream epochs
+    static_base: Arc<AtomicU64>,
+    scheduled: Arc<AtomicU64>,
+    expired: Arc<AtomicU64>,
+}
+
+impl SlotLifecycle {
+    /// The index of the next slot scheduled, past those of all the slots so far
+    pub fn next_index(&self) -> u64 {
+        self.next_index.load(Ordering::Relaxed)
+    }
+
+    /// The index of the static slot `n` is `static_base + n`
+    pub fn static_base(&self) -> u64 {
+        self.static_base.load(Ordering::Relaxed)
+    }
+
+    /// Whether any slot was scheduled since the start or the last reset
+    pub fn has_scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn schedule(&self, index: u64) {
+        self.next_index.fetch_max(index + 1, Ordering::Relaxed);
+        self.scheduled.fetch_add(1, Ordering::Relaxed);
+        self.records.insert(
+            index,
+            SlotRecord {
+                state: SlotState::Scheduled,
+                scheduled_at: Utc::now(),
+                announcements: 0,
+                first_announced_at: None,
+                fetches: 0,
+                last_fetched_at: None,
+                latency: LatencyWindow::default(),
+                expired_at: None,
+            },
+        );
+    }
+
+    pub fn announce(&self, index: u64) {
+        if let Some(mut record) = self.records.get_mut(&index) {
+            record.announcements += 1;
+            if record.state == SlotState::Scheduled {
+                record.state = SlotState::Announced;
+                record.first_announced_at = Some(Utc::now());
+            }
+        }
+    }
+
+    pub fn fetch(&self, index: u64) {
+        if let Some(mut record) = self.records.get_mut(&index) {
+            record.fetches += 1;
+            record.last_fetched_at = Some(Utc::now());
+            if record.state != SlotState::Expired {
+                record.state = SlotState::Fetched;
+            }
+        }
+    }
+
+    /// How long the asset list of the slot took to be served
+    pub fn record_latency(&self, index: u64, latency: Duration) {
+        if let Some(mut record) = self.records.get_mut(&index) {
+            record.latency.record(latency);
+        }
+    }
+
+    pub fn expire(&self, index: u64) {
+        if let Some(mut record) = self.records.get_mut(&index) {
+            record.state = SlotState::Expired;
+            record.expired_at = Some(Utc::now());
+        }
+        self.expired.fetch_add(1, Ordering::Relaxed);
+
+        // Forget the oldest expired slots
+        let mut expired = self
+            .records
+            .iter()
+            .filter(|record| record.state == SlotState::Expired)
+            .map(|record| (*record.key(), record.expired_at))
+            .collect::<Vec<_>>();
+        if expired.len() > MAX_EXPIRED_SLOTS {
+            expired.sort_by_key(|(index, expired_at)| (*expired_at, *index));
+            for (index, _) in &expired[..expired.len() - MAX_EXPIRED_SLOTS] {
+                self.records.remove(index);
+            }
+        }
+    }
+
+    pub fn state(&self, index: u64) -> Option<SlotState> {
+        self.records.get(&index).map(|record| record.state)
+    }
+
+    /// Forget all the slots. The indexes of the next ones carry on from those
+    /// forgotten, which the players may still have in their playlists
+    pub fn clear(&self) {
+        self.records.clear();
+        self.static_base.store(self.next_index(), Ordering::Relaxed);
+        self.scheduled.store(0, Ordering::Relaxed);
+        self.expired.store(0, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut records = self
+            .records
+            .iter()
+            .map(|record| (*record.key(), record.value().clone()))
+            .collect::<Vec<_>>();
+        records.sort_by_key(|(index, _)| *index);
+        let time = |time: Option<DateTime<Utc>>| time.map(|time| time.to_rfc3339());
+        let slots = records
+            .iter()
+            .map(|(index, record)| {
+                json::object! {
+                    "index": *index,
+                    "state": record.state.to_str(),
+                    "scheduled_at": record.scheduled_at.to_rfc3339(),
+                    "announcements": record.announcements,
+                    "first_announced_at": time(record.first_announced_at),
+                    "fetches": record.fetches,
+                    "last_fetched_at": time(record.last_fetched_at),
+                    "asset_list_latency": record.latency.to_json(),
+                    "expired_at": time(record.expired_at),
+                }
+            })
+            .collect::<Vec<_>>();
+        let count = |state: SlotState| records.iter().filter(|(_, record)| record.state == state).count();
+
+        json::object! {
+            "next_index": self.next_index(),
+            "static_base": self.static_base(),
+            "scheduled_total": self.scheduled.load(Ordering::Relaxed),
+            "expired_total": self.expired.load(Ordering::Relaxed),
+            "scheduled": count(SlotState::Scheduled),
+            "announced": count(SlotState::Announced),
+            "fetched": count(SlotState::Fetched),
+            "slots": slots,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_the_slots_until_they_expire() {
+        let lifecycle = SlotLifecycle::default();
+        lifecycle.schedule(0);
+        lifecycle.schedule(1);
+        lifecycle.announce(0);
+        lifecycle.announce(1);
+        lifecycle.fetch(1);
+        lifecycle.record_latency(1, Duration::from_millis(120));
+        lifecycle.record_latency(1, Duration::from_millis(80));
+        assert_eq!(lifecycle.state(0), Some(SlotState::Announced));
+        let latency = &lifecycle.to_json()["slots"][1]["asset_list_latency"];
+        assert_eq!(latency["count"], 2);
+        assert_eq!(latency["p50"], 0.08);
+        assert_eq!(latency["max"], 0.12);
+        assert_eq!(lifecycle.state(1), Some(SlotState::Fetched));
+
+        lifecycle.expire(1);
+        lifecycle.fetch(1);
+        assert_eq!(lifecycle.state(1), Some(SlotState::Expired));
+        // The indexes of the expired slots aren't handed out again
+        assert_eq!(lifecycle.next_index(), 2);
+        for index in 2..2 + MAX_EXPIRED_SLOTS as u64 {
+            lifecycle.schedule(index);
+            lifecycle.expire(index);
+        }
+        assert_eq!(lifecycle.state(1), None);
+        assert_eq!(lifecycle.state(0), Some(SlotState::Announced));
+
+        lifecycle.clear();
+        assert!(!lifecycle.has_scheduled());
+        assert_eq!(lifecycle.static_base(), 2 + MAX_EXPIRED_SLOTS as u64);
+    }
+}
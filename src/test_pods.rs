@@ -0,0 +1,97 @@
+use crate::utils::Tracking;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use url::Url;
+
+/// A creative of a test pod, an HLS stream played as is
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct TestPodAsset {
+    pub url: String,
+    /// Duration in seconds, fractional ones too
+    pub duration: f64,
+    /// Tracking URLs by event, e.g. `start` or `firstQuartile`
+    #[serde(default)]
+    pub tracking: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub impressions: Vec<String>,
+}
+
+impl TestPodAsset {
+    pub fn tracking(&self) -> Vec<Tracking> {
+        self.tracking
+            .iter()
+            .map(|(event, urls)| Tracking {
+                event: event.clone(),
+                offset: None,
+                urls: urls.clone(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct TestPod {
+    pub assets: Vec<TestPodAsset>,
+}
+
+impl TestPod {
+    pub fn duration(&self) -> f64 {
+        self.assets.iter().map(|asset| asset.duration).sum()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TestPodsFile {
+    pods: Vec<TestPod>,
+}
+
+/// Ad pods served instead of the ad server's, in turn for each asset list
+/// request to simulate a varied fill. An empty pod is an unfilled break
+#[derive(Clone, Debug)]
+pub struct TestPods {
+    pods: Arc<Vec<TestPod>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl TestPods {
+    /// The pods of a JSON file: `{"pods": [{"assets": [{"url": ..., "duration": ...}]}]}`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read test assets file {}: {err}", path.display()))?;
+        let file: TestPodsFile = serde_json::from_str(&content)
+            .map_err(|err| format!("Invalid test assets file {}: {err}", path.display()))?;
+        Self::new(file.pods).map_err(|err| format!("Invalid test assets file {}: {err}", path.display()))
+    }
+
+    pub fn new(pods: Vec<TestPod>) -> Result<Self, String> {
+        if pods.is_empty() {
+            return Err("no pods".to_string());
+        }
+        for asset in pods.iter().flat_map(|pod| pod.assets.iter()) {
+            Url::parse(&asset.url).map_err(|err| format!("invalid asset URL {}: {err}", asset.url))?;
+            if !asset.duration.is_finite() || asset.duration <= 0.0 {
+                return Err(format!("asset {} has no duration", asset.url));
+            }
+        }
+
+        Ok(Self {
+            pods: Arc::new(pods),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// The pod of the next asset list
+    pub fn next_pod(&self) -> &TestPod {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.pods.len();
+        &self.pods[index]
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "pods": self.pods.len(),
+            "durations": self.pods.iter().map(TestPod::duration).collect::<Vec<_>>(),
+        }
+    }
+}
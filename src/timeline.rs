@@ -0,0 +1,191 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// The timelines of the sessions not refreshing their playlists for this long are dropped
+const TIMELINE_TTL: Duration = Duration::from_secs(10 * 60);
+// Beyond this many sessions, the idle ones are dropped before a new one is kept
+const MAX_TIMELINES: usize = 10_000;
+
+/// A segment of a media playlist as it was served, with the break cued on it
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimelineSegment {
+    pub sequence: u64,
+    pub uri: String,
+    pub program_date_time: chrono::DateTime<chrono::Utc>,
+    pub duration: f64,
+    // The ID of the DATERANGE of the break starting in the segment
+    pub cue: Option<String>,
+}
+
+/// An ad slot of the stream when the playlist was served
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimelineSlot {
+    pub name: String,
+    pub start_time: chrono::DateTime<chrono::Utc>,
+    pub duration: f64,
+    pub state: Option<String>,
+}
+
+/// The media playlist last served to a session: the program date times of its
+/// segments, the ad slots of the stream and where they were cued
+#[derive(Clone, Debug)]
+pub struct Timeline {
+    pub playlist: String,
+    pub profile: String,
+    pub segments: Vec<TimelineSegment>,
+    pub slots: Vec<TimelineSlot>,
+    served_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Timeline {
+    pub fn new(playlist: &str, profile: &str, segments: Vec<TimelineSegment>, slots: Vec<TimelineSlot>) -> Self {
+        Self {
+            playlist: playlist.to_string(),
+            profile: profile.to_string(),
+            segments,
+            slots,
+            served_at: chrono::Utc::now(),
+        }
+    }
+
+    // Where the slot is relative to the segments, and why it wasn't cued
+    fn placement(&self, slot: &TimelineSlot) -> (&'static str, Option<u64>) {
+        if let Some(segment) = self.segments.iter().find(|segment| segment.cue.as_deref() == Some(&slot.name)) {
+            return ("cued", Some(segment.sequence));
+        }
+        let (Some(first), Some(last)) = (self.segments.first(), self.segments.last()) else {
+            return ("no_segments", None);
+        };
+        let window_end = last.program_date_time + chrono::Duration::milliseconds((last.duration * 1000.0) as i64);
+        let slot_end = slot.start_time + chrono::Duration::milliseconds((slot.duration * 1000.0) as i64);
+        if slot_end <= first.program_date_time {
+            ("before_window", None)
+        } else if slot.start_time >= window_end {
+            ("after_window", None)
+        } else if self.profile == "none" {
+            ("clean_playlist", None)
+        } else {
+            // Within the window but starting in no segment, e.g. a static slot
+            // of another cycle or a break started before the window
+            ("not_cued", None)
+        }
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let format = |time: &chrono::DateTime<chrono::Utc>| time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let segments = self
+            .segments
+            .iter()
+            .map(|segment| {
+                json::object! {
+                    "sequence": segment.sequence,
+                    "uri": segment.uri.as_str(),
+                    "program_date_time": format(&segment.program_date_time),
+                    "duration": segment.duration,
+                    "cue": segment.cue.as_deref(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let slots = self
+            .slots
+            .iter()
+            .map(|slot| {
+                let (placement, sequence) = self.placement(slot);
+                json::object! {
+                    "name": slot.name.as_str(),
+                    "start_time": format(&slot.start_time),
+                    "duration": slot.duration,
+                    "state": slot.state.as_deref(),
+                    "placement": placement,
+                    "segment": sequence,
+                }
+            })
+            .collect::<Vec<_>>();
+        json::object! {
+            "served_at": format(&self.served_at),
+            "playlist": self.playlist.as_str(),
+            "profile": self.profile.as_str(),
+            "window": {
+                "start": self.segments.first().map(|segment| format(&segment.program_date_time)),
+                "end": self.segments.last().map(|segment| {
+                    format(&(segment.program_date_time + chrono::Duration::milliseconds((segment.duration * 1000.0) as i64)))
+                }),
+            },
+            "segments": segments,
+            "slots": slots,
+        }
+    }
+}
+
+/// The timeline of the last media playlist served to each playback session,
+/// shown by /timeline
+#[derive(Clone, Default)]
+pub struct SessionTimelines(Arc<DashMap<String, (Instant, Timeline)>>);
+
+impl SessionTimelines {
+    pub fn record(&self, session: &str, timeline: Timeline) {
+        if self.0.len() >= MAX_TIMELINES && !self.0.contains_key(session) {
+            self.0.retain(|_, (seen_at, _)| seen_at.elapsed() < TIMELINE_TTL);
+            if self.0.len() >= MAX_TIMELINES {
+                return;
+            }
+        }
+        self.0.insert(session.to_string(), (Instant::now(), timeline));
+    }
+
+    pub fn get(&self, session: &str) -> Option<Timeline> {
+        self.0
+            .get(session)
+            .filter(|entry| entry.0.elapsed() < TIMELINE_TTL)
+            .map(|entry| entry.1.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn places_the_slots_on_the_segments() {
+        let start = chrono::DateTime::parse_from_rfc3339("2026-10-15T12:00:00Z").unwrap().to_utc();
+        let at = |seconds: i64| start + chrono::Duration::seconds(seconds);
+        let segments = (0..3)
+            .map(|index| TimelineSegment {
+                sequence: 100 + index,
+                uri: format!("segment{index}.ts"),
+                program_date_time: at(index as i64 * 4),
+                duration: 4.0,
+                cue: (index == 1).then(|| "ad_slot1".to_string()),
+            })
+            .collect();
+        let slot = |name: &str, start_time, duration| TimelineSlot {
+            name: name.to_string(),
+            start_time,
+            duration,
+            state: None,
+        };
+        let slots = vec![
+            slot("ad_slot0", at(-30), 10.0),
+            slot("ad_slot1", at(5), 10.0),
+            slot("ad_slot2", at(30), 10.0),
+            slot("ad_slot3", at(-2), 10.0),
+        ];
+        let timeline = Timeline::new("/v0/media.m3u8", "interstitials", segments, slots);
+        let json = timeline.to_json();
+        let placements = json["slots"]
+            .members()
+            .map(|slot| (slot["placement"].to_string(), slot["segment"].as_u64()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            placements,
+            [
+                ("before_window".to_string(), None),
+                ("cued".to_string(), Some(101)),
+                ("after_window".to_string(), None),
+                ("not_cued".to_string(), None),
+            ]
+        );
+        assert_eq!(json["window"]["end"], "2026-10-15T12:00:12.000Z");
+    }
+}
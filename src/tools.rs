@@ -0,0 +1,281 @@
+use crate::channel::ChannelSpec;
+use crate::date_ranges::OriginDateRanges;
+use crate::dns::DnsResolver;
+use crate::egress_proxy::EgressProxy;
+use crate::device::{DeviceProfiles, InsertionProfile};
+use crate::drm::OriginKeys;
+use crate::epoch::StreamEpoch;
+use crate::experiments::Experiments;
+use crate::header_forwarding::HeaderForwarding;
+use crate::localization::Localization;
+use crate::pod_template::PodTemplate;
+use crate::rules::InsertionRules;
+use crate::utils::{
+    BumperFilter, get_advertiser_for_creative, get_all_raw_creatives_from_vast,
+    get_all_transcoded_creatives_from_vast, get_categories_for_creative,
+    get_duration_and_media_urls_and_tracking_events_from_linear, get_error_urls_for_creative,
+    get_impression_urls_for_creative, get_universal_ad_ids_from_creative,
+    get_video_clicks_from_linear, is_hls_playlist, parse_time_zone, rustls_config, rustls_server_config,
+};
+use crate::cli::{CliArguments, ad_break_settings, fault_injector, transcoder_settings, variant_ladder};
+use crate::handlers::parse_cache_control_override;
+use crate::{
+    AdBreakSettings, AvailableAdSlots, AvailableAds, ServerConfig, insert_interstitials, listener, parse_headers,
+    to_tracking_json,
+};
+use awc::{Client, Connector};
+use hls_m3u8::{MasterPlaylist, MediaPlaylist};
+use json::object;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+// Maximum size of a VAST document or playlist read by the tools
+const MAX_SOURCE_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(clap::Args, Debug)]
+pub struct ParseVastArguments {
+    /// VAST document to parse (file path or URL)
+    source: String,
+
+    /// Creatives whose ad title or media URL contains this text are bumpers, can be repeated
+    #[clap(long, value_delimiter = ',')]
+    bumper_pattern: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PreviewArguments {
+    /// Media playlist to insert the interstitials into (file path or URL)
+    source: String,
+
+    /// Ad break duration in seconds
+    #[clap(long, default_value_t = 10)]
+    default_ad_duration: u64,
+
+    /// Repeat the ad break every 'n' seconds
+    #[clap(long, default_value_t = 30)]
+    default_repeating_cycle: u64,
+
+    /// Number of ad slots to generate
+    #[clap(long, default_value_t = 1000)]
+    default_ad_number: u64,
+
+    /// Stream epoch the ad breaks of a live stream are scheduled from (RFC 3339), now by default
+    #[clap(long)]
+    epoch: Option<String>,
+
+    /// Base URL of the interstitials in the preview
+    #[clap(long, default_value = "http://localhost:3333/")]
+    interstitials_address: Url,
+}
+
+/// Run the startup checks which don't need the network and print the
+/// resulting settings, or every problem found
+pub fn validate_config(args: &CliArguments, settings: &BTreeMap<String, String>) -> io::Result<()> {
+    let mut errors = Vec::new();
+    let mut check = |result: Result<(), String>| {
+        if let Err(err) = result {
+            errors.push(err);
+        }
+    };
+    let parse_url = |name: &str, value: &str| {
+        Url::parse(value).map(|_| ()).map_err(|err| format!("Invalid {name} {value}: {err}"))
+    };
+
+    if let Some(url) = &args.master_playlist_url {
+        check(parse_url("master playlist URL", url));
+    }
+    if let Some(url) = &args.origin_host {
+        check(parse_url("origin host URL", url));
+    }
+    if let Some(url) = &args.esni_url {
+        check(parse_url("SCTE-224 schedule URL", url));
+    }
+    if let Some(url) = &args.epg_url {
+        check(parse_url("EPG URL", url));
+    }
+    if !args.interstitials_address.is_empty() {
+        check(parse_url("interstitials address", &args.interstitials_address));
+    }
+    if !args.test_asset_url.is_empty() && !is_hls_playlist(&args.test_asset_url) {
+        check(Err(format!("Test asset URL is not an HLS playlist: {}", args.test_asset_url)));
+    }
+    check(ad_break_settings(args, &None).map(|_| ()));
+    check(fault_injector(args).map(|_| ()));
+    check(transcoder_settings(args).map(|_| ()));
+    check(ChannelSpec::parse_all(&args.channel).map(|_| ()));
+    check(PodTemplate::parse_all(&args.pod_template).map(|_| ()));
+    check(parse_time_zone(&args.origin_time_zone).map(|_| ()));
+    check(HeaderForwarding::new(&args.forward_origin_header, &args.block_origin_header).map(|_| ()));
+    check(parse_cache_control_override(args.playlist_cache_control.as_deref()).map(|_| ()));
+    check(parse_cache_control_override(args.segment_cache_control.as_deref()).map(|_| ()));
+    check(variant_ladder(args).map(|_| ()));
+    check(DeviceProfiles::parse_all(&args.device_profile).map(|_| ()));
+    check(Experiments::parse_all(&args.experiment_variant).map(|_| ()));
+    check(InsertionRules::load(args.rules_file.as_deref(), args.country_header.clone()).map(|_| ()));
+    check(Localization::new(args.localize_ads, args.locale_param.clone(), args.ad_language_param.clone()).map(|_| ()));
+    check(DnsResolver::parse_overrides(&args.resolve).map(|_| ()));
+    check(parse_headers(&args.ad_server_header).map(|_| ()));
+    check(parse_headers(&args.asset_list_header).map(|_| ()));
+    check(EgressProxy::new(args.http_proxy.as_deref(), args.https_proxy.as_deref(), args.no_proxy.as_deref()).map(|_| ()));
+    check(listener::resolve_addrs(&args.listen_addrs(), args.listen_port).map(|_| ()).map_err(|err| err.to_string()));
+    check(
+        rustls_config(args.upstream_ca.as_deref(), args.insecure_upstream_tls, None)
+            .map(|_| ())
+            .map_err(|err| format!("Failed to load the upstream CA certificates: {err}")),
+    );
+    if let (Some(cert), Some(key)) = (&args.ad_server_client_cert, &args.ad_server_client_key) {
+        check(
+            rustls_config(None, false, Some((cert.as_path(), key.as_path())))
+                .map(|_| ())
+                .map_err(|err| format!("Failed to load the ad server client certificate {}: {err}", cert.display())),
+        );
+    }
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        check(
+            rustls_server_config(cert, key)
+                .map(|_| ())
+                .map_err(|err| format!("Failed to load the TLS certificate {}: {err}", cert.display())),
+        );
+    }
+
+    if !errors.is_empty() {
+        println!("{}", object! { "valid": false, "errors": errors }.pretty(2));
+        std::process::exit(1);
+    }
+
+    let settings = settings
+        .iter()
+        .fold(object! {}, |mut object, (key, value)| {
+            object[key.as_str()] = value.as_str().into();
+            object
+        });
+    println!("{}", object! { "valid": true, "settings": settings }.pretty(2));
+    Ok(())
+}
+
+/// Print the linear creatives of a VAST document the way the proxy sees them
+pub async fn parse_vast(args: &ParseVastArguments) -> io::Result<()> {
+    let xml = read_source(&args.source).await?;
+    let vast = vast4_rs::from_str(&xml)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid VAST: {err:?}")))?;
+
+    // List the bumpers too, marked as such
+    let bumpers = BumperFilter {
+        keep: true,
+        patterns: args.bumper_pattern.clone(),
+    };
+    let raw = get_all_raw_creatives_from_vast(&vast, &bumpers)
+        .into_iter()
+        .map(|creative| (creative, "raw"));
+    let transcoded = get_all_transcoded_creatives_from_vast(&vast, &bumpers)
+        .into_iter()
+        .map(|creative| (creative, "transcoded"));
+    let creatives = raw
+        .chain(transcoded)
+        .filter_map(|(creative, kind)| {
+            let linear = creative.linear.as_ref()?;
+            let (duration, media_urls, tracking) =
+                get_duration_and_media_urls_and_tracking_events_from_linear(linear);
+            let universal_ad_ids = get_universal_ad_ids_from_creative(creative)
+                .into_iter()
+                .map(|id| object! { "scheme": id.scheme, "value": id.value })
+                .collect::<Vec<_>>();
+            let click_through = get_video_clicks_from_linear(linear).and_then(|clicks| clicks.click_through);
+
+            Some(object! {
+                "kind": kind,
+                "bumper": bumpers.is_bumper(&vast, creative),
+                "duration": duration,
+                "media_urls": media_urls,
+                "universal_ad_ids": universal_ad_ids,
+                "tracking": tracking.iter().map(to_tracking_json).collect::<Vec<_>>(),
+                "impressions": get_impression_urls_for_creative(&vast, creative),
+                "errors": get_error_urls_for_creative(&vast, creative),
+                "click_through": click_through,
+                "categories": get_categories_for_creative(&vast, creative),
+                "advertiser": get_advertiser_for_creative(&vast, creative),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    println!("{}", object! { "count": creatives.len(), "creatives": creatives }.pretty(2));
+    Ok(())
+}
+
+/// Print a media playlist with the interstitials of the static ad breaks, to
+/// check where the slots land without running the server
+pub async fn preview_playlist(args: &PreviewArguments) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let text = read_source(&args.source).await?;
+    if MasterPlaylist::try_from(text.as_str()).is_ok() {
+        return Err(invalid(format!("{} is a master playlist, preview one of its media playlists", args.source)));
+    }
+    let (text, keys) = OriginKeys::strip(&text);
+    let mut m3u8 = MediaPlaylist::try_from(text.as_ref())
+        .map_err(|err| invalid(format!("Invalid media playlist: {err}")))?;
+    let date_ranges = OriginDateRanges::take(&text, &mut m3u8);
+
+    let epoch = match &args.epoch {
+        Some(epoch) => chrono::DateTime::parse_from_rfc3339(epoch)
+            .map_err(|err| invalid(format!("Invalid epoch {epoch}: {err}")))?
+            .to_utc(),
+        None => chrono::Utc::now(),
+    };
+    // Asset list URLs are appended to the interstitials address
+    let mut interstitials_address = args.interstitials_address.clone();
+    if !interstitials_address.path().ends_with('/') {
+        interstitials_address.set_path(&format!("{}/", interstitials_address.path()));
+    }
+    let ad_breaks = AdBreakSettings {
+        ad_server_url: Url::parse("http://localhost/no-vast").unwrap(),
+        target_ad_duration: args.default_ad_duration,
+        target_repeating_cycle: args.default_repeating_cycle,
+        target_ad_number: args.default_ad_number,
+    };
+    let config = ServerConfig::new(interstitials_address.clone(), interstitials_address, ad_breaks);
+
+    insert_interstitials(
+        &mut m3u8,
+        &config,
+        &AvailableAdSlots::new(&AvailableAds::default()),
+        &StreamEpoch::new(epoch),
+        InsertionProfile::Interstitials,
+        None,
+    );
+    print!("{}", keys.restore_media(&m3u8, date_ranges.restore(&m3u8, m3u8.to_string())));
+    Ok(())
+}
+
+async fn read_source(source: &str) -> io::Result<String> {
+    let url = match Url::parse(source) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+        _ => {
+            return std::fs::read_to_string(source)
+                .map_err(|err| io::Error::new(err.kind(), format!("Failed to read {source}: {err}")));
+        }
+    };
+
+    let tls = rustls_config(None, false, None)?;
+    let client = Client::builder()
+        .connector(Connector::new().rustls_0_23(Arc::new(tls)))
+        .timeout(Duration::from_secs(10))
+        .finish();
+    let mut response = client
+        .get(url.as_str())
+        .send()
+        .await
+        .map_err(|err| io::Error::other(format!("Failed to fetch {url}: {err}")))?;
+    if !response.status().is_success() {
+        return Err(io::Error::other(format!("Failed to fetch {url}: {}", response.status())));
+    }
+    let body = response
+        .body()
+        .limit(MAX_SOURCE_SIZE)
+        .await
+        .map_err(|err| io::Error::other(format!("Failed to read {url}: {err}")))?;
+
+    String::from_utf8(body.to_vec()).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
@@ -0,0 +1,268 @@
+use crate::metrics::Metrics;
+use crate::utils::{UniversalAdId, universal_ad_id_key};
+use actix_web::http::header;
+use actix_web::web;
+use awc::Client;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::Url;
+
+// A failed creative is submitted again after this long
+const RETRY_FAILED_AFTER: Duration = Duration::from_secs(600);
+// Maximum size of a job response of the transcoding service
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Debug)]
+enum TranscodeJob {
+    Pending,
+    Ready(String),
+    Failed,
+}
+
+struct JobEntry {
+    job: TranscodeJob,
+    updated_at: Instant,
+}
+
+impl JobEntry {
+    fn new(job: TranscodeJob) -> Self {
+        Self {
+            job,
+            updated_at: Instant::now(),
+        }
+    }
+}
+
+/// Where the creatives are transcoded and where their streams are published
+#[derive(Clone, Debug)]
+pub struct TranscoderSettings {
+    /// Base URL of the Encore transcoding service
+    pub endpoint: Url,
+    /// Bearer token sent with the job requests
+    pub token: Option<String>,
+    pub profile: String,
+    /// Folder the jobs write their output to, e.g. s3://bucket/ads/
+    pub output_folder: String,
+    /// Base URL the output folder is served from
+    pub stream_url: Url,
+    pub poll_interval: Duration,
+    /// Give up on a job that isn't done and published after this long
+    pub timeout: Duration,
+    /// Forget a transcoded stream after this long, like the creative cache
+    /// does, zero to keep it
+    pub ttl: Duration,
+    /// Maximum number of creatives kept, new ones aren't submitted while
+    /// all of them are being transcoded
+    pub max_jobs: usize,
+}
+
+/// Sends the creatives the ad server only has progressive MP4s of to an
+/// Encore transcoding service and keeps the resulting HLS stream per
+/// UniversalAdId, so the asset lists use it once it's available. The stream
+/// is expected at `<stream_url>/<base name>/index.m3u8`, where the packager
+/// publishes the job output written to `<output_folder>/<base name>/`. The
+/// base name is the UniversalAdId made file name safe and a hash of it.
+#[derive(Clone)]
+pub struct Transcoder {
+    settings: Arc<TranscoderSettings>,
+    jobs: Arc<DashMap<String, JobEntry>>,
+    metrics: web::Data<Metrics>,
+}
+
+impl Transcoder {
+    pub fn new(mut settings: TranscoderSettings, metrics: web::Data<Metrics>) -> Self {
+        // Relative paths are joined to the base URLs
+        for url in [&mut settings.endpoint, &mut settings.stream_url] {
+            if !url.path().ends_with('/') {
+                url.set_path(&format!("{}/", url.path()));
+            }
+        }
+        settings.output_folder = format!("{}/", settings.output_folder.trim_end_matches('/'));
+
+        Self {
+            settings: Arc::new(settings),
+            jobs: Arc::new(DashMap::new()),
+            metrics,
+        }
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let count = |matches: fn(&TranscodeJob) -> bool| self.jobs.iter().filter(|entry| matches(&entry.job)).count();
+        json::object! {
+            "endpoint": self.settings.endpoint.as_str(),
+            "profile": self.settings.profile.as_str(),
+            "max_jobs": self.settings.max_jobs,
+            "pending": count(|job| matches!(job, TranscodeJob::Pending)),
+            "ready": count(|job| matches!(job, TranscodeJob::Ready(_))),
+            "failed": count(|job| matches!(job, TranscodeJob::Failed)),
+        }
+    }
+
+    /// The HLS stream of the creative, once it has been transcoded
+    pub fn stream_url(&self, universal_ad_ids: &[UniversalAdId]) -> Option<String> {
+        let key = universal_ad_id_key(universal_ad_ids)?;
+        let entry = self.jobs.get(&key).filter(|entry| !self.is_expired(entry))?;
+        match &entry.job {
+            TranscodeJob::Ready(url) => Some(url.clone()),
+            _ => None,
+        }
+    }
+
+    fn is_expired(&self, entry: &JobEntry) -> bool {
+        match entry.job {
+            TranscodeJob::Pending => false,
+            TranscodeJob::Ready(_) => !self.settings.ttl.is_zero() && entry.updated_at.elapsed() >= self.settings.ttl,
+            TranscodeJob::Failed => entry.updated_at.elapsed() >= RETRY_FAILED_AFTER,
+        }
+    }
+
+    // Drop the expired jobs, then the oldest finished one, when the jobs are
+    // at their maximum. False if all of them are still pending
+    fn make_room(&self) -> bool {
+        if self.jobs.len() < self.settings.max_jobs {
+            return true;
+        }
+        self.jobs.retain(|_, entry| !self.is_expired(entry));
+        let oldest = self
+            .jobs
+            .iter()
+            .filter(|entry| !matches!(entry.job, TranscodeJob::Pending))
+            .min_by_key(|entry| entry.updated_at)
+            .map(|entry| entry.key().clone());
+        if let Some(oldest) = oldest.filter(|_| self.jobs.len() >= self.settings.max_jobs) {
+            self.jobs.remove(&oldest);
+        }
+        self.jobs.len() < self.settings.max_jobs
+    }
+
+    /// Start transcoding the MP4 of the creative, unless it's being or has been transcoded
+    pub fn submit(&self, client: &Client, universal_ad_ids: &[UniversalAdId], media_url: &str) {
+        let Some(key) = universal_ad_id_key(universal_ad_ids) else {
+            return;
+        };
+        let retry = match self.jobs.get(&key) {
+            None => false,
+            Some(entry) if self.is_expired(&entry) => true,
+            Some(_) => return,
+        };
+        if !retry && !self.make_room() {
+            log::warn!("Not transcoding creative {key}, {} creatives are being transcoded", self.jobs.len());
+            return;
+        }
+        self.jobs.insert(key.clone(), JobEntry::new(TranscodeJob::Pending));
+        if retry {
+            log::info!("Submitting creative {key} for transcoding again");
+        }
+
+        let transcoder = self.clone();
+        let client = client.clone();
+        let media_url = media_url.to_string();
+        actix_web::rt::spawn(async move {
+            let job = match transcoder.transcode(&client, &key, &media_url).await {
+                Ok(url) => {
+                    log::info!("Creative {key} transcoded, using {url} in the asset lists");
+                    transcoder.metrics.inc("transcoder_jobs_total", &[("result", "ready")]);
+                    TranscodeJob::Ready(url)
+                }
+                Err(err) => {
+                    log::error!("Failed to transcode creative {key} ({media_url}): {err}");
+                    transcoder.metrics.inc("transcoder_jobs_total", &[("result", "failed")]);
+                    TranscodeJob::Failed
+                }
+            };
+            transcoder.jobs.insert(key, JobEntry::new(job));
+        });
+    }
+
+    async fn transcode(&self, client: &Client, key: &str, media_url: &str) -> Result<String, String> {
+        let settings = &self.settings;
+        let base_name = base_name(key);
+        let started = Instant::now();
+        let job = json::object! {
+            "externalId": key,
+            "profile": settings.profile.as_str(),
+            "outputFolder": format!("{}{base_name}/", settings.output_folder),
+            "baseName": base_name.as_str(),
+            "inputs": [{ "uri": media_url, "type": "AudioVideo" }],
+        };
+        let jobs_url = settings.endpoint.join("encoreJobs").map_err(|err| err.to_string())?;
+        let response = self.request(client.post(jobs_url.as_str()), Some(job.dump())).await?;
+        let id = response["id"]
+            .as_str()
+            .ok_or_else(|| format!("No job id in the response {}", response.dump()))?
+            .to_string();
+        log::info!("Submitted creative {key} for transcoding, job {id}");
+
+        let job_url = settings
+            .endpoint
+            .join(&format!("encoreJobs/{id}"))
+            .map_err(|err| err.to_string())?;
+        loop {
+            if started.elapsed() >= settings.timeout {
+                return Err(format!("Job {id} didn't finish within {}s", settings.timeout.as_secs()));
+            }
+            actix_web::rt::time::sleep(settings.poll_interval).await;
+            let status = self.request(client.get(job_url.as_str()), None).await?;
+            match status["status"].as_str().unwrap_or_default() {
+                "SUCCESSFUL" => break,
+                "FAILED" | "CANCELLED" => {
+                    return Err(format!("Job {id} ended with {}: {}", status["status"], status["message"]));
+                }
+                _ => {}
+            }
+        }
+
+        // The packager publishes the stream some time after the job is done
+        let stream_url = settings
+            .stream_url
+            .join(&format!("{base_name}/index.m3u8"))
+            .map_err(|err| err.to_string())?;
+        while started.elapsed() < settings.timeout {
+            match client.get(stream_url.as_str()).send().await {
+                Ok(response) if response.status().is_success() => return Ok(stream_url.to_string()),
+                _ => actix_web::rt::time::sleep(settings.poll_interval).await,
+            }
+        }
+        Err(format!("{stream_url} wasn't published within {}s", settings.timeout.as_secs()))
+    }
+
+    async fn request(&self, request: awc::ClientRequest, body: Option<String>) -> Result<json::JsonValue, String> {
+        let mut request = request.insert_header((header::ACCEPT, "application/json"));
+        if let Some(token) = &self.settings.token {
+            request = request.bearer_auth(token);
+        }
+        let mut response = match body {
+            Some(body) => request
+                .insert_header((header::CONTENT_TYPE, "application/json"))
+                .send_body(body)
+                .await,
+            None => request.send().await,
+        }
+        .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Transcoding service answered with status {}", response.status()));
+        }
+        let body = response
+            .body()
+            .limit(MAX_RESPONSE_SIZE)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        json::parse(&String::from_utf8_lossy(&body)).map_err(|err| format!("Invalid job response: {err}"))
+    }
+}
+
+// A file name safe version of the UniversalAdId. The replaced characters
+// could make two ids the same, a short hash of the id keeps them apart
+fn base_name(key: &str) -> String {
+    let name = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect::<String>();
+    let hash = openssl::sha::sha256(key.as_bytes())[..4]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    format!("{name}-{hash}")
+}
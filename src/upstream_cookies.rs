@@ -0,0 +1,230 @@
+use actix_http::RequestHeadType;
+use actix_web::dev::Service;
+use actix_web::http::Uri;
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use awc::error::SendRequestError;
+use awc::middleware::Transform;
+use awc::{ConnectRequest, ConnectResponse};
+use dashmap::DashMap;
+use futures_util::future::LocalBoxFuture;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Beyond these, the cookies of new hosts and new names aren't kept
+const MAX_HOSTS: usize = 1000;
+const MAX_COOKIES_PER_HOST: usize = 50;
+
+struct StoredCookie {
+    value: String,
+    // Unix timestamp, None for a session cookie
+    expires: Option<i64>,
+}
+
+impl StoredCookie {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// The cookies set by the origins and the ad servers, e.g. the tokens issued
+/// along with a geo redirect, sent back with the next requests to the same
+/// host, redirected or not. The cookies are kept by host and port, whatever
+/// their Domain and Path, and are shared by all the sessions
+#[derive(Default)]
+pub struct UpstreamCookies {
+    hosts: DashMap<String, HashMap<String, StoredCookie>>,
+}
+
+// The host and port the cookies of a request are kept for
+fn host_of(uri: &Uri) -> Option<String> {
+    let host = uri.host()?.to_ascii_lowercase();
+    let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+    Some(format!("{host}:{port}"))
+}
+
+impl UpstreamCookies {
+    /// Keep the cookies of the Set-Cookie headers of a response of the host
+    pub fn store(&self, host: &str, headers: &HeaderMap) {
+        let now = chrono::Utc::now().timestamp();
+        for value in headers.get_all(header::SET_COOKIE) {
+            let Some(cookie) = value.to_str().ok().and_then(|value| awc::cookie::Cookie::parse(value).ok()) else {
+                log::debug!("Ignoring an invalid cookie set by {host}");
+                continue;
+            };
+            // Max-Age takes precedence over Expires
+            let expires = match cookie.max_age() {
+                Some(max_age) => Some(now + max_age.whole_seconds()),
+                None => cookie.expires_datetime().map(|expires| expires.unix_timestamp()),
+            };
+            let stored = StoredCookie {
+                value: cookie.value().to_string(),
+                expires,
+            };
+            if !self.hosts.contains_key(host) && self.hosts.len() >= MAX_HOSTS {
+                log::warn!("Not keeping the cookies of {host}, the cookies of {MAX_HOSTS} hosts are kept already");
+                return;
+            }
+            let mut cookies = self.hosts.entry(host.to_string()).or_default();
+            cookies.retain(|_, cookie| !cookie.is_expired(now));
+            if stored.is_expired(now) {
+                cookies.remove(cookie.name());
+            } else if (cookies.contains_key(cookie.name()) || cookies.len() < MAX_COOKIES_PER_HOST)
+                && cookies.insert(cookie.name().to_string(), stored).is_none()
+            {
+                log::debug!("Keeping the cookie {} set by {host}", cookie.name());
+            }
+        }
+    }
+
+    /// The Cookie header of a request to the host, on top of the cookies the
+    /// request has already, which take precedence
+    pub fn header(&self, host: &str, existing: Option<&HeaderValue>) -> Option<HeaderValue> {
+        let now = chrono::Utc::now().timestamp();
+        let existing = existing.and_then(|value| value.to_str().ok()).unwrap_or_default();
+        let existing_names = existing
+            .split(';')
+            .filter_map(|pair| pair.split_once('=').map(|(name, _)| name.trim()))
+            .collect::<Vec<_>>();
+        let cookies = self.hosts.get(host)?;
+        let mut pairs = cookies
+            .iter()
+            .filter(|(name, cookie)| !cookie.is_expired(now) && !existing_names.contains(&name.as_str()))
+            .map(|(name, cookie)| format!("{name}={}", cookie.value))
+            .collect::<Vec<_>>();
+        if pairs.is_empty() {
+            return None;
+        }
+        pairs.sort();
+        if !existing.trim().is_empty() {
+            pairs.insert(0, existing.trim().to_string());
+        }
+        HeaderValue::try_from(pairs.join("; ")).ok()
+    }
+}
+
+/// Middleware of the upstream clients sending and keeping the cookies of each
+/// host, if enabled. It is wrapped by the client's redirects, so every hop of
+/// a redirected request gets the cookies of its own host
+pub struct CookieJar(Option<Arc<UpstreamCookies>>);
+
+impl CookieJar {
+    pub fn new(cookies: Option<Arc<UpstreamCookies>>) -> Self {
+        Self(cookies)
+    }
+}
+
+impl<S> Transform<S, ConnectRequest> for CookieJar
+where
+    S: Service<ConnectRequest, Response = ConnectResponse, Error = SendRequestError> + 'static,
+{
+    type Transform = CookieJarService<S>;
+
+    fn new_transform(self, service: S) -> Self::Transform {
+        CookieJarService { service, cookies: self.0 }
+    }
+}
+
+pub struct CookieJarService<S> {
+    service: S,
+    cookies: Option<Arc<UpstreamCookies>>,
+}
+
+impl<S> Service<ConnectRequest> for CookieJarService<S>
+where
+    S: Service<ConnectRequest, Response = ConnectResponse, Error = SendRequestError> + 'static,
+{
+    type Response = ConnectResponse;
+    type Error = SendRequestError;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ConnectRequest) -> Self::Future {
+        let (cookies, mut head, body, addr) = match (&self.cookies, req) {
+            (Some(cookies), ConnectRequest::Client(head, body, addr)) => (cookies.clone(), head, body, addr),
+            (_, req) => return Box::pin(self.service.call(req)),
+        };
+        let host = host_of(&head.as_ref().uri);
+        if let Some(host) = &host {
+            match &mut head {
+                RequestHeadType::Owned(head) => {
+                    if let Some(cookie) = cookies.header(host, head.headers.get(header::COOKIE)) {
+                        head.headers.insert(header::COOKIE, cookie);
+                    }
+                }
+                // The extra headers override the ones of the shared head
+                RequestHeadType::Rc(head, extra) => {
+                    let existing = extra
+                        .as_ref()
+                        .and_then(|extra| extra.get(header::COOKIE))
+                        .or_else(|| head.headers.get(header::COOKIE));
+                    if let Some(cookie) = cookies.header(host, existing) {
+                        extra.get_or_insert_with(HeaderMap::new).insert(header::COOKIE, cookie);
+                    }
+                }
+            }
+        }
+
+        let res = self.service.call(ConnectRequest::Client(head, body, addr));
+        Box::pin(async move {
+            let res = res.await?;
+            if let (Some(host), ConnectResponse::Client(res)) = (&host, &res) {
+                cookies.store(host, res.headers());
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+    use awc::Client;
+
+    #[actix_web::test]
+    async fn keeps_the_cookies_across_redirects() {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/master.m3u8",
+                    web::get().to(|| async {
+                        HttpResponse::Found()
+                            .insert_header((header::LOCATION, "/edge/master.m3u8"))
+                            .insert_header((header::SET_COOKIE, "token=abc; Path=/; Max-Age=60"))
+                            .finish()
+                    }),
+                )
+                .default_service(web::get().to(|req: HttpRequest| async move {
+                    let cookie = req.headers().get(header::COOKIE).and_then(|value| value.to_str().ok());
+                    HttpResponse::Ok().body(cookie.unwrap_or_default().to_string())
+                }))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let cookies = Arc::new(UpstreamCookies::default());
+        let client = Client::builder().max_redirects(3).wrap(CookieJar::new(Some(cookies.clone()))).finish();
+        let mut res = client.get(format!("http://{addr}/master.m3u8")).send().await.unwrap();
+        assert_eq!(res.body().await.unwrap(), "token=abc");
+        // The next requests to the host get the cookie too, after the viewer's own
+        let mut res = client
+            .get(format!("http://{addr}/segment.ts"))
+            .insert_header((header::COOKIE, "viewer=1"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.body().await.unwrap(), "viewer=1; token=abc");
+        assert_eq!(cookies.header(&format!("127.0.0.1:{}", addr.port()), None).unwrap(), "token=abc");
+        handle.stop(false).await;
+    }
+}
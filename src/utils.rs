@@ -1,7 +1,20 @@
 use actix_web::{HttpRequest, HttpResponseBuilder};
-use rustls::{ClientConfig, RootCertStore};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
 use url::{ParseError, Url};
 
+// The adType or category code of a bumper
+const BUMPER_MARKERS: [&str; 2] = ["bumper", "slate"];
+// The session ID of a playlist request: the DASH session parameter, or the
+// header AVPlayer sends with every request of a playback session
+pub const SESSION_PARAM: &str = "session";
+pub const SESSION_HEADER: &str = "x-playback-session-id";
+
 #[derive(Clone, Debug)]
 pub struct UniversalAdId {
     pub scheme: String,
@@ -21,6 +34,17 @@ pub struct VideoClicks {
     pub click_through: Option<String>,
 }
 
+/// The interactive layer of a linear creative, e.g. a SIMID one
+#[derive(Clone, Debug)]
+pub struct InteractiveCreative {
+    pub uri: String,
+    pub api_framework: Option<String>,
+    pub mime_type: Option<String>,
+    pub variable_duration: Option<bool>,
+    /// The `<AdParameters>` of the linear, handed to the interactive layer
+    pub parameters: Option<String>,
+}
+
 pub fn get_all_creatives_from_vast<'a>(
     vast: &'a vast4_rs::Vast<'a>,
 ) -> Vec<&'a vast4_rs::Creative<'a>> {
@@ -36,34 +60,200 @@ pub fn get_all_creatives_from_vast<'a>(
 
 pub fn filter_creatives_by<'a>(
     creatives: Vec<&'a vast4_rs::Creative<'a>>,
-    filter: impl Fn(&str) -> bool,
+    filter: impl Fn(&vast4_rs::Creative, &vast4_rs::MediaFile) -> bool,
 ) -> Vec<&'a vast4_rs::Creative<'a>> {
     creatives
         .into_iter()
         // Only return creatives with adId and linear.
         .filter(|creative| creative.ad_id.is_some() && creative.linear.is_some())
         .filter(|creative| {
-            let media_urls = get_media_urls_from_linear(creative.linear.as_ref().unwrap());
             // Only return linears with valid media files.
-            // This is a simple way to filter out bumpers (which end with '*_2023_P8_mp4').
-            !media_urls.is_empty() && filter(media_urls.first().unwrap())
+            let media_file = creative
+                .linear
+                .as_ref()
+                .and_then(|linear| linear.media_files.as_ref())
+                .and_then(|media_files| media_files.media_files.first());
+            media_file.is_some_and(|media_file| filter(creative, media_file))
         })
         .collect::<Vec<_>>()
 }
 
+/// Linear creatives with MP4 media files, the bumpers only if they are kept
 pub fn get_all_raw_creatives_from_vast<'a>(
     vast: &'a vast4_rs::Vast<'a>,
+    bumpers: &BumperFilter,
 ) -> Vec<&'a vast4_rs::Creative<'a>> {
-    filter_creatives_by(get_all_creatives_from_vast(vast), is_media_segment)
+    filter_creatives_by(get_all_creatives_from_vast(vast), |creative, media_file| {
+        if bumpers.is_bumper(vast, creative) {
+            bumpers.keep && is_mp4_media_file(media_file)
+        } else {
+            is_media_segment(&media_file.uri)
+        }
+    })
 }
 
+/// Linear creatives with HLS media files, the bumpers only if they are kept
 pub fn get_all_transcoded_creatives_from_vast<'a>(
     vast: &'a vast4_rs::Vast<'a>,
+    bumpers: &BumperFilter,
 ) -> Vec<&'a vast4_rs::Creative<'a>> {
-    filter_creatives_by(
-        get_all_creatives_from_vast(vast),
-        is_transcoded_media_segment,
-    )
+    filter_creatives_by(get_all_creatives_from_vast(vast), |creative, media_file| {
+        is_transcoded_media_segment(&media_file.uri) && (bumpers.keep || !bumpers.is_bumper(vast, creative))
+    })
+}
+
+/// An MP4 media file by its extension or its MIME type
+pub fn is_mp4_media_file(media_file: &vast4_rs::MediaFile) -> bool {
+    is_media_segment(&media_file.uri) || media_file.mime_type.eq_ignore_ascii_case("video/mp4")
+}
+
+/// Tells the bumpers (channel idents, slates) of a pod from the ads, and
+/// whether they are kept in the asset list. A creative is a bumper when the
+/// `adType` of its Ad or a `<Category>` of it says so, when its ad title or
+/// media URL contains one of the `patterns`, or when its MP4 media file has
+/// no media extension (e.g. `*_2023_P8_mp4`).
+#[derive(Clone, Debug, Default)]
+pub struct BumperFilter {
+    pub keep: bool,
+    pub patterns: Vec<String>,
+}
+
+impl BumperFilter {
+    pub fn is_bumper(&self, vast: &vast4_rs::Vast, creative: &vast4_rs::Creative) -> bool {
+        let is_marker = |value: &str| BUMPER_MARKERS.iter().any(|marker| value.eq_ignore_ascii_case(marker));
+        let matches = |value: &str| {
+            let value = value.to_ascii_lowercase();
+            self.patterns.iter().any(|pattern| value.contains(&pattern.to_ascii_lowercase()))
+        };
+        let media_file = creative
+            .linear
+            .as_ref()
+            .and_then(|linear| linear.media_files.as_ref())
+            .and_then(|media_files| media_files.media_files.first());
+        if let Some(media_file) = media_file {
+            let has_extension = is_media_segment(&media_file.uri) || is_transcoded_media_segment(&media_file.uri);
+            if matches(&media_file.uri) || (!has_extension && is_mp4_media_file(media_file)) {
+                return true;
+            }
+        }
+        let Some(ad) = find_ad_of_creative(vast, creative) else {
+            return false;
+        };
+        ad.ad_type.as_deref().is_some_and(is_marker)
+            || ad.in_line.as_ref().is_some_and(|in_line| {
+                in_line.categories.iter().any(|category| is_marker(category.code.trim()))
+                    || matches(&in_line.ad_title)
+            })
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "keep": self.keep,
+            "patterns": self.patterns.clone(),
+        }
+    }
+}
+
+// The Ad the creative belongs to
+fn find_ad_of_creative<'a>(vast: &'a vast4_rs::Vast<'a>, creative: &vast4_rs::Creative) -> Option<&'a vast4_rs::Ad<'a>> {
+    vast.ads.iter().find(|ad| {
+        ad.in_line.as_ref().is_some_and(|in_line| {
+            in_line
+                .creatives
+                .creatives
+                .iter()
+                .any(|candidate| std::ptr::eq(candidate, creative))
+        })
+    })
+}
+
+/// Drop the Ads with `conditionalAd="true"`, which only players running their
+/// interactive layer can decide on, returns how many were dropped
+// The attribute is deprecated since VAST 4.1 but ad servers still send it
+#[allow(deprecated)]
+pub fn remove_conditional_ads(vast: &mut vast4_rs::Vast) -> usize {
+    let count = vast.ads.len();
+    vast.ads.retain(|ad| ad.conditional_ad != Some(true));
+    count - vast.ads.len()
+}
+
+/// Whether the creative is of an Ad of the VAST pod (with a `sequence`) rather than of its buffet
+pub fn is_sequenced_creative(vast: &vast4_rs::Vast, creative: &vast4_rs::Creative) -> bool {
+    find_ad_of_creative(vast, creative).is_some_and(|ad| ad.sequence.is_some())
+}
+
+// The InLine ad the creative belongs to
+fn find_in_line_of_creative<'a>(
+    vast: &'a vast4_rs::Vast<'a>,
+    creative: &vast4_rs::Creative,
+) -> Option<&'a vast4_rs::InLine<'a>> {
+    find_ad_of_creative(vast, creative).and_then(|ad| ad.in_line.as_ref())
+}
+
+/// The `<Category>` codes of the Ad the creative belongs to
+pub fn get_categories_for_creative(vast: &vast4_rs::Vast, creative: &vast4_rs::Creative) -> Vec<String> {
+    find_in_line_of_creative(vast, creative)
+        .map(|in_line| {
+            in_line
+                .categories
+                .iter()
+                .map(|category| category.code.trim().to_string())
+                .filter(|code| !code.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The `<Advertiser>` of the Ad the creative belongs to
+pub fn get_advertiser_for_creative(vast: &vast4_rs::Vast, creative: &vast4_rs::Creative) -> Option<String> {
+    find_in_line_of_creative(vast, creative)
+        .and_then(|in_line| in_line.advertiser.as_deref())
+        .map(str::trim)
+        .filter(|advertiser| !advertiser.is_empty())
+        .map(str::to_string)
+}
+
+/// Impression URLs of the Ad the creative belongs to. Impressions are counted
+/// once per Ad, so only the first linear creative of an Ad carries them.
+pub fn get_impression_urls_for_creative(
+    vast: &vast4_rs::Vast,
+    creative: &vast4_rs::Creative,
+) -> Vec<String> {
+    find_in_line_of_creative(vast, creative)
+        .filter(|in_line| {
+            in_line
+                .creatives
+                .creatives
+                .iter()
+                .find(|candidate| candidate.linear.is_some())
+                .is_some_and(|first| std::ptr::eq(first, creative))
+        })
+        .map(|in_line| {
+            in_line
+                .impressions
+                .iter()
+                .map(|impression| impression.uri.trim().to_string())
+                .filter(|uri| !uri.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Error URLs of the Ad the creative belongs to, usually carrying the [ERRORCODE] macro
+pub fn get_error_urls_for_creative(
+    vast: &vast4_rs::Vast,
+    creative: &vast4_rs::Creative,
+) -> Vec<String> {
+    find_in_line_of_creative(vast, creative)
+        .map(|in_line| {
+            in_line
+                .errors
+                .iter()
+                .map(|uri| uri.trim().to_string())
+                .filter(|uri| !uri.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 pub fn get_universal_ad_ids_from_creative(creative: &vast4_rs::Creative) -> Vec<UniversalAdId> {
@@ -77,6 +267,34 @@ pub fn get_universal_ad_ids_from_creative(creative: &vast4_rs::Creative) -> Vec<
         .collect()
 }
 
+// The VAST 4 tracking event names, along with the impression and error events
+const VAST_EVENTS: [&str; 31] = [
+    "impression", "error", "creativeView", "loaded", "start", "firstQuartile", "midpoint",
+    "thirdQuartile", "complete", "progress", "mute", "unmute", "pause", "resume", "rewind",
+    "skip", "playerExpand", "playerCollapse", "closeLinear", "notUsed", "otherAdInteraction",
+    "acceptInvitation", "adExpand", "adCollapse", "minimize", "close", "overlayViewDuration",
+    "interactiveStart", "fullscreen", "exitFullscreen", "clickTracking",
+];
+
+/// The VAST name of a reported tracking event, "other" for unknown names,
+/// so that metric labels don't take arbitrary client input
+pub fn tracking_event_label(event: &str) -> &'static str {
+    VAST_EVENTS
+        .iter()
+        .find(|name| name.eq_ignore_ascii_case(event))
+        .copied()
+        .unwrap_or("other")
+}
+
+/// The first registered UniversalAdId as "registry:value", the creatives are
+/// cached by it. "unknown" is the VAST placeholder for no id.
+pub fn universal_ad_id_key(universal_ad_ids: &[UniversalAdId]) -> Option<String> {
+    universal_ad_ids
+        .iter()
+        .find(|id| !id.value.is_empty() && id.value != "unknown")
+        .map(|id| format!("{}:{}", id.scheme, id.value))
+}
+
 pub fn get_duration_from_linear(linear: &vast4_rs::Linear) -> f64 {
     linear
         .duration
@@ -141,6 +359,31 @@ pub fn get_video_clicks_from_linear<'a>(linear: &'a vast4_rs::Linear) -> Option<
         })
 }
 
+/// The `<InteractiveCreativeFile>` of the linear, a SIMID one if there are several
+pub fn get_interactive_creative_from_linear(linear: &vast4_rs::Linear) -> Option<InteractiveCreative> {
+    let files = &linear.media_files.as_ref()?.interactive_creative_files;
+    let is_simid = |file: &&vast4_rs::InteractiveCreativeFile| {
+        file.api_framework.as_deref().is_some_and(|api| api.eq_ignore_ascii_case("SIMID"))
+    };
+    let file = files.iter().find(is_simid).or(files.first())?;
+    Some(InteractiveCreative {
+        uri: file.uri.trim().to_string(),
+        api_framework: file.api_framework.as_ref().map(|api| api.to_string()),
+        mime_type: file.mime_type.as_ref().map(|mime_type| mime_type.to_string()),
+        variable_duration: file.variable_duration,
+        parameters: get_ad_parameters_from_linear(linear),
+    })
+}
+
+/// The `<AdParameters>` of the linear, data for the creative or its interactive layer
+pub fn get_ad_parameters_from_linear(linear: &vast4_rs::Linear) -> Option<String> {
+    linear
+        .ad_parameters
+        .as_ref()
+        .map(|parameters| parameters.metadata.trim().to_string())
+        .filter(|parameters| !parameters.is_empty())
+}
+
 pub fn get_duration_and_media_urls_and_tracking_events_from_linear<'a>(
     linear: &'a vast4_rs::Linear,
 ) -> (f64, Vec<String>, Vec<Tracking>) {
@@ -151,66 +394,81 @@ pub fn get_duration_and_media_urls_and_tracking_events_from_linear<'a>(
     )
 }
 
+/// The first program_date_time of the playlist, `time_zone` being the one of
+/// the origin's date times without an offset
 pub fn find_program_datetime_tag(
     playlist: &hls_m3u8::MediaPlaylist,
-) -> Option<chrono::DateTime<chrono::Local>> {
+    time_zone: chrono::FixedOffset,
+) -> Option<chrono::DateTime<chrono::Utc>> {
     playlist
         .segments
         .iter()
         .find_map(|(_, segment)| segment.program_date_time.as_ref())
         .and_then(|program_date_time| {
             let date_str = program_date_time.date_time.as_ref();
-            parse_date_time(date_str)
+            parse_date_time(date_str, time_zone)
                 // Ignore invalid date times
                 .map_err(|_| log::error!("Invalid date time: {}", date_str))
                 .ok()
         })
-        .map(fixed_offset_to_local)
+        .map(fixed_offset_to_utc)
         .inspect(|program_date_time| {
             log::debug!(
-                "First available program_date_time in local timezone: {:?}",
+                "First available program_date_time in UTC: {:?}",
                 program_date_time
             );
         })
 }
 
-pub fn calculate_expected_program_date_time_list(
-    segments: &hls_m3u8::stable_vec::StableVec<hls_m3u8::MediaSegment>,
-    first_program_date_time: chrono::DateTime<chrono::Local>,
-) -> Vec<(chrono::DateTime<chrono::Local>, std::time::Duration)> {
-    let mut current_program_date_time = first_program_date_time;
-    let mut accumulated_segment_duration_ms = 0u128;
-
-    segments
-        .iter()
-        .map(|(_, segment)| {
-            let optional_program_date_time = segment
-                .program_date_time
-                .as_ref()
-                .and_then(|program_date_time| {
-                    let date_str = program_date_time.date_time.as_ref();
-                    parse_date_time(date_str)
-                        .map_err(|_| log::error!("Invalid date time: {}", date_str))
-                        .ok()
-                })
-                .map(fixed_offset_to_local);
-
-            let segment_duration = segment.duration.duration();
-
-            if let Some(program_date_time) = optional_program_date_time {
-                current_program_date_time = program_date_time;
-                accumulated_segment_duration_ms = segment_duration.as_millis();
+/// Expected program date time of consecutive media segments, anchored on the
+/// last segment carrying a program_date_time tag
+pub struct ProgramDateTimeCursor {
+    current_program_date_time: chrono::DateTime<chrono::Utc>,
+    accumulated_segment_duration_ms: u128,
+    // Of the origin's date times without an offset
+    time_zone: chrono::FixedOffset,
+}
 
-                (program_date_time, segment_duration)
-            } else {
-                let expected_date_time = current_program_date_time
-                    + chrono::Duration::milliseconds(accumulated_segment_duration_ms as i64);
-                accumulated_segment_duration_ms += segment_duration.as_millis();
+impl ProgramDateTimeCursor {
+    pub fn new(first_program_date_time: chrono::DateTime<chrono::Utc>, time_zone: chrono::FixedOffset) -> Self {
+        Self {
+            current_program_date_time: first_program_date_time,
+            accumulated_segment_duration_ms: 0,
+            time_zone,
+        }
+    }
 
-                (expected_date_time, segment_duration)
-            }
-        })
-        .collect()
+    // Start date time and duration of the next segment
+    pub fn advance(
+        &mut self,
+        segment: &hls_m3u8::MediaSegment,
+    ) -> (chrono::DateTime<chrono::Utc>, std::time::Duration) {
+        let optional_program_date_time = segment
+            .program_date_time
+            .as_ref()
+            .and_then(|program_date_time| {
+                let date_str = program_date_time.date_time.as_ref();
+                parse_date_time(date_str, self.time_zone)
+                    .map_err(|_| log::error!("Invalid date time: {}", date_str))
+                    .ok()
+            })
+            .map(fixed_offset_to_utc);
+
+        let segment_duration = segment.duration.duration();
+
+        if let Some(program_date_time) = optional_program_date_time {
+            self.current_program_date_time = program_date_time;
+            self.accumulated_segment_duration_ms = segment_duration.as_millis();
+
+            (program_date_time, segment_duration)
+        } else {
+            let expected_date_time = self.current_program_date_time
+                + chrono::Duration::milliseconds(self.accumulated_segment_duration_ms as i64);
+            self.accumulated_segment_duration_ms += segment_duration.as_millis();
+
+            (expected_date_time, segment_duration)
+        }
+    }
 }
 
 pub fn is_media_segment(path: &str) -> bool {
@@ -225,6 +483,11 @@ pub fn is_hls_playlist(path: &str) -> bool {
     path.ends_with(".m3u8")
 }
 
+/// Whether the content of a playlist is a master playlist, it has variant streams
+pub fn is_master_playlist(m3u8: &str) -> bool {
+    m3u8.lines().any(|line| line.trim_start().starts_with("#EXT-X-STREAM-INF"))
+}
+
 pub fn is_transcoded_media_segment(path: &str) -> bool {
     // Transcoded media segments typically forms a HLS VoD playlist.
     is_hls_playlist(path)
@@ -240,45 +503,164 @@ pub fn is_fragmented_mp4_vod_media_playlist(playlist: &hls_m3u8::MediaPlaylist)
         })
 }
 
-pub fn fixed_offset_to_local(
+pub fn fixed_offset_to_utc(
     date: chrono::DateTime<chrono::FixedOffset>,
-) -> chrono::DateTime<chrono::Local> {
-    date.with_timezone(&chrono::Local)
+) -> chrono::DateTime<chrono::Utc> {
+    date.to_utc()
 }
 
+/// Parse a date time, one without an offset being in `time_zone`
 pub fn parse_date_time(
     date_time: &str,
+    time_zone: chrono::FixedOffset,
 ) -> chrono::ParseResult<chrono::DateTime<chrono::FixedOffset>> {
     let default_date_time_format = "%Y-%m-%dT%H:%M:%S%.3f%z";
+    let local_date_time_format = "%Y-%m-%dT%H:%M:%S%.f";
 
     let date_time = chrono::DateTime::parse_from_rfc3339(date_time)
         .or_else(|_| chrono::DateTime::parse_from_rfc2822(date_time))
-        .or_else(|_| chrono::DateTime::parse_from_str(date_time, default_date_time_format));
+        .or_else(|_| chrono::DateTime::parse_from_str(date_time, default_date_time_format))
+        .or_else(|err| {
+            let naive = chrono::NaiveDateTime::parse_from_str(date_time, local_date_time_format)?;
+            naive.and_local_timezone(time_zone).single().ok_or(err)
+        });
 
     date_time
 }
 
-pub fn date_time_to_string(date_time: &chrono::DateTime<chrono::Local>) -> String {
+/// Parse a time zone given as an offset from UTC, e.g. `+02:00`, or `UTC`
+pub fn parse_time_zone(value: &str) -> Result<chrono::FixedOffset, String> {
+    match value.trim() {
+        "UTC" | "utc" | "Z" => Ok(chrono::FixedOffset::east_opt(0).unwrap()),
+        offset => offset
+            .parse::<chrono::FixedOffset>()
+            .map_err(|err| format!("Invalid time zone {offset}, expected an offset like +02:00: {err}")),
+    }
+}
+
+pub fn date_time_to_string(date_time: &chrono::DateTime<chrono::Utc>) -> String {
     date_time.to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
 }
 
 pub fn make_program_date_time_tag(
-    date_time: &chrono::DateTime<chrono::Local>,
+    date_time: &chrono::DateTime<chrono::Utc>,
 ) -> hls_m3u8::tags::ExtXProgramDateTime<'static> {
     hls_m3u8::tags::ExtXProgramDateTime::new(date_time_to_string(date_time))
 }
 
 /// Create simple rustls client config from root certificates.
-pub fn rustls_config() -> ClientConfig {
-    rustls::crypto::aws_lc_rs::default_provider()
-        .install_default()
-        .unwrap();
+/// `extra_ca` adds the certificates of a PEM bundle to the webpki roots,
+/// `insecure` skips the verification of upstream certificates altogether.
+/// `client_auth` is a PEM certificate chain and private key presented to
+/// servers requesting a client certificate.
+pub fn rustls_config(
+    extra_ca: Option<&Path>,
+    insecure: bool,
+    client_auth: Option<(&Path, &Path)>,
+) -> io::Result<ClientConfig> {
+    // The provider may already be installed by a previous config
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+    let builder = if insecure {
+        let provider = rustls::crypto::CryptoProvider::get_default()
+            .expect("Missing crypto provider")
+            .clone();
+        rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider)))
+    } else {
+        let mut root_store = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.to_owned());
+        if let Some(path) = extra_ca {
+            for certificate in load_certificates(path)? {
+                root_store
+                    .add(certificate)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            }
+        }
+        rustls::ClientConfig::builder().with_root_certificates(root_store)
+    };
+
+    match client_auth {
+        Some((cert_path, key_path)) => builder
+            .with_client_auth_cert(load_certificates(cert_path)?, load_private_key(key_path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+// Accepts any server certificate, only the handshake signatures are checked
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<rustls::crypto::CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Load the PEM encoded certificate chain of a file
+pub fn load_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificate found in {}", path.display()),
+        ));
+    }
+
+    Ok(certificates)
+}
+
+/// Load the first PEM encoded private key (PKCS#1, PKCS#8 or SEC1) of a file
+pub fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No private key found in {}", path.display()),
+        )
+    })
+}
 
-    let root_store = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.to_owned());
+/// TLS settings of the HTTPS listener
+pub fn rustls_server_config(cert_path: &Path, key_path: &Path) -> io::Result<ServerConfig> {
+    let certificates = load_certificates(cert_path)?;
+    let key = load_private_key(key_path)?;
 
-    rustls::ClientConfig::builder()
-        .with_root_certificates(root_store)
+    ServerConfig::builder()
         .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
 }
 
 pub fn base_url(url: &Url) -> Result<Url, ParseError> {
@@ -303,6 +685,22 @@ pub fn copy_headers<T>(res: &awc::ClientResponse<T>, client_resp: &mut HttpRespo
     }
 }
 
+/// A route answering HEAD requests as its GET, without the body
+pub fn get_or_head() -> actix_web::Route {
+    use actix_web::guard;
+    actix_web::web::route().guard(guard::Any(guard::Get()).or(guard::Head()))
+}
+
+/// The Content-Length of an upstream response, the length of the body it streams
+pub fn content_length<T>(res: &awc::ClientResponse<T>) -> Option<u64> {
+    res.headers()
+        .get(awc::http::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
 pub fn build_forward_url(req: &HttpRequest, forward_url: &Url) -> Url {
     let mut new_url = forward_url.clone();
     new_url.set_path(req.uri().path());
@@ -323,3 +721,8 @@ pub fn get_header_value(req: &HttpRequest, key: &str) -> Option<String> {
         .get(key)
         .and_then(|v| v.to_str().ok().map(|s| s.to_string()))
 }
+
+/// The ID a player gives its playback session, if any
+pub fn playback_session_id(req: &HttpRequest) -> Option<String> {
+    get_query_param(req, SESSION_PARAM).or_else(|| get_header_value(req, SESSION_HEADER))
+}
@@ -0,0 +1,242 @@
+use crate::metrics::Metrics;
+use actix_web::web;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use url::Url;
+
+// The reports of each ad server shown by /debug/vast
+const MAX_REPORTS: usize = 20;
+
+/// A problem of a VAST reply, where it is and whether it keeps the creative
+/// from being played
+#[derive(Clone, Debug, PartialEq)]
+pub struct VastIssue {
+    pub kind: &'static str,
+    pub location: String,
+    pub detail: String,
+    pub fatal: bool,
+}
+
+impl VastIssue {
+    fn new(kind: &'static str, location: &str, detail: impl Into<String>, fatal: bool) -> Self {
+        Self {
+            kind,
+            location: location.to_string(),
+            detail: detail.into(),
+            fatal,
+        }
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            "issue": self.kind,
+            "location": self.location.as_str(),
+            "detail": self.detail.as_str(),
+            "fatal": self.fatal,
+        }
+    }
+}
+
+// An absolute HTTP(S) URL, the placeholder about:blank of the impressions aside
+fn is_valid_uri(uri: &str) -> bool {
+    let uri = uri.trim();
+    uri == "about:blank"
+        || Url::parse(uri).is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+}
+
+/// The issues of a linear creative
+fn creative_issues(creative: &vast4_rs::Creative, location: &str) -> Vec<VastIssue> {
+    let Some(linear) = &creative.linear else {
+        return Vec::new();
+    };
+    let mut issues = Vec::new();
+    if creative.ad_id.is_none() {
+        issues.push(VastIssue::new("missing_ad_id", location, "the Creative has no adId attribute", true));
+    }
+    if linear.duration.is_none() {
+        issues.push(VastIssue::new("missing_duration", location, "the Linear has no Duration", true));
+    }
+    let media_files = linear
+        .media_files
+        .as_ref()
+        .map(|media_files| media_files.media_files.as_slice())
+        .unwrap_or_default();
+    if media_files.is_empty() {
+        issues.push(VastIssue::new("empty_media_files", location, "the Linear has no MediaFile", true));
+    }
+    for media_file in media_files.iter().filter(|media_file| !is_valid_uri(&media_file.uri)) {
+        let detail = format!("MediaFile {:?} is not an HTTP(S) URL", media_file.uri.trim());
+        issues.push(VastIssue::new("malformed_media_uri", location, detail, true));
+    }
+    let trackings = linear.tracking_events.iter().flat_map(|events| &events.trackings);
+    for tracking in trackings.filter(|tracking| !is_valid_uri(&tracking.uri)) {
+        let detail = format!("Tracking {} {:?} is not an HTTP(S) URL", tracking.event, tracking.uri.trim());
+        issues.push(VastIssue::new("malformed_tracking_uri", location, detail, false));
+    }
+    issues
+}
+
+// Where an Ad is in the reply, by its ID or position
+fn ad_location(ad_index: usize, ad: &vast4_rs::Ad) -> String {
+    format!("Ad {}", ad.id.as_deref().map_or_else(|| format!("#{ad_index}"), str::to_string))
+}
+
+// Where a creative is in the reply, by the IDs of its Ad and its own
+fn creative_location(ad_index: usize, ad: &vast4_rs::Ad, creative_index: usize, creative: &vast4_rs::Creative) -> String {
+    let creative_id = creative
+        .ad_id
+        .as_deref()
+        .or(creative.id.as_deref())
+        .map_or_else(|| format!("#{creative_index}"), str::to_string);
+    format!("{} Creative {creative_id}", ad_location(ad_index, ad))
+}
+
+/// The issues of a parsed VAST reply
+pub fn validate(vast: &vast4_rs::Vast) -> Vec<VastIssue> {
+    let mut issues = Vec::new();
+    for (ad_index, ad) in vast.ads.iter().enumerate() {
+        let location = ad_location(ad_index, ad);
+        let Some(in_line) = &ad.in_line else {
+            if ad.wrapper.is_none() {
+                issues.push(VastIssue::new("empty_ad", &location, "the Ad has neither InLine nor Wrapper", false));
+            }
+            continue;
+        };
+        for impression in in_line.impressions.iter().filter(|impression| !is_valid_uri(&impression.uri)) {
+            let detail = format!("Impression {:?} is not an HTTP(S) URL", impression.uri.trim());
+            issues.push(VastIssue::new("malformed_impression_uri", &location, detail, false));
+        }
+        for (creative_index, creative) in in_line.creatives.creatives.iter().enumerate() {
+            issues.extend(creative_issues(creative, &creative_location(ad_index, ad, creative_index, creative)));
+        }
+    }
+    issues
+}
+
+/// Drop the linear creatives with a fatal issue, returns how many were dropped
+pub fn strip_invalid(vast: &mut vast4_rs::Vast) -> usize {
+    let mut dropped = 0;
+    for in_line in vast.ads.iter_mut().filter_map(|ad| ad.in_line.as_mut()) {
+        let count = in_line.creatives.creatives.len();
+        in_line
+            .creatives
+            .creatives
+            .retain(|creative| !creative_issues(creative, "").iter().any(|issue| issue.fatal));
+        dropped += count - in_line.creatives.creatives.len();
+    }
+    dropped
+}
+
+/// Parses the VAST replies of the ad servers, reporting their issues in the
+/// logs, in `vast_issues_total` and by ad server on /debug/vast. With `strict`,
+/// the linear creatives with a fatal issue are left out of the pods.
+#[derive(Clone, Default)]
+pub struct VastValidator {
+    strict: bool,
+    // The last replies with issues of each ad server, latest first
+    reports: Arc<DashMap<String, VecDeque<json::JsonValue>>>,
+    metrics: web::Data<Metrics>,
+}
+
+impl VastValidator {
+    pub fn new(strict: bool, metrics: web::Data<Metrics>) -> Self {
+        Self {
+            strict,
+            reports: Arc::default(),
+            metrics,
+        }
+    }
+
+    /// The VAST of an ad server reply, empty when it can't be parsed
+    pub fn parse<'a>(&self, ad_url: &Url, xml: &'a str) -> vast4_rs::Vast<'a> {
+        // A failed ad server request was reported already
+        if xml.trim().is_empty() {
+            return vast4_rs::Vast::default();
+        }
+        let mut vast: vast4_rs::Vast = match vast4_rs::from_str(xml) {
+            Ok(vast) => vast,
+            Err(err) => {
+                let issue = VastIssue::new("parse_error", "VAST", format!("{err:?}"), true);
+                self.report(ad_url, &[issue]);
+                return vast4_rs::Vast::default();
+            }
+        };
+        let issues = validate(&vast);
+        if !issues.is_empty() {
+            self.report(ad_url, &issues);
+        }
+        if self.strict && issues.iter().any(|issue| issue.fatal) {
+            let dropped = strip_invalid(&mut vast);
+            log::warn!("Dropped {dropped} invalid creatives of the VAST of {ad_url}");
+        }
+        vast
+    }
+
+    fn report(&self, ad_url: &Url, issues: &[VastIssue]) {
+        let ad_server = ad_url.host_str().unwrap_or_default().to_string();
+        for issue in issues {
+            log::warn!("VAST of {ad_server}, {}: {} ({})", issue.location, issue.detail, issue.kind);
+            self.metrics.inc("vast_issues_total", &[("ad_server", &ad_server), ("issue", issue.kind)]);
+        }
+        let report = json::object! {
+            "at": chrono::Utc::now().to_rfc3339(),
+            "url": ad_url.as_str(),
+            "issues": issues.iter().map(VastIssue::to_json).collect::<Vec<_>>(),
+        };
+        let mut reports = self.reports.entry(ad_server).or_default();
+        reports.push_front(report);
+        reports.truncate(MAX_REPORTS);
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut ad_servers = json::object! {};
+        for entry in self.reports.iter() {
+            ad_servers[entry.key().as_str()] = entry.value().iter().cloned().collect::<Vec<_>>().into();
+        }
+        json::object! {
+            "strict": self.strict,
+            "ad_servers": ad_servers,
+        }
+    }
+}
+
+impl fmt::Debug for VastValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VastValidator(strict: {})", self.strict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_the_issues_of_the_creatives() {
+        let xml = r#"<VAST version="4.1"><Ad id="1"><InLine><AdSystem>test</AdSystem><AdTitle>ad</AdTitle><Impression><![CDATA[/relative]]></Impression><Creatives>
+            <Creative id="ok" adId="ok"><Linear><Duration>00:00:10</Duration><MediaFiles><MediaFile delivery="progressive" type="video/mp4" width="1280" height="720"><![CDATA[https://cdn.example.com/ad.mp4]]></MediaFile></MediaFiles></Linear></Creative>
+            <Creative id="no-duration" adId="no-duration"><Linear><MediaFiles><MediaFile delivery="progressive" type="video/mp4" width="1280" height="720"><![CDATA[cdn.example.com/ad.mp4]]></MediaFile></MediaFiles></Linear></Creative>
+            <Creative id="no-media" adId="no-media"><Linear><Duration>00:00:10</Duration></Linear></Creative>
+            </Creatives></InLine></Ad></VAST>"#;
+        let mut vast: vast4_rs::Vast = vast4_rs::from_str(xml).unwrap();
+        let issues = validate(&vast)
+            .into_iter()
+            .map(|issue| (issue.kind, issue.location))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            issues,
+            [
+                ("malformed_impression_uri", "Ad 1".to_string()),
+                ("missing_duration", "Ad 1 Creative no-duration".to_string()),
+                ("malformed_media_uri", "Ad 1 Creative no-duration".to_string()),
+                ("empty_media_files", "Ad 1 Creative no-media".to_string()),
+            ]
+        );
+
+        assert_eq!(strip_invalid(&mut vast), 2);
+        let creatives = &vast.ads[0].in_line.as_ref().unwrap().creatives.creatives;
+        assert_eq!(creatives.len(), 1);
+        assert_eq!(creatives[0].id.as_deref(), Some("ok"));
+    }
+}
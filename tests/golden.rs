@@ -0,0 +1,157 @@
+//! Golden-file tests of the playlist manipulation. The recorded playlists
+//! under `tests/golden/origin` are served by a local origin and requested
+//! through the proxy handlers, the responses are compared with the files
+//! under `tests/golden/expected`.
+//!
+//! After an intended change of the output, rewrite the expected files with
+//! `UPDATE_GOLDEN=1 cargo test --test golden` and review their diff.
+
+use actix_web::dev::ServerHandle;
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, test, web};
+use ad_proxy::epoch::StreamEpoch;
+use ad_proxy::metrics::Metrics;
+use ad_proxy::origin_cache::OriginCache;
+use ad_proxy::shutdown::ShutdownState;
+use ad_proxy::{AdBreakSettings, AssetUrlFormat, AvailableAds, ServerConfig, StreamState, UserDefinedQueryParams, handle_media_stream};
+use std::path::PathBuf;
+use std::sync::Once;
+use std::time::Duration;
+use url::Url;
+
+// The recorded live playlists start at 2024-03-01T12:00:00Z, the static ad
+// breaks every 30 seconds from the epoch land inside their windows
+const EPOCH: &str = "2024-03-01T11:59:00Z";
+
+// The proxy writes the date times of the interstitials in its local time zone
+static UTC: Once = Once::new();
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+async fn serve_recorded_playlist(req: HttpRequest) -> HttpResponse {
+    let path = golden_dir().join("origin").join(req.path().trim_start_matches('/'));
+    match std::fs::read(path) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/vnd.apple.mpegurl")
+            .body(body),
+        Err(_) => HttpResponse::NotFound().finish(),
+    }
+}
+
+fn start_origin() -> (Url, ServerHandle) {
+    let server = HttpServer::new(|| App::new().default_service(web::get().to(serve_recorded_playlist)))
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .expect("Failed to bind the origin");
+    let addr = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    (Url::parse(&format!("http://{addr}/")).unwrap(), handle)
+}
+
+// Request the playlist through the proxy, proxying any path of the origin
+async fn proxy(path: &str, configure: impl FnOnce(ServerConfig) -> ServerConfig) -> String {
+    // SAFETY: the tests don't read or write any other environment variable concurrently
+    UTC.call_once(|| unsafe { std::env::set_var("TZ", "UTC") });
+    let (origin, handle) = start_origin();
+    let ad_breaks = AdBreakSettings {
+        ad_server_url: Url::parse("http://ads.example.com/vast").unwrap(),
+        target_ad_duration: 10,
+        target_repeating_cycle: 30,
+        target_ad_number: 1000,
+    };
+    let config = configure(ServerConfig::new(origin, Url::parse("http://proxy.example.com/").unwrap(), ad_breaks));
+    let metrics = web::Data::new(Metrics::default());
+    let epoch = chrono::DateTime::parse_from_rfc3339(EPOCH).unwrap().to_utc();
+    let origin_cache = OriginCache::new(Duration::ZERO, 8 * 1024 * 1024, metrics.clone());
+    let stream = StreamState::new(config, origin_cache, &AvailableAds::default()).with_epoch(StreamEpoch::new(epoch));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(awc::Client::default()))
+            .app_data(web::Data::new(stream))
+            .app_data(web::Data::new(UserDefinedQueryParams::default()))
+            .app_data(web::Data::new(ShutdownState::default()))
+            .app_data(metrics)
+            .default_service(web::to(handle_media_stream)),
+    )
+    .await;
+    let response = test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+    assert!(response.status().is_success(), "{path}: {}", response.status());
+    let body = test::read_body(response).await;
+    handle.stop(false).await;
+
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+async fn check(path: &str) {
+    check_with(path, path, |config| config).await;
+}
+
+// Compare the playlist proxied with another configuration with its own file
+async fn check_with(path: &str, expected: &str, configure: impl FnOnce(ServerConfig) -> ServerConfig) {
+    let actual = proxy(&format!("/{path}"), configure).await;
+    let expected_path = golden_dir().join("expected").join(expected);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(expected_path.parent().unwrap()).unwrap();
+        std::fs::write(&expected_path, &actual).unwrap();
+        return;
+    }
+
+    let expected = std::fs::read_to_string(&expected_path).unwrap_or_else(|err| {
+        panic!("{}: {err}, run with UPDATE_GOLDEN=1 to create it", expected_path.display())
+    });
+    assert_eq!(
+        actual,
+        expected,
+        "{path} differs from {}, run with UPDATE_GOLDEN=1 if the change is intended",
+        expected_path.display()
+    );
+}
+
+#[actix_web::test]
+async fn vod_master_playlist() {
+    check("vod/master.m3u8").await;
+}
+
+#[actix_web::test]
+async fn vod_media_playlist() {
+    check("vod/720p.m3u8").await;
+}
+
+#[actix_web::test]
+async fn vod_media_playlist_with_rolls() {
+    check_with("vod/720p.m3u8", "vod-rolls/720p.m3u8", |config| config.with_vod_rolls(true, true)).await;
+}
+
+#[actix_web::test]
+async fn live_master_playlist_with_absolute_urls() {
+    check("live/master.m3u8").await;
+}
+
+#[actix_web::test]
+async fn live_media_playlist() {
+    check("live/720p.m3u8").await;
+}
+
+#[actix_web::test]
+async fn live_media_playlist_with_asset_paths() {
+    check_with("live/720p.m3u8", "live-paths/720p.m3u8", |config| {
+        config.with_asset_url_format(AssetUrlFormat::Path)
+    })
+    .await;
+}
+
+#[actix_web::test]
+async fn low_latency_media_playlist() {
+    check("llhls/720p.m3u8").await;
+}
+
+#[actix_web::test]
+async fn scte35_marked_media_playlist() {
+    check("scte/720p.m3u8").await;
+}
@@ -0,0 +1,255 @@
+//! Tests of the segment pass-through. A local origin serves a segment with
+//! byte-range support, the segment is requested through the proxy handlers
+//! with and without a `Range` header, and with a HEAD request. A large
+//! segment is streamed to an HTTP/2 player reading it slowly.
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::ServerHandle;
+use actix_web::http::{Method, StatusCode, header};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, test, web};
+use ad_proxy::metrics::Metrics;
+use ad_proxy::origin_cache::OriginCache;
+use ad_proxy::shutdown::ShutdownState;
+use ad_proxy::{AdBreakSettings, AvailableAds, ServerConfig, StreamState, UserDefinedQueryParams, handle_media_stream};
+use futures_util::StreamExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use url::Url;
+
+const SEGMENT_SIZE: usize = 4096;
+// Larger than what the socket buffers between the origin and the proxy hold
+const LARGE_SEGMENT_SIZE: usize = 32 * 1024 * 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn segment() -> Vec<u8> {
+    (0..SEGMENT_SIZE).map(|i| (i % 251) as u8).collect()
+}
+
+// Serve the segment, honoring a single `bytes=<first>-<last>` range
+async fn serve_segment(req: HttpRequest) -> HttpResponse {
+    let body = segment();
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes="))
+        .and_then(|value| value.split_once('-'))
+        .and_then(|(first, last)| Some((first.parse::<usize>().ok()?, last.parse::<usize>().ok()?)));
+    match range {
+        Some((first, last)) if first <= last && last < body.len() => HttpResponse::PartialContent()
+            .content_type("video/mp4")
+            .insert_header((header::CONTENT_RANGE, format!("bytes {first}-{last}/{}", body.len())))
+            .body(body[first..=last].to_vec()),
+        _ => HttpResponse::Ok().content_type("video/mp4").body(body),
+    }
+}
+
+fn start_origin() -> (Url, ServerHandle) {
+    let server = HttpServer::new(|| App::new().default_service(web::to(serve_segment)))
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .expect("Failed to bind the origin");
+    let addr = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    (Url::parse(&format!("http://{addr}/")).unwrap(), handle)
+}
+
+struct Proxied {
+    status: StatusCode,
+    content_range: Option<String>,
+    // The length the body is sent with, None when chunked
+    content_length: Option<u64>,
+    // Whether the body was streamed rather than buffered
+    streamed: bool,
+    body: Vec<u8>,
+}
+
+// Request the segment through the proxy
+async fn proxy(method: Method, range: Option<&str>) -> Proxied {
+    let (origin, handle) = start_origin();
+    let ad_breaks = AdBreakSettings {
+        ad_server_url: Url::parse("http://ads.example.com/vast").unwrap(),
+        target_ad_duration: 10,
+        target_repeating_cycle: 30,
+        target_ad_number: 1000,
+    };
+    let config = ServerConfig::new(origin, Url::parse("http://proxy.example.com/").unwrap(), ad_breaks);
+    let metrics = web::Data::new(Metrics::default());
+    let origin_cache = OriginCache::new(Duration::ZERO, 8 * 1024 * 1024, metrics.clone());
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(awc::Client::default()))
+            .app_data(web::Data::new(StreamState::new(config, origin_cache, &AvailableAds::default())))
+            .app_data(web::Data::new(UserDefinedQueryParams::default()))
+            .app_data(web::Data::new(ShutdownState::default()))
+            .app_data(metrics)
+            .default_service(web::to(handle_media_stream)),
+    )
+    .await;
+    let mut request = test::TestRequest::default()
+        .method(method)
+        .uri("/vod/720p/segment_0.m4s");
+    if let Some(range) = range {
+        request = request.insert_header((header::RANGE, range));
+    }
+    let response = test::call_service(&app, request.to_request()).await;
+    let status = response.status();
+    let content_range = response
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .map(|value| value.to_str().unwrap().to_string());
+    let content_length = match response.response().body().size() {
+        BodySize::Sized(length) => Some(length),
+        _ => None,
+    };
+    // A buffered body is held as bytes, a streamed one is read as it arrives
+    let (streamed, body) = match response.into_body().try_into_bytes() {
+        Ok(body) => (false, body),
+        Err(body) => (true, actix_web::body::to_bytes(body).await.unwrap()),
+    };
+    handle.stop(false).await;
+
+    Proxied {
+        status,
+        content_range,
+        content_length,
+        streamed,
+        body: body.to_vec(),
+    }
+}
+
+#[actix_web::test]
+async fn range_request_is_passed_through() {
+    let proxied = proxy(Method::GET, Some("bytes=100-1123")).await;
+    assert_eq!(proxied.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(proxied.content_range.as_deref(), Some("bytes 100-1123/4096"));
+    assert_eq!(proxied.content_length, Some(1024));
+    assert!(proxied.streamed, "the segment body was buffered");
+    assert_eq!(proxied.body, segment()[100..=1123]);
+}
+
+#[actix_web::test]
+async fn request_without_range_gets_the_whole_segment() {
+    let proxied = proxy(Method::GET, None).await;
+    assert_eq!(proxied.status, StatusCode::OK);
+    assert_eq!(proxied.content_range, None);
+    assert_eq!(proxied.content_length, Some(SEGMENT_SIZE as u64));
+    assert!(proxied.streamed, "the segment body was buffered");
+    assert_eq!(proxied.body, segment());
+}
+
+#[actix_web::test]
+async fn head_request_gets_the_segment_length() {
+    let proxied = proxy(Method::HEAD, None).await;
+    assert_eq!(proxied.status, StatusCode::OK);
+    assert_eq!(proxied.content_length, Some(SEGMENT_SIZE as u64));
+    assert!(proxied.body.is_empty());
+}
+
+// Serve the large segment in chunks, counting the bytes the proxy read
+fn start_large_origin(sent: Arc<AtomicUsize>) -> (Url, ServerHandle) {
+    let server = HttpServer::new(move || {
+        let sent = sent.clone();
+        App::new().default_service(web::to(move || {
+            let sent = sent.clone();
+            async move {
+                let chunks = futures_util::stream::iter(0..LARGE_SEGMENT_SIZE / CHUNK_SIZE).map(move |index| {
+                    sent.fetch_add(CHUNK_SIZE, Ordering::SeqCst);
+                    Ok::<_, actix_web::Error>(web::Bytes::from(vec![(index % 251) as u8; CHUNK_SIZE]))
+                });
+                HttpResponse::Ok()
+                    .content_type("video/mp4")
+                    .no_chunking(LARGE_SEGMENT_SIZE as u64)
+                    .streaming(chunks)
+            }
+        }))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .expect("Failed to bind the origin");
+    let addr = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    (Url::parse(&format!("http://{addr}/")).unwrap(), handle)
+}
+
+#[actix_web::test]
+async fn large_segment_is_streamed_at_the_pace_of_an_http2_player() {
+    let sent = Arc::new(AtomicUsize::new(0));
+    let (origin, origin_handle) = start_large_origin(sent.clone());
+    let ad_breaks = AdBreakSettings {
+        ad_server_url: Url::parse("http://ads.example.com/vast").unwrap(),
+        target_ad_duration: 10,
+        target_repeating_cycle: 30,
+        target_ad_number: 1000,
+    };
+    let config = ServerConfig::new(origin, Url::parse("http://proxy.example.com/").unwrap(), ad_breaks);
+    let metrics = web::Data::new(Metrics::default());
+    let stream = StreamState::new(
+        config,
+        OriginCache::new(Duration::ZERO, 8 * 1024 * 1024, metrics.clone()),
+        &AvailableAds::default(),
+    );
+    // The proxy listening as with --http2
+    let proxy = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(awc::Client::default()))
+            .app_data(web::Data::new(stream.clone()))
+            .app_data(web::Data::new(UserDefinedQueryParams::default()))
+            .app_data(web::Data::new(ShutdownState::default()))
+            .app_data(metrics.clone())
+            .default_service(web::to(handle_media_stream))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind_auto_h2c(("127.0.0.1", 0))
+    .expect("Failed to bind the proxy");
+    let addr = proxy.addrs()[0];
+    let proxy = proxy.run();
+    let proxy_handle = proxy.handle();
+    actix_web::rt::spawn(proxy);
+
+    let tcp = actix_web::rt::net::TcpStream::connect(addr).await.unwrap();
+    let (mut player, connection) = h2::client::Builder::new()
+        .initial_window_size(1024 * 1024)
+        .initial_connection_window_size(1024 * 1024)
+        .handshake::<_, web::Bytes>(tcp)
+        .await
+        .unwrap();
+    actix_web::rt::spawn(async move { connection.await.ok() });
+    let request = http::Request::get(format!("http://{addr}/vod/720p/segment_0.m4s")).body(()).unwrap();
+    let (response, _) = player.send_request(request, true).unwrap();
+    let response = response.await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_LENGTH.as_str()], LARGE_SEGMENT_SIZE.to_string());
+
+    // Without the player reading, the origin is read no further than the buffers hold
+    actix_web::rt::time::sleep(Duration::from_millis(500)).await;
+    let sent_while_stalled = sent.load(Ordering::SeqCst);
+    assert!(sent_while_stalled < LARGE_SEGMENT_SIZE / 2, "{sent_while_stalled} bytes read from the origin");
+
+    let mut body = response.into_body();
+    let mut received = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.unwrap();
+        body.flow_control().release_capacity(chunk.len()).unwrap();
+        assert!(
+            chunk.iter().enumerate().all(|(offset, byte)| *byte == ((received + offset) / CHUNK_SIZE % 251) as u8),
+            "corrupted chunk at {received}"
+        );
+        received += chunk.len();
+    }
+    assert_eq!(received, LARGE_SEGMENT_SIZE);
+
+    proxy_handle.stop(false).await;
+    origin_handle.stop(false).await;
+}